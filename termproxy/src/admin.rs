@@ -0,0 +1,129 @@
+//! Watching any session as an administrator
+//!
+//! Sessions writing status files to `--status-dir` also listen on an admin socket,
+//! `<session-id>.admin.sock` next to the status file. Processes running as root, or with
+//! CAP_SYS_ADMIN, connect to it with `termproxy observe <session-id>` to watch the session for
+//! incident response, without a ticket and regardless of the session's clients. They only get
+//! to watch: whatever they send is discarded.
+//!
+//! An administrator never watches unnoticed, joining and leaving is announced in the session's
+//! output to all of its clients, both visible on the terminal and as control message.
+
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, format_err, Result};
+use mio::net::UnixListener;
+use nix::sys::socket::{getsockopt, sockopt};
+
+use crate::cli::ObserveOptions;
+use crate::connection::Connection;
+
+/// The bit of CAP_SYS_ADMIN in the capability sets.
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// The path of the admin socket of the session `session_id`.
+pub fn socket_path(dir: &Path, session_id: &str) -> PathBuf {
+    dir.join(format!("{session_id}.admin.sock"))
+}
+
+pub struct AdminSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl AdminSocket {
+    pub fn bind(dir: &Path, session_id: &str) -> Result<Self> {
+        std::fs::create_dir_all(dir).map_err(|err| {
+            format_err!("failed to create admin socket directory {dir:?} - {err}")
+        })?;
+        let path = socket_path(dir, session_id);
+        let listener = crate::listener::bind_unix(&path, false)
+            .map_err(|err| format_err!("failed to bind admin socket {path:?} - {err}"))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Self { listener, path })
+    }
+
+    /// Accepts a connection on the admin socket, `None` if no one is waiting.
+    pub fn accept(&self) -> Result<Option<AdminPeer>> {
+        let stream = match self.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let credentials = getsockopt(stream.as_raw_fd(), sockopt::PeerCredentials)?;
+        Ok(Some(AdminPeer {
+            connection: Connection::Unix(stream),
+            uid: credentials.uid(),
+            pid: credentials.pid(),
+        }))
+    }
+}
+
+/// A process that connected to the admin socket
+pub struct AdminPeer {
+    pub connection: Connection,
+    pub uid: u32,
+    pid: i32,
+}
+
+impl AdminPeer {
+    /// Checks whether the process is an administrator, i.e. root or has CAP_SYS_ADMIN.
+    pub fn authorize(&self) -> Result<()> {
+        if self.uid != 0 && !has_sys_admin(self.pid) {
+            bail!("uid {} is neither root nor has CAP_SYS_ADMIN", self.uid);
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for AdminSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for AdminSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Whether the process `pid` has CAP_SYS_ADMIN in its effective capabilities.
+///
+/// A process that is gone by now has no status left to read and is rejected, the connection
+/// would not be watched by anyone anyway.
+fn has_sys_admin(pid: i32) -> bool {
+    let Ok(status) = std::fs::read_to_string(format!("/proc/{pid}/status")) else {
+        return false;
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .is_some_and(|caps| caps & (1 << CAP_SYS_ADMIN) != 0)
+}
+
+/// Watches the session `options.session_id`, printing its output until it ends.
+pub fn observe(options: &ObserveOptions) -> Result<()> {
+    let path = socket_path(&options.status_dir, &options.session_id);
+    let mut stream = std::os::unix::net::UnixStream::connect(&path).map_err(|err| {
+        format_err!(
+            "failed to connect to session '{}' - {err}",
+            options.session_id
+        )
+    })?;
+    // stdout is line buffered, but a prompt has to show up without a line break as well
+    let mut stdout = std::io::stdout().lock();
+    let mut buf = [0u8; 4096];
+    loop {
+        let bytes = stream.read(&mut buf)?;
+        if bytes == 0 {
+            return Ok(());
+        }
+        stdout.write_all(&buf[..bytes])?;
+        stdout.flush()?;
+    }
+}
@@ -0,0 +1,53 @@
+//! Accounting of the relay loop's wakeups
+//!
+//! An idle session is supposed to sleep until the client or the command does something, or a
+//! timer of the session expires. With `--audit-wakeups` the loop counts its iterations, the
+//! polls it did without waiting because work was left, and the wakeups that brought neither an
+//! event nor an expired timer, and prints the counts for every second in which it woke up at all.
+//! The counts of a second are printed with the first wakeup after it, so an idle session stays
+//! silent instead of waking up to report that it was idle.
+
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+pub struct WakeupAudit {
+    /// The first iteration counted in the current window
+    window_start: Instant,
+    iterations: u64,
+    zero_timeout: u64,
+    spurious: u64,
+}
+
+impl WakeupAudit {
+    pub fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            iterations: 0,
+            zero_timeout: 0,
+            spurious: 0,
+        }
+    }
+
+    /// Accounts for a loop iteration, which polled without waiting if `zero_timeout` is set.
+    ///
+    /// Returns a report of the previous window once it ended.
+    pub fn record(&mut self, zero_timeout: bool, spurious: bool) -> Option<String> {
+        let now = Instant::now();
+        let mut report = None;
+        if now.duration_since(self.window_start) >= WINDOW {
+            if self.iterations > 0 {
+                report = Some(format!(
+                    "loop wakeups: {} iterations, {} polls without waiting, {} spurious \
+                     wakeups within a second",
+                    self.iterations, self.zero_timeout, self.spurious,
+                ));
+            }
+            *self = Self::new();
+        }
+        self.iterations += 1;
+        self.zero_timeout += zero_timeout as u64;
+        self.spurious += spurious as u64;
+        report
+    }
+}
@@ -0,0 +1,94 @@
+//! Authentication of the client's ticket against the local API daemon
+
+use anyhow::{bail, Result};
+use zeroize::Zeroize;
+
+use crate::cli::Options;
+
+/// The credentials the API daemon hands out on successful authentication
+#[derive(Default)]
+pub struct AuthResponse {
+    pub ticket: Option<String>,
+    pub csrf_token: Option<String>,
+}
+
+impl Drop for AuthResponse {
+    fn drop(&mut self) {
+        self.ticket.zeroize();
+        self.csrf_token.zeroize();
+    }
+}
+
+#[cfg(feature = "auth-http")]
+impl AuthResponse {
+    fn parse(res: ureq::Response) -> Self {
+        let value: serde_json::Value = match serde_json::from_reader(res.into_reader()) {
+            Ok(value) => value,
+            Err(err) => {
+                crate::log::warn(
+                    "auth-response-invalid",
+                    format_args!("could not parse authentication response - {err}"),
+                );
+                return Self::default();
+            }
+        };
+
+        let data = &value["data"];
+        Self {
+            ticket: data["ticket"].as_str().map(String::from),
+            csrf_token: data["CSRFPreventionToken"].as_str().map(String::from),
+        }
+    }
+}
+
+#[cfg(feature = "auth-http")]
+pub fn authenticate(
+    username: &[u8],
+    ticket: &[u8],
+    options: &Options,
+    listen_port: u16,
+) -> Result<AuthResponse> {
+    let mut post_fields: Vec<(&str, &str)> = Vec::with_capacity(5);
+    post_fields.push(("username", std::str::from_utf8(username)?));
+    post_fields.push(("password", std::str::from_utf8(ticket)?));
+    post_fields.push(("path", &options.acl_path));
+    if let Some(perm) = options.acl_permission.as_ref() {
+        post_fields.push(("privs", perm));
+    }
+
+    // if the listen-port was passed indirectly via an FD, it's encoded also in the ticket so that
+    // the access system can enforce that the users actually can access that port.
+    let port_str;
+    if options.use_listen_port_as_fd() {
+        port_str = listen_port.to_string();
+        post_fields.push(("port", &port_str));
+    }
+
+    let url = format!(
+        "http://localhost:{}/api2/json/access/ticket",
+        options.api_daemon_port
+    );
+
+    let agent = ureq::AgentBuilder::new()
+        .resolver(crate::dial::InterleavedResolver)
+        .timeout_connect(crate::dial::CONNECT_TIMEOUT)
+        .build();
+    match agent.post(&url).send_form(&post_fields[..]) {
+        Ok(res) if res.status() == 200 => Ok(AuthResponse::parse(res)),
+        Ok(res) | Err(ureq::Error::Status(_, res)) => {
+            let code = res.status();
+            bail!("invalid authentication - {code} {}", res.status_text())
+        }
+        Err(err) => bail!("authentication request failed - {err}"),
+    }
+}
+
+#[cfg(not(feature = "auth-http"))]
+pub fn authenticate(
+    _username: &[u8],
+    _ticket: &[u8],
+    _options: &Options,
+    _listen_port: u16,
+) -> Result<AuthResponse> {
+    bail!("termproxy was built without support for HTTP authentication");
+}
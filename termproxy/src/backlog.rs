@@ -0,0 +1,96 @@
+//! Output of the command while no client is attached
+//!
+//! A session outliving its clients, e.g. one started with `--background` for a long running
+//! task, keeps reading the terminal while no one watches, so the command doesn't block on a
+//! full terminal. The output goes to a backlog of a fixed size instead, which drops the oldest
+//! output once it is full, and is replayed to the next client attaching.
+
+use std::collections::VecDeque;
+
+/// How much output a session keeps while no client is attached.
+pub const BACKLOG_SIZE: usize = 64 * 1024;
+
+pub struct Backlog {
+    data: VecDeque<u8>,
+    capacity: usize,
+    /// The output dropped since the backlog was replayed the last time
+    dropped: u64,
+}
+
+impl Backlog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            data: VecDeque::new(),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The output dropped because the backlog was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Appends `output`, dropping the oldest output if it doesn't fit.
+    pub fn push(&mut self, output: &[u8]) {
+        let skipped = output.len().saturating_sub(self.capacity);
+        let output = &output[skipped..];
+        let excess = (self.data.len() + output.len()).saturating_sub(self.capacity);
+        self.data.drain(..excess);
+        self.dropped += (skipped + excess) as u64;
+        self.data.extend(output);
+    }
+
+    /// Drops up to `max` bytes of the oldest output to make room elsewhere, returns how much.
+    pub fn drop_oldest(&mut self, max: usize) -> usize {
+        let len = max.min(self.data.len());
+        self.data.drain(..len);
+        self.dropped += len as u64;
+        len
+    }
+
+    /// Takes up to `max` bytes of the oldest output, for replaying it.
+    pub fn take(&mut self, max: usize) -> Vec<u8> {
+        let len = max.min(self.data.len());
+        let taken = self.data.drain(..len).collect();
+        if self.data.is_empty() {
+            self.dropped = 0;
+        }
+        taken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_oldest_output() {
+        let mut backlog = Backlog::new(8);
+        backlog.push(b"12345");
+        backlog.push(b"6789");
+        assert_eq!(backlog.len(), 8);
+        assert_eq!(backlog.dropped(), 1);
+        // output longer than the backlog keeps its end only
+        backlog.push(b"abcdefghij");
+        assert_eq!(backlog.dropped(), 11);
+        assert_eq!(backlog.take(3), b"cde");
+        assert_eq!(backlog.take(100), b"fghij");
+        assert!(backlog.is_empty());
+        assert_eq!(backlog.dropped(), 0);
+
+        // making room for something else counts as dropped as well
+        backlog.push(b"123456");
+        assert_eq!(backlog.drop_oldest(4), 4);
+        assert_eq!(backlog.dropped(), 4);
+        assert_eq!(backlog.take(100), b"56");
+    }
+}
@@ -0,0 +1,61 @@
+//! Detection of binary output
+//!
+//! Accidentally printing a binary file sends a lot of random control characters and escape
+//! sequences to the terminal, which can leave it in an unusable state. Text, even with lots of
+//! escape sequences, hardly contains control characters other than line breaks, tabs and escape
+//! itself, so output is judged in windows by the share of other control characters in it.
+
+/// The amount of output judged at once.
+const WINDOW: usize = 4096;
+
+/// Windows with at least this many suspicious bytes are considered binary, random data has about
+/// twice as many.
+const THRESHOLD: usize = WINDOW / 16;
+
+/// The outcome of scanning some output
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// No window was completed.
+    Undecided,
+    /// The last completed window looked like text.
+    Text,
+    /// A completed window looked like binary data.
+    Binary,
+}
+
+#[derive(Default)]
+pub struct BinaryDetector {
+    seen: usize,
+    suspicious: usize,
+}
+
+fn is_suspicious(byte: u8) -> bool {
+    match byte {
+        // backspace, tab, line breaks, bell, shift in/out and escape are common in text
+        0x07..=0x0a | 0x0d..=0x0f | 0x1b => false,
+        0x00..=0x1f | 0x7f => true,
+        _ => false,
+    }
+}
+
+impl BinaryDetector {
+    pub fn scan(&mut self, data: &[u8]) -> Verdict {
+        let mut verdict = Verdict::Undecided;
+        for byte in data {
+            self.seen += 1;
+            if is_suspicious(*byte) {
+                self.suspicious += 1;
+            }
+            if self.seen == WINDOW {
+                if self.suspicious >= THRESHOLD {
+                    verdict = Verdict::Binary;
+                } else if verdict != Verdict::Binary {
+                    verdict = Verdict::Text;
+                }
+                self.seen = 0;
+                self.suspicious = 0;
+            }
+        }
+        verdict
+    }
+}
@@ -0,0 +1,128 @@
+//! Helper for running the terminal command in a dedicated cgroup
+//!
+//! Only the unified (v2) hierarchy is supported. The cgroup is named after the session ID, so
+//! resource usage of whatever got started from a console can be attributed to that session.
+//!
+//! With `--memory-max` and `--cpu-quota`, the cgroup gets limits, so a runaway command can't
+//! starve the host. They need the memory and cpu controllers enabled for the children of the
+//! parent, in its `cgroup.subtree_control`.
+
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use anyhow::{format_err, Result};
+
+const CGROUP_MOUNT: &str = "/sys/fs/cgroup";
+
+/// The length of a period of `cpu.max`, in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+/// Resource limits for the command
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CgroupLimits {
+    /// The memory the command may use, in bytes
+    pub memory_max: Option<u64>,
+    /// The CPU time the command may use, in percent of one CPU
+    pub cpu_quota: Option<u32>,
+}
+
+impl CgroupLimits {
+    pub fn is_empty(&self) -> bool {
+        self.memory_max.is_none() && self.cpu_quota.is_none()
+    }
+
+    /// The limits as properties of a systemd unit.
+    pub fn unit_properties(&self) -> Vec<String> {
+        let mut properties = Vec::new();
+        if let Some(bytes) = self.memory_max {
+            properties.push(format!("MemoryMax={bytes}"));
+        }
+        if let Some(percent) = self.cpu_quota {
+            properties.push(format!("CPUQuota={percent}%"));
+        }
+        properties
+    }
+
+    /// Sets the limits on the cgroup at `path`.
+    fn apply(&self, path: &Path) -> Result<()> {
+        if let Some(bytes) = self.memory_max {
+            write_limit(path, "memory.max", &bytes.to_string())?;
+        }
+        if let Some(percent) = self.cpu_quota {
+            let quota = u64::from(percent) * CPU_PERIOD / 100;
+            write_limit(path, "cpu.max", &format!("{quota} {CPU_PERIOD}"))?;
+        }
+        Ok(())
+    }
+}
+
+fn write_limit(path: &Path, file: &str, value: &str) -> Result<()> {
+    std::fs::write(path.join(file), value).map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => {
+            let controller = file.split('.').next().unwrap_or(file);
+            format_err!("the {controller} controller is not enabled for cgroup {path:?}")
+        }
+        _ => format_err!("failed to set {file} of cgroup {path:?} - {err}"),
+    })
+}
+
+pub struct SessionCgroup {
+    path: PathBuf,
+    procs: File,
+}
+
+impl SessionCgroup {
+    /// Creates the cgroup `termproxy-<session_id>` below `parent`, which is relative to the
+    /// cgroup2 mount point, with `limits`.
+    pub fn create(parent: &str, session_id: &str, limits: &CgroupLimits) -> Result<Self> {
+        let path = Path::new(CGROUP_MOUNT)
+            .join(parent.trim_start_matches('/'))
+            .join(format!("termproxy-{session_id}"));
+
+        std::fs::create_dir(&path)
+            .map_err(|err| format_err!("failed to create cgroup {path:?} - {err}"))?;
+
+        if let Err(err) = limits.apply(&path) {
+            let _ = std::fs::remove_dir(&path);
+            return Err(err);
+        }
+
+        let procs = match std::fs::OpenOptions::new()
+            .write(true)
+            .open(path.join("cgroup.procs"))
+        {
+            Ok(procs) => procs,
+            Err(err) => {
+                let _ = std::fs::remove_dir(&path);
+                return Err(format_err!("failed to open cgroup {path:?} - {err}"));
+            }
+        };
+
+        Ok(Self { path, procs })
+    }
+
+    /// The file descriptor of the cgroup's `cgroup.procs` file.
+    ///
+    /// The command joins the cgroup by writing `0` to it between fork and exec, which does not
+    /// need any allocation. The file is opened with `O_CLOEXEC`, so the command won't inherit it.
+    pub fn procs_fd(&self) -> RawFd {
+        self.procs.as_raw_fd()
+    }
+}
+
+impl Drop for SessionCgroup {
+    fn drop(&mut self) {
+        // only succeeds if no process is left in the cgroup, which is fine - we don't want to
+        // kill anything the user explicitly detached from the session
+        let _ = std::fs::remove_dir(&self.path);
+    }
+}
+
+/// Moves the calling process into the cgroup, for use in a `pre_exec` hook.
+pub fn join_cgroup(procs_fd: RawFd) -> std::io::Result<()> {
+    if unsafe { libc::write(procs_fd, b"0".as_ptr() as *const _, 1) } != 1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
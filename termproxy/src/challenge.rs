@@ -0,0 +1,65 @@
+//! Challenge binding the ticket line to a single connection
+//!
+//! A ticket line captured from one connection is valid for any termproxy instance accepting the
+//! same ticket. With `--auth-challenge-key-fd`, termproxy sends `challenge:PORT:NONCE\n` first,
+//! with its listening port and a random nonce, and the client answers with `PORT:NONCE:MAC\n`
+//! right before its ticket line, where MAC is the hex encoded HMAC-SHA256 over
+//! `PORT:NONCE:TICKET-LINE` with the key shared between the frontend and termproxy. As the nonce
+//! differs for every connection, a captured answer is worthless for any other one.
+
+use anyhow::{bail, Result};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+/// How many random bytes the nonce consists of.
+const NONCE_LEN: usize = 16;
+
+pub struct AuthChallenge {
+    port: u16,
+    nonce: String,
+}
+
+impl AuthChallenge {
+    pub fn new(port: u16) -> Result<Self> {
+        Ok(Self {
+            port,
+            nonce: crate::cli::random_hex(NONCE_LEN)?,
+        })
+    }
+
+    /// The line sent to the client.
+    pub fn line(&self) -> String {
+        format!("challenge:{}:{}\n", self.port, self.nonce)
+    }
+
+    /// Checks the client's answer to the challenge for `ticket_line`, without the line feed.
+    pub fn verify(&self, key: &[u8; 32], answer: &[u8], ticket_line: &[u8]) -> Result<()> {
+        let mut parts = answer.splitn(3, |&b| b == b':');
+        let (Some(port), Some(nonce), Some(mac)) = (parts.next(), parts.next(), parts.next())
+        else {
+            bail!("challenge answer is invalid");
+        };
+        if port != self.port.to_string().as_bytes() {
+            bail!("challenge answer is for another port");
+        }
+        if nonce != self.nonce.as_bytes() {
+            bail!("challenge answer is for another connection");
+        }
+        let expected = mac_hex(key, self.port, &self.nonce, ticket_line)?;
+        if !crate::handshake::secret_matches(mac, expected.as_bytes()) {
+            bail!("challenge answer has an invalid MAC");
+        }
+        Ok(())
+    }
+}
+
+/// The MAC the client has to answer with, as lower case hex digits.
+fn mac_hex(key: &[u8; 32], port: u16, nonce: &str, ticket_line: &[u8]) -> Result<String> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(format!("{port}:{nonce}:").as_bytes())?;
+    signer.update(ticket_line)?;
+    let mac = signer.sign_to_vec()?;
+    Ok(mac.iter().map(|b| format!("{b:02x}")).collect())
+}
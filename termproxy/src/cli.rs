@@ -1,10 +1,51 @@
 use std::ffi::OsString;
 use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::{bail, Result};
+use nix::sys::signal::Signal;
+
+use crate::cgroup::CgroupLimits;
+use crate::control::{parse_hex, parse_signal};
+use crate::pacing::PollStrategy;
+use crate::systemd::ScopeOptions;
 
 const CMD_HELP: &str = "\
 Usage: proxmox-termproxy [OPTIONS] --path <path> <listen-port> -- <terminal-cmd>...
+       proxmox-termproxy [OPTIONS] --path <path> --login-shell <user> <listen-port>
+       proxmox-termproxy [OPTIONS] --path <path> --listen-unix <socket> -- <terminal-cmd>...
+       proxmox-termproxy [OPTIONS] --path <path> --listen-unix <socket> --peer-user
+       proxmox-termproxy [OPTIONS] --path <path> --cmd-from <program> <listen-port>
+       proxmox-termproxy [OPTIONS] --path <path> --attach <session-id> <listen-port>
+       proxmox-termproxy verify-client [--port-as-fd] <listen-port>
+       proxmox-termproxy preflight [--authport <authport>] [-- <terminal-cmd>...]
+       proxmox-termproxy list [--status-dir <dir>] [--output-format <format>]
+       proxmox-termproxy replay [--speed <factor>] <file>
+       proxmox-termproxy serve-recordings --dir <dir> [--port-as-fd] <listen-port>
+       proxmox-termproxy observe [--status-dir <dir>] <session-id>
+       proxmox-termproxy share [--status-dir <dir>] [--valid-for <secs>] [--observe] <session-id>
+
+Commands:
+  verify-client           Instead of running a command, guide the user of a connecting
+                          client through some protocol exercises and report whether the
+                          client's messages conform to the protocol
+  preflight               Check whether the system provides what sessions need, like
+                          pseudo terminals, the API daemon and the terminal command
+  list                    List the sessions writing status files to --status-dir, default
+                          /run/termproxy, as a table (text) or as JSON (json, json-pretty)
+  replay                  Play back a recording made with --record on the terminal, e.g.
+                          as the command of a session, --speed <factor> speeds it up
+  serve-recordings        Serve the recordings in --dir over HTTP on localhost, a listing
+                          and each recording as asciicast, to clients presenting the
+                          access token printed on startup
+  observe                 Watch the session <session-id> of a --status-dir, default
+                          /run/termproxy, as administrator (root or CAP_SYS_ADMIN), its
+                          clients get notified
+  share                   Register a token with the session <session-id> started with
+                          --share-links and print it, a client presenting 'share:TOKEN'
+                          joins it once within --valid-for seconds (default 600), as
+                          observer with --observe
 
 Arguments:
   <listen-port>           Port or file descriptor to listen for TCP connections
@@ -13,15 +54,291 @@ Arguments:
 Options:
       --authport <authport>       Port to relay auth-request, default 85
       --port-as-fd                Use <listen-port> as file descriptor.
+      --listen-unix <socket>      Listen on a Unix socket at <socket> instead of a TCP port,
+                                  the socket file is removed on exit.
+      --unix-abstract <name>      Listen on the Unix socket <name> in the abstract namespace
+                                  instead of a TCP port, the control socket is bound there
+                                  as well, so no socket files are left behind.
       --path <path>               ACL object path to test <perm> on.
       --perm <perm>               Permission to test.
+      --login-shell <user>        Instead of a command, run the login shell of <user>.
+      --cmd-from <program>        Instead of a command, run the command line <program> prints
+                                  as JSON array after the client authenticated, with the user
+                                  and path in TERMPROXY_USER and TERMPROXY_PATH.
+      --preauthenticated <user>   Skip the ticket exchange, the caller already authenticated
+                                  <user>. Requires --port-as-fd and the environment variable
+                                  TERMPROXY_PREAUTHENTICATED set to <user>.
+      --peer-user                 With --listen-unix, skip the ticket exchange and authenticate
+                                  clients as the local user that connected, as <name>@pam,
+                                  and run the login shell of that user.
+      --websocket                 Accept WebSocket connections (RFC 6455) instead of a plain
+                                  TCP stream, the messages carry the same protocol.
+      --tls-cert <path>           Wrap client connections in TLS with the PEM encoded
+                                  certificate chain at <path>, requires --tls-key.
+      --tls-key <path>            The PEM encoded private key for --tls-cert.
+      --connection-secret         Print a random secret to stdout, which the client has to
+                                  send as a line of its own before the ticket line.
+      --encryption-key-fd <fd>    Read a key (64 hex digits) from <fd> and encrypt the
+                                  connection after authentication with it.
+      --auth-challenge-key-fd <fd>
+                                  Read a key (64 hex digits) from <fd> and challenge clients to
+                                  sign the port, a nonce and their ticket line with it.
+      --accept-attempts <n>       Keep listening after a client failed to authenticate, for
+                                  up to <n> connections in total, default 1.
+      --max-clients <n>           Let up to <n> authenticated clients share the session, all
+                                  of them get the output and their input is merged, default 1.
+      --observers                 Clients joining a shared session after the first one only
+                                  watch it, their input is discarded.
+      --resize-policy <policy>    Which size the terminal of a shared session gets when its
+                                  clients differ: 'last' (the client resizing last), 'largest'
+                                  or 'primary' (the client attached longest), announced to all.
+      --share-links               Accept tokens registered with 'share' for clients to join the
+                                  session without a ticket, requires --max-clients of at least 2.
+      --join-context <lines>      Send clients joining a shared session the text on the screen
+                                  and up to <lines> lines scrolled off it, at most 1000.
+      --detachable                Keep the session running without clients, until one attaches
+                                  again. Clients detach with a detach control message, or all
+                                  of them once termproxy gets SIGUSR1.
+      --attach <session-id>       Instead of running a command, hand the client over to the
+                                  detachable session <session-id> on this host.
+      --background <user>         Run the command for <user> right away, without waiting for a
+                                  client, which attach later. Requires --detachable.
+      --reconnect-grace <secs>    Keep the session for <secs> seconds after the client's
+                                  connection dropped, for the same user to reconnect and get
+                                  the output held back in the meantime.
+      --freeze-detached           Stop the command (SIGSTOP) while no client is attached to a
+                                  session with --reconnect-grace or --detachable.
+      --keepalive <secs>          Answer the client's pings, ping clients silent for <secs>
+                                  seconds and drop them after another <secs> seconds.
+      --max-frame-size <bytes>    Send output in writes of at most <bytes> bytes on the wire,
+                                  e.g. to stay below the MTU of a VPN link.
+      --max-input-rate <bytes>    Let each client send at most <bytes> bytes per second, with an
+                                  optional K or M suffix, reading slows down beyond that.
+      --max-memory <bytes>        Limit what termproxy itself buffers for the session (not the
+                                  command, see --memory-max), with an optional K or M suffix:
+                                  scrollback and held back output are dropped beyond it, and
+                                  clients whose buffers don't fit are rejected.
+      --max-auth-line <bytes>     Reject ticket lines, connection secrets and challenge answers
+                                  longer than <bytes> bytes, default is 65535.
+      --tcp-defer-accept <secs>   Only wake up for a connection once data arrived on it, or
+                                  after <secs> seconds (TCP_DEFER_ACCEPT).
+      --tcp-fastopen <qlen>       Enable TCP Fast Open on the listener with the given queue
+                                  length for pending requests.
+      --security-log <path>       Append every authentication attempt to <path> as a JSON
+                                  line, the file must only be accessible by its owner.
+      --export-auth-env           Pass the user, ticket and CSRF prevention token returned
+                                  by the authentication to the command's environment.
+      --allow-signals <list>      Comma separated list of signals (e.g. INT,TERM,KILL) the
+                                  client may send to the command's process group.
+      --allow-hex-input <list>    Comma separated list of byte sequences as hex digits (e.g.
+                                  00,1b5b32347e) the client may send with hex control
+                                  messages, for keys a browser cannot produce.
+      --first-output-timeout <secs>
+                                  Warn the client if the command produced no output after
+                                  <secs> seconds.
+      --first-output-kill         Terminate the command if the first output timeout hits.
+      --quality-hints             Send connection quality hints derived from the jitter of
+                                  the client's pings.
+      --break-command <path>      Program to run when the client requests a serial BREAK, for
+                                  backends that are not a real serial line.
+      --allow-sysrq               Let the client send magic SysRq keys via --break-command,
+                                  each one needs to be confirmed by the client.
+      --lock-after <secs>         Lock the session after <secs> seconds without input, until
+                                  the client sends a new ticket.
+      --idle-timeout <secs>       End the session and hang up the command if no client sent
+                                  any input for <secs> seconds.
+      --child-stderr <mode>       Pass the command's stderr through a separate pipe, either
+                                  inline in red (color) or as control messages (frame).
+      --detect-binary             Pause output that looks like binary data (e.g. a binary file
+                                  printed by accident) until the client resumes or flushes it.
+      --loop-watchdog <secs>      Warn the client if the output repeated the same lines at a
+                                  high rate for <secs> seconds, and let it throttle or kill
+                                  the foreground job producing it.
+      --detect-guest              Tell the client what the output of a serial console comes
+                                  from (firmware, GRUB, Linux, a login prompt, Windows SAC).
+      --semantic-lines            Also send the lines the command finished on the screen as
+                                  plain text in control messages, e.g. for screen readers.
+      --sac                       Translate the output of a Windows SAC console for the
+                                  terminal (line feeds, UTF-16LE text) and let the client
+                                  switch its channels.
+      --secret-provider <path>    Answer password prompts of the command with the first line
+                                  <path> prints, at most 3 times per session.
+      --term <list>               Comma separated list of TERM values for the command, the
+                                  first one with a terminfo entry is used, default
+                                  xterm-256color,xterm,vt100.
+      --terminfo-root <dir>       Look for terminfo entries in the system below <dir>, e.g.
+                                  the root file system of a container, default /.
+      --cols <cols>               The width of the terminal until the client sends its size,
+                                  default 80.
+      --rows <rows>               The height of the terminal until the client sends its size,
+                                  default 20.
+      --escape-char <char>        Enable SSH-like escape sequences like <char>. (disconnect)
+                                  or <char>? (help) at the beginning of a line.
+      --session-id <id>           Identifier for this session, default is a random ID.
+      --tag <key>=<value>         Attach metadata to the session, shown in logs, status
+                                  files and crash reports, can be given multiple times.
+      --env <name>=<value>        Set an environment variable for the command, can be given
+                                  multiple times.
+      --env-keep <name>           Pass the environment variable <name> on to the command,
+                                  besides PATH, USER, HOME, LANG, LANGUAGE and LC_*, can be
+                                  given multiple times.
+      --pam-service <name>        Open a session of the PAM service <name> for the user of
+                                  --login-shell while the shell runs, like login does.
+      --seccomp                   Restrict the system calls of termproxy to the ones the relay
+                                  needs once the command runs, others fail with EPERM.
+      --drop-privileges <user>    Switch termproxy to <user> once the command runs, so the
+                                  relay does not keep running as root.
+      --utmp                      Register the session's terminal in utmp and wtmp, for who, w
+                                  and last, which needs root.
+      --cwd <dir>                 Run the command in <dir>, instead of the working directory of
+                                  termproxy or the home directory of the login shell's user.
+      --isolate                   Run the command in new mount, IPC and UTS namespaces.
+      --read-only-root            Mount the root file system read-only for the command, requires
+                                  --isolate.
+      --new-root <dir>            Switch the root of the command to <dir>, requires --isolate.
+                                  --cwd is then relative to it.
+      --cgroup-parent <path>      Run the command in its own cgroup below <path>, relative
+                                  to the cgroup2 mount point.
+      --memory-max <size>         Limit the memory of the command to <size> bytes, with an
+                                  optional K, M, G or T suffix. Requires --cgroup-parent or
+                                  --systemd-scope.
+      --cpu-quota <percent>       Limit the command to <percent> percent of one CPU, e.g. 200
+                                  for two. Requires --cgroup-parent or --systemd-scope.
+      --systemd-scope             Run the command in a transient systemd scope unit named
+                                  termproxy-<session-id>.scope.
+      --systemd-slice <slice>     Place the scope in <slice>, implies --systemd-scope.
+      --systemd-property <prop>   Set a property like MemoryMax=1G on the scope, can be
+                                  given multiple times, implies --systemd-scope.
+      --status-dir <dir>          Periodically write the session's status to
+                                  <dir>/<session-id>.status (e.g. /run/termproxy), and let
+                                  administrators observe the session.
+      --snapshot-interval <secs>  Write the text on the screen to the status directory as
+                                  <session-id>.snapshot every <secs> seconds while it changes,
+                                  requires --status-dir.
+      --files-root <dir>          Let clients list, download and upload files below <dir>, with
+                                  the permissions of the command's user.
+      --record <path>             Record the output of the command to <path>, which must not
+                                  exist yet.
+      --record-format <format>    The format of the recording, asciicast (default) or ttyrec.
+      --record-group <id>         Time the recording from the start of the first recording of
+                                  group <id> in the same directory, for synchronized replay,
+                                  and note the group in it. Requires an asciicast recording.
+      --command-history <path>    Write the commands entered at a shell prompt to <path>, which
+                                  must not exist yet, as JSON lines.
+      --audit-wakeups             Print how often the relay loop woke up, polled without
+                                  waiting and woke up for nothing, for every second it was
+                                  awake at all.
+      --timing                    Print how long starting the session took at exit, from the
+                                  listener being ready over accept, authentication and spawn
+                                  to the first output sent to the client.
+      --poll-strategy <strategy>  How the relay loop polls while it has work left: immediate
+                                  (default) never waits, small-sleep waits up to 1ms and
+                                  adaptive only does once it stayed busy for a while.
+      --crash-dir <dir>           Write a crash report to <dir> on internal errors.
+      --stderr-json               Log diagnostics as JSON lines with level, phase and an
+                                  error code.
+      --log-dedup-window <secs>   Only count repetitions of a message within <secs> seconds,
+                                  default 10, 0 logs every message.
       -h, --help                  Print help
 ";
 
+/// The environment variable that has to confirm `--preauthenticated`.
+const PREAUTHENTICATED_ENV: &str = "TERMPROXY_PREAUTHENTICATED";
+
+/// The size of the terminal without `--cols` and `--rows`, as columns and rows.
+const DEFAULT_SIZE: (u16, u16) = (80, 20);
+
+/// Smaller frames would mostly consist of overhead.
+const MIN_FRAME_SIZE: usize = 128;
+
+/// The longest ticket line, connection secret or challenge answer without `--max-auth-line`.
+pub const DEFAULT_MAX_AUTH_LINE: usize = 64 * 1024 - 1;
+
+/// Tickets alone are longer than that.
+const MIN_AUTH_LINE: usize = 256;
+
+/// The least `--max-memory`, the relay buffers of the session and a client take 12 KiB.
+const MIN_MAX_MEMORY: u64 = 32 * 1024;
+
+/// How many seconds a share link is valid without `--valid-for`.
+const DEFAULT_SHARE_VALIDITY: u64 = 600;
+
+/// The most lines of scrollback `--join-context` keeps for joining clients.
+const MAX_JOIN_CONTEXT: usize = 1000;
+
+/// How the command's stderr is passed to the client, if kept apart from the terminal
+#[derive(Clone, Copy, Debug)]
+pub enum ChildStderr {
+    /// Inline with the terminal output, in red
+    Color,
+    /// As `stderr` control messages
+    Frame,
+}
+
+impl std::str::FromStr for ChildStderr {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "color" => Ok(Self::Color),
+            "frame" => Ok(Self::Frame),
+            _ => bail!("unknown stderr mode '{value}', expected 'color' or 'frame'"),
+        }
+    }
+}
+
+/// Which of the sizes the clients of a shared session ask for the terminal gets
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResizePolicy {
+    /// The size of the client that resized last
+    #[default]
+    Last,
+    /// The most columns and the most rows any client asked for
+    Largest,
+    /// The size of the client attached the longest, the others follow it
+    Primary,
+}
+
+impl ResizePolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Last => "last",
+            Self::Largest => "largest",
+            Self::Primary => "primary",
+        }
+    }
+}
+
+impl std::str::FromStr for ResizePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "last" => Ok(Self::Last),
+            "largest" => Ok(Self::Largest),
+            "primary" => Ok(Self::Primary),
+            _ => bail!("unknown resize policy '{value}', expected last, largest or primary"),
+        }
+    }
+}
+
+/// Socket options for the TCP listener
+#[derive(Debug, Default)]
+pub struct ListenerOptions {
+    /// TCP_DEFER_ACCEPT timeout in seconds
+    pub defer_accept: Option<u32>,
+    /// TCP_FASTOPEN queue length
+    pub fastopen: Option<u32>,
+}
+
 #[derive(Debug)]
 pub enum PortOrFd {
     Port(u16),
     Fd(RawFd),
+    /// The path of a Unix socket
+    Unix(PathBuf),
+    /// The name of a Unix socket in the abstract namespace, without the leading NUL
+    Abstract(String),
 }
 
 impl PortOrFd {
@@ -38,35 +355,618 @@ impl PortOrFd {
             Ok(Self::Port(value as u16))
         }
     }
+
+    /// Whether clients connect through a Unix socket.
+    pub fn is_unix(&self) -> bool {
+        matches!(self, Self::Unix(_) | Self::Abstract(_))
+    }
+}
+
+/// The longest name of a socket in the abstract namespace, the size of `sun_path` without the
+/// leading NUL.
+const MAX_ABSTRACT_NAME: usize = 107;
+
+fn parse_abstract_name(name: String) -> Result<String> {
+    if name.is_empty() || name.len() > MAX_ABSTRACT_NAME || name.contains('\0') {
+        bail!("invalid abstract socket name '{name}'");
+    }
+    Ok(name)
+}
+
+/// Whether `id` names files safely, as session ids and recording groups do.
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+fn parse_session_id(id: String) -> Result<String> {
+    if !valid_id(&id) {
+        bail!("invalid session id '{id}'");
+    }
+    Ok(id)
+}
+
+fn parse_record_group(id: String) -> Result<String> {
+    if !valid_id(&id) {
+        bail!("invalid recording group '{id}'");
+    }
+    Ok(id)
+}
+
+/// Fails for `what`, an option or command, if this build lacks the cargo `feature` providing it.
+fn require_feature(what: &str, feature: &str) -> Result<()> {
+    let enabled = match feature {
+        "daemon" => cfg!(feature = "daemon"),
+        "recording" => cfg!(feature = "recording"),
+        "serial" => cfg!(feature = "serial"),
+        "tls" => cfg!(feature = "tls"),
+        "websocket" => cfg!(feature = "websocket"),
+        _ => unreachable!("unknown feature {feature}"),
+    };
+    if !enabled {
+        bail!("{what} is not supported, termproxy was built without the '{feature}' feature");
+    }
+    Ok(())
+}
+
+/// Parses a `key=value` session tag.
+fn parse_tag(tag: String) -> Result<(String, String)> {
+    let Some((key, value)) = tag.split_once('=') else {
+        bail!("invalid tag '{tag}', expected key=value");
+    };
+    if key.is_empty()
+        || !key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+    {
+        bail!("invalid tag key '{key}'");
+    }
+    if value.chars().any(char::is_control) {
+        bail!("invalid value for tag '{key}'");
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parses a size in bytes, with an optional K, M, G or T suffix for powers of 1024.
+fn parse_size(size: String) -> Result<u64> {
+    let (number, shift) = match size.strip_suffix(['K', 'M', 'G', 'T']) {
+        Some(number) => {
+            let shift = match size.as_bytes()[size.len() - 1] {
+                b'K' => 10,
+                b'M' => 20,
+                b'G' => 30,
+                _ => 40,
+            };
+            (number, shift)
+        }
+        None => (size.as_str(), 0),
+    };
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 && number.leading_zeros() >= shift => Ok(number << shift),
+        _ => bail!("invalid size '{size}'"),
+    }
+}
+
+/// Parses a CPU quota in percent of one CPU, with or without the percent sign.
+fn parse_cpu_quota(quota: String) -> Result<u32> {
+    match quota.strip_suffix('%').unwrap_or(&quota).parse::<u32>() {
+        Ok(percent) if percent > 0 => Ok(percent),
+        _ => bail!("invalid CPU quota '{quota}'"),
+    }
+}
+
+/// Checks the name of an environment variable passed to the command.
+fn parse_env_name(name: String) -> Result<String> {
+    if name.is_empty() || name.contains(['=', '\0']) {
+        bail!("invalid environment variable name '{name}'");
+    }
+    Ok(name)
+}
+
+/// Parses a `NAME=VALUE` environment variable for the command.
+fn parse_env(var: String) -> Result<(String, String)> {
+    let Some((name, value)) = var.split_once('=') else {
+        bail!("invalid environment variable '{var}', expected NAME=VALUE");
+    };
+    let name = parse_env_name(name.to_string())?;
+    if value.contains('\0') {
+        bail!("invalid value for environment variable '{name}'");
+    }
+    Ok((name, value.to_string()))
+}
+
+/// Returns `len` random bytes, hex encoded.
+pub fn random_hex(len: usize) -> Result<String> {
+    let mut bytes = vec![0u8; len];
+    let res = unsafe { libc::getrandom(bytes.as_mut_ptr() as *mut _, bytes.len(), 0) };
+    if res != bytes.len() as isize {
+        bail!(
+            "failed to get random bytes - {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// What termproxy got invoked for
+#[derive(Debug)]
+pub enum Mode {
+    /// Run a command and proxy its terminal to the client
+    Proxy(Box<Options>),
+    /// Check the protocol implementation of a client, listening on the given port or FD
+    VerifyClient(PortOrFd),
+    /// Check the runtime environment
+    Preflight(PreflightOptions),
+    /// List the sessions of the host
+    List(ListOptions),
+    /// Play back a recording
+    #[cfg_attr(not(feature = "recording"), allow(dead_code))]
+    Replay(ReplayOptions),
+    /// Serve the recordings of a directory over HTTP
+    #[cfg_attr(not(feature = "recording"), allow(dead_code))]
+    ServeRecordings(ServeOptions),
+    /// Watch a session as administrator
+    Observe(ObserveOptions),
+    /// Register a share link with a session
+    #[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+    Share(ShareOptions),
+}
+
+#[derive(Debug)]
+pub struct PreflightOptions {
+    /// The port of the local privileged daemon that authentication is relayed to
+    pub api_daemon_port: u16,
+    /// The command that sessions are going to run, if known
+    pub terminal_command: Vec<OsString>,
+}
+
+/// How `list` prints the sessions
+#[derive(Clone, Copy, Debug)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    JsonPretty,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "json-pretty" => Ok(Self::JsonPretty),
+            _ => bail!("unknown output format '{value}', expected text, json or json-pretty"),
+        }
+    }
+}
+
+/// The format of a recording
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordFormat {
+    Asciicast,
+    Ttyrec,
+}
+
+impl std::str::FromStr for RecordFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "asciicast" => Ok(Self::Asciicast),
+            "ttyrec" => Ok(Self::Ttyrec),
+            _ => bail!("unknown recording format '{value}', expected asciicast or ttyrec"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ListOptions {
+    /// The directory the sessions write their status files to
+    pub status_dir: PathBuf,
+    pub output_format: OutputFormat,
+}
+
+#[derive(Debug)]
+#[cfg_attr(not(feature = "recording"), allow(dead_code))]
+pub struct ReplayOptions {
+    /// The recording to play back
+    pub file: PathBuf,
+    /// How much faster than recorded to play it back
+    pub speed: f64,
+}
+
+#[derive(Debug)]
+#[cfg_attr(not(feature = "recording"), allow(dead_code))]
+pub struct ServeOptions {
+    /// The directory holding the recordings
+    pub dir: PathBuf,
+    /// The port or FD to listen on for HTTP requests
+    pub listen_port: PortOrFd,
+}
+
+#[derive(Debug)]
+pub struct ObserveOptions {
+    /// The directory the session writes its status file to
+    pub status_dir: PathBuf,
+    pub session_id: String,
+}
+
+#[derive(Debug)]
+#[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+pub struct ShareOptions {
+    /// The directory of the session's control socket
+    pub status_dir: PathBuf,
+    pub session_id: String,
+    /// How long the token is valid
+    pub valid_for: Duration,
+    /// Whether the client presenting the token only watches
+    pub observe: bool,
+}
+
+/// Removes the command after `--` and the `--` itself from `args`.
+fn split_terminal_command(args: &mut Vec<OsString>) -> Option<Vec<OsString>> {
+    let dash_dash = args.iter().position(|arg| arg == "--")?;
+    let command = args.drain(dash_dash + 1..).collect();
+    args.pop();
+    Some(command)
+}
+
+impl Mode {
+    pub fn from_env() -> Result<Self> {
+        let mut args: Vec<_> = std::env::args_os().collect();
+        args.remove(0); // remove the executable path.
+
+        // applied right away, so that errors about the other arguments are logged as JSON too
+        if let Some(pos) = args
+            .iter()
+            .take_while(|arg| *arg != "--")
+            .position(|arg| arg == "--stderr-json")
+        {
+            args.remove(pos);
+            crate::log::set_json(true);
+        }
+
+        if args
+            .first()
+            .map(|arg| arg == "verify-client")
+            .unwrap_or(false)
+        {
+            args.remove(0);
+            let mut args = pico_args::Arguments::from_vec(args);
+            if args.contains(["-h", "--help"]) {
+                print!("{CMD_HELP}");
+                std::process::exit(0);
+            }
+            let listen_port =
+                PortOrFd::from_cli(args.free_from_str()?, args.contains("--port-as-fd"))?;
+            if !args.finish().is_empty() {
+                bail!("unexpected extra arguments, use '-h' for usage");
+            }
+            return Ok(Mode::VerifyClient(listen_port));
+        }
+
+        if args.first().map(|arg| arg == "preflight").unwrap_or(false) {
+            args.remove(0);
+            let terminal_command = split_terminal_command(&mut args).unwrap_or_default();
+            let mut args = pico_args::Arguments::from_vec(args);
+            if args.contains(["-h", "--help"]) {
+                print!("{CMD_HELP}");
+                std::process::exit(0);
+            }
+            let options = PreflightOptions {
+                api_daemon_port: args.opt_value_from_str("--authport")?.unwrap_or(85),
+                terminal_command,
+            };
+            if !args.finish().is_empty() {
+                bail!("unexpected extra arguments, use '-h' for usage");
+            }
+            return Ok(Mode::Preflight(options));
+        }
+
+        if args.first().map(|arg| arg == "list").unwrap_or(false) {
+            args.remove(0);
+            let mut args = pico_args::Arguments::from_vec(args);
+            if args.contains(["-h", "--help"]) {
+                print!("{CMD_HELP}");
+                std::process::exit(0);
+            }
+            let options = ListOptions {
+                status_dir: args
+                    .opt_value_from_str("--status-dir")?
+                    .unwrap_or_else(|| PathBuf::from(crate::status::DEFAULT_STATUS_DIR)),
+                output_format: args
+                    .opt_value_from_str("--output-format")?
+                    .unwrap_or(OutputFormat::Text),
+            };
+            if !args.finish().is_empty() {
+                bail!("unexpected extra arguments, use '-h' for usage");
+            }
+            return Ok(Mode::List(options));
+        }
+
+        if args.first().map(|arg| arg == "replay").unwrap_or(false) {
+            require_feature("replay", "recording")?;
+            args.remove(0);
+            let mut args = pico_args::Arguments::from_vec(args);
+            if args.contains(["-h", "--help"]) {
+                print!("{CMD_HELP}");
+                std::process::exit(0);
+            }
+            let speed = args.opt_value_from_str("--speed")?.unwrap_or(1.0);
+            if !(speed > 0.0 && f64::is_finite(speed)) {
+                bail!("--speed must be a positive number");
+            }
+            let options = ReplayOptions {
+                file: args.free_from_str()?,
+                speed,
+            };
+            if !args.finish().is_empty() {
+                bail!("unexpected extra arguments, use '-h' for usage");
+            }
+            return Ok(Mode::Replay(options));
+        }
+
+        if args
+            .first()
+            .map(|arg| arg == "serve-recordings")
+            .unwrap_or(false)
+        {
+            require_feature("serve-recordings", "recording")?;
+            args.remove(0);
+            let mut args = pico_args::Arguments::from_vec(args);
+            if args.contains(["-h", "--help"]) {
+                print!("{CMD_HELP}");
+                std::process::exit(0);
+            }
+            let dir = args.value_from_str("--dir")?;
+            let port_as_fd = args.contains("--port-as-fd");
+            let options = ServeOptions {
+                dir,
+                listen_port: PortOrFd::from_cli(args.free_from_str()?, port_as_fd)?,
+            };
+            if !args.finish().is_empty() {
+                bail!("unexpected extra arguments, use '-h' for usage");
+            }
+            return Ok(Mode::ServeRecordings(options));
+        }
+
+        if args.first().map(|arg| arg == "observe").unwrap_or(false) {
+            args.remove(0);
+            let mut args = pico_args::Arguments::from_vec(args);
+            if args.contains(["-h", "--help"]) {
+                print!("{CMD_HELP}");
+                std::process::exit(0);
+            }
+            let options = ObserveOptions {
+                status_dir: args
+                    .opt_value_from_str("--status-dir")?
+                    .unwrap_or_else(|| PathBuf::from(crate::status::DEFAULT_STATUS_DIR)),
+                session_id: parse_session_id(args.free_from_str()?)?,
+            };
+            if !args.finish().is_empty() {
+                bail!("unexpected extra arguments, use '-h' for usage");
+            }
+            return Ok(Mode::Observe(options));
+        }
+
+        if args.first().map(|arg| arg == "share").unwrap_or(false) {
+            require_feature("share", "daemon")?;
+            args.remove(0);
+            let mut args = pico_args::Arguments::from_vec(args);
+            if args.contains(["-h", "--help"]) {
+                print!("{CMD_HELP}");
+                std::process::exit(0);
+            }
+            let options = ShareOptions {
+                status_dir: args
+                    .opt_value_from_str("--status-dir")?
+                    .unwrap_or_else(|| PathBuf::from(crate::status::DEFAULT_STATUS_DIR)),
+                valid_for: Duration::from_secs(
+                    args.opt_value_from_str("--valid-for")?
+                        .unwrap_or(DEFAULT_SHARE_VALIDITY),
+                ),
+                observe: args.contains("--observe"),
+                session_id: parse_session_id(args.free_from_str()?)?,
+            };
+            if options.valid_for.is_zero() {
+                bail!("--valid-for must be at least 1 second");
+            }
+            if !args.finish().is_empty() {
+                bail!("unexpected extra arguments, use '-h' for usage");
+            }
+            return Ok(Mode::Share(options));
+        }
+
+        Ok(Mode::Proxy(Box::new(Options::from_args(args)?)))
+    }
 }
 
 #[derive(Debug)]
+// builds without some of the features don't look at their options
+#[cfg_attr(
+    not(all(
+        feature = "auth-http",
+        feature = "daemon",
+        feature = "recording",
+        feature = "serial",
+        feature = "tls",
+        feature = "websocket",
+    )),
+    allow(dead_code)
+)]
 pub struct Options {
-    /// The actual command to run proxied in a pseudo terminal.
+    /// The actual command to run proxied in a pseudo terminal, empty if running a login shell.
     pub terminal_command: Vec<OsString>,
+    /// The user whose login shell is run instead of a terminal command
+    pub login_shell: Option<String>,
+    /// Program printing the command line to run once the client is authenticated
+    pub cmd_from: Option<PathBuf>,
     /// The port or FD that termproxy will listen on for an incoming conection
     pub listen_port: PortOrFd,
+    /// The user the caller already authenticated, if the ticket exchange is skipped
+    pub preauthenticated: Option<String>,
+    /// Whether clients are authenticated as the local user of the process that connected to the
+    /// Unix socket, whose login shell runs
+    pub peer_user: bool,
+    /// Whether clients connect with the WebSocket protocol
+    pub websocket: bool,
+    /// The certificate chain to wrap client connections in TLS with
+    pub tls_cert: Option<PathBuf>,
+    /// The private key of the TLS certificate
+    pub tls_key: Option<PathBuf>,
+    /// The secret the client has to send before anything else
+    pub connection_secret: Option<String>,
+    /// The file descriptor to read the key for the encrypted relay from
+    pub encryption_key_fd: Option<RawFd>,
+    /// The file descriptor to read the key for answers to the authentication challenge from
+    pub auth_challenge_key_fd: Option<RawFd>,
+    /// How many clients may try to authenticate before giving up
+    pub accept_attempts: usize,
+    /// How many clients may be attached to the session at the same time
+    pub max_clients: usize,
+    /// Whether clients joining after the first one are observers
+    pub observers: bool,
+    /// How the size of a shared session is decided, announced to the clients if given
+    pub resize_policy: Option<ResizePolicy>,
+    /// Whether clients may join with tokens registered through the control socket
+    pub share_links: bool,
+    /// How many lines scrolled off the screen clients joining a shared session get
+    pub join_context: Option<usize>,
+    /// Whether the session keeps running without clients
+    pub detachable: bool,
+    /// The user to start the session for without a client
+    pub background: Option<String>,
+    /// The detachable session to hand the client over to, instead of running a command
+    pub attach: Option<String>,
+    /// How long the session waits for a client to reconnect after the last one dropped
+    pub reconnect_grace: Option<Duration>,
+    /// Whether the command is stopped while no client is attached
+    pub freeze_detached: bool,
+    /// How long a client may be silent before it gets pinged, and dropped after another one
+    pub keepalive: Option<Duration>,
+    /// The maximal size of a single write to the client, including encryption overhead
+    pub max_frame_size: Option<usize>,
+    /// How many bytes per second a client may send
+    pub max_input_rate: Option<u64>,
+    /// How much termproxy may buffer for the session at most
+    pub max_memory: Option<usize>,
+    /// The longest line a client may authenticate with
+    pub max_auth_line: usize,
+    /// Socket options to set on the listener
+    pub listener_options: ListenerOptions,
     /// The port of the local privileged daemon that authentication is relayed to. Defaults to `85`
     pub api_daemon_port: u16,
     /// The ACL object path the 'acl_permission' is checked on
     pub acl_path: String,
     /// The ACL permission that the ticket, read from the stream, is required to have on 'acl_path'
     pub acl_permission: Option<String>,
+    /// Where to append a record of every authentication attempt to
+    pub security_log: Option<PathBuf>,
+    /// Whether the credentials returned on authentication are exported to the command
+    pub export_auth_env: bool,
+    /// The signals the client is allowed to send to the command
+    pub allowed_signals: Vec<Signal>,
+    /// The byte sequences the client may send as hex
+    pub allowed_hex_input: Vec<Vec<u8>>,
+    /// Warn if the command produced no output within this time
+    pub first_output_timeout: Option<Duration>,
+    /// Terminate the command if it produced no output within the first output timeout
+    pub first_output_kill: bool,
+    /// Whether to send connection quality control messages to the client
+    pub quality_hints: bool,
+    /// Program that sends a BREAK to the serial backend of the command
+    pub break_command: Option<PathBuf>,
+    /// Whether the client may send magic SysRq keys to the serial backend
+    pub allow_sysrq: bool,
+    /// Lock the session if the client sent no input for this long
+    pub lock_after: Option<Duration>,
+    /// End the session if no client sent input for this long
+    pub idle_timeout: Option<Duration>,
+    /// Keep the command's stderr apart from the terminal and pass it on like this
+    pub child_stderr: Option<ChildStderr>,
+    /// Whether to pause output that looks like binary data
+    pub detect_binary: bool,
+    /// Warn about output looping for this long
+    pub loop_watchdog: Option<Duration>,
+    /// Whether to detect what the output of a serial console comes from
+    pub detect_guest: bool,
+    /// Whether to send the finished lines of the screen as plain text
+    pub semantic_lines: bool,
+    /// Whether the command is a Windows SAC console
+    pub sac: bool,
+    /// Program printing the secret to answer password prompts with
+    pub secret_provider: Option<PathBuf>,
+    /// TERM values for the command, in order of preference
+    pub term_candidates: Vec<String>,
+    /// The root of the system whose terminfo database decides between the TERM candidates
+    pub terminfo_root: PathBuf,
+    /// The size of the terminal until the client sends its own, as columns and rows
+    pub initial_size: (u16, u16),
+    /// The escape character for proxy commands in the client's input
+    pub escape_char: Option<u8>,
+    /// Identifies this session, e.g. in cgroup names
+    pub session_id: String,
+    /// Metadata attached to the session
+    pub tags: Vec<(String, String)>,
+    /// Environment variables set for the command
+    pub env: Vec<(String, String)>,
+    /// Environment variables passed on to the command besides the default ones
+    pub env_keep: Vec<String>,
+    /// The working directory of the command
+    pub cwd: Option<PathBuf>,
+    /// Whether to run the command in namespaces of its own
+    pub isolate: bool,
+    /// Whether the root file system is read-only for the command
+    pub read_only_root: bool,
+    /// The directory to switch the command's root to
+    pub new_root: Option<PathBuf>,
+    /// The cgroup below which a cgroup for the terminal command gets created
+    pub cgroup_parent: Option<String>,
+    /// The resource limits of the command's cgroup or scope
+    pub cgroup_limits: CgroupLimits,
+    /// Settings for running the command in its own systemd scope
+    pub systemd_scope: Option<ScopeOptions>,
+    /// Where to write the status file of the session to
+    pub status_dir: Option<PathBuf>,
+    /// How often to write a snapshot of the screen to the status directory
+    pub snapshot_interval: Option<Duration>,
+    /// The directory below which clients may access files
+    pub files_root: Option<PathBuf>,
+    /// How long repetitions of a log message are suppressed
+    pub log_dedup_window: Duration,
+    /// Where to write crash reports to
+    pub crash_dir: Option<PathBuf>,
+    /// Where to record the output of the command to
+    pub record: Option<PathBuf>,
+    /// The format of the recording
+    pub record_format: RecordFormat,
+    /// The group of recordings sharing a time base the recording belongs to
+    pub record_group: Option<String>,
+    /// Where to write the commands entered in the session to
+    pub command_history: Option<PathBuf>,
+    /// Whether to print statistics about the wakeups of the relay loop
+    pub audit_wakeups: bool,
+    /// How the relay loop polls while it has work left
+    pub poll_strategy: PollStrategy,
+    /// Whether to register the session in utmp and wtmp
+    pub utmp: bool,
+    /// Whether to print how long the steps of starting the session took
+    pub timing: bool,
+    /// Whether to restrict the system calls of the relay with a seccomp filter
+    pub seccomp: bool,
+    /// The PAM service to open a session of for the user of the login shell
+    pub pam_service: Option<String>,
+    /// The user to switch the relay to once the command runs
+    pub drop_privileges: Option<String>,
 }
 
 impl Options {
-    pub fn from_env() -> Result<Self> {
-        let mut args: Vec<_> = std::env::args_os().collect();
-        args.remove(0); // remove the executable path.
-
+    fn from_args(mut args: Vec<OsString>) -> Result<Self> {
         // handle finding command after `--` first so that we only parse our options later
-        let terminal_command = if let Some(dash_dash) = args.iter().position(|arg| arg == "--") {
-            let later_args = args.drain(dash_dash + 1..).collect();
-            args.pop(); // .. then remove the `--`
-            Some(later_args)
-        } else {
-            None
-        };
+        let terminal_command = split_terminal_command(&mut args);
 
         // Now pass the remaining arguments through to `pico_args`.
         let mut args = pico_args::Arguments::from_vec(args);
@@ -74,29 +974,577 @@ impl Options {
         if args.contains(["-h", "--help"]) {
             print!("{CMD_HELP}");
             std::process::exit(0);
-        } else if terminal_command.is_none() {
-            bail!("missing terminal command or -- option-end marker, see '-h' for usage");
         }
 
-        let options = Self {
-            terminal_command: terminal_command.unwrap(), // checked above
-            listen_port: PortOrFd::from_cli(args.free_from_str()?, args.contains("--port-as-fd"))?,
+        let login_shell: Option<String> = args.opt_value_from_str("--login-shell")?;
+        let attach = args
+            .opt_value_from_str("--attach")?
+            .map(parse_session_id)
+            .transpose()?;
+        let cmd_from: Option<PathBuf> = args.opt_value_from_str("--cmd-from")?;
+        if cmd_from.is_some() && (login_shell.is_some() || attach.is_some()) {
+            bail!("--cmd-from cannot be combined with --login-shell or --attach");
+        }
+        let peer_user = args.contains("--peer-user");
+        if peer_user && (login_shell.is_some() || cmd_from.is_some()) {
+            bail!("--peer-user cannot be combined with --login-shell or --cmd-from");
+        }
+        let terminal_command = match (terminal_command, &login_shell) {
+            (Some(_), None) if peer_user => {
+                bail!("--peer-user cannot be combined with a terminal command")
+            }
+            (None, None) if peer_user => Vec::new(),
+            (Some(_), Some(_)) => bail!("--login-shell cannot be combined with a terminal command"),
+            (Some(_), None) if cmd_from.is_some() => {
+                bail!("--cmd-from cannot be combined with a terminal command")
+            }
+            (None, None) if cmd_from.is_some() => Vec::new(),
+            _ if attach.is_some() && login_shell.is_some() => {
+                bail!("--attach cannot be combined with --login-shell")
+            }
+            (Some(_), None) if attach.is_some() => {
+                bail!("--attach cannot be combined with a terminal command")
+            }
+            (None, None) if attach.is_some() => Vec::new(),
+            (Some(command), None) if command.is_empty() => bail!("missing terminal command"),
+            (Some(command), None) => command,
+            (None, Some(_)) => Vec::new(),
+            (None, None) => {
+                bail!("missing terminal command or -- option-end marker, see '-h' for usage")
+            }
+        };
+
+        let unix_abstract: Option<String> = args.opt_value_from_str("--unix-abstract")?;
+        let listen_port = match (args.opt_value_from_str("--listen-unix")?, unix_abstract) {
+            (Some(path), Some(_)) => {
+                bail!("--listen-unix {path:?} cannot be combined with --unix-abstract")
+            }
+            (Some(path), None) if args.contains("--port-as-fd") => {
+                bail!("--listen-unix {path:?} cannot be combined with --port-as-fd")
+            }
+            (None, Some(name)) if args.contains("--port-as-fd") => {
+                bail!("--unix-abstract '{name}' cannot be combined with --port-as-fd")
+            }
+            (Some(path), None) => PortOrFd::Unix(path),
+            (None, Some(name)) => PortOrFd::Abstract(parse_abstract_name(name)?),
+            (None, None) => {
+                PortOrFd::from_cli(args.free_from_str()?, args.contains("--port-as-fd"))?
+            }
+        };
+
+        let mut options = Self {
+            terminal_command,
+            login_shell,
+            cmd_from,
+            listen_port,
+            preauthenticated: args.opt_value_from_str("--preauthenticated")?,
+            peer_user,
+            websocket: args.contains("--websocket"),
+            tls_cert: args.opt_value_from_str("--tls-cert")?,
+            tls_key: args.opt_value_from_str("--tls-key")?,
+            connection_secret: if args.contains("--connection-secret") {
+                Some(random_hex(16)?)
+            } else {
+                None
+            },
+            encryption_key_fd: args.opt_value_from_str("--encryption-key-fd")?,
+            auth_challenge_key_fd: args.opt_value_from_str("--auth-challenge-key-fd")?,
+            accept_attempts: args.opt_value_from_str("--accept-attempts")?.unwrap_or(1),
+            max_clients: args.opt_value_from_str("--max-clients")?.unwrap_or(1),
+            observers: args.contains("--observers"),
+            resize_policy: args.opt_value_from_str("--resize-policy")?,
+            share_links: args.contains("--share-links"),
+            join_context: args.opt_value_from_str("--join-context")?,
+            detachable: args.contains("--detachable"),
+            background: args.opt_value_from_str("--background")?,
+            attach,
+            reconnect_grace: args
+                .opt_value_from_str("--reconnect-grace")?
+                .map(Duration::from_secs),
+            freeze_detached: args.contains("--freeze-detached"),
+            keepalive: args
+                .opt_value_from_str("--keepalive")?
+                .map(Duration::from_secs),
+            max_frame_size: args.opt_value_from_str("--max-frame-size")?,
+            max_input_rate: args
+                .opt_value_from_str("--max-input-rate")?
+                .map(parse_size)
+                .transpose()?,
+            max_memory: args
+                .opt_value_from_str("--max-memory")?
+                .map(parse_size)
+                .transpose()?
+                .map(|size| usize::try_from(size).unwrap_or(usize::MAX)),
+            max_auth_line: args
+                .opt_value_from_str("--max-auth-line")?
+                .unwrap_or(DEFAULT_MAX_AUTH_LINE),
+            listener_options: ListenerOptions {
+                defer_accept: args.opt_value_from_str("--tcp-defer-accept")?,
+                fastopen: args.opt_value_from_str("--tcp-fastopen")?,
+            },
             api_daemon_port: args.opt_value_from_str("--authport")?.unwrap_or(85),
             acl_path: args.value_from_str("--path")?,
             acl_permission: args.opt_value_from_str("--perm")?,
+            security_log: args.opt_value_from_str("--security-log")?,
+            export_auth_env: args.contains("--export-auth-env"),
+            allowed_signals: match args.opt_value_from_str::<_, String>("--allow-signals")? {
+                Some(list) => list
+                    .split(',')
+                    .map(parse_signal)
+                    .collect::<Result<_, _>>()?,
+                None => Vec::new(),
+            },
+            allowed_hex_input: match args.opt_value_from_str::<_, String>("--allow-hex-input")? {
+                Some(list) => list.split(',').map(parse_hex).collect::<Result<_>>()?,
+                None => Vec::new(),
+            },
+            first_output_timeout: args
+                .opt_value_from_str("--first-output-timeout")?
+                .map(Duration::from_secs),
+            first_output_kill: args.contains("--first-output-kill"),
+            quality_hints: args.contains("--quality-hints"),
+            break_command: args.opt_value_from_str("--break-command")?,
+            allow_sysrq: args.contains("--allow-sysrq"),
+            lock_after: args
+                .opt_value_from_str("--lock-after")?
+                .map(Duration::from_secs),
+            idle_timeout: args
+                .opt_value_from_str("--idle-timeout")?
+                .map(Duration::from_secs),
+            child_stderr: args.opt_value_from_str("--child-stderr")?,
+            detect_binary: args.contains("--detect-binary"),
+            loop_watchdog: args
+                .opt_value_from_str("--loop-watchdog")?
+                .map(Duration::from_secs),
+            detect_guest: args.contains("--detect-guest"),
+            semantic_lines: args.contains("--semantic-lines"),
+            sac: args.contains("--sac"),
+            secret_provider: args.opt_value_from_str("--secret-provider")?,
+            term_candidates: match args.opt_value_from_str::<_, String>("--term")? {
+                Some(list) => list.split(',').map(str::to_string).collect(),
+                None => crate::terminfo::DEFAULT_CANDIDATES
+                    .iter()
+                    .map(|term| term.to_string())
+                    .collect(),
+            },
+            terminfo_root: args
+                .opt_value_from_str("--terminfo-root")?
+                .unwrap_or_else(|| PathBuf::from("/")),
+            initial_size: (
+                args.opt_value_from_str("--cols")?.unwrap_or(DEFAULT_SIZE.0),
+                args.opt_value_from_str("--rows")?.unwrap_or(DEFAULT_SIZE.1),
+            ),
+            escape_char: match args.opt_value_from_str::<_, String>("--escape-char")? {
+                Some(c) if c.len() == 1 && c.is_ascii() => Some(c.as_bytes()[0]),
+                Some(c) => bail!("invalid escape character '{c}'"),
+                None => None,
+            },
+            session_id: match args.opt_value_from_str("--session-id")? {
+                Some(id) => parse_session_id(id)?,
+                None => random_hex(8)?,
+            },
+            tags: args
+                .values_from_str::<_, String>("--tag")?
+                .into_iter()
+                .map(parse_tag)
+                .collect::<Result<_>>()?,
+            env: args
+                .values_from_str::<_, String>("--env")?
+                .into_iter()
+                .map(parse_env)
+                .collect::<Result<_>>()?,
+            env_keep: args
+                .values_from_str::<_, String>("--env-keep")?
+                .into_iter()
+                .map(parse_env_name)
+                .collect::<Result<_>>()?,
+            cwd: args.opt_value_from_str("--cwd")?,
+            isolate: args.contains("--isolate"),
+            read_only_root: args.contains("--read-only-root"),
+            new_root: args.opt_value_from_str("--new-root")?,
+            cgroup_parent: args.opt_value_from_str("--cgroup-parent")?,
+            cgroup_limits: CgroupLimits {
+                memory_max: args
+                    .opt_value_from_str("--memory-max")?
+                    .map(parse_size)
+                    .transpose()?,
+                cpu_quota: args
+                    .opt_value_from_str("--cpu-quota")?
+                    .map(parse_cpu_quota)
+                    .transpose()?,
+            },
+            systemd_scope: {
+                let scope = args.contains("--systemd-scope");
+                let slice: Option<String> = args.opt_value_from_str("--systemd-slice")?;
+                let properties: Vec<String> = args.values_from_str("--systemd-property")?;
+                if scope || slice.is_some() || !properties.is_empty() {
+                    Some(ScopeOptions { slice, properties })
+                } else {
+                    None
+                }
+            },
+            status_dir: args.opt_value_from_str("--status-dir")?,
+            snapshot_interval: args
+                .opt_value_from_str("--snapshot-interval")?
+                .map(Duration::from_secs),
+            files_root: args.opt_value_from_str("--files-root")?,
+            log_dedup_window: args
+                .opt_value_from_str("--log-dedup-window")?
+                .map(Duration::from_secs)
+                .unwrap_or(crate::log::DEFAULT_DEDUP_WINDOW),
+            crash_dir: args.opt_value_from_str("--crash-dir")?,
+            record: args.opt_value_from_str("--record")?,
+            record_format: args
+                .opt_value_from_str("--record-format")?
+                .unwrap_or(RecordFormat::Asciicast),
+            record_group: args
+                .opt_value_from_str("--record-group")?
+                .map(parse_record_group)
+                .transpose()?,
+            command_history: args.opt_value_from_str("--command-history")?,
+            audit_wakeups: args.contains("--audit-wakeups"),
+            poll_strategy: args
+                .opt_value_from_str("--poll-strategy")?
+                .unwrap_or(PollStrategy::Immediate),
+            utmp: args.contains("--utmp"),
+            timing: args.contains("--timing"),
+            seccomp: args.contains("--seccomp"),
+            pam_service: args.opt_value_from_str("--pam-service")?,
+            drop_privileges: args.opt_value_from_str("--drop-privileges")?,
         };
 
         if !args.finish().is_empty() {
             bail!("unexpected extra arguments, use '-h' for usage");
         }
 
+        for (used, option, feature) in [
+            (options.record.is_some(), "--record", "recording"),
+            (options.websocket, "--websocket", "websocket"),
+            (options.tls_cert.is_some(), "--tls-cert", "tls"),
+            (options.tls_key.is_some(), "--tls-key", "tls"),
+            (options.detachable, "--detachable", "daemon"),
+            (options.background.is_some(), "--background", "daemon"),
+            (options.attach.is_some(), "--attach", "daemon"),
+            (options.share_links, "--share-links", "daemon"),
+            (options.break_command.is_some(), "--break-command", "serial"),
+            (options.allow_sysrq, "--allow-sysrq", "serial"),
+            (options.detect_guest, "--detect-guest", "serial"),
+            (options.sac, "--sac", "serial"),
+        ] {
+            if used {
+                require_feature(option, feature)?;
+            }
+        }
+
+        if options.first_output_kill && options.first_output_timeout.is_none() {
+            bail!("--first-output-kill requires --first-output-timeout");
+        }
+
+        if options.record_group.is_some() {
+            if options.record.is_none() {
+                bail!("--record-group requires --record");
+            }
+            // ttyrec frames carry the time since the epoch, without room for the group
+            if options.record_format != RecordFormat::Asciicast {
+                bail!("--record-group requires an asciicast recording");
+            }
+        }
+
+        if !options.cgroup_limits.is_empty() {
+            match &mut options.systemd_scope {
+                Some(scope) => scope
+                    .properties
+                    .extend(options.cgroup_limits.unit_properties()),
+                None if options.cgroup_parent.is_none() => {
+                    bail!(
+                        "--memory-max and --cpu-quota require --cgroup-parent or --systemd-scope"
+                    );
+                }
+                None => (),
+            }
+        }
+
+        if options.systemd_scope.is_some() {
+            if options.login_shell.is_some() || options.peer_user {
+                bail!("--systemd-scope cannot be combined with --login-shell or --peer-user");
+            }
+            if options.cgroup_parent.is_some() {
+                bail!("--systemd-scope cannot be combined with --cgroup-parent");
+            }
+        }
+
+        if let Some(user) = &options.preauthenticated {
+            if !matches!(options.listen_port, PortOrFd::Fd(_)) {
+                bail!("--preauthenticated requires --port-as-fd");
+            }
+            // an injected argument alone must not be enough to skip authentication
+            if std::env::var_os(PREAUTHENTICATED_ENV).as_deref() != Some(user.as_ref()) {
+                bail!("--preauthenticated requires {PREAUTHENTICATED_ENV} to be set to '{user}'");
+            }
+            if options.connection_secret.is_some() {
+                bail!("--preauthenticated cannot be combined with --connection-secret");
+            }
+            if options.auth_challenge_key_fd.is_some() {
+                bail!("--preauthenticated cannot be combined with --auth-challenge-key-fd");
+            }
+        }
+
+        if options.peer_user {
+            if !options.listen_port.is_unix() {
+                bail!("--peer-user requires --listen-unix or --unix-abstract");
+            }
+            if options.preauthenticated.is_some() || options.background.is_some() {
+                bail!("--peer-user cannot be combined with --preauthenticated or --background");
+            }
+            if options.attach.is_some() {
+                bail!("--peer-user cannot be combined with --attach");
+            }
+            if options.connection_secret.is_some() || options.auth_challenge_key_fd.is_some() {
+                bail!(
+                    "--peer-user cannot be combined with --connection-secret or \
+                     --auth-challenge-key-fd"
+                );
+            }
+        }
+
+        if !options.isolate {
+            if options.read_only_root {
+                bail!("--read-only-root requires --isolate");
+            }
+            if options.new_root.is_some() {
+                bail!("--new-root requires --isolate");
+            }
+        } else if options.systemd_scope.is_some() {
+            // the namespaces would be the ones of systemd-run, not of the command
+            bail!("--isolate cannot be combined with --systemd-scope");
+        }
+        if options
+            .new_root
+            .as_ref()
+            .is_some_and(|root| !root.is_absolute())
+        {
+            bail!("--new-root must be an absolute path");
+        }
+
+        if options.pam_service.is_some() && options.login_shell.is_none() && !options.peer_user {
+            bail!("--pam-service requires --login-shell or --peer-user");
+        }
+
+        if options.seccomp {
+            if options.break_command.is_some() {
+                bail!("--seccomp cannot be combined with --break-command");
+            }
+            if options.secret_provider.is_some() {
+                bail!("--seccomp cannot be combined with --secret-provider");
+            }
+            if options.pam_service.is_some() {
+                bail!("--seccomp cannot be combined with --pam-service");
+            }
+        }
+
+        if options.drop_privileges.is_some() {
+            // these need root again once the session ends
+            if options.pam_service.is_some() {
+                bail!("--drop-privileges cannot be combined with --pam-service");
+            }
+            if options.utmp {
+                bail!("--drop-privileges cannot be combined with --utmp");
+            }
+            if options.cgroup_parent.is_some() {
+                bail!("--drop-privileges cannot be combined with --cgroup-parent");
+            }
+            if options.status_dir.is_some() {
+                bail!("--drop-privileges cannot be combined with --status-dir");
+            }
+            // sockets bound as root could not be removed anymore, and would block the next
+            // session binding them, those in the abstract namespace go away on their own
+            let abstract_sockets = options.abstract_sockets();
+            if options.detachable && !abstract_sockets {
+                bail!("--drop-privileges cannot be combined with --detachable");
+            }
+            if options.share_links && !abstract_sockets {
+                bail!("--drop-privileges cannot be combined with --share-links");
+            }
+            if matches!(options.listen_port, PortOrFd::Unix(_)) {
+                bail!("--drop-privileges cannot be combined with --listen-unix");
+            }
+        }
+
+        if options.term_candidates.iter().any(|term| term.is_empty()) {
+            bail!("--term must not contain empty values");
+        }
+
+        if options
+            .max_frame_size
+            .is_some_and(|size| size < MIN_FRAME_SIZE)
+        {
+            bail!("--max-frame-size must be at least {MIN_FRAME_SIZE}");
+        }
+
+        if options.max_auth_line < MIN_AUTH_LINE {
+            bail!("--max-auth-line must be at least {MIN_AUTH_LINE}");
+        }
+
+        if options.websocket && options.encryption_key_fd.is_some() {
+            bail!("--websocket cannot be combined with --encryption-key-fd");
+        }
+
+        if options.tls_cert.is_some() != options.tls_key.is_some() {
+            bail!("--tls-cert and --tls-key have to be given together");
+        }
+
+        if options.tls_cert.is_some() && options.encryption_key_fd.is_some() {
+            bail!("--tls-cert cannot be combined with --encryption-key-fd");
+        }
+
+        if options.listen_port.is_unix()
+            && (options.listener_options.defer_accept.is_some()
+                || options.listener_options.fastopen.is_some())
+        {
+            bail!("TCP listener options cannot be combined with a Unix socket listener");
+        }
+
+        if options.accept_attempts == 0 {
+            bail!("--accept-attempts must be at least 1");
+        }
+
+        if options.max_clients == 0 {
+            bail!("--max-clients must be at least 1");
+        }
+
+        if options.observers && options.max_clients < 2 {
+            bail!("--observers requires --max-clients of at least 2");
+        }
+
+        if options.resize_policy.is_some() && options.max_clients < 2 {
+            bail!("--resize-policy requires --max-clients of at least 2");
+        }
+
+        if options.share_links {
+            if options.max_clients < 2 {
+                bail!("--share-links requires --max-clients of at least 2");
+            }
+            // joining clients don't present anything there
+            if options.preauthenticated.is_some() || options.peer_user {
+                bail!("--share-links cannot be combined with --preauthenticated or --peer-user");
+            }
+            if options.attach.is_some() {
+                bail!("--share-links cannot be combined with --attach");
+            }
+        }
+
+        if options
+            .max_memory
+            .is_some_and(|size| (size as u64) < MIN_MAX_MEMORY)
+        {
+            bail!("--max-memory must be at least {}K", MIN_MAX_MEMORY >> 10);
+        }
+
+        if options.join_context.is_some() && options.max_clients < 2 {
+            bail!("--join-context requires --max-clients of at least 2");
+        }
+
+        if options
+            .join_context
+            .is_some_and(|lines| lines > MAX_JOIN_CONTEXT)
+        {
+            bail!("--join-context must be at most {MAX_JOIN_CONTEXT}");
+        }
+
+        // the secret is only valid for a single connection
+        if options.max_clients > 1 && options.connection_secret.is_some() {
+            bail!("--max-clients cannot be combined with --connection-secret");
+        }
+
+        // the session only takes over the bare connection
+        if options.attach.is_some()
+            && (options.websocket
+                || options.tls_cert.is_some()
+                || options.encryption_key_fd.is_some())
+        {
+            bail!(
+                "--attach cannot be combined with --websocket, --tls-cert or --encryption-key-fd"
+            );
+        }
+
+        if options.background.is_some() {
+            if !options.detachable {
+                bail!("--background requires --detachable");
+            }
+            if options.attach.is_some() {
+                bail!("--background cannot be combined with --attach");
+            }
+            // there is no ticket without a client
+            if options.export_auth_env {
+                bail!("--background cannot be combined with --export-auth-env");
+            }
+        }
+
+        if options.detachable && options.connection_secret.is_some() {
+            bail!("--detachable cannot be combined with --connection-secret");
+        }
+
+        if options.reconnect_grace.is_some() && options.connection_secret.is_some() {
+            bail!("--reconnect-grace cannot be combined with --connection-secret");
+        }
+
+        if options.keepalive == Some(Duration::ZERO) {
+            bail!("--keepalive must be at least 1 second");
+        }
+
+        if options.freeze_detached && !options.outlives_clients() {
+            bail!("--freeze-detached requires --reconnect-grace or --detachable");
+        }
+
+        if options.initial_size.0 == 0 || options.initial_size.1 == 0 {
+            bail!("--cols and --rows must be at least 1");
+        }
+
+        if options.snapshot_interval.is_some() && options.status_dir.is_none() {
+            bail!("--snapshot-interval requires --status-dir");
+        }
+
+        if options.snapshot_interval == Some(Duration::ZERO) {
+            bail!("--snapshot-interval must be at least 1 second");
+        }
+
+        if options.allow_sysrq && options.break_command.is_none() {
+            bail!("--allow-sysrq requires --break-command");
+        }
+
         Ok(options)
     }
 
-    pub fn use_listen_port_as_fd(&self) -> bool {
-        match self.listen_port {
-            PortOrFd::Fd(_) => true,
-            _ => false,
+    /// Whether the session keeps running for a while without clients.
+    pub fn outlives_clients(&self) -> bool {
+        self.detachable || self.reconnect_grace.is_some()
+    }
+
+    /// Whether the sockets of the session are bound in the abstract namespace, with
+    /// --unix-abstract.
+    pub fn abstract_sockets(&self) -> bool {
+        matches!(self.listen_port, PortOrFd::Abstract(_))
+    }
+
+    /// The directory of the status file and the control socket of the session.
+    #[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+    pub fn runtime_dir(&self) -> &Path {
+        self.status_dir
+            .as_deref()
+            .unwrap_or(Path::new(crate::status::DEFAULT_STATUS_DIR))
+    }
+
+    /// A short description of what runs in the terminal, for messages.
+    pub fn command_name(&self) -> String {
+        match (&self.login_shell, self.terminal_command.first()) {
+            (Some(user), _) => format!("login shell of {user}"),
+            (None, Some(command)) => command.to_string_lossy().into_owned(),
+            (None, None) => String::new(),
         }
     }
+
+    #[cfg_attr(not(feature = "auth-http"), allow(dead_code))]
+    pub fn use_listen_port_as_fd(&self) -> bool {
+        matches!(self.listen_port, PortOrFd::Fd(_))
+    }
 }
@@ -0,0 +1,595 @@
+//! The clients of a session
+//!
+//! Each client has its connection, wrapped in TLS, the WebSocket protocol or the encrypted
+//! relay as negotiated, and buffers for its input and for the output not sent yet. The
+//! helpers here attach clients to the session and hand the output out to all of them.
+
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use anyhow::{format_err, Result};
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+use openssl::ssl::SslAcceptor;
+
+use crate::backlog::Backlog;
+use crate::cli::Options;
+use crate::compat::ByteBuffer;
+use crate::connection::Connection;
+use crate::control::{base64_encode, encode_control_message};
+use crate::crypt::{EncryptedStream, NONCE_PREFIX_LEN, RECORD_OVERHEAD};
+use crate::escape::EscapeFilter;
+use crate::handshake::{authenticate_connection, read_until, Handshake};
+use crate::heartbeat::Heartbeat;
+use crate::pty::PTY;
+use crate::screen::Screen;
+use crate::session::{input_allowance, queue_data, queue_message, ControlState, Readiness};
+use crate::timer::Deadline;
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+#[cfg(feature = "websocket")]
+use crate::websocket::WebSocketStream;
+
+/// The connection to the client as seen by the relay loop
+pub enum ClientStream {
+    Plain(Connection),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+    Encrypted(Box<EncryptedStream>),
+    /// The WebSocket protocol on a plain or TLS stream
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<WebSocketStream<ClientStream>>),
+}
+
+impl ClientStream {
+    pub fn connection(&mut self) -> &mut Connection {
+        match self {
+            ClientStream::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.stream_mut(),
+            ClientStream::Encrypted(stream) => stream.stream_mut(),
+            #[cfg(feature = "websocket")]
+            ClientStream::WebSocket(stream) => stream.stream_mut().connection(),
+        }
+    }
+
+    pub fn has_pending_output(&self) -> bool {
+        match self {
+            ClientStream::Plain(_) => false,
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(_) => false,
+            ClientStream::Encrypted(stream) => stream.has_pending_output(),
+            #[cfg(feature = "websocket")]
+            ClientStream::WebSocket(stream) => stream.has_pending_output(),
+        }
+    }
+
+    /// How much output fits into a single write of at most `frame_size` bytes on the wire.
+    fn frame_data_size(&self, frame_size: usize) -> usize {
+        match self {
+            ClientStream::Plain(_) => frame_size,
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(_) => frame_size.saturating_sub(crate::tls::RECORD_OVERHEAD),
+            ClientStream::Encrypted(_) => frame_size.saturating_sub(RECORD_OVERHEAD),
+            #[cfg(feature = "websocket")]
+            ClientStream::WebSocket(stream) => stream
+                .stream()
+                .frame_data_size(frame_size)
+                .saturating_sub(crate::websocket::MAX_HEADER_LEN),
+        }
+    }
+
+    /// Announces the end of the connection to the client, if the protocol has a way to.
+    pub fn close(&mut self) {
+        match self {
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.close(),
+            #[cfg(feature = "websocket")]
+            ClientStream::WebSocket(stream) => stream.close(),
+            ClientStream::Plain(_) | ClientStream::Encrypted(_) => (),
+        }
+    }
+}
+
+impl Source for ClientStream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.connection().register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.connection().reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> std::io::Result<()> {
+        self.connection().deregister(registry)
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.read(buf),
+            ClientStream::Encrypted(stream) => stream.read(buf),
+            #[cfg(feature = "websocket")]
+            ClientStream::WebSocket(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.write(buf),
+            ClientStream::Encrypted(stream) => stream.write(buf),
+            #[cfg(feature = "websocket")]
+            ClientStream::WebSocket(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ClientStream::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.flush(),
+            ClientStream::Encrypted(stream) => stream.flush(),
+            #[cfg(feature = "websocket")]
+            ClientStream::WebSocket(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Exchanges nonce prefixes with the client and switches to the encrypted record protocol.
+///
+/// `buf` holds whatever the client sent after its ticket line, which is consumed.
+fn start_encryption(
+    mut stream: Connection,
+    buf: &mut ByteBuffer,
+    key: &[u8; 32],
+) -> Result<EncryptedStream> {
+    let prefix = crate::crypt::random_prefix()?;
+    stream.write_all(&prefix)?;
+
+    read_until(
+        &mut stream,
+        buf,
+        Deadline::after(Duration::new(10, 0)),
+        |data| data.len() >= NONCE_PREFIX_LEN,
+    )
+    .map_err(|err| format_err!("failed reading nonce: {err}"))?;
+    let mut client_prefix = [0u8; NONCE_PREFIX_LEN];
+    client_prefix.copy_from_slice(&buf.remove_data(NONCE_PREFIX_LEN));
+
+    let initial = buf.remove_data(buf.len());
+    EncryptedStream::new(stream, key, prefix, client_prefix, &initial)
+}
+
+/// A client attached to the session
+pub struct Client {
+    pub token: Token,
+    pub stream: ClientStream,
+    pub ready: Readiness,
+    /// Input from the client not written to the terminal yet
+    pub input: ByteBuffer,
+    /// Output not sent to the client yet
+    pub output: ByteBuffer,
+    /// The rest of the current data message that still has to be written to the terminal
+    pub remaining: usize,
+    /// The rest of a message too long to be handled that is still to be dropped
+    pub discard: usize,
+    /// The maximal size of a single write to the client
+    pub max_write: usize,
+    pub escape: Option<EscapeFilter>,
+    pub heartbeat: Heartbeat,
+    /// When the client sent anything the last time, for --keepalive
+    pub last_heard: Instant,
+    /// When the client got pinged first since then
+    pub pinged: Option<Instant>,
+    /// Whether the client only watches, its data, resize and control messages are discarded
+    pub observer: bool,
+    /// The uid of an administrator watching through the admin socket, who is no participant of
+    /// the session
+    pub admin: Option<u32>,
+    /// A reply to a request of the client that did not fit into its output buffer yet, no
+    /// further input of the client is handled until it does
+    pub pending_reply: Option<String>,
+    /// The input the client may still send in the current interval, with --max-input-rate
+    pub input_allowance: Option<usize>,
+    /// Whether the client asked to hold back output until it resumes it
+    pub output_paused: bool,
+    /// Whether the client is gone and has to be removed from the session
+    pub closed: bool,
+}
+
+impl Client {
+    pub fn register(&mut self, registry: &Registry) -> std::io::Result<()> {
+        registry.register(
+            self.stream.connection(),
+            self.token,
+            Interest::READABLE | Interest::WRITABLE,
+        )
+    }
+
+    /// Handles a failure of the client's connection, which only ends the session with
+    /// `ends_session`, i.e. if it is the only client and it can't reconnect. Administrators
+    /// watching the session never end it.
+    pub fn fail(&mut self, err: anyhow::Error, ends_session: bool) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        if ends_session && self.admin.is_none() {
+            return Err(err);
+        }
+        crate::log::warn("client-failed", format_args!("dropping client - {err}"));
+        self.closed = true;
+        Ok(())
+    }
+
+    /// Sends `message` to this client only, as soon as its output buffer has room for it.
+    pub fn reply(&mut self, message: String) {
+        self.pending_reply = Some(message);
+        self.queue_pending_reply();
+    }
+
+    pub fn queue_pending_reply(&mut self) {
+        if let Some(message) = &self.pending_reply {
+            if queue_message(&mut self.output, message) {
+                self.pending_reply = None;
+            }
+        }
+    }
+
+    pub fn report_pings(&self) {
+        if self.heartbeat.pings() > 1 {
+            println!(
+                "client pings: {}, jitter: {}ms",
+                self.heartbeat.pings(),
+                self.heartbeat.jitter().as_millis()
+            );
+        }
+    }
+}
+
+/// Pings a client that was silent for `interval`, and drops it if it stayed silent for another
+/// `interval` after the ping, as its connection is most likely dead.
+///
+/// The check runs once per `interval`, so a client is never dropped without getting pinged
+/// first, however late the check comes.
+pub fn check_keepalive(client: &mut Client, interval: Duration, ends_session: bool) -> Result<()> {
+    let silent = client.last_heard.elapsed();
+    if client
+        .pinged
+        .is_some_and(|pinged| pinged.elapsed() >= interval)
+    {
+        let secs = silent.as_secs();
+        return client
+            .fail(
+                format_err!("no message from the client for {secs}s"),
+                ends_session,
+            )
+            .map_err(crate::log::coded("keepalive-timeout"));
+    }
+    if silent >= interval && queue_message(&mut client.output, &encode_control_message("ping", &[]))
+    {
+        client.pinged.get_or_insert_with(Instant::now);
+    }
+    Ok(())
+}
+
+/// Finishes the handshake with an authenticated client and sets up its side of the relay.
+///
+/// `buf` holds whatever the client sent after its ticket line.
+pub fn attach_client(
+    mut stream: ClientStream,
+    mut buf: ByteBuffer,
+    options: &Options,
+    encryption_key: Option<&[u8; 32]>,
+    token: Token,
+    observer: bool,
+    admin: Option<u32>,
+) -> Result<Client> {
+    // administrators didn't go through the handshake
+    if options.preauthenticated.is_none() && admin.is_none() {
+        stream
+            .write_all(b"OK")
+            .map_err(|err| format_err!("error writing response: {err}"))?;
+    }
+
+    // encryption is only available on plain connections
+    let mut stream = match (encryption_key, stream) {
+        (Some(key), ClientStream::Plain(stream)) => ClientStream::Encrypted(Box::new(
+            start_encryption(stream, &mut buf, key)
+                .map_err(crate::log::coded("encryption-failed"))?,
+        )),
+        (_, stream) => stream,
+    };
+
+    // each write is supposed to leave as a frame of its own
+    let max_write = match options.max_frame_size {
+        Some(size) => {
+            stream.connection().set_nodelay(true)?;
+            stream.frame_data_size(size)
+        }
+        None => usize::MAX,
+    };
+
+    Ok(Client {
+        token,
+        stream,
+        // whatever arrived before the registration doesn't necessarily trigger an event
+        ready: Readiness::ready(),
+        input: buf,
+        output: ByteBuffer::new(),
+        remaining: 0,
+        discard: 0,
+        max_write,
+        escape: options.escape_char.map(EscapeFilter::new),
+        heartbeat: Heartbeat::default(),
+        last_heard: Instant::now(),
+        pinged: None,
+        observer: observer || admin.is_some(),
+        admin,
+        pending_reply: None,
+        input_allowance: options.max_input_rate.map(input_allowance),
+        output_paused: false,
+        closed: false,
+    })
+}
+
+/// Authenticates a client joining a shared session, or reconnecting to a session as `user`,
+/// and attaches it. Returns the client and the user it authenticated as.
+///
+/// This runs on a thread of its own, see [`Joiner`], the relay doesn't wait for it.
+#[allow(clippy::too_many_arguments)]
+pub fn join_client(
+    stream: Connection,
+    options: &Options,
+    tls_acceptor: Option<&SslAcceptor>,
+    listen_port: u16,
+    handshake: &Handshake,
+    encryption_key: Option<&[u8; 32]>,
+    token: Token,
+    user: Option<&[u8]>,
+) -> Result<(Client, Box<[u8]>)> {
+    let mut buf = ByteBuffer::new();
+    let (stream, authenticated) = authenticate_connection(
+        stream,
+        &mut buf,
+        options,
+        tls_acceptor,
+        listen_port,
+        handshake,
+    )?;
+    if user.is_some_and(|user| *user != *authenticated.username) {
+        return Err(crate::log::with_code(
+            "reconnect-denied",
+            format_err!(
+                "{} cannot reconnect to the session of another user",
+                String::from_utf8_lossy(&authenticated.username),
+            ),
+        ));
+    }
+    // a client taking over a session without clients is not a joining one
+    let observer = authenticated.observer || options.observers && user.is_none();
+    let mut client = attach_client(stream, buf, options, encryption_key, token, observer, None)?;
+    if let Some(requested) = &authenticated.features {
+        offer_features(&mut client, options, requested);
+    }
+    Ok((client, authenticated.username))
+}
+
+/// The features of the session a client can ask for with the JSON handshake.
+fn session_features(options: &Options) -> Vec<&'static str> {
+    let mut features = vec!["size", "reset", "suspend", "flow"];
+    let optional = [
+        ("files", options.files_root.is_some()),
+        ("signal", !options.allowed_signals.is_empty()),
+        ("hex", !options.allowed_hex_input.is_empty()),
+        ("break", options.break_command.is_some()),
+        ("sysrq", options.allow_sysrq),
+        ("lock", options.lock_after.is_some()),
+        ("binary", options.detect_binary),
+        ("loop", options.loop_watchdog.is_some()),
+        ("guest", options.detect_guest),
+        ("lines", options.semantic_lines),
+        ("sac", options.sac),
+        ("stderr", options.child_stderr.is_some()),
+        ("keepalive", options.keepalive.is_some()),
+        ("quality", options.quality_hints),
+        ("detach", options.detachable),
+    ];
+    features.extend(
+        optional
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(feature, _)| feature),
+    );
+    features
+}
+
+/// Tells a client which of the features it asked for the session has, before any output.
+pub fn offer_features(client: &mut Client, options: &Options, requested: &[String]) {
+    let features: Vec<&str> = session_features(options)
+        .into_iter()
+        .filter(|feature| requested.iter().any(|requested| requested == feature))
+        .collect();
+    let message = encode_control_message("features", &[("list", features.join(","))]);
+    queue_message(&mut client.output, &message);
+}
+
+/// Whether the session's output in `buf` fits into the output buffers of all clients.
+pub fn fits_all_clients(buf: &ByteBuffer, clients: &[Client]) -> bool {
+    clients
+        .iter()
+        .all(|client| client.output.free_size() >= buf.len())
+}
+
+/// The number of clients taking part in the session, i.e. those not being administrators.
+pub fn participants(clients: &[Client]) -> usize {
+    clients
+        .iter()
+        .filter(|client| client.admin.is_none())
+        .count()
+}
+
+/// Whether a client still attached asked to hold back output, which stops reading from the
+/// terminal for everyone, like a full buffer would.
+fn output_paused(clients: &[Client]) -> bool {
+    clients
+        .iter()
+        .any(|client| client.output_paused && !client.closed)
+}
+
+/// Whether reading from the terminal waits, as a client asked to hold back output or the
+/// backlog is still being replayed to the clients.
+pub fn reading_paused(clients: &[Client], backlog: &Backlog) -> bool {
+    output_paused(clients) || participants(clients) > 0 && !backlog.is_empty()
+}
+
+/// Hands the session's output to all clients, once all of them have room for it.
+///
+/// The slowest client decides how fast output is read from the terminal, just like a single
+/// client does. Output is handed over as a whole, so that messages queued for a single client
+/// never end up in the middle of one for all of them.
+pub fn fan_out(buf: &mut ByteBuffer, clients: &mut [Client], backlog: &mut Backlog) {
+    // without clients, output is kept for the client attaching next, even if an administrator
+    // watches
+    if participants(clients) == 0 {
+        backlog.push(buf);
+        buf.consume(buf.len());
+        return;
+    }
+    if buf.is_empty() && !backlog.is_empty() {
+        let replay = backlog.take(buf.free_size());
+        queue_data(buf, &replay);
+    }
+    if buf.is_empty() || !fits_all_clients(buf, clients) {
+        return;
+    }
+    for client in clients.iter_mut() {
+        queue_data(&mut client.output, &buf[..]);
+    }
+    buf.consume(buf.len());
+}
+
+/// The memory a buffer takes, which is allocated in full up front.
+pub fn buffer_size(buf: &ByteBuffer) -> usize {
+    buf.len() + buf.free_size()
+}
+
+/// The memory the buffers of a client take, for --max-memory.
+pub fn client_memory(client: &Client) -> usize {
+    buffer_size(&client.input)
+        + buffer_size(&client.output)
+        + client.pending_reply.as_ref().map_or(0, String::len)
+}
+
+/// Tells a client the current size of the terminal, in answer to a size query.
+pub fn answer_size(pty: &PTY, output: &mut ByteBuffer) {
+    match pty.get_size() {
+        Ok((cols, rows)) => {
+            let message = encode_control_message(
+                "size",
+                &[("cols", cols.to_string()), ("rows", rows.to_string())],
+            );
+            queue_message(output, &message);
+        }
+        Err(err) => crate::log::warn(
+            "control-failed",
+            format_args!("failed to get terminal size - {err}"),
+        ),
+    }
+}
+
+/// How much of the screen or scrollback a single context message carries at most.
+const CONTEXT_CHUNK: usize = 3 * 1024;
+
+/// Sends a client joining a shared session the screen and the lines scrolled off it, in context
+/// messages ahead of any output.
+///
+/// Its output buffer grows by the messages, so that the output of the session fits just like
+/// for the other clients, which don't wait for the joining one to catch up.
+pub fn send_context(client: &mut Client, screen: &Screen) {
+    let mut messages = String::new();
+    for (part, text) in [
+        ("scrollback", screen.scrollback()),
+        ("screen", screen.text()),
+    ] {
+        for chunk in text.as_bytes().chunks(CONTEXT_CHUNK) {
+            messages.push_str(&encode_control_message(
+                "context",
+                &[("part", part.to_string()), ("data", base64_encode(chunk))],
+            ));
+        }
+    }
+    let (row, col) = screen.cursor();
+    messages.push_str(&encode_control_message(
+        "context",
+        &[
+            ("part", "end".to_string()),
+            ("row", row.to_string()),
+            ("col", col.to_string()),
+        ],
+    ));
+    let capacity = client.output.len() + client.output.free_size() + messages.len();
+    let mut output = ByteBuffer::with_capacity(capacity);
+    queue_data(&mut output, &client.output[..]);
+    queue_message(&mut output, &messages);
+    client.output = output;
+}
+
+/// Tells the clients of a shared session how many clients there are.
+pub fn announce_clients(
+    clients: &[Client],
+    control_state: &mut ControlState,
+    buf: &mut ByteBuffer,
+) {
+    let observers = clients
+        .iter()
+        .filter(|client| client.observer && client.admin.is_none())
+        .count();
+    let message = encode_control_message(
+        "clients",
+        &[
+            ("count", participants(clients).to_string()),
+            ("observers", observers.to_string()),
+        ],
+    );
+    control_state.notify(buf, &message);
+}
+
+/// Tells all clients that the administrator `uid` started or stopped watching the session,
+/// visibly on the terminal as well.
+pub fn announce_admin(
+    uid: u32,
+    joined: bool,
+    control_state: &mut ControlState,
+    buf: &mut ByteBuffer,
+) {
+    let (state, text) = if joined {
+        ("observing", "is now watching")
+    } else {
+        ("left", "stopped watching")
+    };
+    let message = encode_control_message(
+        "admin",
+        &[("state", state.to_string()), ("uid", uid.to_string())],
+    );
+    control_state.notify(
+        buf,
+        &format!("\r\n{message}[termproxy: an administrator (uid {uid}) {text} this session]\r\n"),
+    );
+}
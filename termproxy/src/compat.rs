@@ -0,0 +1,102 @@
+//! Stand-ins for the proxmox crates
+//!
+//! termproxy only needs `ByteBuffer` of proxmox-io and `io_err_other` of proxmox-lang. With
+//! the `vendored` feature, or without the default `proxmox` feature, the implementations below
+//! are used instead, so termproxy builds where the proxmox crates aren't packaged, e.g. with
+//! `cargo build --no-default-features --features vendored,auth-http`.
+
+#[cfg(all(feature = "proxmox", not(feature = "vendored")))]
+pub use proxmox_io::ByteBuffer;
+#[cfg(all(feature = "proxmox", not(feature = "vendored")))]
+pub use proxmox_lang::error::io_err_other;
+
+#[cfg(any(not(feature = "proxmox"), feature = "vendored"))]
+pub use vendored::{io_err_other, ByteBuffer};
+
+#[cfg(any(not(feature = "proxmox"), feature = "vendored"))]
+mod vendored {
+    use std::io::Read;
+    use std::ops::{Deref, DerefMut};
+
+    /// The default capacity of a buffer, like the one of proxmox-io.
+    const DEFAULT_CAPACITY: usize = 4096;
+
+    /// A buffer of a fixed capacity, filled at the end and consumed at the beginning
+    pub struct ByteBuffer {
+        buf: Box<[u8]>,
+        data_size: usize,
+    }
+
+    impl ByteBuffer {
+        pub fn new() -> Self {
+            Self::with_capacity(DEFAULT_CAPACITY)
+        }
+
+        pub fn with_capacity(capacity: usize) -> Self {
+            Self {
+                buf: vec![0u8; capacity].into_boxed_slice(),
+                data_size: 0,
+            }
+        }
+
+        pub fn free_size(&self) -> usize {
+            self.buf.len() - self.data_size
+        }
+
+        pub fn is_full(&self) -> bool {
+            self.data_size >= self.buf.len()
+        }
+
+        /// The part of the buffer after the data.
+        pub fn get_free_mut_slice(&mut self) -> &mut [u8] {
+            &mut self.buf[self.data_size..]
+        }
+
+        /// Removes up to `max_amount` bytes from the beginning, returns how many were removed.
+        pub fn consume(&mut self, max_amount: usize) -> usize {
+            let size = max_amount.min(self.data_size);
+            self.buf.copy_within(size..self.data_size, 0);
+            self.data_size -= size;
+            size
+        }
+
+        /// Like [`ByteBuffer::consume`], returning the removed bytes.
+        pub fn remove_data(&mut self, max_amount: usize) -> Box<[u8]> {
+            let size = max_amount.min(self.data_size);
+            let data: Box<[u8]> = self.buf[..size].into();
+            self.consume(size);
+            data
+        }
+
+        /// Appends what a single read from `input` returns, as much as fits.
+        pub fn read_from<T: Read + ?Sized>(&mut self, input: &mut T) -> std::io::Result<usize> {
+            let bytes = input.read(&mut self.buf[self.data_size..])?;
+            self.data_size += bytes;
+            Ok(bytes)
+        }
+    }
+
+    impl Default for ByteBuffer {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Deref for ByteBuffer {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            &self.buf[..self.data_size]
+        }
+    }
+
+    impl DerefMut for ByteBuffer {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            &mut self.buf[..self.data_size]
+        }
+    }
+
+    pub fn io_err_other<E: ToString>(err: E) -> std::io::Error {
+        std::io::Error::other(err.to_string())
+    }
+}
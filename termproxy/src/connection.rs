@@ -0,0 +1,117 @@
+//! The socket of a client connection
+//!
+//! Clients connect via TCP, or with `--listen-unix` via a Unix domain socket, which local
+//! frontends can use without taking up a TCP port. Everything above the socket, like TLS, the
+//! WebSocket protocol or the encrypted relay, works the same on both.
+
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use mio::event::Source;
+use mio::net::{TcpStream, UnixStream};
+use mio::{Interest, Registry, Token};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    /// Disables Nagle's algorithm on TCP connections, Unix sockets don't delay writes anyway.
+    pub fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_nodelay(nodelay),
+            Connection::Unix(_) => Ok(()),
+        }
+    }
+
+    /// The user ID of the process that connected, only known on Unix sockets.
+    pub fn peer_uid(&self) -> std::io::Result<Option<u32>> {
+        match self {
+            Connection::Tcp(_) => Ok(None),
+            Connection::Unix(stream) => {
+                let credentials = getsockopt(stream.as_raw_fd(), PeerCredentials)?;
+                Ok(Some(credentials.uid()))
+            }
+        }
+    }
+
+    /// Where the client connected from, for logs.
+    pub fn peer(&self) -> String {
+        match self {
+            Connection::Tcp(stream) => match stream.peer_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "unknown".to_string(),
+            },
+            Connection::Unix(_) => "unix".to_string(),
+        }
+    }
+}
+
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Connection::Tcp(stream) => stream.as_raw_fd(),
+            Connection::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Source for Connection {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.register(registry, token, interests),
+            Connection::Unix(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.reregister(registry, token, interests),
+            Connection::Unix(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.deregister(registry),
+            Connection::Unix(stream) => stream.deregister(registry),
+        }
+    }
+}
@@ -0,0 +1,387 @@
+//! Control messages between client and termproxy
+//!
+//! Besides data, resize and ping messages, the client can send control messages of the form
+//! `3:LENGTH:PAYLOAD`, where the payload is a command word, optionally followed by arguments,
+//! all separated by `:`. Unknown or malformed commands are ignored.
+//!
+//! The other direction carries the raw terminal output, so messages from termproxy itself are
+//! embedded into it as `ESC ] 2016 ; KIND [; KEY=VALUE]... BEL` operating system commands.
+//! Terminals ignore OSCs they don't know, frontends can handle them, for example with xterm.js'
+//! `parser.registerOscHandler(2016, ...)`.
+
+use anyhow::{bail, Result};
+use nix::sys::signal::Signal;
+
+/// The maximal length of a control message payload.
+pub const MAX_CONTROL_LEN: usize = 2048;
+
+#[derive(Debug)]
+pub enum ControlCommand {
+    /// Stop the foreground process group of the terminal with SIGSTOP.
+    Suspend,
+    /// Continue a previously suspended process group with SIGCONT.
+    Resume,
+    /// Send a signal to the process group of the command.
+    Signal(Signal),
+    /// Send a BREAK condition to the serial backend.
+    Break,
+    /// Request a magic SysRq key to be sent to the serial backend, which needs to be confirmed.
+    Sysrq(u8),
+    /// Confirm a previously requested SysRq key.
+    SysrqConfirm(u8),
+    /// Reset the client's terminal emulator, and with `sane` the settings of the terminal.
+    Reset { sane: bool },
+    /// Throttle output reported as looping, or stop throttling it.
+    LoopThrottle(bool),
+    /// Kill the foreground process group producing output reported as looping.
+    LoopKill,
+    /// Relay output paused because it looked binary after all.
+    BinaryResume,
+    /// Discard output paused because it looked binary, until it looks like text again.
+    BinaryFlush,
+    /// Unlock a locked session with a fresh ticket.
+    Unlock { username: String, ticket: String },
+    /// Switch to the next channel of a Windows SAC console, or back to the SAC channel.
+    SacChannel {
+        #[cfg_attr(not(feature = "serial"), allow(dead_code))]
+        home: bool,
+    },
+    /// Write raw bytes to the terminal, which have to be allowed with `--allow-hex-input`.
+    Hex(Vec<u8>),
+    /// Detach the client from a detachable session.
+    Detach,
+    /// Ask for the current size of the terminal.
+    Size,
+    /// Access a file below `--files-root`, answered with the request's `id`.
+    Files { id: String, request: FileRequest },
+}
+
+#[derive(Debug)]
+pub enum FileRequest {
+    /// List the entries of a directory.
+    List { path: String },
+    /// Read a chunk of a file, starting at `offset`.
+    Get { offset: u64, path: String },
+    /// Write `data` to a file at `offset`, creating or truncating it at offset 0.
+    Put {
+        offset: u64,
+        data: Vec<u8>,
+        path: String,
+    },
+}
+
+impl FileRequest {
+    /// The name of the operation, as used in commands and replies.
+    pub fn op(&self) -> &'static str {
+        match self {
+            FileRequest::List { .. } => "list",
+            FileRequest::Get { .. } => "get",
+            FileRequest::Put { .. } => "put",
+        }
+    }
+
+    /// Parses `OP:ID:...:PATH`, the path comes last as it may contain colons.
+    fn parse(request: &str) -> Result<(String, Self)> {
+        let Some((op, rest)) = request.split_once(':') else {
+            bail!("file request without id");
+        };
+        let fields = match op {
+            "list" => 2,
+            "get" => 3,
+            "put" => 4,
+            _ => bail!("unknown file operation '{op}'"),
+        };
+        let args: Vec<&str> = rest.splitn(fields, ':').collect();
+        if args.len() < fields {
+            bail!("file request '{op}' without path");
+        }
+        let id = args[0];
+        // echoed in the reply's fields
+        if id.is_empty() || id.len() > 32 || !id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            bail!("invalid file request id '{id}'");
+        }
+        let path = args[fields - 1].to_string();
+        let request = match op {
+            "list" => Self::List { path },
+            "get" => Self::Get {
+                offset: args[1].parse()?,
+                path,
+            },
+            _ => Self::Put {
+                offset: args[1].parse()?,
+                data: base64_decode(args[2].as_bytes())?,
+                path,
+            },
+        };
+        Ok((id.to_string(), request))
+    }
+}
+
+/// Parses a magic SysRq key, a single lowercase letter or digit.
+fn parse_sysrq_key(key: &str) -> Result<u8> {
+    match key.as_bytes() {
+        [key] if key.is_ascii_lowercase() || key.is_ascii_digit() => Ok(*key),
+        _ => bail!("invalid sysrq key '{key}'"),
+    }
+}
+
+/// The OSC number of control messages sent to the client.
+pub const CONTROL_OSC: u32 = 2016;
+
+/// Encodes a control message for the client.
+pub fn encode_control_message(kind: &str, fields: &[(&str, String)]) -> String {
+    let mut message = format!("\x1b]{CONTROL_OSC};{kind}");
+    for (key, value) in fields {
+        // neither may end the OSC early or add fields
+        let value: String = value
+            .chars()
+            .filter(|c| !c.is_control() && *c != ';')
+            .collect();
+        message.push_str(&format!(";{key}={value}"));
+    }
+    message.push('\x07');
+    message
+}
+
+/// Encodes binary data for control messages with standard base64.
+pub fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes standard base64 with padding, as sent in base64 data messages.
+pub fn base64_decode(encoded: &[u8]) -> Result<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a' + 26) as u32),
+            b'0'..=b'9' => Some((c - b'0' + 52) as u32),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    if !encoded.len().is_multiple_of(4) {
+        bail!("invalid base64 length {}", encoded.len());
+    }
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    let chunks = encoded.len() / 4;
+    for (i, chunk) in encoded.chunks(4).enumerate() {
+        // only the last chunk may be padded
+        let padding = match chunk {
+            [.., b'=', b'='] if i + 1 == chunks => 2,
+            [.., b'='] if i + 1 == chunks => 1,
+            _ => 0,
+        };
+        let mut bits = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let Some(value) = value(c) else {
+                bail!("invalid base64 character {:?}", c as char);
+            };
+            bits = bits << 6 | value;
+        }
+        bits <<= 6 * padding;
+        decoded.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(decoded)
+}
+
+/// Parses signal names like `INT` or `SIGINT`.
+pub fn parse_signal(name: &str) -> Result<Signal> {
+    let name = name.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{name}")
+    };
+    match name.parse() {
+        Ok(signal) => Ok(signal),
+        Err(_) => bail!("unknown signal '{name}'"),
+    }
+}
+
+/// Parses a byte sequence given as hex digits, e.g. `1b5b41`.
+pub fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        bail!("invalid hex sequence '{hex}'");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|pos| Ok(u8::from_str_radix(&hex[pos..pos + 2], 16)?))
+        .collect()
+}
+
+impl ControlCommand {
+    pub fn parse(payload: &[u8]) -> Result<Self> {
+        let payload = std::str::from_utf8(payload)?;
+
+        // tickets contain colons themselves
+        if let Some(credentials) = payload.strip_prefix("unlock:") {
+            return match credentials.split_once(':') {
+                Some((username, ticket)) => Ok(Self::Unlock {
+                    username: username.to_string(),
+                    ticket: ticket.to_string(),
+                }),
+                None => bail!("unlock command without ticket"),
+            };
+        }
+
+        if let Some(request) = payload.strip_prefix("files-") {
+            let (id, request) = FileRequest::parse(request)?;
+            return Ok(Self::Files { id, request });
+        }
+
+        let mut parts = payload.split(':');
+        let command = parts.next().unwrap_or_default();
+        let args: Vec<&str> = parts.collect();
+
+        Ok(match (command, &args[..]) {
+            ("suspend", []) => Self::Suspend,
+            ("resume", []) => Self::Resume,
+            ("signal", [name]) => Self::Signal(parse_signal(name)?),
+            ("break", []) => Self::Break,
+            ("sysrq", [key]) => Self::Sysrq(parse_sysrq_key(key)?),
+            ("sysrq-confirm", [key]) => Self::SysrqConfirm(parse_sysrq_key(key)?),
+            ("reset", []) => Self::Reset { sane: false },
+            ("reset", ["sane"]) => Self::Reset { sane: true },
+            ("loop-throttle", []) => Self::LoopThrottle(true),
+            ("loop-unthrottle", []) => Self::LoopThrottle(false),
+            ("loop-kill", []) => Self::LoopKill,
+            ("binary-resume", []) => Self::BinaryResume,
+            ("binary-flush", []) => Self::BinaryFlush,
+            ("sac-next", []) => Self::SacChannel { home: false },
+            ("sac-home", []) => Self::SacChannel { home: true },
+            ("hex", [hex]) => Self::Hex(parse_hex(hex)?),
+            ("detach", []) => Self::Detach,
+            ("size", []) => Self::Size,
+            _ => bail!("unknown control command '{payload}'"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        let parse = |payload: &str| ControlCommand::parse(payload.as_bytes());
+        assert!(matches!(parse("suspend"), Ok(ControlCommand::Suspend)));
+        assert!(matches!(
+            parse("signal:int"),
+            Ok(ControlCommand::Signal(Signal::SIGINT))
+        ));
+        assert!(matches!(
+            parse("reset:sane"),
+            Ok(ControlCommand::Reset { sane: true })
+        ));
+        assert!(matches!(parse("sysrq:b"), Ok(ControlCommand::Sysrq(b'b'))));
+        assert!(matches!(parse("hex:1b5b41"), Ok(ControlCommand::Hex(hex)) if hex == b"\x1b[A"));
+
+        // the ticket and the path keep their colons
+        let unlock = parse("unlock:root@pam:PVE:root@pam:65F1A2B3::c2lnbmF0dXJl");
+        assert!(matches!(
+            unlock,
+            Ok(ControlCommand::Unlock { username, ticket })
+                if username == "root@pam" && ticket == "PVE:root@pam:65F1A2B3::c2lnbmF0dXJl"
+        ));
+        assert!(matches!(
+            parse("files-get:a1:4096:/etc/a:b"),
+            Ok(ControlCommand::Files { id, request: FileRequest::Get { offset: 4096, path } })
+                if id == "a1" && path == "/etc/a:b"
+        ));
+        assert!(matches!(
+            parse("files-put:7:0:aGk=:/tmp/x"),
+            Ok(ControlCommand::Files { request: FileRequest::Put { offset: 0, data, path }, .. })
+                if data == b"hi" && path == "/tmp/x"
+        ));
+
+        for invalid in [
+            "",
+            "suspend:now",
+            "reset:insane",
+            "sysrq:B",
+            "sysrq:ab",
+            "signal:NOSUCH",
+            "hex:1b5",
+            "unlock:root@pam",
+            "files-list:a-1:/",
+            "files-get:1:/etc/a",
+            "files-put:1:0:aGk:/tmp/x",
+            "files-move:1:/a",
+            "unknown",
+        ] {
+            assert!(parse(invalid).is_err(), "{invalid:?} parsed");
+        }
+        assert!(ControlCommand::parse(b"signal:\xff").is_err());
+    }
+
+    #[test]
+    fn encodes_control_messages() {
+        assert_eq!(encode_control_message("pong", &[]), "\x1b]2016;pong\x07");
+        assert_eq!(
+            encode_control_message("size", &[("cols", "80".into()), ("rows", "24".into())]),
+            "\x1b]2016;size;cols=80;rows=24\x07",
+        );
+        // values can neither end the OSC nor add fields
+        assert_eq!(
+            encode_control_message("lock", &[("error", "bad;x=1\x07\x1b\\\nticket".into())]),
+            "\x1b]2016;lock;error=badx=1\\ticket\x07",
+        );
+    }
+
+    #[test]
+    fn encodes_base64() {
+        // the test vectors of RFC 4648
+        let vectors: [(&[u8], &str); 7] = [
+            (b"", ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"fooba", "Zm9vYmE="),
+            (b"foobar", "Zm9vYmFy"),
+        ];
+        for (data, encoded) in vectors {
+            assert_eq!(base64_encode(data), encoded);
+            assert_eq!(base64_decode(encoded.as_bytes()).unwrap(), data);
+        }
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(base64_decode(base64_encode(&all).as_bytes()).unwrap(), all);
+
+        for invalid in ["Zg=", "Zg", "Zm9v=", "Zg==Zm9v", "Z===", "Zm9*", "Zm 9"] {
+            assert!(base64_decode(invalid.as_bytes()).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn parses_signals_and_hex() {
+        assert_eq!(parse_signal("int").unwrap(), Signal::SIGINT);
+        assert_eq!(parse_signal("Hup").unwrap(), Signal::SIGHUP);
+        assert_eq!(parse_signal("SIGTERM").unwrap(), Signal::SIGTERM);
+        assert!(parse_signal("").is_err());
+        assert!(parse_signal("SIGSIGINT").is_err());
+        assert!(parse_signal("9").is_err());
+
+        assert_eq!(parse_hex("1b5b41").unwrap(), b"\x1b[A");
+        assert_eq!(parse_hex("00fF").unwrap(), b"\x00\xff");
+        for invalid in ["", "1", "1b5", "+1", "zz", "0x1b"] {
+            assert!(parse_hex(invalid).is_err(), "{invalid:?}");
+        }
+    }
+}
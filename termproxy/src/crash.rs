@@ -0,0 +1,89 @@
+//! Panic handling
+//!
+//! A panic in the relay loop would otherwise just kill the connection, leaving the user with a
+//! dead console and the maintainers with at most a line in some task log. The panic hook
+//! installed here tells the client what happened, optionally writes a crash report and aborts.
+
+use std::os::unix::io::RawFd;
+use std::panic::PanicHookInfo;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static CLIENT_FD: AtomicI32 = AtomicI32::new(-1);
+static SESSION_ID: OnceLock<String> = OnceLock::new();
+static CRASH_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+static TAGS: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
+const CLIENT_MESSAGE: &[u8] = b"\r\n\x1b[0m\r\ninternal proxy error, session closed\r\n";
+
+/// Installs the panic hook, crash reports are written to `crash_dir` if set.
+pub fn install_panic_hook(session_id: &str, tags: &[(String, String)], crash_dir: Option<PathBuf>) {
+    let _ = SESSION_ID.set(session_id.to_string());
+    let _ = TAGS.set(tags.to_vec());
+    let _ = CRASH_DIR.set(crash_dir);
+    std::panic::set_hook(Box::new(panic_hook));
+}
+
+/// Sets the connection the client gets notified on in case of a panic.
+pub fn set_client_fd(fd: RawFd) {
+    CLIENT_FD.store(fd, Ordering::SeqCst);
+}
+
+fn panic_hook(info: &PanicHookInfo) {
+    let backtrace = std::backtrace::Backtrace::force_capture();
+    crate::log::error("panic", format_args!("{info}\n{backtrace}"));
+
+    let fd = CLIENT_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        // best effort, the socket is non-blocking and the client may be gone already
+        let _ = nix::unistd::write(fd, CLIENT_MESSAGE);
+    }
+
+    if let Some(Some(dir)) = CRASH_DIR.get() {
+        if let Err(err) = write_crash_report(dir, info, &backtrace) {
+            crate::log::error(
+                "crash-report-failed",
+                format_args!("failed to write crash report - {err}"),
+            );
+        }
+    }
+
+    std::process::abort();
+}
+
+fn write_crash_report(
+    dir: &Path,
+    info: &PanicHookInfo,
+    backtrace: &std::backtrace::Backtrace,
+) -> std::io::Result<()> {
+    let session_id = SESSION_ID.get().map(String::as_str).unwrap_or("unknown");
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+
+    let message = match info.payload().downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match info.payload().downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "unknown panic payload".to_string(),
+        },
+    };
+
+    let report = serde_json::json!({
+        "session-id": session_id,
+        "tags": TAGS.get().map(|tags| tags.iter().cloned().collect::<std::collections::BTreeMap<_, _>>()),
+        "time": time,
+        "pid": std::process::id(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "message": message,
+        "location": info.location().map(|location| location.to_string()),
+        "backtrace": backtrace.to_string(),
+    });
+
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("termproxy-{session_id}-{time}.crash"));
+    std::fs::write(path, format!("{report:#}\n"))
+}
@@ -0,0 +1,348 @@
+//! Encrypted relay with a pre-shared key
+//!
+//! After the authentication handshake, both sides send a random 16 byte nonce prefix in the
+//! clear, termproxy right after its `OK`, the client right after its ticket line. Everything
+//! after that is a sequence of records:
+//!
+//! ```text
+//! LENGTH (u16, big endian) || XChaCha20-Poly1305(DATA) (LENGTH + 16 bytes)
+//! ```
+//!
+//! Each direction has a key of its own, derived from the pre-shared key with HKDF-SHA256 and
+//! the info `LABEL || SERVER-PREFIX || CLIENT-PREFIX`, LABEL being `termproxy c2s` for records
+//! of the client and `termproxy s2c` for those of termproxy. So records reflected back to their
+//! sender don't decrypt, and no key and nonce pair is used twice, not even across connections.
+//!
+//! The nonce of a record is the sender's prefix followed by a 64 bit big endian counter of its
+//! records, starting at zero. The additional data is the receiver's prefix followed by LENGTH,
+//! which binds records to the connection they were sent on and detects replays from other
+//! sessions.
+
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use anyhow::{bail, format_err, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use openssl::md::Md;
+use openssl::pkey::Id;
+use openssl::pkey_ctx::PkeyCtx;
+use zeroize::Zeroizing;
+
+use crate::connection::Connection;
+
+pub const NONCE_PREFIX_LEN: usize = 16;
+
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = 2;
+
+/// The size of a record beyond the data in it.
+pub const RECORD_OVERHEAD: usize = HEADER_LEN + TAG_LEN;
+
+/// The maximal amount of data in a single record.
+const MAX_RECORD_DATA: usize = 16 * 1024;
+
+/// Encrypted data queued for sending beyond which writes block.
+const MAX_PENDING_OUTPUT: usize = 64 * 1024;
+
+/// Reads the key, 64 hex digits optionally followed by whitespace, from `fd`, `what` names the
+/// key in errors.
+pub fn read_key_fd(fd: RawFd, what: &str) -> Result<Zeroizing<[u8; 32]>> {
+    let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+    let mut content = Zeroizing::new(String::new());
+    file.read_to_string(&mut content)
+        .map_err(|err| format_err!("failed to read {what} - {err}"))?;
+
+    let hex = content.trim();
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("{what} must be 64 hex digits");
+    }
+    let mut key = Zeroizing::new([0u8; 32]);
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)?;
+    }
+    Ok(key)
+}
+
+/// Returns a random nonce prefix.
+pub fn random_prefix() -> Result<[u8; NONCE_PREFIX_LEN]> {
+    let mut prefix = [0u8; NONCE_PREFIX_LEN];
+    let res = unsafe { libc::getrandom(prefix.as_mut_ptr() as *mut _, prefix.len(), 0) };
+    if res != prefix.len() as isize {
+        bail!(
+            "failed to get random bytes - {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(prefix)
+}
+
+/// Derives the key of the direction `label` from the pre-shared `key`, for the connection with
+/// the nonce prefixes `prefix` of termproxy and `client_prefix`.
+fn direction_key(
+    key: &[u8; 32],
+    label: &[u8],
+    prefix: &[u8; NONCE_PREFIX_LEN],
+    client_prefix: &[u8; NONCE_PREFIX_LEN],
+) -> Result<XChaCha20Poly1305> {
+    let mut ctx = PkeyCtx::new_id(Id::HKDF)?;
+    ctx.derive_init()?;
+    ctx.set_hkdf_md(Md::sha256())?;
+    ctx.set_hkdf_key(key)?;
+    ctx.add_hkdf_info(label)?;
+    ctx.add_hkdf_info(prefix)?;
+    ctx.add_hkdf_info(client_prefix)?;
+    let mut derived = Zeroizing::new([0u8; 32]);
+    ctx.derive(Some(&mut derived[..]))?;
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&derived[..])))
+}
+
+/// One direction of the record stream
+struct Direction {
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    peer_prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u64,
+}
+
+impl Direction {
+    fn next_nonce(&mut self) -> XNonce {
+        let mut nonce = [0u8; 24];
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.prefix);
+        nonce[NONCE_PREFIX_LEN..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        nonce.into()
+    }
+
+    fn aad(&self, header: &[u8]) -> Vec<u8> {
+        let mut aad = self.peer_prefix.to_vec();
+        aad.extend_from_slice(header);
+        aad
+    }
+}
+
+/// A client connection speaking the record protocol
+pub struct EncryptedStream {
+    stream: Connection,
+    send: Direction,
+    receive: Direction,
+    /// Received data not forming a complete record yet
+    input: Vec<u8>,
+    /// Decrypted data not read yet
+    plain: Vec<u8>,
+    /// Encrypted records not sent yet
+    output: Vec<u8>,
+}
+
+impl EncryptedStream {
+    /// Wraps `stream`, `initial` is data already received after the client's nonce prefix.
+    pub fn new(
+        stream: Connection,
+        key: &[u8; 32],
+        prefix: [u8; NONCE_PREFIX_LEN],
+        client_prefix: [u8; NONCE_PREFIX_LEN],
+        initial: &[u8],
+    ) -> Result<Self> {
+        // a client echoing termproxy's prefix would get the same nonces for both directions
+        if client_prefix == prefix {
+            bail!("client uses the nonce prefix of termproxy");
+        }
+        Ok(Self {
+            stream,
+            send: Direction {
+                cipher: direction_key(key, b"termproxy s2c", &prefix, &client_prefix)?,
+                prefix,
+                peer_prefix: client_prefix,
+                counter: 0,
+            },
+            receive: Direction {
+                cipher: direction_key(key, b"termproxy c2s", &prefix, &client_prefix)?,
+                prefix: client_prefix,
+                peer_prefix: prefix,
+                counter: 0,
+            },
+            input: initial.to_vec(),
+            plain: Vec::new(),
+            output: Vec::new(),
+        })
+    }
+
+    pub fn stream_mut(&mut self) -> &mut Connection {
+        &mut self.stream
+    }
+
+    /// Whether there are encrypted records waiting to be sent.
+    pub fn has_pending_output(&self) -> bool {
+        !self.output.is_empty()
+    }
+
+    /// Decrypts all complete records in the input.
+    fn decrypt_input(&mut self) -> std::io::Result<()> {
+        while self.input.len() >= HEADER_LEN {
+            let len = u16::from_be_bytes([self.input[0], self.input[1]]) as usize;
+            let end = HEADER_LEN + len + TAG_LEN;
+            if self.input.len() < end {
+                break;
+            }
+            let nonce = self.receive.next_nonce();
+            let aad = self.receive.aad(&self.input[..HEADER_LEN]);
+            let payload = Payload {
+                msg: &self.input[HEADER_LEN..end],
+                aad: &aad,
+            };
+            let data = self.receive.cipher.decrypt(&nonce, payload).map_err(|_| {
+                std::io::Error::new(ErrorKind::InvalidData, "failed to decrypt record")
+            })?;
+            self.plain.extend_from_slice(&data);
+            self.input.drain(..end);
+        }
+        Ok(())
+    }
+
+    fn write_output(&mut self) -> std::io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for EncryptedStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.decrypt_input()?;
+        while self.plain.is_empty() {
+            let mut chunk = [0u8; 4096];
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.input.extend_from_slice(&chunk[..n]);
+            self.decrypt_input()?;
+        }
+        let n = buf.len().min(self.plain.len());
+        buf[..n].copy_from_slice(&self.plain[..n]);
+        self.plain.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for EncryptedStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.output.len() >= MAX_PENDING_OUTPUT {
+            self.write_output()?;
+        }
+
+        let data = &buf[..buf.len().min(MAX_RECORD_DATA)];
+        let header = (data.len() as u16).to_be_bytes();
+        let nonce = self.send.next_nonce();
+        let aad = self.send.aad(&header);
+        let record = self
+            .send
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: data,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| std::io::Error::other("failed to encrypt record"))?;
+        self.output.extend_from_slice(&header);
+        self.output.extend_from_slice(&record);
+
+        // the data is accepted either way, what could not be sent is flushed later
+        match self.write_output() {
+            Err(err) if err.kind() != ErrorKind::WouldBlock => Err(err),
+            _ => Ok(data.len()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+    const PREFIX: [u8; NONCE_PREFIX_LEN] = [1; NONCE_PREFIX_LEN];
+    const CLIENT_PREFIX: [u8; NONCE_PREFIX_LEN] = [2; NONCE_PREFIX_LEN];
+
+    /// The sending and the receiving end of the direction `label`, whose records are sent
+    /// with `prefix` to the side with `peer_prefix`.
+    fn direction(
+        label: &[u8],
+        prefix: [u8; NONCE_PREFIX_LEN],
+        peer_prefix: [u8; NONCE_PREFIX_LEN],
+    ) -> (Direction, Direction) {
+        let end = || Direction {
+            cipher: direction_key(&KEY, label, &PREFIX, &CLIENT_PREFIX).unwrap(),
+            prefix,
+            peer_prefix,
+            counter: 0,
+        };
+        (end(), end())
+    }
+
+    fn seal(send: &mut Direction, data: &[u8]) -> Vec<u8> {
+        let header = (data.len() as u16).to_be_bytes();
+        let nonce = send.next_nonce();
+        let aad = send.aad(&header);
+        let payload = Payload {
+            msg: data,
+            aad: &aad,
+        };
+        [&header[..], &send.cipher.encrypt(&nonce, payload).unwrap()].concat()
+    }
+
+    fn open(receive: &mut Direction, record: &[u8]) -> Option<Vec<u8>> {
+        let nonce = receive.next_nonce();
+        let aad = receive.aad(&record[..HEADER_LEN]);
+        let payload = Payload {
+            msg: &record[HEADER_LEN..],
+            aad: &aad,
+        };
+        receive.cipher.decrypt(&nonce, payload).ok()
+    }
+
+    #[test]
+    fn counts_nonces() {
+        let (mut send, _) = direction(b"termproxy s2c", PREFIX, CLIENT_PREFIX);
+        for counter in 0u64..3 {
+            let nonce = send.next_nonce();
+            assert_eq!(nonce[..NONCE_PREFIX_LEN], PREFIX);
+            assert_eq!(nonce[NONCE_PREFIX_LEN..], counter.to_be_bytes());
+        }
+        assert_eq!(send.counter, 3);
+    }
+
+    #[test]
+    fn records_decrypt_in_order_only() {
+        let (mut send, mut receive) = direction(b"termproxy s2c", PREFIX, CLIENT_PREFIX);
+        let first = seal(&mut send, b"first");
+        let second = seal(&mut send, b"second");
+        assert_eq!(open(&mut receive, &first).as_deref(), Some(&b"first"[..]));
+        assert_eq!(open(&mut receive, &second).as_deref(), Some(&b"second"[..]));
+
+        // a record replayed, skipped or reordered is out of step with the counter
+        let (mut send, mut receive) = direction(b"termproxy s2c", PREFIX, CLIENT_PREFIX);
+        let first = seal(&mut send, b"first");
+        let second = seal(&mut send, b"second");
+        assert_eq!(open(&mut receive, &second), None);
+        assert_eq!(open(&mut receive, &first), None);
+
+        // records reflected back to the sender are meant for the other direction
+        let (mut send, _) = direction(b"termproxy s2c", PREFIX, CLIENT_PREFIX);
+        let (_, mut reflected) = direction(b"termproxy c2s", CLIENT_PREFIX, PREFIX);
+        let record = seal(&mut send, b"data");
+        assert_eq!(open(&mut reflected, &record), None);
+    }
+}
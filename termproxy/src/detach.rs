@@ -0,0 +1,309 @@
+//! Detaching from sessions and attaching to them again
+//!
+//! With `--detachable`, a session keeps running when its last client goes away, be it because
+//! the client sent a `detach` control message, termproxy got SIGUSR1 or the connection dropped.
+//! Clients can attach again through the session's own listener, or through a later termproxy
+//! invocation with `--attach <session-id>`: that one authenticates the client like any session
+//! does and hands its connection over to the session via the session's control socket,
+//! `<session-id>.sock` in the status directory, or that path as name in the abstract namespace
+//! with `--unix-abstract`. A session started with `--background` starts out without any
+//! client, as if its first one had detached right away.
+//!
+//! The hand over is a single message on the control socket, the user name of the client, the
+//! ACL path and privilege it was authenticated for, each followed by a line break, and whatever
+//! the client sent after its ticket line, with the connection's file descriptor attached. Only
+//! processes of the same user may hand over clients, and only clients authenticated for the
+//! session's own ACL path and privilege are taken over, like clients joining on its listener.
+//! Messages without a connection attached register share links instead, see [`crate::share`].
+//!
+//! Each connection to the control socket is read on a thread of its own, like the handshakes of
+//! joining clients, so that a process sending its message slowly, or not at all, doesn't stall
+//! the session.
+
+use std::io::{ErrorKind, IoSlice, IoSliceMut, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, format_err, Result};
+use mio::net::{UnixListener, UnixStream};
+use mio::Waker;
+use nix::sys::socket::{
+    getsockname, getsockopt, recvmsg, sendmsg, sockopt, AddressFamily, ControlMessage,
+    ControlMessageOwned, MsgFlags, SockaddrLike, SockaddrStorage,
+};
+use nix::unistd::geteuid;
+
+use crate::cli::Options;
+use crate::connection::Connection;
+use crate::share::{self, ShareRequest};
+
+/// How long a connection on the control socket may take to send its message, the sender is a
+/// local process that sends it right away.
+const HAND_OVER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many connections on the control socket may be read at the same time, further ones are
+/// rejected right away.
+pub const MAX_PENDING: usize = 8;
+
+/// The maximal size of a hand over message.
+const MAX_HAND_OVER: usize = 64 * 1024;
+
+/// The path of the control socket of the session `session_id`.
+pub fn socket_path(dir: &Path, session_id: &str) -> PathBuf {
+    dir.join(format!("{session_id}.sock"))
+}
+
+/// A client handed over by another termproxy
+pub struct HandOver {
+    pub connection: Connection,
+    pub username: Vec<u8>,
+    /// The ACL path the client was authenticated for
+    pub acl_path: String,
+    /// The privilege the client was authenticated for on `acl_path`, empty for none
+    pub acl_permission: String,
+    /// What the client sent after its ticket line
+    pub input: Vec<u8>,
+}
+
+/// A request of another process on the control socket
+pub enum Request {
+    HandOver(HandOver),
+    Share(ShareRequest),
+}
+
+pub struct ControlSocket {
+    listener: UnixListener,
+    /// The socket file, removed with the socket, none in the abstract namespace
+    path: Option<PathBuf>,
+    sender: Sender<Result<Request>>,
+    receiver: Receiver<Result<Request>>,
+    pending: usize,
+}
+
+impl ControlSocket {
+    /// Binds the control socket of the session `session_id` in `dir`, or with its path as
+    /// name in the abstract namespace with `abstract_namespace`.
+    pub fn bind(dir: &Path, session_id: &str, abstract_namespace: bool) -> Result<Self> {
+        let path = socket_path(dir, session_id);
+        if abstract_namespace {
+            let listener = crate::listener::bind_unix(&path, true).map_err(|err| {
+                format_err!("failed to bind abstract control socket {path:?} - {err}")
+            })?;
+            return Ok(Self::new(listener, None));
+        }
+        std::fs::create_dir_all(dir).map_err(|err| {
+            format_err!("failed to create control socket directory {dir:?} - {err}")
+        })?;
+        let listener = crate::listener::bind_unix(&path, false)
+            .map_err(|err| format_err!("failed to bind control socket {path:?} - {err}"))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Self::new(listener, Some(path)))
+    }
+
+    fn new(listener: UnixListener, path: Option<PathBuf>) -> Self {
+        let (sender, receiver) = channel();
+        Self {
+            listener,
+            path,
+            sender,
+            receiver,
+            pending: 0,
+        }
+    }
+
+    /// Accepts a connection on the control socket, `None` if no one is waiting.
+    pub fn accept(&self) -> std::io::Result<Option<UnixStream>> {
+        match self.listener.accept() {
+            Ok((stream, _)) => Ok(Some(stream)),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Starts reading the request on a connection accepted on the control socket, `waker`
+    /// wakes up the relay loop once it was read, see [`ControlSocket::received`].
+    pub fn receive(&mut self, stream: UnixStream, waker: Arc<Waker>) -> Result<()> {
+        if self.pending >= MAX_PENDING {
+            bail!("{} requests are still being read", self.pending);
+        }
+        let sender = self.sender.clone();
+        std::thread::Builder::new().spawn(move || {
+            // the session may have ended in the meantime
+            if sender.send(HandOver::receive(stream)).is_ok() {
+                let _ = waker.wake();
+            }
+        })?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Takes the next request that was read, if any.
+    pub fn received(&mut self) -> Option<Result<Request>> {
+        let request = self.receiver.try_recv().ok()?;
+        self.pending -= 1;
+        Some(request)
+    }
+}
+
+impl HandOver {
+    /// Receives the client handed over on a connection to the control socket, or a share link
+    /// to register. This blocks until the whole message arrived, at most for
+    /// [`HAND_OVER_TIMEOUT`].
+    fn receive(stream: UnixStream) -> Result<Request> {
+        let credentials = getsockopt(stream.as_raw_fd(), sockopt::PeerCredentials)?;
+        if credentials.uid() != geteuid().as_raw() {
+            bail!("rejecting hand over from uid {}", credentials.uid());
+        }
+
+        let mut stream =
+            unsafe { std::os::unix::net::UnixStream::from_raw_fd(stream.into_raw_fd()) };
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(HAND_OVER_TIMEOUT))?;
+
+        let mut message = vec![0u8; MAX_HAND_OVER];
+        let mut cmsg_buffer = nix::cmsg_space!([RawFd; 1]);
+        let (bytes, fds) = {
+            let mut iov = [IoSliceMut::new(&mut message)];
+            let msg = recvmsg::<()>(
+                stream.as_raw_fd(),
+                &mut iov,
+                Some(&mut cmsg_buffer),
+                MsgFlags::MSG_CMSG_CLOEXEC,
+            )?;
+            let fds: Vec<RawFd> = msg
+                .cmsgs()
+                .flat_map(|cmsg| match cmsg {
+                    ControlMessageOwned::ScmRights(fds) => fds,
+                    _ => Vec::new(),
+                })
+                .collect();
+            (msg.bytes, fds)
+        };
+        // take ownership of the descriptors right away, so they get closed on errors
+        let mut fds = fds
+            .into_iter()
+            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+
+        // the rest of a message that didn't arrive in one piece
+        message.truncate(bytes);
+        (&mut stream)
+            .take((MAX_HAND_OVER - bytes) as u64)
+            .read_to_end(&mut message)?;
+
+        let Some(fd) = fds.next() else {
+            let Some(request) = message.strip_prefix(share::REQUEST_PREFIX) else {
+                bail!("hand over without a connection");
+            };
+            return Ok(Request::Share(ShareRequest::parse(request, stream)?));
+        };
+
+        let mut lines = message.splitn(4, |&b| b == b'\n');
+        let (Some(username), Some(acl_path), Some(acl_permission), Some(input)) =
+            (lines.next(), lines.next(), lines.next(), lines.next())
+        else {
+            bail!("hand over without a user name and ACL");
+        };
+
+        let unix =
+            getsockname::<SockaddrStorage>(fd.as_raw_fd())?.family() == Some(AddressFamily::Unix);
+        let connection = if unix {
+            let stream = std::os::unix::net::UnixStream::from(fd);
+            stream.set_nonblocking(true)?;
+            Connection::Unix(mio::net::UnixStream::from_std(stream))
+        } else {
+            let stream = std::net::TcpStream::from(fd);
+            stream.set_nonblocking(true)?;
+            Connection::Tcp(mio::net::TcpStream::from_std(stream))
+        };
+
+        Ok(Request::HandOver(HandOver {
+            connection,
+            username: username.to_vec(),
+            acl_path: String::from_utf8_lossy(acl_path).into_owned(),
+            acl_permission: String::from_utf8_lossy(acl_permission).into_owned(),
+            input: input.to_vec(),
+        }))
+    }
+
+    /// Whether the client was authenticated for what clients of the session need.
+    pub fn authorized_for(&self, options: &Options) -> bool {
+        self.acl_path == options.acl_path
+            && self.acl_permission == options.acl_permission.as_deref().unwrap_or_default()
+    }
+}
+
+impl AsRawFd for ControlSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Connects to the control socket of the session `session_id` in `dir`, which is in the
+/// abstract namespace if there is no socket file.
+pub fn connect(dir: &Path, session_id: &str) -> Result<std::os::unix::net::UnixStream> {
+    let path = socket_path(dir, session_id);
+    let result = match std::os::unix::net::UnixStream::connect(&path) {
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            let address = SocketAddr::from_abstract_name(path.as_os_str().as_bytes())?;
+            std::os::unix::net::UnixStream::connect_addr(&address)
+        }
+        result => result,
+    };
+    result.map_err(|err| format_err!("failed to connect to session '{session_id}' - {err}"))
+}
+
+/// Hands the connection of a client authenticated with `options` over to the session
+/// `session_id`.
+pub fn hand_over(
+    options: &Options,
+    session_id: &str,
+    connection: &Connection,
+    username: &[u8],
+    input: &[u8],
+) -> Result<()> {
+    let stream = connect(options.runtime_dir(), session_id)?;
+
+    let message = [
+        username,
+        b"\n",
+        options.acl_path.as_bytes(),
+        b"\n",
+        options
+            .acl_permission
+            .as_deref()
+            .unwrap_or_default()
+            .as_bytes(),
+        b"\n",
+        input,
+    ]
+    .concat();
+    if message.len() > MAX_HAND_OVER {
+        bail!("too much input to hand over");
+    }
+    let fds = [connection.as_raw_fd()];
+    let sent = sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(&message)],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    // the connection comes with the first part of the message
+    (&stream).write_all(&message[sent..])?;
+    Ok(())
+}
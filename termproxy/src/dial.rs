@@ -0,0 +1,48 @@
+//! Connecting to hosts with addresses of both IP families
+//!
+//! Host names like `localhost` resolve to IPv6 and IPv4 addresses, and trying all addresses of
+//! one family before the other means waiting for the connection timeout once per address
+//! whenever the first family is broken, e.g. filtered by a firewall. The addresses are tried
+//! alternating between the families instead, one after the other. ureq gives each attempt half
+//! of what is left of [`CONNECT_TIMEOUT`], so a broken family delays the request, but doesn't
+//! fail it.
+//!
+//! This is not Happy Eyeballs (RFC 8305), the attempts are not raced: ureq connects on its own
+//! and can't be handed a connection established elsewhere.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+/// How long connecting may take for all addresses together.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Orders the addresses alternating between the families, starting with the family of the
+/// first one, which the resolver prefers.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_is_ipv6 = first.is_ipv6();
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut sorted = Vec::new();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return sorted,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Resolves host names for HTTP requests to their addresses alternating between the families,
+/// to be used with a connect timeout of [`CONNECT_TIMEOUT`].
+pub struct InterleavedResolver;
+
+impl ureq::Resolver for InterleavedResolver {
+    fn resolve(&self, netloc: &str) -> std::io::Result<Vec<SocketAddr>> {
+        Ok(interleave(netloc.to_socket_addrs()?.collect()))
+    }
+}
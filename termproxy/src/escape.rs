@@ -0,0 +1,114 @@
+//! SSH-like escape sequences in the client's input
+//!
+//! If enabled, the escape character (e.g. `~`) typed at the beginning of a line is not passed
+//! to the terminal; together with the following character it forms a command for termproxy
+//! itself, like `~.` to disconnect. This gives frontends without dedicated buttons access to
+//! proxy-level functions. Typing the escape character twice sends it once.
+//!
+//! Writes to the terminal can be partial, so scanning the input is kept separate from updating
+//! the state, which only happens for the bytes that actually got written.
+
+/// Commands available after the escape character, with a description for the help menu.
+pub const ESCAPE_COMMANDS: &[(u8, &str)] = &[
+    (b'.', "disconnect"),
+    (b's', "show session statistics"),
+    (b'B', "send a BREAK to the serial backend"),
+    (b'r', "resume paused binary output"),
+    (b'f', "flush paused binary output"),
+    (b'?', "show this help"),
+];
+
+/// What to do with the input at hand.
+#[derive(Debug, PartialEq)]
+pub enum Scan {
+    /// Write that many bytes to the terminal unchanged.
+    Pass(usize),
+    /// The first byte is the escape character, it needs to be consumed and held back.
+    Escape,
+    /// The first byte is a command following the escape character, consume and run it.
+    Command(u8),
+    /// The held back escape character turned out not to start a command, write it before
+    /// continuing with the input.
+    Release,
+}
+
+pub struct EscapeFilter {
+    escape: u8,
+    at_line_start: bool,
+    pending: bool,
+}
+
+impl EscapeFilter {
+    pub fn new(escape: u8) -> Self {
+        Self {
+            escape,
+            at_line_start: true,
+            pending: false,
+        }
+    }
+
+    pub fn escape_char(&self) -> u8 {
+        self.escape
+    }
+
+    /// Decides what to do with the (non-empty) input `data`, without changing any state.
+    pub fn scan(&self, data: &[u8]) -> Scan {
+        if self.pending {
+            let byte = data[0];
+            if byte == self.escape {
+                return Scan::Pass(1);
+            } else if ESCAPE_COMMANDS.iter().any(|(command, _)| *command == byte) {
+                return Scan::Command(byte);
+            }
+            return Scan::Release;
+        }
+
+        let mut at_line_start = self.at_line_start;
+        for (pos, &byte) in data.iter().enumerate() {
+            if at_line_start && byte == self.escape {
+                return if pos == 0 {
+                    Scan::Escape
+                } else {
+                    Scan::Pass(pos)
+                };
+            }
+            at_line_start = byte == b'\r' || byte == b'\n';
+        }
+        Scan::Pass(data.len())
+    }
+
+    /// Marks the escape character as consumed and held back.
+    pub fn hold(&mut self) {
+        self.pending = true;
+    }
+
+    /// Marks a command as consumed.
+    pub fn release(&mut self) {
+        self.pending = false;
+    }
+
+    /// Updates the state with the bytes written to the terminal.
+    pub fn written(&mut self, data: &[u8]) {
+        if let Some(&last) = data.last() {
+            self.pending = false;
+            self.at_line_start = last == b'\r' || last == b'\n';
+        }
+    }
+
+    /// The help menu, ready to be sent to the client.
+    pub fn help(&self) -> String {
+        let escape = self.escape as char;
+        let mut help = String::from("\r\nSupported escape sequences:\r\n");
+        for (command, description) in ESCAPE_COMMANDS {
+            help.push_str(&format!(
+                " {escape}{} - {description}\r\n",
+                *command as char
+            ));
+        }
+        help.push_str(&format!(
+            " {escape}{escape} - send the escape character\r\n"
+        ));
+        help.push_str("(Note that escapes are only recognized immediately after newline.)\r\n");
+        help
+    }
+}
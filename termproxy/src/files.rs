@@ -0,0 +1,254 @@
+//! Browsing files next to the terminal
+//!
+//! With `--files-root`, clients list, download and upload files below that directory with
+//! `files-*` control messages, e.g. for a file browser in a side panel of the console. Each
+//! request is answered with a `files` control message to the requesting client only.
+//!
+//! Files are accessed with the file system user and group of the command (`setfsuid`), so a
+//! client gets no further than the user of its shell would. Supplementary groups of that user
+//! are not taken into account, which can only deny access the shell would have. Paths are
+//! relative to the root, and may not leave it, neither with `..` nor through symbolic links.
+//!
+//! Paths are opened component by component relative to the root, without following any
+//! symbolic link, so they can't be swapped in between checking and opening them. Files are
+//! opened non-blocking and checked to be regular files before reading or writing, so a FIFO
+//! can't block the relay loop.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::path::{Component, Path};
+
+use anyhow::{bail, format_err, Result};
+use nix::dir::Dir;
+use nix::fcntl::{fcntl, openat, FcntlArg, OFlag};
+use nix::sys::stat::{fstat, fstatat, FileStat, Mode, SFlag};
+use nix::unistd::{setfsgid, setfsuid, Gid, Uid};
+
+use crate::control::{base64_encode, encode_control_message, FileRequest};
+use crate::login::Credentials;
+
+/// The maximal size of a chunk of a file sent with one reply, the client asks for the next one
+/// with the offset after it.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// The maximal size of a directory listing, before encoding it for the reply, which has to fit
+/// into the client's output buffer at once.
+const MAX_LISTING: usize = 24 * 1024;
+
+pub struct FileAccess {
+    /// The root directory, opened as path only
+    root: OwnedFd,
+    owner: Option<(Uid, Gid)>,
+}
+
+/// The type of the file with the status `stat`.
+fn file_type(stat: &FileStat) -> SFlag {
+    SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT
+}
+
+impl FileAccess {
+    /// Gives access to the files below `root`, as the user of `credentials` if the command runs
+    /// as a different one than termproxy.
+    pub fn new(root: &Path, credentials: Option<&Credentials>) -> Result<Self> {
+        if !root.is_dir() {
+            bail!("files root {root:?} is not a directory");
+        }
+        let fd = nix::fcntl::open(
+            root,
+            OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .map_err(|err| format_err!("failed to open files root {root:?} - {err}"))?;
+        Ok(Self {
+            root: unsafe { OwnedFd::from_raw_fd(fd) },
+            owner: credentials.map(|credentials| (credentials.uid(), credentials.gid())),
+        })
+    }
+
+    /// Handles `request`, returning the control message to answer it with.
+    pub fn handle(access: Option<&Self>, id: &str, request: &FileRequest) -> String {
+        let op = request.op();
+        let result = match access {
+            Some(access) => {
+                let _user = access.owner.map(|(uid, gid)| FsUser::switch(uid, gid));
+                access.run(request)
+            }
+            None => Err(format_err!("file access not enabled")),
+        };
+        match result {
+            Ok(fields) => {
+                let mut reply = vec![("op", op.to_string()), ("id", id.to_string())];
+                reply.extend(fields);
+                encode_control_message("files", &reply)
+            }
+            Err(err) => encode_control_message(
+                "files",
+                &[
+                    ("op", op.to_string()),
+                    ("id", id.to_string()),
+                    ("error", err.to_string()),
+                ],
+            ),
+        }
+    }
+
+    fn run(&self, request: &FileRequest) -> Result<Vec<(&'static str, String)>> {
+        match request {
+            FileRequest::List { path } => {
+                let fd = self.open(path, OFlag::O_RDONLY | OFlag::O_DIRECTORY, SFlag::S_IFDIR)?;
+                let mut dir = Dir::from_fd(fd.into_raw_fd())?;
+                let dir_fd = dir.as_raw_fd();
+                let mut entries = Vec::new();
+                let mut size = 0;
+                let mut truncated = false;
+                for entry in dir.iter() {
+                    let entry = entry?;
+                    let name = entry.file_name();
+                    if name.to_bytes() == b"." || name.to_bytes() == b".." {
+                        continue;
+                    }
+                    // not following links, they are listed as what they are
+                    let Ok(stat) = fstatat(dir_fd, name, nix::fcntl::AtFlags::AT_SYMLINK_NOFOLLOW)
+                    else {
+                        continue;
+                    };
+                    let kind = match file_type(&stat) {
+                        SFlag::S_IFDIR => "dir",
+                        SFlag::S_IFREG => "file",
+                        SFlag::S_IFLNK => "link",
+                        _ => "other",
+                    };
+                    let entry = serde_json::json!({
+                        "name": name.to_string_lossy(),
+                        "type": kind,
+                        "size": stat.st_size,
+                        "mtime": stat.st_mtime,
+                        "mode": stat.st_mode & 0o7777,
+                    });
+                    size += entry.to_string().len() + 1;
+                    if size > MAX_LISTING {
+                        truncated = true;
+                        break;
+                    }
+                    entries.push(entry);
+                }
+                let listing = serde_json::json!({ "entries": entries, "truncated": truncated });
+                Ok(vec![(
+                    "data",
+                    base64_encode(listing.to_string().as_bytes()),
+                )])
+            }
+            FileRequest::Get { offset, path } => {
+                let mut file = File::from(self.open(path, OFlag::O_RDONLY, SFlag::S_IFREG)?);
+                file.seek(SeekFrom::Start(*offset))?;
+                let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+                file.take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+                let eof = chunk.len() < CHUNK_SIZE;
+                Ok(vec![
+                    ("offset", offset.to_string()),
+                    ("eof", (eof as u8).to_string()),
+                    ("data", base64_encode(&chunk)),
+                ])
+            }
+            FileRequest::Put { offset, data, path } => {
+                let mut flags = OFlag::O_WRONLY;
+                if *offset == 0 {
+                    flags |= OFlag::O_CREAT;
+                }
+                let mut file = File::from(self.open(path, flags, SFlag::S_IFREG)?);
+                // truncating only after the type check, a FIFO or device must not see it
+                if *offset == 0 {
+                    file.set_len(0)?;
+                }
+                file.seek(SeekFrom::Start(*offset))?;
+                file.write_all(data)?;
+                Ok(vec![("size", file.metadata()?.len().to_string())])
+            }
+        }
+    }
+
+    /// Opens `path` relative to the root with `flags`, which has to be a file of `kind`.
+    ///
+    /// No symbolic link is followed, and the file is opened non-blocking until its type is
+    /// checked, so opening a FIFO doesn't wait for a writer or reader.
+    fn open(&self, path: &str, flags: OFlag, kind: SFlag) -> Result<OwnedFd> {
+        let mut names: Vec<&OsStr> = Vec::new();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => names.push(name),
+                Component::CurDir => (),
+                _ => bail!("invalid path '{path}'"),
+            }
+        }
+        let last = names.pop().unwrap_or(OsStr::new("."));
+
+        let mut dir: Option<OwnedFd> = None;
+        for name in names {
+            let parent = dir.as_ref().unwrap_or(&self.root).as_raw_fd();
+            let fd = openat(
+                parent,
+                name,
+                OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )
+            .map_err(|err| symlink_error(path, err))?;
+            dir = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+
+        let parent = dir.as_ref().unwrap_or(&self.root).as_raw_fd();
+        let fd = openat(
+            parent,
+            last,
+            flags | OFlag::O_NOFOLLOW | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC,
+            Mode::from_bits_truncate(0o644),
+        )
+        .map_err(|err| symlink_error(path, err))?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        if file_type(&fstat(fd.as_raw_fd())?) != kind {
+            match kind {
+                SFlag::S_IFDIR => bail!("not a directory"),
+                _ => bail!("not a regular file"),
+            }
+        }
+        let status = OFlag::from_bits_truncate(fcntl(fd.as_raw_fd(), FcntlArg::F_GETFL)?);
+        fcntl(
+            fd.as_raw_fd(),
+            FcntlArg::F_SETFL(status & !OFlag::O_NONBLOCK),
+        )?;
+        Ok(fd)
+    }
+}
+
+/// Turns the error of opening a symbolic link, which is never followed, into a readable one.
+fn symlink_error(path: &str, err: nix::Error) -> anyhow::Error {
+    match err {
+        // O_NOFOLLOW fails with ELOOP, O_DIRECTORY with ENOTDIR on links
+        nix::Error::ELOOP => format_err!("path '{path}' is a symbolic link"),
+        err => err.into(),
+    }
+}
+
+/// Switches the file system user and group of termproxy until dropped.
+struct FsUser {
+    uid: Uid,
+    gid: Gid,
+}
+
+impl FsUser {
+    fn switch(uid: Uid, gid: Gid) -> Self {
+        // the group first, it cannot be changed anymore after giving up the user
+        let gid = setfsgid(gid);
+        let uid = setfsuid(uid);
+        Self { uid, gid }
+    }
+}
+
+impl Drop for FsUser {
+    fn drop(&mut self) {
+        setfsuid(self.uid);
+        setfsgid(self.gid);
+    }
+}
@@ -0,0 +1,131 @@
+//! Detection of what runs on a serial console
+//!
+//! Serial consoles of guests show whatever the guest is currently at, be it the firmware, the
+//! boot loader, a booting kernel or a login prompt. With `--detect-guest`, termproxy looks for
+//! well-known lines of those in the output and tells the client whenever that changes, so the
+//! frontend can label the console, e.g. as "GRUB" or "Windows SAC".
+
+/// How much text is kept to look for signatures in, they all fit into a line.
+const MAX_TEXT: usize = 256;
+
+/// What the output looks like
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Guest {
+    Firmware,
+    Grub,
+    Linux,
+    LinuxLogin,
+    Login,
+    WindowsSac,
+}
+
+impl Guest {
+    /// The identifier of the guest in control messages.
+    pub fn id(self) -> &'static str {
+        match self {
+            Guest::Firmware => "firmware",
+            Guest::Grub => "grub",
+            Guest::Linux => "linux",
+            Guest::LinuxLogin => "linux-login",
+            Guest::Login => "login",
+            Guest::WindowsSac => "windows-sac",
+        }
+    }
+
+    /// A label for the console.
+    pub fn label(self) -> &'static str {
+        match self {
+            Guest::Firmware => "Firmware",
+            Guest::Grub => "GRUB",
+            Guest::Linux => "Linux",
+            Guest::LinuxLogin => "Linux login",
+            Guest::Login => "Login",
+            Guest::WindowsSac => "Windows SAC",
+        }
+    }
+}
+
+/// Text that identifies what the output comes from, checked in order
+const SIGNATURES: &[(&str, Guest)] = &[
+    ("SeaBIOS", Guest::Firmware),
+    ("BdsDxe:", Guest::Firmware),
+    ("GNU GRUB", Guest::Grub),
+    ("Linux version ", Guest::Linux),
+    ("SAC>", Guest::WindowsSac),
+    ("Special Administration Console", Guest::WindowsSac),
+];
+
+#[derive(Default)]
+pub struct GuestDetector {
+    /// The printable output of the current line, without escape sequences
+    text: String,
+    /// Whether an escape sequence is being skipped
+    escape: Escape,
+    /// Whether a Linux kernel or distribution showed up, which tells Linux logins apart
+    linux: bool,
+    detected: Option<Guest>,
+}
+
+#[derive(Default)]
+enum Escape {
+    #[default]
+    None,
+    /// After ESC
+    Start,
+    /// Within a control sequence, up to its final byte
+    Csi,
+}
+
+impl GuestDetector {
+    /// Accounts for output of the command, returns what it comes from if that changed.
+    pub fn scan(&mut self, data: &[u8]) -> Option<Guest> {
+        let mut changed = None;
+        for &byte in data {
+            match (&self.escape, byte) {
+                (Escape::Start, b'[') => self.escape = Escape::Csi,
+                (Escape::Start, _) => self.escape = Escape::None,
+                (Escape::Csi, 0x40..=0x7e) => self.escape = Escape::None,
+                (Escape::Csi, _) => (),
+                (Escape::None, 0x1b) => self.escape = Escape::Start,
+                (Escape::None, b'\n' | b'\r') => self.text.clear(),
+                (Escape::None, 0x20..=0x7e) if self.text.len() < MAX_TEXT => {
+                    self.text.push(byte as char);
+                    if let Some(guest) = self.check() {
+                        changed = Some(guest);
+                    }
+                }
+                _ => (),
+            }
+        }
+        changed
+    }
+
+    /// Checks the current line for a signature ending at its last character.
+    fn check(&mut self) -> Option<Guest> {
+        let mut guest = SIGNATURES
+            .iter()
+            .find(|(signature, _)| self.text.ends_with(signature))
+            .map(|(_, guest)| *guest);
+        if self.text.ends_with("GNU/Linux") {
+            self.linux = true;
+        }
+        if self.text.ends_with("login: ") {
+            guest = Some(if self.linux {
+                Guest::LinuxLogin
+            } else {
+                Guest::Login
+            });
+        }
+        match guest? {
+            Guest::Linux => self.linux = true,
+            // the firmware starts over, e.g. after a reboot into another system
+            Guest::Firmware => self.linux = false,
+            _ => (),
+        }
+        if self.detected == guest {
+            return None;
+        }
+        self.detected = guest;
+        guest
+    }
+}
@@ -0,0 +1,467 @@
+//! Authenticating clients
+//!
+//! Before a client gets to the session, it sends the connection secret if there is one, its
+//! ticket line and, with a challenge key, the answer to the challenge, through TLS and the
+//! WebSocket protocol if enabled. Clients connecting through a Unix socket with --peer-user
+//! and clients presenting a share link skip the ticket check.
+
+use std::io::{ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{bail, format_err, Result};
+use mio::event::Source;
+use mio::{Events, Interest, Poll, Token};
+use nix::unistd::{Uid, User};
+use openssl::ssl::SslAcceptor;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::auth::{authenticate, AuthResponse};
+use crate::challenge::AuthChallenge;
+use crate::cli::Options;
+use crate::client::ClientStream;
+use crate::compat::ByteBuffer;
+use crate::connection::Connection;
+use crate::session::queue_data;
+#[cfg(feature = "daemon")]
+use crate::share::ShareTokens;
+use crate::timer::Deadline;
+
+/// What a client authenticates with
+pub(crate) struct TicketLine {
+    pub username: Box<[u8]>,
+    pub ticket: Zeroizing<Box<[u8]>>,
+    /// Whether a client using the JSON handshake asked to only watch the session
+    pub observe: bool,
+    /// The features a client using the JSON handshake asked for, `None` for 'USER:TICKET'
+    pub features: Option<Vec<String>>,
+    /// The line as received
+    pub line: Zeroizing<Box<[u8]>>,
+}
+
+/// The ticket line of the JSON handshake
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonHandshake<'a> {
+    user: String,
+    // borrowed unless it has escapes, so that no copy of it is left behind
+    #[serde(borrow)]
+    ticket: std::borrow::Cow<'a, str>,
+    #[serde(default)]
+    features: Vec<String>,
+    #[serde(default)]
+    observe: bool,
+}
+
+/// The buffer a client's connection secret and ticket line are read into, apart from the buffer
+/// its input is relayed from, so the credentials don't end up there.
+///
+/// Everything it ever held is wiped once it is dropped.
+struct AuthBuffer(ByteBuffer);
+
+impl AuthBuffer {
+    /// A buffer for lines of up to `max_line` bytes and their newline.
+    pub fn new(max_line: usize) -> Self {
+        Self(ByteBuffer::with_capacity(max_line + 1))
+    }
+}
+
+impl Drop for AuthBuffer {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        let len = self.0.len();
+        self.0.consume(len);
+        self.0.get_free_mut_slice().zeroize();
+    }
+}
+
+/// Reads from the stream until a complete line is buffered and returns it without the newline,
+/// anything after it stays in the buffer. Lines longer than `max_len` are rejected.
+pub(crate) fn read_line<S: Read + Source>(
+    stream: &mut S,
+    buf: &mut ByteBuffer,
+    deadline: Deadline,
+    max_len: usize,
+) -> Result<Box<[u8]>> {
+    read_until(stream, buf, deadline, |data| {
+        data.contains(&b'\n') || data.len() > max_len
+    })?;
+    let newline_idx = match buf[..].iter().position(|&x| x == b'\n') {
+        Some(idx) if idx <= max_len => idx,
+        _ => bail!("authentication line is longer than {max_len} bytes, see --max-auth-line"),
+    };
+    let line = buf.remove_data(newline_idx);
+    buf.consume(1); // discard newline
+    Ok(line)
+}
+
+/// Reads from the stream into `buf` until `done` returns true for the buffered data.
+pub fn read_until<S: Read + Source>(
+    stream: &mut S,
+    buf: &mut ByteBuffer,
+    deadline: Deadline,
+    done: impl Fn(&[u8]) -> bool,
+) -> Result<()> {
+    let mut poll = Poll::new()?;
+    poll.registry()
+        .register(stream, Token(0), Interest::READABLE)?;
+    let result = wait_until(&mut poll, stream, buf, deadline, done);
+    // the stream gets registered with other polls afterwards
+    poll.registry().deregister(stream)?;
+    result
+}
+
+fn wait_until<S: Read>(
+    poll: &mut Poll,
+    stream: &mut S,
+    buf: &mut ByteBuffer,
+    deadline: Deadline,
+    done: impl Fn(&[u8]) -> bool,
+) -> Result<()> {
+    let mut events = Events::with_capacity(1);
+
+    loop {
+        if done(&buf[..]) {
+            return Ok(());
+        }
+
+        if buf.is_full() {
+            // the data may hold credentials, so it must not end up in any log
+            bail!(
+                "authentication data is incomplete after {} bytes",
+                buf.len()
+            );
+        }
+
+        // streams decoding a protocol may have data buffered without the socket being readable
+        match buf.read_from(stream) {
+            Ok(0) => bail!("connection closed before authentication"),
+            Ok(_) => continue,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        if deadline.is_expired() {
+            bail!("timed out");
+        }
+
+        poll.poll(&mut events, Some(deadline.remaining()))?;
+    }
+}
+
+/// Reads the ticket line from the stream, either 'USER:TICKET' or a JSON object with the user,
+/// the ticket and the features the client asks for.
+pub(crate) fn read_ticket_line<S: Read + Source>(
+    stream: &mut S,
+    buf: &mut ByteBuffer,
+    deadline: Deadline,
+    max_len: usize,
+) -> Result<TicketLine> {
+    let line = Zeroizing::new(read_line(stream, buf, deadline, max_len)?);
+
+    // user names never start with a brace
+    if line.first() == Some(&b'{') {
+        let handshake: JsonHandshake = serde_json::from_slice(&line)
+            .map_err(|err| format_err!("authentication data is invalid - {err}"))?;
+        let ticket = Zeroizing::new(handshake.ticket.as_bytes().into());
+        if let std::borrow::Cow::Owned(mut owned) = handshake.ticket {
+            owned.zeroize();
+        }
+        return Ok(TicketLine {
+            username: handshake.user.into_bytes().into(),
+            ticket,
+            observe: handshake.observe,
+            features: Some(handshake.features),
+            line,
+        });
+    }
+
+    match line.iter().position(|&b| b == b':') {
+        Some(pos) => {
+            let (username, ticket) = line.split_at(pos);
+            Ok(TicketLine {
+                username: username.into(),
+                ticket: Zeroizing::new(ticket[1..].into()),
+                observe: false,
+                features: None,
+                line: line.clone(),
+            })
+        }
+        None => bail!("authentication data is invalid"),
+    }
+}
+
+/// Compares two secrets in constant time, with respect to their contents.
+pub fn secret_matches(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// A client that passed authentication
+pub struct Authenticated {
+    pub username: Box<[u8]>,
+    pub auth: AuthResponse,
+    /// Whether the client asked to only watch the session
+    pub observer: bool,
+    /// The features the client asked for with the JSON handshake
+    pub features: Option<Vec<String>>,
+}
+
+/// The prefix of the ticket line of clients asking to only watch the session.
+const OBSERVER_PREFIX: &[u8] = b"observe";
+
+/// What the authentication of clients keeps track of across connections, which may be
+/// authenticated at the same time
+pub struct Handshake {
+    /// Set once a client presented the connection secret, which is valid only once
+    pub secret_used: AtomicBool,
+    /// The key for answers to the challenge with --auth-challenge-key-fd
+    pub challenge_key: Option<Zeroizing<[u8; 32]>>,
+    /// The tokens of share links registered with --share-links
+    #[cfg(feature = "daemon")]
+    pub shares: ShareTokens,
+}
+
+/// Reads the connection secret, if any, and the ticket line from a freshly accepted client and
+/// authenticates it. Whatever the client sent after its ticket line is left in `buf`.
+///
+/// With a challenge key, the client is challenged first and has to answer before its ticket
+/// line. The attempt is recorded in the security log with `source`.
+fn authenticate_client<S: Read + Write + Source>(
+    stream: &mut S,
+    buf: &mut ByteBuffer,
+    options: &Options,
+    listen_port: u16,
+    handshake: &Handshake,
+    source: &str,
+    peer_user: Option<Box<[u8]>>,
+) -> Result<Authenticated> {
+    let known_user = match (&options.preauthenticated, peer_user) {
+        (Some(user), _) => Some(("preauthenticated", user.as_bytes().into())),
+        (None, Some(user)) => Some(("peer", user)),
+        (None, None) => None,
+    };
+    if let Some((kind, username)) = known_user {
+        crate::seclog::record(kind, source, Some(&username), options, None);
+        return Ok(Authenticated {
+            username,
+            auth: AuthResponse::default(),
+            observer: false,
+            features: None,
+        });
+    }
+
+    let deadline = Deadline::after(Duration::new(10, 0));
+    let reject = |kind: &str, user: Option<&[u8]>, err: anyhow::Error| {
+        crate::seclog::record(kind, source, user, options, Some(&err));
+        err
+    };
+
+    let challenge = match handshake.challenge_key {
+        Some(_) => {
+            let challenge = AuthChallenge::new(listen_port)?;
+            stream
+                .write_all(challenge.line().as_bytes())
+                .map_err(|err| format_err!("error writing challenge: {err}"))
+                .map_err(crate::log::coded("challenge-failed"))?;
+            Some(challenge)
+        }
+        None => None,
+    };
+
+    // the rest of what the client sent goes to `input` once the credentials are read
+    let input = buf;
+    let mut auth_buf = AuthBuffer::new(options.max_auth_line);
+    let buf = &mut auth_buf.0;
+
+    if let Some(secret) = &options.connection_secret {
+        if handshake.secret_used.load(Ordering::SeqCst) {
+            return Err(reject(
+                "ticket",
+                None,
+                crate::log::with_code(
+                    "secret-invalid",
+                    format_err!("connection secret was already used"),
+                ),
+            ));
+        }
+        let line = read_line(stream, buf, deadline, options.max_auth_line)
+            .map(Zeroizing::new)
+            .map_err(|err| format_err!("failed reading connection secret: {err}"))
+            .map_err(crate::log::coded("secret-invalid"))
+            .map_err(|err| reject("ticket", None, err))?;
+        if !secret_matches(&line, secret.as_bytes()) {
+            return Err(reject(
+                "ticket",
+                None,
+                crate::log::with_code("secret-invalid", format_err!("invalid connection secret")),
+            ));
+        }
+        // another client may have presented it in the meantime
+        if handshake.secret_used.swap(true, Ordering::SeqCst) {
+            return Err(reject(
+                "ticket",
+                None,
+                crate::log::with_code(
+                    "secret-invalid",
+                    format_err!("connection secret was already used"),
+                ),
+            ));
+        }
+    }
+
+    let answer = match &challenge {
+        Some(_) => Some(
+            read_line(stream, buf, deadline, options.max_auth_line)
+                .map_err(|err| format_err!("failed reading challenge answer: {err}"))
+                .map_err(crate::log::coded("challenge-failed"))
+                .map_err(|err| reject("ticket", None, err))?,
+        ),
+        None => None,
+    };
+
+    let TicketLine {
+        mut username,
+        mut ticket,
+        observe,
+        features,
+        line,
+    } = read_ticket_line(stream, buf, deadline, options.max_auth_line)
+        .map_err(|err| format_err!("failed reading ticket: {err}"))
+        .map_err(crate::log::coded("ticket-invalid"))
+        .map_err(|err| reject("ticket", None, err))?;
+
+    if let (Some(challenge), Some(answer), Some(key)) =
+        (&challenge, &answer, &handshake.challenge_key)
+    {
+        challenge
+            .verify(key, answer, &line)
+            .map_err(crate::log::coded("challenge-failed"))
+            .map_err(|err| reject("ticket", Some(&username), err))?;
+    }
+
+    // a share link stands in for the user and ticket of the session's user
+    #[cfg(feature = "daemon")]
+    if options.share_links && features.is_none() && &*username == crate::share::TICKET_PREFIX {
+        let redeemed = handshake
+            .shares
+            .redeem(&ticket)
+            .map_err(crate::log::coded("share-invalid"))
+            .map_err(|err| reject("share", None, err))?;
+        crate::seclog::record("share", source, Some(&redeemed.username), options, None);
+        println!("client from {source} redeemed a share link");
+        queue_data(input, buf);
+        return Ok(Authenticated {
+            username: redeemed.username,
+            auth: AuthResponse::default(),
+            observer: redeemed.observer,
+            features: None,
+        });
+    }
+
+    // user names contain a realm, so 'observe:USER:TICKET' can't be mistaken for a ticket line
+    let prefixed = features.is_none() && &*username == OBSERVER_PREFIX;
+    let observer = observe || prefixed;
+    if prefixed {
+        let Some(pos) = ticket.iter().position(|&b| b == b':') else {
+            return Err(reject(
+                "observe",
+                None,
+                crate::log::with_code(
+                    "ticket-invalid",
+                    format_err!("failed reading ticket: authentication data is invalid"),
+                ),
+            ));
+        };
+        username = ticket[..pos].into();
+        ticket = Zeroizing::new(ticket[pos + 1..].into());
+    }
+
+    let kind = if observer { "observe" } else { "ticket" };
+    let auth = authenticate(&username, &ticket, options, listen_port)
+        .map_err(crate::log::coded("auth-failed"))
+        .map_err(|err| reject(kind, Some(&username), err))?;
+    crate::seclog::record(kind, source, Some(&username), options, None);
+    queue_data(input, buf);
+    Ok(Authenticated {
+        username,
+        auth,
+        observer,
+        features,
+    })
+}
+
+/// The realm of the users clients are authenticated as with --peer-user.
+pub const PEER_REALM: &str = "@pam";
+
+/// The local user of the process that connected to the Unix socket, as user name with
+/// [`PEER_REALM`].
+fn peer_user(stream: &Connection) -> Result<Box<[u8]>> {
+    let uid = stream
+        .peer_uid()?
+        .ok_or_else(|| format_err!("peer credentials are only known on Unix sockets"))?;
+    let user =
+        User::from_uid(Uid::from_raw(uid))?.ok_or_else(|| format_err!("no user with UID {uid}"))?;
+    Ok(format!("{}{PEER_REALM}", user.name).into_bytes().into())
+}
+
+/// Authenticates a new connection, wrapping it in TLS and speaking the WebSocket protocol on it
+/// if enabled.
+pub fn authenticate_connection(
+    stream: Connection,
+    buf: &mut ByteBuffer,
+    options: &Options,
+    tls_acceptor: Option<&SslAcceptor>,
+    listen_port: u16,
+    handshake: &Handshake,
+) -> Result<(ClientStream, Authenticated)> {
+    let source = stream.peer();
+    let peer_user = if options.peer_user {
+        Some(peer_user(&stream).map_err(crate::log::coded("peer-user-failed"))?)
+    } else {
+        None
+    };
+    #[cfg(any(feature = "tls", feature = "websocket"))]
+    let deadline = Deadline::after(Duration::new(10, 0));
+    let mut stream = match tls_acceptor {
+        #[cfg(feature = "tls")]
+        Some(acceptor) => ClientStream::Tls(Box::new(
+            crate::tls::accept(acceptor, stream, deadline)
+                .map_err(crate::log::coded("tls-failed"))?,
+        )),
+        #[cfg(not(feature = "tls"))]
+        Some(_) => bail!("termproxy was built without support for TLS"),
+        None => ClientStream::Plain(stream),
+    };
+
+    #[cfg(feature = "websocket")]
+    if options.websocket {
+        let mut stream = crate::websocket::accept(stream, deadline)
+            .map_err(crate::log::coded("websocket-failed"))?;
+        let authenticated = authenticate_client(
+            &mut stream,
+            buf,
+            options,
+            listen_port,
+            handshake,
+            &source,
+            peer_user,
+        )?;
+        return Ok((ClientStream::WebSocket(Box::new(stream)), authenticated));
+    }
+
+    let authenticated = authenticate_client(
+        &mut stream,
+        buf,
+        options,
+        listen_port,
+        handshake,
+        &source,
+        peer_user,
+    )?;
+    Ok((stream, authenticated))
+}
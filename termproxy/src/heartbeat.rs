@@ -0,0 +1,75 @@
+//! Connection quality estimation from the client's keep-alive pings
+//!
+//! Clients ping at a fixed interval (the xterm.js frontend every 30 seconds), so any variation
+//! of the time between two pings arriving is caused by the network path. Like RTP (RFC 3550,
+//! section 6.4.1) we keep a smoothed estimate of that variation, the inter-arrival jitter, and
+//! derive a coarse quality level from it, which frontends can use to e.g. reduce rendering work
+//! on bad links.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quality {
+    Good,
+    Fair,
+    Poor,
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Quality::Good => "good",
+            Quality::Fair => "fair",
+            Quality::Poor => "poor",
+        })
+    }
+}
+
+const FAIR_JITTER: Duration = Duration::from_millis(100);
+const POOR_JITTER: Duration = Duration::from_millis(500);
+
+#[derive(Default)]
+pub struct Heartbeat {
+    pings: u64,
+    last_ping: Option<Instant>,
+    last_interval: Option<Duration>,
+    /// smoothed jitter estimate in seconds
+    jitter: f64,
+    quality: Option<Quality>,
+}
+
+impl Heartbeat {
+    /// Records a ping arriving now, returns the new quality level if it changed.
+    pub fn record_ping(&mut self) -> Option<Quality> {
+        let now = Instant::now();
+        self.pings += 1;
+
+        let interval = self.last_ping.replace(now).map(|last| now - last)?;
+        // need two intervals to compare
+        let last_interval = self.last_interval.replace(interval)?;
+
+        let deviation = (interval.as_secs_f64() - last_interval.as_secs_f64()).abs();
+        self.jitter += (deviation - self.jitter) / 16.0;
+
+        let quality = match self.jitter() {
+            jitter if jitter >= POOR_JITTER => Quality::Poor,
+            jitter if jitter >= FAIR_JITTER => Quality::Fair,
+            _ => Quality::Good,
+        };
+
+        if self.quality.replace(quality) != Some(quality) {
+            Some(quality)
+        } else {
+            None
+        }
+    }
+
+    pub fn pings(&self) -> u64 {
+        self.pings
+    }
+
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter)
+    }
+}
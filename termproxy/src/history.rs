@@ -0,0 +1,137 @@
+//! Exporting the commands run in a session
+//!
+//! With `--command-history`, termproxy writes the commands entered at a shell prompt to a file,
+//! one JSON line per command with the time, the session and the user, for auditors to see what
+//! happened in a session without replaying its recording.
+//!
+//! Commands are read off the screen, as the shell echoed them: when the client starts typing,
+//! the text left of the cursor is taken as the prompt if it ends like one (`$`, `#`, `%` or
+//! `>`), and once it sends a line break, whatever follows the prompt up to the cursor is the
+//! command, right before the shell moves on to the next line. That is a heuristic: input to
+//! programs showing something prompt-like ends up in the history as well, while lines read
+//! without echo, e.g. passwords, and anything typed on the alternate screen of full screen
+//! programs never do.
+
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{format_err, Result};
+
+use crate::screen::Screen;
+
+/// Longer text left of the cursor is no prompt.
+const MAX_PROMPT_LEN: usize = 256;
+
+pub struct CommandHistory {
+    file: File,
+    screen: Screen,
+    session_id: String,
+    user: String,
+    /// Whether the client typed anything since the last line break
+    typing: bool,
+    /// The prompt the current command is typed at
+    prompt: Option<String>,
+    /// Whether a command was entered that is taken off the screen with the next line feed
+    entered: bool,
+}
+
+impl CommandHistory {
+    /// Creates the history file at `path` for a terminal of the given size.
+    pub fn create(
+        path: &Path,
+        session_id: &str,
+        user: &[u8],
+        cols: u16,
+        rows: u16,
+    ) -> Result<Self> {
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|err| format_err!("failed to create command history {path:?} - {err}"))?;
+        Ok(Self {
+            file,
+            screen: Screen::new(cols, rows),
+            session_id: session_id.to_string(),
+            user: String::from_utf8_lossy(user).into_owned(),
+            typing: false,
+            prompt: None,
+            entered: false,
+        })
+    }
+
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        self.screen.resize(cols, rows);
+    }
+
+    /// Accounts for input written to the terminal, `hidden` if the terminal doesn't echo it.
+    pub fn input(&mut self, data: &[u8], hidden: bool) {
+        for &byte in data {
+            match byte {
+                b'\r' | b'\n' => {
+                    self.entered = self.typing && self.prompt.is_some() && !hidden;
+                    self.typing = false;
+                }
+                _ if !self.typing => {
+                    self.typing = true;
+                    self.prompt = self.screen.before_cursor().filter(|text| is_prompt(text));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Accounts for output of the command, recording an entered command once it is complete.
+    pub fn output(&mut self, mut data: &[u8]) -> Result<()> {
+        while self.entered {
+            let Some(newline) = data.iter().position(|&b| b == b'\n') else {
+                break;
+            };
+            self.screen.feed(&data[..newline]);
+            data = &data[newline..];
+            self.entered = false;
+            if let Some(command) = self.entered_command() {
+                self.write(&command)?;
+            }
+        }
+        self.screen.feed(data);
+        Ok(())
+    }
+
+    /// The command following the prompt, which may span several rows.
+    fn entered_command(&self) -> Option<String> {
+        let prompt = self.prompt.as_ref()?;
+        let rows = self.screen.rows_to_cursor()?;
+        let mut text = String::new();
+        for row in rows.iter().rev() {
+            text.insert_str(0, row);
+            if let Some(command) = text.strip_prefix(prompt.as_str()) {
+                let command = command.trim();
+                return (!command.is_empty()).then(|| command.to_string());
+            }
+        }
+        None
+    }
+
+    fn write(&mut self, command: &str) -> Result<()> {
+        let entry = serde_json::json!({
+            "time": crate::status::unix_time(SystemTime::now()),
+            "session": self.session_id,
+            "user": self.user,
+            "command": command,
+        });
+        let mut line = entry.to_string();
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn is_prompt(text: &str) -> bool {
+    let text = text.trim_end();
+    text.len() <= MAX_PROMPT_LEN && text.ends_with(['$', '#', '%', '>'])
+}
@@ -0,0 +1,81 @@
+//! Constructing the command of a session with a helper program
+//!
+//! With `--cmd-from`, the command is not part of termproxy's own command line. Once the client
+//! is authenticated, the helper program runs with the user, the ACL path and the session id in
+//! its environment and prints the command line to run as JSON array of strings, e.g.
+//! `["/usr/bin/ssh", "-t", "root@node2"]`. That keeps per-user console logic out of the caller
+//! and every argument stays an argument, without any shell quoting in between.
+
+use std::ffi::OsString;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{bail, format_err, Result};
+
+use crate::timer::Deadline;
+
+/// How long the helper may take to print the command line.
+const HELPER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximal size of the printed command line.
+const MAX_OUTPUT: u64 = 64 * 1024;
+
+/// What the helper gets to know about the session
+pub struct HookContext<'a> {
+    pub user: &'a [u8],
+    pub acl_path: &'a str,
+    pub session_id: &'a str,
+}
+
+/// Runs the helper `program` and returns the command line it printed.
+pub fn command_from(program: &Path, context: &HookContext) -> Result<Vec<OsString>> {
+    let user = std::str::from_utf8(context.user)
+        .map_err(|_| format_err!("user name is not valid UTF-8"))?;
+    let mut child = Command::new(program)
+        .env("TERMPROXY_USER", user)
+        .env("TERMPROXY_PATH", context.acl_path)
+        .env("TERMPROXY_SESSION_ID", context.session_id)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| format_err!("failed to run {program:?} - {err}"))?;
+
+    // read while waiting, a long command line would not fit into the pipe
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let reader = std::thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout
+            .take(MAX_OUTPUT)
+            .read_to_end(&mut output)
+            .map(|_| output)
+    });
+
+    let deadline = Deadline::after(HELPER_TIMEOUT);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if deadline.is_expired() {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("{program:?} timed out");
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    if !status.success() {
+        bail!("{program:?} failed - {status}");
+    }
+
+    let output = reader
+        .join()
+        .map_err(|_| format_err!("failed to read output of {program:?}"))??;
+    let command: Vec<String> = serde_json::from_slice(&output)
+        .map_err(|err| format_err!("{program:?} printed no valid command line - {err}"))?;
+    match command.first() {
+        None => bail!("{program:?} printed an empty command line"),
+        Some(first) if first.is_empty() => bail!("{program:?} printed an empty program name"),
+        Some(_) => Ok(command.into_iter().map(OsString::from).collect()),
+    }
+}
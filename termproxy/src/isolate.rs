@@ -0,0 +1,68 @@
+//! Confining the command to namespaces of its own
+//!
+//! With `--isolate`, the command runs in new mount, IPC and UTS namespaces, so whatever it
+//! mounts, the shared memory and message queues it creates and the host name it sets stay
+//! within the session. Mounts don't propagate back to the host either way.
+//!
+//! On top of that, `--read-only-root` makes the root file system read-only for the command,
+//! mounts below it like `/dev` or `/tmp` stay as they are, and `--new-root` switches the root to
+//! a prepared directory with `pivot_root`, which then has to provide everything the command
+//! needs. The terminal is opened before, so the directory doesn't need a `/dev/pts`.
+
+use std::path::{Path, PathBuf};
+
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::unistd::{chdir, pivot_root};
+
+/// How to confine the command, entered between fork and exec.
+pub struct Isolation {
+    new_root: Option<PathBuf>,
+    read_only: bool,
+    /// Where the command starts below the new root, its top by default
+    cwd: Option<PathBuf>,
+}
+
+impl Isolation {
+    pub fn new(new_root: Option<&Path>, read_only: bool, cwd: Option<&Path>) -> Self {
+        Self {
+            new_root: new_root.map(Path::to_path_buf),
+            read_only,
+            cwd: cwd.map(Path::to_path_buf),
+        }
+    }
+
+    /// Moves the calling process into new namespaces and sets up its root there.
+    pub fn enter(&self) -> nix::Result<()> {
+        unshare(CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWIPC | CloneFlags::CLONE_NEWUTS)?;
+        // with shared mounts, changes of the command would show up on the host
+        mount::<str, str, str, str>(None, "/", None, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None)?;
+
+        if let Some(root) = &self.new_root {
+            // pivot_root requires the new root to be a mount point
+            mount::<Path, Path, str, str>(
+                Some(root),
+                root,
+                None,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None,
+            )?;
+            chdir(root)?;
+            // stacks the old root on top of the new one, from where it is detached right away
+            pivot_root(".", ".")?;
+            umount2(".", MntFlags::MNT_DETACH)?;
+            chdir(self.cwd.as_deref().unwrap_or(Path::new("/")))?;
+        }
+
+        if self.read_only {
+            mount::<str, str, str, str>(
+                None,
+                "/",
+                None,
+                MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY,
+                None,
+            )?;
+        }
+        Ok(())
+    }
+}
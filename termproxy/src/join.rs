@@ -0,0 +1,139 @@
+//! Clients joining a running session
+//!
+//! Clients connecting while the session runs, to share it or to reconnect to it, go through
+//! the same handshake as the first one: TLS, the WebSocket protocol, their ticket and checking
+//! it with the API daemon. A slow or malicious client may take until the handshake times out,
+//! so each handshake runs on a thread of its own. The relay loop keeps going meanwhile, and
+//! gets the client once it is attached, woken up through a [`Waker`].
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+
+use anyhow::Result;
+use mio::{Registry, Token, Waker};
+use openssl::ssl::SslAcceptor;
+use zeroize::Zeroizing;
+
+use crate::cli::Options;
+use crate::client::{join_client, Client};
+use crate::connection::Connection;
+use crate::handshake::Handshake;
+
+/// How many clients may be in the middle of their handshake at the same time, further ones are
+/// rejected right away.
+pub const MAX_PENDING: usize = 8;
+
+/// A client that finished its handshake, or failed to
+pub struct Joined {
+    /// The attached client and the user it authenticated as
+    pub result: Result<(Client, Box<[u8]>)>,
+    /// Whether the client replaces the connection of the only client, which it reconnects for
+    pub replacing: bool,
+    /// Whether the client was checked to be the session's user
+    pub user_checked: bool,
+}
+
+/// Runs the handshakes of joining clients
+pub struct Joiner {
+    options: Arc<Options>,
+    tls_acceptor: Option<SslAcceptor>,
+    listen_port: u16,
+    handshake: Arc<Handshake>,
+    encryption_key: Option<Zeroizing<[u8; 32]>>,
+    waker: Arc<Waker>,
+    sender: Sender<Joined>,
+    receiver: Receiver<Joined>,
+    pending: usize,
+}
+
+impl Joiner {
+    /// Sets up the handshakes, which wake up `registry` with `token` whenever one finished.
+    pub fn new(
+        registry: &Registry,
+        token: Token,
+        options: Arc<Options>,
+        tls_acceptor: Option<SslAcceptor>,
+        listen_port: u16,
+        handshake: Arc<Handshake>,
+        encryption_key: Option<Zeroizing<[u8; 32]>>,
+    ) -> Result<Self> {
+        let (sender, receiver) = channel();
+        Ok(Self {
+            options,
+            tls_acceptor,
+            listen_port,
+            handshake,
+            encryption_key,
+            waker: Arc::new(Waker::new(registry, token)?),
+            sender,
+            receiver,
+            pending: 0,
+        })
+    }
+
+    /// What the handshakes share with the session.
+    #[cfg(feature = "daemon")]
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    /// The waker of the relay loop, for other threads reporting back to it as well, as a poll
+    /// only has one.
+    pub fn waker(&self) -> Arc<Waker> {
+        Arc::clone(&self.waker)
+    }
+
+    /// The number of clients still in their handshake.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Starts the handshake with a client that just connected, which gets `token` once it is
+    /// attached. With `user`, the client has to authenticate as that user.
+    pub fn start(
+        &mut self,
+        stream: Connection,
+        token: Token,
+        user: Option<&[u8]>,
+        replacing: bool,
+    ) -> Result<()> {
+        let options = Arc::clone(&self.options);
+        let tls_acceptor = self.tls_acceptor.clone();
+        let listen_port = self.listen_port;
+        let handshake = Arc::clone(&self.handshake);
+        let encryption_key = self.encryption_key.clone();
+        let waker = Arc::clone(&self.waker);
+        let sender = self.sender.clone();
+        let user: Option<Box<[u8]>> = user.map(Into::into);
+        std::thread::Builder::new().spawn(move || {
+            let result = join_client(
+                stream,
+                &options,
+                tls_acceptor.as_ref(),
+                listen_port,
+                &handshake,
+                encryption_key.as_deref(),
+                token,
+                user.as_deref(),
+            );
+            let joined = Joined {
+                result,
+                replacing,
+                user_checked: user.is_some(),
+            };
+            // the session may have ended in the meantime
+            if sender.send(joined).is_ok() {
+                let _ = waker.wake();
+            }
+        })?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Takes the next client that finished its handshake, if any.
+    pub fn finished(&mut self) -> Option<Joined> {
+        let joined = self.receiver.try_recv().ok()?;
+        self.pending -= 1;
+        Some(joined)
+    }
+}
@@ -0,0 +1,166 @@
+//! Listing the sessions running on this host
+//!
+//! `list` collects the status files sessions write with `--status-dir` and prints them as a
+//! table for humans or as JSON for other tools. The JSON output is a stable interface: fields
+//! are only ever added, never renamed or removed.
+
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{format_err, Result};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use serde::Serialize;
+
+use crate::cli::{ListOptions, OutputFormat};
+use crate::status::{read_status, unix_time};
+
+/// A session as listed by `list`
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SessionInfo {
+    pub id: String,
+    /// The user the session was started for
+    pub user: String,
+    /// What runs in the terminal
+    pub command: String,
+    /// The guest of a console session, from the session's `vmid` tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vmid: Option<u32>,
+    /// `running`, `locked`, `binary-paused`, `suspended` or `frozen`, `stale` if the command is
+    /// gone
+    pub state: String,
+    pub pid: u32,
+    /// Seconds since the session started
+    pub age: u64,
+    pub bytes_from_client: u64,
+    pub bytes_to_client: u64,
+    pub clients: u64,
+}
+
+impl SessionInfo {
+    fn from_status(fields: &[(String, String)], now: u64) -> Result<Self> {
+        let field = |key: &str| {
+            fields
+                .iter()
+                .find(|(field, _)| field == key)
+                .map(|(_, value)| value.as_str())
+        };
+        let number = |key: &str| -> Result<u64> {
+            let value = field(key).ok_or_else(|| format_err!("missing field '{key}'"))?;
+            value
+                .parse()
+                .map_err(|err| format_err!("invalid field '{key}' - {err}"))
+        };
+
+        let pid = number("pid")? as u32;
+        // status files of sessions that got killed are left behind
+        let state = match kill(Pid::from_raw(pid as i32), None) {
+            Err(Errno::ESRCH) => "stale",
+            _ => field("state").unwrap_or_default(),
+        };
+        Ok(Self {
+            id: field("session").unwrap_or_default().to_string(),
+            user: field("user").unwrap_or_default().to_string(),
+            command: field("command").unwrap_or_default().to_string(),
+            vmid: field("tag.vmid").and_then(|vmid| vmid.parse().ok()),
+            state: state.to_string(),
+            pid,
+            age: now.saturating_sub(number("started")?),
+            bytes_from_client: number("bytes-from-client")?,
+            bytes_to_client: number("bytes-to-client")?,
+            // written since sessions can be shared
+            clients: number("clients").unwrap_or(1),
+        })
+    }
+}
+
+/// Reads the status files in `dir`, sorted by age.
+pub fn sessions(dir: &Path) -> Result<Vec<SessionInfo>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // no session ran yet
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(format_err!("failed to read {dir:?} - {err}")),
+    };
+
+    let now = unix_time(SystemTime::now());
+    let mut sessions = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_none_or(|extension| extension != "status")
+        {
+            continue;
+        }
+        // the session may have ended in the meantime
+        let Some(fields) = read_status(&path)? else {
+            continue;
+        };
+        match SessionInfo::from_status(&fields, now) {
+            Ok(session) => sessions.push(session),
+            Err(err) => crate::log::warn(
+                "status-invalid",
+                format_args!("ignoring status file {path:?} - {err}"),
+            ),
+        }
+    }
+    sessions.sort_by(|a, b| b.age.cmp(&a.age).then_with(|| a.id.cmp(&b.id)));
+    Ok(sessions)
+}
+
+fn print_table(sessions: &[SessionInfo]) {
+    let header = [
+        "ID", "USER", "VMID", "STATE", "AGE", "CLIENTS", "BYTES", "COMMAND",
+    ];
+    let rows: Vec<[String; 8]> = sessions
+        .iter()
+        .map(|session| {
+            [
+                session.id.clone(),
+                session.user.clone(),
+                session
+                    .vmid
+                    .map(|vmid| vmid.to_string())
+                    .unwrap_or_default(),
+                session.state.clone(),
+                format!("{}s", session.age),
+                session.clients.to_string(),
+                format!("{}/{}", session.bytes_from_client, session.bytes_to_client),
+                session.command.clone(),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, value) in widths.iter_mut().zip(row) {
+            *width = (*width).max(value.chars().count());
+        }
+    }
+
+    let print_row = |row: &[&str]| {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(value, width)| format!("{value:width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(&header);
+    for row in &rows {
+        print_row(&row.each_ref().map(String::as_str));
+    }
+}
+
+pub fn list(options: &ListOptions) -> Result<()> {
+    let sessions = sessions(&options.status_dir)?;
+    match options.output_format {
+        OutputFormat::Text => print_table(&sessions),
+        OutputFormat::Json => println!("{}", serde_json::to_string(&sessions)?),
+        OutputFormat::JsonPretty => println!("{}", serde_json::to_string_pretty(&sessions)?),
+    }
+    Ok(())
+}
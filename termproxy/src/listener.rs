@@ -0,0 +1,208 @@
+//! The socket clients connect to
+//!
+//! A TCP port, a listener passed as file descriptor or a Unix socket, in the file system or in
+//! the abstract namespace.
+
+use std::io::ErrorKind;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, format_err, Result};
+use mio::net::{TcpListener, UnixListener};
+use mio::{Events, Interest, Poll, Token};
+
+use crate::cli::{ListenerOptions, PortOrFd};
+use crate::connection::Connection;
+use crate::timer::Deadline;
+
+fn set_tcp_option(listener: &std::net::TcpListener, option: libc::c_int, value: u32) -> Result<()> {
+    let value = value as libc::c_int;
+    let res = unsafe {
+        libc::setsockopt(
+            listener.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn apply_listener_options(
+    listener: &std::net::TcpListener,
+    options: &ListenerOptions,
+) -> Result<()> {
+    if let Some(timeout) = options.defer_accept {
+        set_tcp_option(listener, libc::TCP_DEFER_ACCEPT, timeout)
+            .map_err(|err| format_err!("failed to set TCP_DEFER_ACCEPT - {err}"))?;
+    }
+    if let Some(queue_len) = options.fastopen {
+        set_tcp_option(listener, libc::TCP_FASTOPEN, queue_len)
+            .map_err(|err| format_err!("failed to enable TCP Fast Open - {err}"))?;
+    }
+    Ok(())
+}
+
+/// Binds a Unix socket at `path`, replacing a stale socket of an earlier session.
+///
+/// With `abstract_namespace`, the socket is bound in the abstract namespace instead, with
+/// `path` as its name. Such a socket goes away with the last process having it open, so there
+/// is neither a stale one to replace nor a file to remove later.
+pub(crate) fn bind_unix(path: &Path, abstract_namespace: bool) -> Result<UnixListener> {
+    if abstract_namespace {
+        let address = SocketAddr::from_abstract_name(path.as_os_str().as_bytes())?;
+        let listener = std::os::unix::net::UnixListener::bind_addr(&address)?;
+        listener.set_nonblocking(true)?;
+        return Ok(UnixListener::from_std(listener));
+    }
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => bail!("{path:?} exists and is not a socket"),
+        Err(err) if err.kind() == ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+enum ListenSocket {
+    Tcp(TcpListener),
+    /// A Unix socket and its path, which is removed with the listener, none in the abstract
+    /// namespace
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+/// The listening socket clients connect to
+pub(crate) struct Listener {
+    listener: ListenSocket,
+    poll: Poll,
+    port: u16,
+}
+
+impl Listener {
+    pub(crate) fn bind(
+        hostname: &str,
+        listen_port: &PortOrFd,
+        listener_options: &ListenerOptions,
+    ) -> Result<Self> {
+        let listener = match listen_port {
+            PortOrFd::Fd(fd) => unsafe { std::net::TcpListener::from_raw_fd(*fd) },
+            PortOrFd::Port(port) => std::net::TcpListener::bind((hostname, *port))?,
+            PortOrFd::Unix(path) => {
+                let listener = bind_unix(path, false)?;
+                return Self::with_socket(ListenSocket::Unix(listener, Some(path.clone())), 0);
+            }
+            PortOrFd::Abstract(name) => {
+                let listener = bind_unix(Path::new(name), true)?;
+                return Self::with_socket(ListenSocket::Unix(listener, None), 0);
+            }
+        };
+        apply_listener_options(&listener, listener_options)?;
+        // a listener passed as FD may be blocking, which accepting pending connections can't be
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        Self::with_socket(ListenSocket::Tcp(TcpListener::from_std(listener)), port)
+    }
+
+    fn with_socket(mut listener: ListenSocket, port: u16) -> Result<Self> {
+        let poll = Poll::new()?;
+
+        match &mut listener {
+            ListenSocket::Tcp(listener) => {
+                poll.registry()
+                    .register(listener, Token(0), Interest::READABLE)?
+            }
+            ListenSocket::Unix(listener, _) => {
+                poll.registry()
+                    .register(listener, Token(0), Interest::READABLE)?
+            }
+        }
+
+        Ok(Self {
+            listener,
+            poll,
+            port,
+        })
+    }
+
+    /// The local port of the listener, even if it was passed as FD, 0 for Unix sockets.
+    pub(crate) fn port(&self) -> u16 {
+        self.port
+    }
+
+    fn try_accept(&self) -> std::io::Result<Connection> {
+        match &self.listener {
+            ListenSocket::Tcp(listener) => listener.accept().map(|(stream, client)| {
+                println!("client connection: {client:?}");
+                Connection::Tcp(stream)
+            }),
+            ListenSocket::Unix(listener, _) => listener.accept().map(|(stream, client)| {
+                println!("client connection: {client:?}");
+                Connection::Unix(stream)
+            }),
+        }
+    }
+
+    pub(crate) fn accept(&mut self, deadline: Deadline) -> Result<Connection> {
+        let mut events = Events::with_capacity(1);
+
+        loop {
+            self.poll.poll(&mut events, Some(deadline.remaining()))?;
+            if !events.is_empty() {
+                match self.try_accept() {
+                    Ok(stream) => return Ok(stream),
+                    // the connection might have been reset in the meantime
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => (),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+
+            if deadline.is_expired() {
+                bail!("timed out");
+            }
+        }
+    }
+
+    /// Accepts a pending connection without waiting for one, `None` if there is none.
+    pub(crate) fn accept_pending(&mut self) -> Result<Option<Connection>> {
+        match self.try_accept() {
+            Ok(stream) => Ok(Some(stream)),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.listener {
+            ListenSocket::Tcp(listener) => listener.as_raw_fd(),
+            ListenSocket::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let ListenSocket::Unix(_, Some(path)) = &self.listener {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+pub(crate) fn listen_and_accept(
+    hostname: &str,
+    listen_port: &PortOrFd,
+    listener_options: &ListenerOptions,
+    deadline: Deadline,
+) -> Result<(Connection, u16)> {
+    let mut listener = Listener::bind(hostname, listen_port, listener_options)?;
+    Ok((listener.accept(deadline)?, listener.port()))
+}
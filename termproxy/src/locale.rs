@@ -0,0 +1,65 @@
+//! Falling back to a working locale
+//!
+//! `LANG` and `LC_*` are passed on to the command as termproxy got them, but on guests and nodes
+//! with a broken locale configuration they may name locales that aren't installed, which gets
+//! programs to fall back to ASCII, garbling the terminal, and perl to complain on every start.
+//! So they are checked before the command starts: `LANG` falls back to `C.UTF-8` and the `LC_*`
+//! variables are dropped, so `LANG` applies instead, and the user gets a notice about it.
+//!
+//! Locales are looked up on the system termproxy runs on, so this is skipped for commands with
+//! a `--new-root` of their own.
+
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+
+/// The locale used instead of an invalid `LANG`, available without being generated.
+const FALLBACK: &str = "C.UTF-8";
+
+/// Whether the locale `name` is installed.
+fn is_available(name: &OsStr) -> bool {
+    let Ok(name) = CString::new(name.as_bytes()) else {
+        return false;
+    };
+    let locale = unsafe { libc::newlocale(libc::LC_ALL_MASK, name.as_ptr(), std::ptr::null_mut()) };
+    if locale.is_null() {
+        return false;
+    }
+    unsafe { libc::freelocale(locale) };
+    true
+}
+
+/// Replaces or drops the locale variables in `env` naming locales that aren't installed, and
+/// returns a notice for each of them.
+pub fn fix_env(env: &mut HashMap<OsString, OsString>) -> Vec<String> {
+    let mut invalid: Vec<OsString> = env
+        .iter()
+        .filter(|(name, value)| {
+            (*name == "LANG" || name.as_bytes().starts_with(b"LC_"))
+                // empty values are the same as unset ones
+                && !value.is_empty()
+                && !is_available(value)
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    invalid.sort();
+
+    let mut notices = Vec::new();
+    for name in invalid {
+        let Some(value) = env.remove(&name) else {
+            continue;
+        };
+        let action = if name == "LANG" {
+            env.insert(name.clone(), FALLBACK.into());
+            format!("using {FALLBACK}")
+        } else {
+            "ignoring it".to_string()
+        };
+        notices.push(format!(
+            "termproxy: locale '{}' of {} is not installed, {action}",
+            value.to_string_lossy(),
+            name.to_string_lossy(),
+        ));
+    }
+    notices
+}
@@ -0,0 +1,226 @@
+//! Diagnostics on stderr
+//!
+//! By default diagnostics are plain text lines. With `--stderr-json` every line is a JSON object
+//! instead, with the level, the phase of the session the message originates from, a short
+//! machine-readable code and the message itself, so callers capturing stderr can tell failure
+//! reasons apart without parsing messages meant for humans.
+//!
+//! Fatal errors carry their code through [`coded`], everything else without one is reported as
+//! `error`.
+//!
+//! A message repeating the previous one within the deduplication window is only counted, the
+//! count is reported as `last message repeated N times` before the next different message, once
+//! the window passed, or on [`flush`].
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static PHASE: AtomicU8 = AtomicU8::new(Phase::Startup as u8);
+static SESSION_ID: OnceLock<String> = OnceLock::new();
+static TAGS: OnceLock<serde_json::Map<String, serde_json::Value>> = OnceLock::new();
+static DEDUP_WINDOW_MS: AtomicU64 = AtomicU64::new(DEFAULT_DEDUP_WINDOW.as_millis() as u64);
+static LAST: Mutex<Option<LastMessage>> = Mutex::new(None);
+
+/// How long repetitions of a message are suppressed by default.
+pub const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_secs(10);
+
+/// The previously logged message
+struct LastMessage {
+    level: Level,
+    code: String,
+    message: String,
+    logged: Instant,
+    repeated: u64,
+}
+
+#[derive(Clone, Copy)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        }
+    }
+}
+
+/// The phases of a session, in order.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum Phase {
+    Startup,
+    Accept,
+    Auth,
+    Spawn,
+    Session,
+    Shutdown,
+}
+
+impl Phase {
+    fn as_str(self) -> &'static str {
+        match self {
+            Phase::Startup => "startup",
+            Phase::Accept => "accept",
+            Phase::Auth => "auth",
+            Phase::Spawn => "spawn",
+            Phase::Session => "session",
+            Phase::Shutdown => "shutdown",
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Phase::Accept,
+            2 => Phase::Auth,
+            3 => Phase::Spawn,
+            4 => Phase::Session,
+            5 => Phase::Shutdown,
+            _ => Phase::Startup,
+        }
+    }
+}
+
+pub fn set_json(enabled: bool) {
+    JSON.store(enabled, Ordering::SeqCst);
+}
+
+pub fn set_phase(phase: Phase) {
+    PHASE.store(phase as u8, Ordering::SeqCst);
+}
+
+pub fn set_session_id(session_id: &str) {
+    let _ = SESSION_ID.set(session_id.to_string());
+}
+
+/// Sets the session's tags, which are included in JSON lines.
+pub fn set_tags(tags: &[(String, String)]) {
+    let tags = tags
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone().into()))
+        .collect();
+    let _ = TAGS.set(tags);
+}
+
+/// Sets the window in which repeated messages are suppressed, zero disables deduplication.
+pub fn set_dedup_window(window: Duration) {
+    DEDUP_WINDOW_MS.store(window.as_millis() as u64, Ordering::SeqCst);
+}
+
+pub fn log(level: Level, code: &str, message: impl Display) {
+    let message = message.to_string();
+    let window = Duration::from_millis(DEDUP_WINDOW_MS.load(Ordering::SeqCst));
+
+    let mut last = LAST.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(last) = last.as_mut() {
+        if last.code == code && last.message == message && last.logged.elapsed() < window {
+            last.repeated += 1;
+            return;
+        }
+    }
+    if let Some(last) = last.take() {
+        report_repeated(&last);
+    }
+
+    write_line(level, code, &message, None);
+    *last = Some(LastMessage {
+        level,
+        code: code.to_string(),
+        message,
+        logged: Instant::now(),
+        repeated: 0,
+    });
+}
+
+/// Reports how often the last message got suppressed, if at all.
+pub fn flush() {
+    let mut last = LAST.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(last) = last.take() {
+        report_repeated(&last);
+    }
+}
+
+fn report_repeated(last: &LastMessage) {
+    if last.repeated > 0 {
+        let message = format!("last message repeated {} times", last.repeated);
+        write_line(last.level, &last.code, &message, Some(last.repeated));
+    }
+}
+
+fn write_line(level: Level, code: &str, message: &str, repeated: Option<u64>) {
+    if JSON.load(Ordering::SeqCst) {
+        let phase = Phase::from_u8(PHASE.load(Ordering::SeqCst));
+        let mut line = serde_json::json!({
+            "level": level.as_str(),
+            "phase": phase.as_str(),
+            "code": code,
+            "session-id": SESSION_ID.get(),
+            "message": message,
+        });
+        if let Some(repeated) = repeated {
+            line["repeated"] = repeated.into();
+        }
+        if let Some(tags) = TAGS.get().filter(|tags| !tags.is_empty()) {
+            line["tags"] = tags.clone().into();
+        }
+        eprintln!("{line}");
+    } else {
+        eprintln!("{message}");
+    }
+}
+
+pub fn error(code: &str, message: impl Display) {
+    log(Level::Error, code, message);
+}
+
+pub fn warn(code: &str, message: impl Display) {
+    log(Level::Warning, code, message);
+}
+
+/// An error with a machine-readable code.
+#[derive(Debug)]
+struct CodedError {
+    code: &'static str,
+    error: anyhow::Error,
+}
+
+impl Display for CodedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+/// Attaches `code` to an error.
+pub fn with_code(code: &'static str, error: impl Into<anyhow::Error>) -> anyhow::Error {
+    CodedError {
+        code,
+        error: error.into(),
+    }
+    .into()
+}
+
+/// Attaches `code` to an error, for use with `map_err`.
+pub fn coded<E: Into<anyhow::Error>>(code: &'static str) -> impl FnOnce(E) -> anyhow::Error {
+    move |error| with_code(code, error)
+}
+
+/// Returns the code attached to `error` with [`coded`], or `error`.
+pub fn error_code(error: &anyhow::Error) -> &'static str {
+    match error.downcast_ref::<CodedError>() {
+        Some(coded) => coded.code,
+        None => "error",
+    }
+}
+
+/// Logs an error that ends termproxy.
+pub fn fatal(error: &anyhow::Error) {
+    self::error(error_code(error), error);
+}
@@ -0,0 +1,102 @@
+//! Running a user's login shell
+//!
+//! This does roughly what login(1) does once a user is authenticated: look up the user's shell
+//! and home, hand the terminal over to the user and exec the shell with a leading '-' in argv[0],
+//! so that it sources the profile like on an SSH login.
+
+use std::collections::HashMap;
+use std::ffi::{CString, OsString};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use anyhow::{format_err, Result};
+use nix::sys::stat::{fchmodat, FchmodatFlags, Mode};
+use nix::unistd::{chown, getgrouplist, getuid, setgid, setgroups, setuid, Gid, Uid, User};
+
+pub struct LoginShell {
+    user: User,
+    groups: Vec<Gid>,
+}
+
+/// What the spawned process has to switch to between fork and exec.
+#[derive(Clone)]
+pub struct Credentials {
+    groups: Vec<Gid>,
+    gid: Gid,
+    uid: Uid,
+}
+
+impl LoginShell {
+    pub fn lookup(name: &str) -> Result<Self> {
+        let user = User::from_name(name)?.ok_or_else(|| format_err!("no such user '{name}'"))?;
+        let groups = getgrouplist(&CString::new(name)?, user.gid)?;
+        Ok(Self { user, groups })
+    }
+
+    /// Builds the command running the user's shell as login shell and adapts the environment.
+    pub fn command(&self, env: &mut HashMap<OsString, OsString>) -> Command {
+        let shell = &self.user.shell;
+        let shell_name = shell
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "sh".to_string());
+
+        env.insert("HOME".into(), self.user.dir.clone().into());
+        env.insert("SHELL".into(), shell.clone().into());
+        env.insert("USER".into(), self.user.name.clone().into());
+        env.insert("LOGNAME".into(), self.user.name.clone().into());
+
+        let mut command = Command::new(shell);
+        command.arg0(format!("-{shell_name}"));
+        if self.user.dir.is_dir() {
+            command.current_dir(&self.user.dir);
+        } else {
+            command.current_dir("/");
+        }
+        command
+    }
+
+    /// Hands the terminal over to the user, like login(1) does.
+    ///
+    /// Nothing to do if we are running as the user already, `grantpt` made the terminal theirs
+    /// and changing its group requires privileges.
+    pub fn prepare_terminal(&self, terminal: &str) -> Result<()> {
+        if getuid() == self.user.uid {
+            return Ok(());
+        }
+        let tty_group = nix::unistd::Group::from_name("tty")?.map(|group| group.gid);
+        chown(terminal, Some(self.user.uid), tty_group)?;
+        let mode = Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IWGRP; // 0620
+        fchmodat(None, terminal, mode, FchmodatFlags::FollowSymlink)?;
+        Ok(())
+    }
+
+    /// The credentials to switch to, `None` if we are running as the user already.
+    pub fn credentials(&self) -> Option<Credentials> {
+        if getuid() == self.user.uid {
+            return None;
+        }
+        Some(Credentials {
+            groups: self.groups.clone(),
+            gid: self.user.gid,
+            uid: self.user.uid,
+        })
+    }
+}
+
+impl Credentials {
+    pub fn uid(&self) -> Uid {
+        self.uid
+    }
+
+    pub fn gid(&self) -> Gid {
+        self.gid
+    }
+
+    /// Switches the calling process to the user, for use in a `pre_exec` hook.
+    pub fn switch(&self) -> nix::Result<()> {
+        setgroups(&self.groups)?;
+        setgid(self.gid)?;
+        setuid(self.uid)
+    }
+}
@@ -1,421 +1,890 @@
-use std::cmp::min;
 use std::collections::HashMap;
 use std::ffi::OsString;
-use std::io::{ErrorKind, Write};
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::process::CommandExt;
-use std::process::Command;
-use std::time::{Duration, Instant};
+#[cfg(feature = "recording")]
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{bail, format_err, Result};
-use mio::net::{TcpListener, TcpStream};
 use mio::unix::SourceFd;
-use mio::{Events, Interest, Poll, Token};
+use mio::{Interest, Poll, Token};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
 
-use proxmox_io::ByteBuffer;
-use proxmox_lang::error::io_err_other;
+mod admin;
+use crate::admin::AdminSocket;
+
+mod audit;
+
+mod auth;
+use crate::auth::AuthResponse;
+
+mod backlog;
+use crate::backlog::{Backlog, BACKLOG_SIZE};
+
+mod binary;
+use crate::binary::BinaryDetector;
+
+mod challenge;
+
+mod cgroup;
+use crate::cgroup::{join_cgroup, SessionCgroup};
+
+#[cfg(feature = "daemon")]
+mod detach;
+#[cfg(feature = "daemon")]
+use crate::detach::ControlSocket;
 
 mod cli;
-use crate::cli::{Options, PortOrFd};
+use crate::cli::{Mode, Options};
+
+mod client;
+#[cfg(feature = "daemon")]
+use crate::client::ClientStream;
+use crate::client::{attach_client, offer_features, participants};
+
+mod compat;
+use crate::compat::{io_err_other, ByteBuffer};
+
+mod connection;
+
+mod crash;
+
+mod crypt;
+
+mod control;
+
+#[cfg(feature = "auth-http")]
+mod dial;
+
+mod escape;
+
+mod files;
+use crate::files::FileAccess;
+
+#[cfg(feature = "serial")]
+mod guest;
+#[cfg(feature = "serial")]
+use crate::guest::GuestDetector;
+
+mod handshake;
+use crate::handshake::{authenticate_connection, Authenticated, Handshake, PEER_REALM};
+
+mod heartbeat;
+
+mod history;
+use crate::history::CommandHistory;
+
+mod hook;
+use crate::hook::HookContext;
+
+mod isolate;
+use crate::isolate::Isolation;
+
+mod join;
+use crate::join::Joiner;
+
+mod list;
+
+mod listener;
+use crate::listener::Listener;
+
+mod locale;
+
+mod log;
+use crate::log::Phase;
+
+mod login;
+use crate::login::{Credentials, LoginShell};
+
+mod message;
+
+mod pacing;
+
+mod pam;
+use crate::pam::PamSession;
+
+mod preflight;
+
+mod prompt;
+use crate::prompt::PromptDetector;
 
 mod pty;
 use crate::pty::{make_controlling_terminal, PTY};
 
-const MSG_TYPE_DATA: u8 = 0;
-const MSG_TYPE_RESIZE: u8 = 1;
-//const MSG_TYPE_PING: u8 = 2;
-
-fn remove_number(buf: &mut ByteBuffer) -> Option<usize> {
-    loop {
-        if let Some(pos) = &buf.iter().position(|&x| x == b':') {
-            let data = buf.remove_data(*pos);
-            buf.consume(1); // the ':'
-            let len = match std::str::from_utf8(&data) {
-                Ok(len_str) => match len_str.parse() {
-                    Ok(len) => len,
-                    Err(err) => {
-                        eprintln!("error parsing number: '{err}'");
-                        break;
-                    }
-                },
-                Err(err) => {
-                    eprintln!("error decoding number: '{err}'");
-                    break;
-                }
-            };
-            return Some(len);
-        } else if buf.len() > 20 {
-            buf.consume(20);
-        } else {
-            break;
-        }
-    }
-    None
-}
-
-fn process_queue(buf: &mut ByteBuffer, pty: &mut PTY) -> Option<usize> {
-    if buf.is_empty() {
-        return None;
-    }
+#[cfg(feature = "recording")]
+mod record;
+#[cfg(feature = "recording")]
+use crate::record::{RecordGroup, Recorder};
 
-    loop {
-        if buf.len() < 2 {
-            break;
-        }
+mod relay;
+#[cfg(feature = "daemon")]
+use crate::relay::CONTROL;
+use crate::relay::{Relay, ADMIN, FIRST_CLIENT, JOINER, LISTENER, PTY, SIGNAL, STDERR};
 
-        let msgtype = buf[0] - b'0';
+mod seccomp;
 
-        if msgtype == MSG_TYPE_DATA {
-            buf.consume(2);
-            if let Some(len) = remove_number(buf) {
-                return Some(len);
-            }
-        } else if msgtype == MSG_TYPE_RESIZE {
-            buf.consume(2);
-            if let Some(cols) = remove_number(buf) {
-                if let Some(rows) = remove_number(buf) {
-                    pty.set_size(cols as u16, rows as u16).ok()?;
-                }
-            }
-        // ignore incomplete messages
-        } else {
-            buf.consume(1);
-            // ignore invalid or ping (msgtype 2)
-        }
-    }
+mod seclog;
 
-    None
-}
+#[cfg(feature = "recording")]
+mod replay;
 
-type TicketResult = Result<(Box<[u8]>, Box<[u8]>)>;
-
-/// Reads from the stream and returns the first line and the rest
-fn read_ticket_line(
-    stream: &mut TcpStream,
-    buf: &mut ByteBuffer,
-    timeout: Duration,
-) -> TicketResult {
-    let mut poll = Poll::new()?;
-    poll.registry()
-        .register(stream, Token(0), Interest::READABLE)?;
-    let mut events = Events::with_capacity(1);
-
-    let now = Instant::now();
-    let mut elapsed = Duration::new(0, 0);
-
-    loop {
-        poll.poll(&mut events, Some(timeout - elapsed))?;
-        if !events.is_empty() {
-            match buf.read_from(stream) {
-                Ok(n) => {
-                    if n == 0 {
-                        bail!("connection closed before authentication");
-                    }
-                }
-                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
-                Err(err) => return Err(err.into()),
-            }
+mod resize;
+use crate::resize::SizeArbiter;
 
-            if buf[..].contains(&b'\n') {
-                break;
-            }
+#[cfg(feature = "serial")]
+mod sac;
+#[cfg(feature = "serial")]
+use crate::sac::SacFilter;
 
-            if buf.is_full() {
-                bail!("authentication data is incomplete: {:?}", &buf[..]);
-            }
-        }
+#[cfg(feature = "recording")]
+mod serve;
 
-        elapsed = now.elapsed();
-        if elapsed > timeout {
-            bail!("timed out");
-        }
-    }
+mod session;
+use crate::session::{
+    client_host, drop_privileges, register_utmp, write_status, ControlState, Readiness,
+    SessionStats, SessionTimer, STATUS_INTERVAL,
+};
 
-    let newline_idx = &buf[..].iter().position(|&x| x == b'\n').unwrap();
+#[cfg(feature = "daemon")]
+mod share;
+#[cfg(feature = "daemon")]
+use crate::share::ShareTokens;
 
-    let line = buf.remove_data(*newline_idx);
-    buf.consume(1); // discard newline
+mod screen;
+use crate::screen::{Screen, SnapshotFile};
 
-    match line.iter().position(|&b| b == b':') {
-        Some(pos) => {
-            let (username, ticket) = line.split_at(pos);
-            Ok((username.into(), ticket[1..].into()))
-        }
-        None => bail!("authentication data is invalid"),
-    }
-}
+mod status;
+use crate::status::StatusFile;
 
-fn authenticate(username: &[u8], ticket: &[u8], options: &Options, listen_port: u16) -> Result<()> {
-    let mut post_fields: Vec<(&str, &str)> = Vec::with_capacity(5);
-    post_fields.push(("username", std::str::from_utf8(username)?));
-    post_fields.push(("password", std::str::from_utf8(ticket)?));
-    post_fields.push(("path", &options.acl_path));
-    if let Some(perm) = options.acl_permission.as_ref() {
-        post_fields.push(("privs", perm));
-    }
+mod systemd;
+use crate::systemd::scope_command;
 
-    // if the listen-port was passed indirectly via an FD, it's encoded also in the ticket so that
-    // the access system can enforce that the users actually can access that port.
-    let port_str;
-    if options.use_listen_port_as_fd() {
-        port_str = listen_port.to_string();
-        post_fields.push(("port", &port_str));
-    }
+mod terminfo;
 
-    let url = format!(
-        "http://localhost:{}/api2/json/access/ticket",
-        options.api_daemon_port
-    );
+#[cfg(feature = "tls")]
+mod tls;
 
-    match ureq::post(&url).send_form(&post_fields[..]) {
-        Ok(res) if res.status() == 200 => Ok(()),
-        Ok(res) | Err(ureq::Error::Status(_, res)) => {
-            let code = res.status();
-            bail!("invalid authentication - {code} {}", res.status_text())
-        }
-        Err(err) => bail!("authentication request failed - {err}"),
-    }
-}
+#[cfg(feature = "websocket")]
+mod websocket;
 
-fn listen_and_accept(
-    hostname: &str,
-    listen_port: &PortOrFd,
-    timeout: Duration,
-) -> Result<(TcpStream, u16)> {
-    let listener = match listen_port {
-        PortOrFd::Fd(fd) => unsafe { std::net::TcpListener::from_raw_fd(*fd) },
-        PortOrFd::Port(port) => std::net::TcpListener::bind((hostname, *port as u16))?,
-    };
-    let port = listener.local_addr()?.port();
-    let mut listener = TcpListener::from_std(listener);
-    let mut poll = Poll::new()?;
+mod watchdog;
+use crate::watchdog::LoopWatchdog;
 
-    poll.registry()
-        .register(&mut listener, Token(0), Interest::READABLE)?;
+mod timer;
+use crate::timer::{Deadline, Timers};
 
-    let mut events = Events::with_capacity(1);
+mod timing;
+use crate::timing::{StartupTiming, Step};
 
-    let now = Instant::now();
-    let mut elapsed = Duration::new(0, 0);
+mod utmp;
 
-    loop {
-        poll.poll(&mut events, Some(timeout - elapsed))?;
-        if !events.is_empty() {
-            let (stream, client) = listener.accept()?;
-            println!("client connection: {client:?}");
-            return Ok((stream, port));
-        }
+mod verify;
 
-        elapsed = now.elapsed();
-        if elapsed > timeout {
-            bail!("timed out");
-        }
+/// Picks the TERM value for the command from the configured candidates.
+fn select_term(options: &Options) -> &str {
+    if let Some(term) = terminfo::select(&options.terminfo_root, &options.term_candidates) {
+        return term;
     }
+    // checked to be non-empty when parsing the options
+    let term = options
+        .term_candidates
+        .last()
+        .map(String::as_str)
+        .unwrap_or_default();
+    log::warn(
+        "terminfo-missing",
+        format_args!(
+            "no terminfo entry found for any of {} below {:?}, using '{term}'",
+            options.term_candidates.join(", "),
+            options.terminfo_root,
+        ),
+    );
+    term
 }
 
-fn run_pty<'a>(mut full_cmd: impl Iterator<Item = &'a OsString>) -> Result<PTY> {
-    let cmd_exe = full_cmd.next().unwrap();
-    let params = full_cmd; // rest
-
+/// Runs the command in a new PTY, with `stderr` as its stderr instead of the terminal if set.
+fn run_pty(
+    options: &Options,
+    cgroup: Option<&SessionCgroup>,
+    extra_env: &[(&str, &str)],
+    stderr: Option<RawFd>,
+    host: Option<&str>,
+) -> Result<(PTY, Child, Option<Credentials>, Option<PamSession>)> {
     let (mut pty, secondary_name) = PTY::new().map_err(io_err_other)?;
 
     let mut filtered_env: HashMap<OsString, OsString> = std::env::vars_os()
-        .filter(|&(ref k, _)| {
+        .filter(|(k, _)| {
             k == "PATH"
                 || k == "USER"
                 || k == "HOME"
                 || k == "LANG"
                 || k == "LANGUAGE"
                 || k.to_string_lossy().starts_with("LC_")
+                || options.env_keep.iter().any(|name| k == name.as_str())
         })
         .collect();
-    filtered_env.insert("TERM".into(), "xterm-256color".into());
+    filtered_env.insert("TERM".into(), select_term(options).into());
+    for (key, value) in &options.env {
+        filtered_env.insert(key.into(), value.into());
+    }
+    for (key, value) in extra_env {
+        filtered_env.insert(key.into(), value.into());
+    }
 
-    let mut command = Command::new(cmd_exe);
+    let mut credentials = None;
+    let mut pam_session = None;
+    let mut command = match options.login_shell.as_deref() {
+        Some(user) => {
+            let login = LoginShell::lookup(user)?;
+            login.prepare_terminal(&secondary_name)?;
+            credentials = login.credentials();
+            let command = login.command(&mut filtered_env);
+            if let Some(service) = &options.pam_service {
+                let mut session = PamSession::open(service, user, &secondary_name, host)?;
+                if let Err(err) = show_messages(&secondary_name, &session.take_messages()) {
+                    log::warn(
+                        "pam-failed",
+                        format_args!("failed to show messages of PAM modules - {err}"),
+                    );
+                }
+                filtered_env.extend(
+                    session
+                        .env()
+                        .into_iter()
+                        .map(|(name, value)| (name.into(), value.into())),
+                );
+                pam_session = Some(session);
+            }
+            command
+        }
+        None => match &options.systemd_scope {
+            Some(scope) => scope_command(scope, &options.session_id, &options.terminal_command),
+            None => {
+                let mut command = Command::new(&options.terminal_command[0]);
+                command.args(&options.terminal_command[1..]);
+                command
+            }
+        },
+    };
+
+    // the locales below a new root are none of ours
+    let notices = match options.new_root {
+        Some(_) => Vec::new(),
+        None => locale::fix_env(&mut filtered_env),
+    };
+    for notice in &notices {
+        log::warn("locale-invalid", notice);
+    }
+    if let Err(err) = show_messages(&secondary_name, &notices) {
+        log::warn(
+            "locale-invalid",
+            format_args!("failed to show locale notices - {err}"),
+        );
+    }
+    command.env_clear().envs(&filtered_env);
+    let isolation = options.isolate.then(|| {
+        Isolation::new(
+            options.new_root.as_deref(),
+            options.read_only_root,
+            options.cwd.as_deref(),
+        )
+    });
+    if let Some(root) = &options.new_root {
+        if !root.is_dir() {
+            bail!("new root {root:?} is not a directory");
+        }
+        // the working directory is entered below the new root instead
+    } else if let Some(dir) = &options.cwd {
+        if !dir.is_dir() {
+            bail!("working directory {dir:?} is not a directory");
+        }
+        command.current_dir(dir);
+    }
 
-    command.args(params).env_clear().envs(&filtered_env);
+    let cgroup_procs_fd = cgroup.map(|cgroup| cgroup.procs_fd());
+    let signals = session_signals(options);
+    let user = credentials.clone();
 
     unsafe {
         command.pre_exec(move || {
+            if let Some(fd) = cgroup_procs_fd {
+                join_cgroup(fd)?;
+            }
             make_controlling_terminal(&secondary_name).map_err(io_err_other)?;
+            if let Some(fd) = stderr {
+                nix::unistd::dup2(fd, 2).map_err(io_err_other)?;
+            }
+            if let Some(isolation) = &isolation {
+                isolation.enter().map_err(io_err_other)?;
+            }
+            if let Some(credentials) = &credentials {
+                credentials.switch().map_err(io_err_other)?;
+            }
+            // blocked for the signalfd of the session, see run_proxy
+            signals.thread_unblock().map_err(io_err_other)?;
             Ok(())
         });
     }
 
-    command.spawn()?;
+    // set before the command starts, which may look at the size right away
+    let (cols, rows) = options.initial_size;
+    pty.set_size(cols, rows)?;
+    let child = command.spawn()?;
+    Ok((pty, child, user, pam_session))
+}
 
-    pty.set_size(80, 20)?;
-    Ok(pty)
+/// Writes messages to the terminal before the command starts, like login does with the ones of
+/// PAM modules.
+///
+/// Whatever doesn't fit into the buffer of the terminal is left out, it is read only once the
+/// session started.
+fn show_messages(terminal: &str, messages: &[String]) -> std::io::Result<()> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+    // the terminal turns the line feeds into CRLF
+    let text = messages.join("\n") + "\n";
+    let mut file = std::fs::File::options()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY)
+        .open(terminal)?;
+    file.write(text.as_bytes()).map(drop)
 }
 
-const TCP: Token = Token(0);
-const PTY: Token = Token(1);
+/// The signals the session reads from its signalfd instead of being interrupted by them.
+///
+/// SIGTERM and SIGINT end the session gracefully, SIGUSR1 detaches the clients of a detachable
+/// session.
+fn session_signals(options: &Options) -> SigSet {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGINT);
+    if options.detachable {
+        signals.add(Signal::SIGUSR1);
+    }
+    signals
+}
 
-fn do_main() -> Result<()> {
-    let options = Options::from_env()?;
+fn run_proxy(mut options: Options) -> Result<()> {
+    // printed once this returns, so it's dropped last
+    let mut timing = options.timing.then(StartupTiming::new);
+    crash::install_panic_hook(
+        &options.session_id,
+        &options.tags,
+        options.crash_dir.clone(),
+    );
+    log::set_session_id(&options.session_id);
+    log::set_tags(&options.tags);
+    log::set_dedup_window(options.log_dedup_window);
+    let cgroup = match options.cgroup_parent.as_deref() {
+        Some(parent) => Some(
+            SessionCgroup::create(parent, &options.session_id, &options.cgroup_limits)
+                .map_err(log::coded("cgroup-failed"))?,
+        ),
+        None => None,
+    };
 
-    let (mut tcp_handle, listen_port) =
-        listen_and_accept("localhost", &options.listen_port, Duration::new(10, 0))
-            .map_err(|err| format_err!("failed waiting for client: {err}"))?;
+    let challenge_key = match options.auth_challenge_key_fd {
+        Some(fd) => {
+            Some(crypt::read_key_fd(fd, "challenge key").map_err(log::coded("key-invalid"))?)
+        }
+        None => None,
+    };
+    let encryption_key = match options.encryption_key_fd {
+        Some(fd) => {
+            Some(crypt::read_key_fd(fd, "encryption key").map_err(log::coded("key-invalid"))?)
+        }
+        None => None,
+    };
+    #[cfg(feature = "tls")]
+    let tls_acceptor = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => {
+            Some(tls::acceptor(cert, key).map_err(log::coded("tls-config-invalid"))?)
+        }
+        _ => None,
+    };
+    // without TLS support, --tls-cert and --tls-key are rejected on the command line
+    #[cfg(not(feature = "tls"))]
+    let tls_acceptor: Option<openssl::ssl::SslAcceptor> = None;
 
-    let mut pty_buf = ByteBuffer::new();
-    let mut tcp_buf = ByteBuffer::new();
+    if let Some(path) = &options.security_log {
+        seclog::open(path).map_err(log::coded("security-log-failed"))?;
+    }
 
-    let (username, ticket) = read_ticket_line(&mut tcp_handle, &mut pty_buf, Duration::new(10, 0))
-        .map_err(|err| format_err!("failed reading ticket: {err}"))?;
+    log::set_phase(Phase::Accept);
+    let mut listener = Listener::bind("localhost", &options.listen_port, &options.listener_options)
+        .map_err(|err| format_err!("failed waiting for client: {err}"))
+        .map_err(log::coded("listen-failed"))?;
+    let listen_port = listener.port();
+    if let Some(timing) = &mut timing {
+        timing.mark(Step::Listen);
+    }
+    let accept_deadline = Deadline::after(Duration::new(10, 0));
+    if let Some(secret) = &options.connection_secret {
+        println!("connection secret: {secret}");
+    }
 
-    authenticate(&username, &ticket, &options, listen_port)?;
+    let mut input = ByteBuffer::new();
+    // output for all clients, before it is handed to each of them
+    let tcp_buf = ByteBuffer::new();
+    // output while no client is attached
+    let backlog = Backlog::new(BACKLOG_SIZE);
+
+    let handshake = Arc::new(Handshake {
+        secret_used: AtomicBool::new(false),
+        challenge_key,
+        #[cfg(feature = "daemon")]
+        shares: ShareTokens::default(),
+    });
+    // a session started in the background runs for its user right away, its clients attach
+    // later
+    let (
+        first,
+        Authenticated {
+            username,
+            auth,
+            observer,
+            features,
+        },
+    ) = match &options.background {
+        Some(user) => (
+            None,
+            Authenticated {
+                username: user.as_bytes().into(),
+                auth: AuthResponse::default(),
+                observer: false,
+                features: None,
+            },
+        ),
+        None => {
+            // clients that fail to authenticate don't get to use up the listener if more
+            // attempts are allowed, e.g. port scanners connecting before the actual client
+            let mut attempts = 0;
+            let (stream, authenticated) = loop {
+                attempts += 1;
+                log::set_phase(Phase::Accept);
+                let stream = listener
+                    .accept(accept_deadline)
+                    .map_err(|err| format_err!("failed waiting for client: {err}"))
+                    .map_err(log::coded("accept-failed"))?;
+                crash::set_client_fd(stream.as_raw_fd());
+                if let Some(timing) = &mut timing {
+                    timing.mark(Step::Accept);
+                }
 
-    tcp_handle.write_all(b"OK").expect("error writing response");
+                log::set_phase(Phase::Auth);
+                match authenticate_connection(
+                    stream,
+                    &mut input,
+                    &options,
+                    tls_acceptor.as_ref(),
+                    listen_port,
+                    &handshake,
+                ) {
+                    Ok(authenticated) => break authenticated,
+                    Err(err)
+                        if attempts < options.accept_attempts && !accept_deadline.is_expired() =>
+                    {
+                        log::warn(
+                            log::error_code(&err),
+                            format_args!("{err}, waiting for another client"),
+                        );
+                        input = ByteBuffer::new();
+                    }
+                    Err(err) => return Err(err),
+                }
+            };
+            if let Some(timing) = &mut timing {
+                timing.mark(Step::Auth);
+            }
+            (Some(stream), authenticated)
+        }
+    };
 
-    let mut poll = Poll::new()?;
-    let mut events = Events::with_capacity(128);
+    #[cfg(feature = "daemon")]
+    if let Some(session_id) = &options.attach {
+        let Some(ClientStream::Plain(connection)) = &first else {
+            bail!("only plain connections can be handed over");
+        };
+        detach::hand_over(&options, session_id, connection, &username, &input)
+            .map_err(log::coded("attach-failed"))?;
+        println!(
+            "handed client of {} over to session {session_id}",
+            String::from_utf8_lossy(&username),
+        );
+        return Ok(());
+    }
 
-    let mut pty = run_pty(options.terminal_command.iter())?;
+    if options.peer_user {
+        // the session runs the login shell of the local user that connected
+        let user = std::str::from_utf8(&username)?;
+        options.login_shell = user.strip_suffix(PEER_REALM).map(str::to_string);
+    }
 
-    poll.registry().register(
-        &mut tcp_handle,
-        TCP,
-        Interest::READABLE | Interest::WRITABLE,
-    )?;
+    if let Some(program) = &options.cmd_from {
+        let context = HookContext {
+            user: &username,
+            acl_path: &options.acl_path,
+            session_id: &options.session_id,
+        };
+        options.terminal_command =
+            hook::command_from(program, &context).map_err(log::coded("cmd-from-failed"))?;
+    }
+    // shared with the handshakes of joining clients
+    let options = Arc::new(options);
+
+    let mut clients = Vec::new();
+    if let Some(stream) = first {
+        let mut client = attach_client(
+            stream,
+            input,
+            &options,
+            encryption_key.as_deref(),
+            Token(FIRST_CLIENT),
+            observer,
+            None,
+        )?;
+        if let Some(requested) = &features {
+            offer_features(&mut client, &options, requested);
+        }
+        clients.push(client);
+    }
+    let next_token = FIRST_CLIENT + 1;
+
+    let poll = Poll::new()?;
+
+    let mut extra_env = Vec::new();
+    if options.export_auth_env {
+        extra_env.push(("TERMPROXY_USER", std::str::from_utf8(&username)?));
+        match (auth.ticket.as_deref(), auth.csrf_token.as_deref()) {
+            (Some(ticket), Some(csrf_token)) => {
+                extra_env.push(("TERMPROXY_TICKET", ticket));
+                extra_env.push(("TERMPROXY_CSRF_TOKEN", csrf_token));
+            }
+            _ => log::warn(
+                "auth-env-missing",
+                "authentication response did not contain a ticket and CSRF token",
+            ),
+        }
+    }
+
+    let mask = session_signals(&options);
+    mask.thread_block()?;
+    let signals = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)?;
+
+    #[cfg(feature = "daemon")]
+    let control_socket = if options.detachable || options.share_links {
+        Some(
+            ControlSocket::bind(
+                options.runtime_dir(),
+                &options.session_id,
+                options.abstract_sockets(),
+            )
+            .map_err(log::coded("control-socket-failed"))?,
+        )
+    } else {
+        None
+    };
+    let admin_socket = match &options.status_dir {
+        Some(dir) => Some(
+            AdminSocket::bind(dir, &options.session_id)
+                .map_err(log::coded("admin-socket-failed"))?,
+        ),
+        None => None,
+    };
+
+    log::set_phase(Phase::Spawn);
+    let stderr_pipe = match options.child_stderr {
+        Some(_) => {
+            let (read, write) = nix::unistd::pipe2(OFlag::O_CLOEXEC)?;
+            fcntl(read, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+            Some((read, write))
+        }
+        None => None,
+    };
+    let host = clients.first_mut().and_then(client_host);
+    let (pty, child, credentials, _pam_session) = run_pty(
+        &options,
+        cgroup.as_ref(),
+        &extra_env,
+        stderr_pipe.map(|(_, write)| write),
+        host.as_deref(),
+    )
+    .map_err(log::coded("spawn-failed"))?;
+    if let Some(timing) = &mut timing {
+        timing.mark(Step::Spawn);
+    }
+    // only the command writes to the pipe, so its end of the pipe has to be closed here
+    let child_stderr = stderr_pipe.map(|(read, write)| {
+        let _ = nix::unistd::close(write);
+        unsafe { std::fs::File::from_raw_fd(read) }
+    });
+    let _utmp = if options.utmp {
+        register_utmp(&options, &pty, &child, &username, host.as_deref())
+    } else {
+        None
+    };
+    log::set_phase(Phase::Session);
+    if clients.is_empty() {
+        println!("session started in the background, waiting for a client to attach");
+    }
+
+    for client in clients.iter_mut() {
+        client.register(poll.registry())?;
+    }
     poll.registry().register(
         &mut SourceFd(&pty.as_raw_fd()),
         PTY,
         Interest::READABLE | Interest::WRITABLE,
     )?;
+    if let Some(stderr) = &child_stderr {
+        poll.registry().register(
+            &mut SourceFd(&stderr.as_raw_fd()),
+            STDERR,
+            Interest::READABLE,
+        )?;
+    }
 
-    let mut tcp_writable = true;
-    let mut pty_writable = true;
-    let mut tcp_readable = true;
-    let mut pty_readable = true;
-    let mut remaining = 0;
-    let mut finished = false;
-
-    while !finished {
-        if tcp_readable && !pty_buf.is_full() || pty_readable && !tcp_buf.is_full() {
-            poll.poll(&mut events, Some(Duration::new(0, 0)))?;
-        } else {
-            poll.poll(&mut events, None)?;
-        }
+    // further clients join a shared session, or reconnect, through the same listener
+    let mut listener_ready = false;
+    if options.max_clients > 1 || options.outlives_clients() {
+        poll.registry().register(
+            &mut SourceFd(&listener.as_raw_fd()),
+            LISTENER,
+            Interest::READABLE,
+        )?;
+        listener_ready = true;
+    }
+    let joiner = Joiner::new(
+        poll.registry(),
+        JOINER,
+        Arc::clone(&options),
+        tls_acceptor,
+        listen_port,
+        handshake,
+        encryption_key,
+    )?;
 
-        for event in &events {
-            let writable = event.is_writable();
-            let readable = event.is_readable();
-            if event.is_read_closed() {
-                finished = true;
-            }
-            match event.token() {
-                TCP => {
-                    if readable {
-                        tcp_readable = true;
-                    }
-                    if writable {
-                        tcp_writable = true;
-                    }
-                }
-                PTY => {
-                    if readable {
-                        pty_readable = true;
-                    }
-                    if writable {
-                        pty_writable = true;
-                    }
-                }
-                _ => unreachable!(),
-            }
-        }
+    #[cfg(feature = "daemon")]
+    if let Some(socket) = &control_socket {
+        poll.registry().register(
+            &mut SourceFd(&socket.as_raw_fd()),
+            CONTROL,
+            Interest::READABLE,
+        )?;
+    }
+    poll.registry().register(
+        &mut SourceFd(&signals.as_raw_fd()),
+        SIGNAL,
+        Interest::READABLE,
+    )?;
+    if let Some(socket) = &admin_socket {
+        poll.registry().register(
+            &mut SourceFd(&socket.as_raw_fd()),
+            ADMIN,
+            Interest::READABLE,
+        )?;
+    }
+    let control_state = ControlState::default();
+    let stats = SessionStats::new();
 
-        while tcp_readable && !pty_buf.is_full() {
-            let bytes = match pty_buf.read_from(&mut tcp_handle) {
-                Ok(bytes) => bytes,
-                Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                    tcp_readable = false;
-                    break;
-                }
-                Err(err) => {
-                    if !finished {
-                        return Err(format_err!("error reading from tcp: {err}"));
-                    }
-                    break;
-                }
-            };
-            if bytes == 0 {
-                finished = true;
-                break;
-            }
+    let mut timers = Timers::new();
+    if let Some(timeout) = options.first_output_timeout {
+        timers.set(SessionTimer::FirstOutput, timeout);
+    }
+    if let Some(lock_after) = options.lock_after {
+        timers.set(SessionTimer::Lock, lock_after);
+    }
+    if let Some(timeout) = options.idle_timeout {
+        timers.set(SessionTimer::Idle, timeout);
+    }
+    if let Some(interval) = options.keepalive {
+        timers.set(SessionTimer::Keepalive, interval);
+    }
+    let snapshot = match (&options.status_dir, options.snapshot_interval) {
+        (Some(dir), Some(interval)) => {
+            timers.set(SessionTimer::Snapshot, interval);
+            Some(SnapshotFile::new(dir, &options.session_id))
         }
-
-        while pty_readable && !tcp_buf.is_full() {
-            let bytes = match tcp_buf.read_from(&mut pty) {
-                Ok(bytes) => bytes,
-                Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                    pty_readable = false;
-                    break;
-                }
-                Err(err) => {
-                    if !finished {
-                        return Err(format_err!("error reading from pty: {err}"));
-                    }
-                    break;
-                }
-            };
-            if bytes == 0 {
-                finished = true;
-                break;
+        _ => None,
+    };
+    let screen = (snapshot.is_some() || options.join_context.is_some() || options.semantic_lines)
+        .then(|| {
+            let screen = Screen::new(options.initial_size.0, options.initial_size.1)
+                .with_scrollback(options.join_context.unwrap_or(0));
+            if options.semantic_lines {
+                screen.with_line_log()
+            } else {
+                screen
             }
-        }
-
-        while !tcp_buf.is_empty() && tcp_writable {
-            let bytes = match tcp_handle.write(&tcp_buf[..]) {
-                Ok(bytes) => bytes,
-                Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                    tcp_writable = false;
-                    break;
-                }
-                Err(err) => {
-                    if !finished {
-                        return Err(format_err!("error writing to tcp : {err}"));
-                    }
-                    break;
-                }
+        });
+    #[cfg(feature = "recording")]
+    let recorder = match &options.record {
+        Some(path) => {
+            let (cols, rows) = options.initial_size;
+            let group = match &options.record_group {
+                Some(id) => Some(RecordGroup::join(
+                    path.parent().unwrap_or(Path::new(".")),
+                    id,
+                )?),
+                None => None,
             };
-            tcp_buf.consume(bytes);
+            Some(Recorder::create(
+                path,
+                options.record_format,
+                cols,
+                rows,
+                group.as_ref(),
+            )?)
+        }
+        None => None,
+    };
+    let history = match &options.command_history {
+        Some(path) => {
+            let (cols, rows) = options.initial_size;
+            Some(CommandHistory::create(
+                path,
+                &options.session_id,
+                &username,
+                cols,
+                rows,
+            )?)
         }
+        None => None,
+    };
+    let file_access = match &options.files_root {
+        Some(root) => Some(
+            FileAccess::new(root, credentials.as_ref()).map_err(log::coded("files-root-failed"))?,
+        ),
+        None => None,
+    };
 
-        while !pty_buf.is_empty() && pty_writable {
-            if remaining == 0 {
-                remaining = match process_queue(&mut pty_buf, &mut pty) {
-                    Some(val) => val,
-                    None => break,
-                };
-            }
-            let len = min(remaining, pty_buf.len());
-            let bytes = match pty.write(&pty_buf[..len]) {
-                Ok(bytes) => bytes,
-                Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                    pty_writable = false;
-                    break;
-                }
-                Err(err) => {
-                    if !finished {
-                        return Err(format_err!("error writing to pty : {err}"));
-                    }
-                    break;
-                }
-            };
-            remaining -= bytes;
-            pty_buf.consume(bytes);
+    let status = match &options.status_dir {
+        Some(dir) => {
+            let status = StatusFile::new(dir, &options.session_id)?;
+            write_status(
+                &status,
+                &options,
+                &child,
+                &stats,
+                &control_state,
+                &username,
+                participants(&clients),
+            );
+            timers.set(SessionTimer::Status, STATUS_INTERVAL);
+            Some(status)
         }
+        None => None,
+    };
+
+    if let Some(user) = &options.drop_privileges {
+        drop_privileges(user).map_err(log::coded("drop-privileges-failed"))?;
+    }
+
+    if options.seccomp {
+        // the command is a session leader, so its PID is also its process group ID
+        let needs = seccomp::Needs::new(&options, child.id() as i32);
+        seccomp::install(&needs).map_err(log::coded("seccomp-failed"))?;
     }
 
-    Ok(())
+    let mut relay = Relay {
+        username,
+        listen_port,
+        poll,
+        listener,
+        listener_ready,
+        joiner,
+        #[cfg(feature = "daemon")]
+        control_ready: control_socket.is_some(),
+        #[cfg(not(feature = "daemon"))]
+        control_ready: false,
+        #[cfg(feature = "daemon")]
+        control_socket,
+        admin_ready: admin_socket.is_some(),
+        admin_socket,
+        signals,
+        signal_ready: true,
+        pty,
+        // whatever arrived before the registration doesn't necessarily trigger an event
+        pty_ready: Readiness::ready(),
+        child,
+        stderr_ready: Readiness {
+            readable: child_stderr.is_some(),
+            writable: false,
+        },
+        child_stderr,
+        clients,
+        next_token,
+        tcp_buf,
+        backlog,
+        finished: false,
+        client_closed: false,
+        ended_by: None,
+        reconnect_expired: false,
+        control_state,
+        stats,
+        timers,
+        binary_detector: options.detect_binary.then(BinaryDetector::default),
+        loop_watchdog: options.loop_watchdog.map(LoopWatchdog::new),
+        prompt_detector: options
+            .secret_provider
+            .is_some()
+            .then(PromptDetector::default),
+        #[cfg(feature = "serial")]
+        guest_detector: options.detect_guest.then(GuestDetector::default),
+        #[cfg(feature = "serial")]
+        sac_filter: options.sac.then(SacFilter::default),
+        screen,
+        snapshot,
+        #[cfg(feature = "recording")]
+        recorder,
+        history,
+        file_access,
+        status,
+        sizes: SizeArbiter::new(options.resize_policy.unwrap_or_default()),
+        sysrq_break: None,
+        options,
+    };
+    relay.run(&mut timing)?;
+    relay.shut_down()
+}
+
+fn do_main() -> Result<()> {
+    match Mode::from_env().map_err(log::coded("invalid-arguments"))? {
+        Mode::Proxy(options) => run_proxy(*options),
+        Mode::VerifyClient(listen_port) => verify::verify_client(&listen_port),
+        Mode::Preflight(options) => preflight::preflight(&options),
+        Mode::List(options) => list::list(&options),
+        #[cfg(feature = "recording")]
+        Mode::Replay(options) => replay::replay(&options),
+        #[cfg(feature = "recording")]
+        Mode::ServeRecordings(options) => serve::serve_recordings(&options),
+        Mode::Observe(options) => admin::observe(&options),
+        #[cfg(feature = "daemon")]
+        Mode::Share(options) => share::share(&options),
+        // refused while parsing the command line, see cli::require_feature
+        #[cfg(not(all(feature = "recording", feature = "daemon")))]
+        _ => unreachable!("command of a feature termproxy was built without"),
+    }
 }
 
 fn main() {
     std::process::exit(match do_main() {
-        Ok(_) => 0,
+        Ok(_) => {
+            log::flush();
+            0
+        }
         Err(err) => {
-            eprintln!("{err}");
+            log::fatal(&err);
+            log::flush();
             1
         }
     });
@@ -0,0 +1,302 @@
+//! The messages of the client
+//!
+//! Clients send their input, resizes, pings, control commands and flow control as messages
+//! of the form 'TYPE:LENGTH:PAYLOAD', see [`process_queue`], which takes them off the input
+//! buffer of the client as far as they arrived.
+
+use anyhow::{format_err, Result};
+
+use crate::compat::ByteBuffer;
+use crate::control::{base64_decode, ControlCommand, MAX_CONTROL_LEN};
+
+const MSG_TYPE_DATA: u8 = 0;
+const MSG_TYPE_RESIZE: u8 = 1;
+const MSG_TYPE_PING: u8 = 2;
+const MSG_TYPE_CONTROL: u8 = 3;
+const MSG_TYPE_BASE64: u8 = 4;
+const MSG_TYPE_PAUSE: u8 = 5;
+const MSG_TYPE_RESUME: u8 = 6;
+
+/// The maximal length of the payload of a base64 data message, it has to fit into the 4 KiB
+/// input buffer of a client as a whole, along with its header.
+pub const MAX_BASE64_LEN: usize = 4064;
+
+/// Messages from the client that need to be handled by the relay loop
+pub enum Message {
+    /// The next LENGTH bytes of input are to be written to the terminal
+    Data(usize),
+    /// The client's terminal changed its size
+    Resize {
+        cols: u16,
+        rows: u16,
+    },
+    Ping,
+    Control(ControlCommand),
+    /// The next LENGTH bytes of input belong to a message too long to be handled and are dropped
+    Discard(usize),
+    /// The client can't keep up with the output and asks to hold it back, or is ready again
+    FlowControl {
+        paused: bool,
+    },
+}
+
+fn remove_number(buf: &mut ByteBuffer) -> Option<usize> {
+    loop {
+        if let Some(pos) = &buf.iter().position(|&x| x == b':') {
+            let data = buf.remove_data(*pos);
+            buf.consume(1); // the ':'
+            let len = match std::str::from_utf8(&data) {
+                Ok(len_str) => match len_str.parse() {
+                    Ok(len) => len,
+                    Err(err) => {
+                        crate::log::warn(
+                            "invalid-message",
+                            format_args!("error parsing number: '{err}'"),
+                        );
+                        break;
+                    }
+                },
+                Err(err) => {
+                    crate::log::warn(
+                        "invalid-message",
+                        format_args!("error decoding number: '{err}'"),
+                    );
+                    break;
+                }
+            };
+            return Some(len);
+        } else if buf.len() > 20 {
+            buf.consume(20);
+        } else {
+            break;
+        }
+    }
+    None
+}
+
+/// Parses the header of a `TYPE:LENGTH:PAYLOAD` message, and returns where the payload starts
+/// and its length.
+///
+/// Returns `None` if the header is not complete yet.
+fn message_header(buf: &ByteBuffer) -> Option<Result<(usize, usize)>> {
+    let header = &buf[2..];
+    let colon = match header.iter().position(|&x| x == b':') {
+        Some(colon) => colon,
+        None if header.len() > 20 => return Some(Err(format_err!("missing length"))),
+        None => return None,
+    };
+
+    match std::str::from_utf8(&header[..colon]).map(str::parse) {
+        Ok(Ok(len)) => Some(Ok((2 + colon + 1, len))),
+        _ => Some(Err(format_err!("invalid length"))),
+    }
+}
+
+/// Parses the header of a complete `TYPE:LENGTH:PAYLOAD` message with a payload of at most
+/// `max_len` bytes, and returns where the payload starts and its length.
+///
+/// Returns `None` if the message is not complete yet.
+fn complete_message(buf: &ByteBuffer, max_len: usize) -> Option<Result<(usize, usize)>> {
+    let (start, len) = match message_header(buf)? {
+        Ok((_, len)) if len > max_len => return Some(Err(format_err!("invalid length"))),
+        Ok(payload) => payload,
+        Err(err) => return Some(Err(err)),
+    };
+
+    if buf.len() < start + len {
+        return None;
+    }
+    Some(Ok((start, len)))
+}
+
+/// Takes the payload of a complete `3:LENGTH:PAYLOAD` control message from the buffer.
+///
+/// Returns `None` if the message is not complete yet.
+fn take_control_message(buf: &mut ByteBuffer) -> Option<Result<Box<[u8]>>> {
+    let (start, len) = match complete_message(buf, MAX_CONTROL_LEN)? {
+        Ok(payload) => payload,
+        Err(err) => return Some(Err(err)),
+    };
+    buf.consume(start);
+    Some(Ok(buf.remove_data(len)))
+}
+
+/// Decodes a complete `4:LENGTH:BASE64` data message in place, so that the buffer starts with
+/// the decoded data, and returns its length.
+///
+/// That way the data takes the same path as the data of a normal message. Returns `None` if the
+/// message is not complete yet. A message with invalid base64 is dropped as a whole, an invalid
+/// header is skipped like any other garbage.
+fn decode_base64_message(buf: &mut ByteBuffer) -> Option<Result<usize>> {
+    let (start, len) = match complete_message(buf, MAX_BASE64_LEN)? {
+        Ok(payload) => payload,
+        Err(err) => {
+            buf.consume(1);
+            return Some(Err(err));
+        }
+    };
+    let end = start + len;
+    let decoded = match base64_decode(&buf[start..end]) {
+        Ok(decoded) => decoded,
+        Err(err) => {
+            buf.consume(end);
+            return Some(Err(err));
+        }
+    };
+    // the decoded data is shorter than the message, so it fits at its end
+    buf[end - decoded.len()..end].copy_from_slice(&decoded);
+    buf.consume(end - decoded.len());
+    Some(Ok(decoded.len()))
+}
+
+/// Whether the `fields` numbers following the message type are buffered completely, or are
+/// too long to be valid anyway.
+fn header_complete(buf: &ByteBuffer, fields: usize) -> bool {
+    let header = &buf[2..];
+    header.iter().filter(|&&x| x == b':').count() >= fields || header.len() > 20 * fields
+}
+
+pub fn process_queue(buf: &mut ByteBuffer) -> Option<Message> {
+    if buf.is_empty() {
+        return None;
+    }
+
+    while let Some(&first) = buf.first() {
+        let msgtype = first.wrapping_sub(b'0');
+
+        // the messages without any header
+        if msgtype == MSG_TYPE_PING {
+            buf.consume(1);
+            return Some(Message::Ping);
+        }
+        if msgtype == MSG_TYPE_PAUSE || msgtype == MSG_TYPE_RESUME {
+            buf.consume(1);
+            return Some(Message::FlowControl {
+                paused: msgtype == MSG_TYPE_PAUSE,
+            });
+        }
+
+        if buf.len() < 2 {
+            break;
+        }
+
+        if msgtype == MSG_TYPE_DATA {
+            // the rest of the header may still be on its way
+            if !header_complete(buf, 1) {
+                break;
+            }
+            buf.consume(2);
+            if let Some(len) = remove_number(buf) {
+                return Some(Message::Data(len));
+            }
+        } else if msgtype == MSG_TYPE_RESIZE {
+            if !header_complete(buf, 2) {
+                break;
+            }
+            buf.consume(2);
+            if let Some(cols) = remove_number(buf) {
+                if let Some(rows) = remove_number(buf) {
+                    return Some(Message::Resize {
+                        cols: cols as u16,
+                        rows: rows as u16,
+                    });
+                }
+            }
+        // ignore incomplete messages
+        } else if msgtype == MSG_TYPE_BASE64 {
+            // it would never fit into the buffer, so it's dropped while it arrives
+            if let Some(Ok((start, len))) = message_header(buf) {
+                if len > MAX_BASE64_LEN {
+                    crate::log::warn(
+                        "invalid-base64",
+                        format_args!(
+                            "ignoring base64 data message of {len} bytes, longer than \
+                             {MAX_BASE64_LEN}"
+                        ),
+                    );
+                    buf.consume(start);
+                    return Some(Message::Discard(len));
+                }
+            }
+            match decode_base64_message(buf) {
+                Some(Ok(len)) => return Some(Message::Data(len)),
+                Some(Err(err)) => {
+                    crate::log::warn(
+                        "invalid-base64",
+                        format_args!("ignoring base64 data message - {err}"),
+                    );
+                }
+                None => break, // wait for the rest of the message
+            }
+        } else if msgtype == MSG_TYPE_CONTROL {
+            match take_control_message(buf) {
+                Some(Ok(payload)) => match ControlCommand::parse(&payload) {
+                    Ok(command) => return Some(Message::Control(command)),
+                    Err(err) => crate::log::warn(
+                        "invalid-control",
+                        format_args!("ignoring control message - {err}"),
+                    ),
+                },
+                Some(Err(err)) => {
+                    crate::log::warn(
+                        "invalid-control",
+                        format_args!("invalid control message - {err}"),
+                    );
+                    buf.consume(1);
+                }
+                None => break, // wait for the rest of the message
+            }
+        } else {
+            buf.consume(1);
+            // ignore invalid
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(data: &[u8]) -> ByteBuffer {
+        let mut buf = ByteBuffer::new();
+        buf.read_from(&mut &data[..]).unwrap();
+        buf
+    }
+
+    #[test]
+    fn completes_messages() {
+        let complete = |data: &[u8], max_len| {
+            complete_message(&buffer(data), max_len)
+                .map(|result| result.map_err(|err| err.to_string()))
+        };
+        // incomplete headers and payloads wait for more data
+        assert_eq!(complete(b"3:", 8), None);
+        assert_eq!(complete(b"3:5", 8), None);
+        assert_eq!(complete(b"3:5:abcd", 8), None);
+        assert_eq!(complete(b"3:5:abcde", 8), Some(Ok((4, 5))));
+        assert_eq!(complete(b"3:5:abcdefgh", 8), Some(Ok((4, 5))));
+        assert_eq!(complete(b"3:0:", 8), Some(Ok((4, 0))));
+
+        // the payload may be exactly as long as allowed, and is rejected before it arrived
+        assert_eq!(complete(b"3:8:abcdefgh", 8), Some(Ok((4, 8))));
+        assert_eq!(
+            complete(b"3:9:", 8),
+            Some(Err("invalid length".to_string()))
+        );
+
+        // up to 20 bytes without a colon may still become a length
+        assert_eq!(complete(&[b"3:".as_slice(), &[b'0'; 20]].concat(), 8), None);
+        assert_eq!(
+            complete(&[b"3:".as_slice(), &[b'0'; 21]].concat(), 8),
+            Some(Err("missing length".to_string()))
+        );
+        for invalid in [&b"3::"[..], b"3:-1:", b"3:1x:a", b"3:\xff:"] {
+            assert_eq!(
+                complete(invalid, 8),
+                Some(Err("invalid length".to_string()))
+            );
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Pacing of the relay loop
+//!
+//! While the relay loop has work left that it can do right away, e.g. output a client can take,
+//! it polls for new events without waiting. With `--poll-strategy`, it can wait a little instead,
+//! trading latency for fewer wakeups: `immediate` never waits (the default), `small-sleep`
+//! always waits up to a millisecond and `adaptive` only once the loop stayed busy for a while.
+//!
+//! Independent of the strategy, a guard watches for the loop spinning: polling without waiting
+//! over and over while neither an event comes in nor any data moves. That is a bug rather than
+//! work, so once it went on for [`SPIN_LIMIT`], the loop backs off to a poll every
+//! [`BACKOFF`] until it gets something done again, and a warning is logged.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+/// How long a small sleep is.
+const SMALL_SLEEP: Duration = Duration::from_millis(1);
+
+/// How many iterations in a row the adaptive strategy polls without waiting.
+const ADAPTIVE_SPINS: u32 = 64;
+
+/// How long the loop may spin without getting anything done before the guard backs off.
+pub const SPIN_LIMIT: Duration = Duration::from_secs(1);
+
+/// How long the loop waits per iteration while backing off.
+pub const BACKOFF: Duration = Duration::from_millis(10);
+
+/// How the relay loop polls while it has work left
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PollStrategy {
+    Immediate,
+    SmallSleep,
+    Adaptive,
+}
+
+impl std::str::FromStr for PollStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "immediate" => Ok(Self::Immediate),
+            "small-sleep" => Ok(Self::SmallSleep),
+            "adaptive" => Ok(Self::Adaptive),
+            _ => bail!(
+                "unknown poll strategy '{value}', expected immediate, small-sleep or adaptive"
+            ),
+        }
+    }
+}
+
+pub struct LoopPacer {
+    strategy: PollStrategy,
+    /// Iterations in a row that had work left
+    busy: u32,
+    /// Since when the loop polls without getting anything done
+    spinning_since: Option<Instant>,
+    backing_off: bool,
+    /// The progress counter at the previous iteration
+    progress: u64,
+}
+
+impl LoopPacer {
+    pub fn new(strategy: PollStrategy) -> Self {
+        Self {
+            strategy,
+            busy: 0,
+            spinning_since: None,
+            backing_off: false,
+            progress: 0,
+        }
+    }
+
+    /// Returns the timeout of the next poll, `work_left` if the loop has work it can do right
+    /// away, and whether the guard just started to back off.
+    ///
+    /// `events` tells whether the previous poll returned any, `progress` is a counter of the
+    /// data moved so far, any change counts as progress.
+    pub fn next_timeout(
+        &mut self,
+        work_left: bool,
+        events: bool,
+        progress: u64,
+        next_timer: Option<Duration>,
+    ) -> (Option<Duration>, bool) {
+        let moved = std::mem::replace(&mut self.progress, progress) != progress;
+        if !work_left {
+            self.busy = 0;
+            self.spinning_since = None;
+            self.backing_off = false;
+            return (next_timer, false);
+        }
+
+        self.busy = self.busy.saturating_add(1);
+        let mut started_backoff = false;
+        if events || moved {
+            self.spinning_since = None;
+            self.backing_off = false;
+        } else {
+            let since = *self.spinning_since.get_or_insert_with(Instant::now);
+            if !self.backing_off && since.elapsed() >= SPIN_LIMIT {
+                self.backing_off = true;
+                started_backoff = true;
+            }
+        }
+
+        let timeout = if self.backing_off {
+            BACKOFF
+        } else {
+            match self.strategy {
+                PollStrategy::Immediate => Duration::ZERO,
+                PollStrategy::SmallSleep => SMALL_SLEEP,
+                PollStrategy::Adaptive if self.busy <= ADAPTIVE_SPINS => Duration::ZERO,
+                PollStrategy::Adaptive => SMALL_SLEEP,
+            }
+        };
+        let timeout = next_timer.map_or(timeout, |next| next.min(timeout));
+        (Some(timeout), started_backoff)
+    }
+}
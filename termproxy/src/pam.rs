@@ -0,0 +1,222 @@
+//! PAM sessions around the login shell
+//!
+//! With `--pam-service`, a PAM session of the given service is opened for the user of
+//! `--login-shell` before the shell is spawned and closed again once the session ends, the way
+//! sshd and login do. So the session modules of the service apply: limits of pam_limits, the
+//! keyring of pam_keyinit, the environment of pam_env, or the logind session of pam_systemd.
+//!
+//! The session is opened by termproxy itself, so the limits and the keyring are termproxy's and
+//! inherited by the shell, and pam_systemd moves termproxy into the scope of the user's session.
+//! There is nobody to talk to, so modules asking for input fail; the user was authenticated
+//! with the ticket before already. What modules like pam_motd or pam_lastlog have to say is
+//! shown on the terminal, the way login does.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+
+use anyhow::{bail, Result};
+
+#[allow(non_camel_case_types)]
+type pam_handle_t = c_void;
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+#[repr(C)]
+struct PamConv {
+    conv: extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int,
+    appdata_ptr: *mut c_void,
+}
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_CONV_ERR: c_int = 19;
+const PAM_TTY: c_int = 3;
+const PAM_RHOST: c_int = 4;
+const PAM_ESTABLISH_CRED: c_int = 0x2;
+const PAM_DELETE_CRED: c_int = 0x4;
+const PAM_ERROR_MSG: c_int = 3;
+const PAM_TEXT_INFO: c_int = 4;
+
+#[link(name = "pam")]
+extern "C" {
+    fn pam_start(
+        service_name: *const c_char,
+        user: *const c_char,
+        pam_conversation: *const PamConv,
+        pamh: *mut *mut pam_handle_t,
+    ) -> c_int;
+    fn pam_end(pamh: *mut pam_handle_t, pam_status: c_int) -> c_int;
+    fn pam_set_item(pamh: *mut pam_handle_t, item_type: c_int, item: *const c_void) -> c_int;
+    fn pam_setcred(pamh: *mut pam_handle_t, flags: c_int) -> c_int;
+    fn pam_open_session(pamh: *mut pam_handle_t, flags: c_int) -> c_int;
+    fn pam_close_session(pamh: *mut pam_handle_t, flags: c_int) -> c_int;
+    fn pam_getenvlist(pamh: *mut pam_handle_t) -> *mut *mut c_char;
+    fn pam_strerror(pamh: *mut pam_handle_t, errnum: c_int) -> *const c_char;
+}
+
+/// Collects what the modules have to say in the `Vec<String>` behind `appdata` and refuses to
+/// answer prompts.
+extern "C" fn conversation(
+    count: c_int,
+    messages: *mut *const PamMessage,
+    responses: *mut *mut PamResponse,
+    appdata: *mut c_void,
+) -> c_int {
+    let collected = unsafe { &mut *(appdata as *mut Vec<String>) };
+    let count = count.max(0) as usize;
+    for i in 0..count {
+        let message = unsafe { &**messages.add(i) };
+        if message.msg_style != PAM_TEXT_INFO && message.msg_style != PAM_ERROR_MSG {
+            return PAM_CONV_ERR;
+        }
+    }
+    for i in 0..count {
+        let message = unsafe { &**messages.add(i) };
+        if !message.msg.is_null() {
+            let text = unsafe { CStr::from_ptr(message.msg) }.to_string_lossy();
+            collected.push(text.into_owned());
+        }
+    }
+    // PAM frees the responses, so they have to come from malloc, empty ones for messages
+    let reply = unsafe { libc::calloc(count.max(1), std::mem::size_of::<PamResponse>()) };
+    if reply.is_null() {
+        return PAM_CONV_ERR;
+    }
+    unsafe { *responses = reply as *mut PamResponse };
+    PAM_SUCCESS
+}
+
+/// An open PAM session, closed once dropped
+pub struct PamSession {
+    handle: *mut pam_handle_t,
+    // PAM keeps pointers to both until the end
+    _conversation: Box<PamConv>,
+    #[allow(clippy::box_collection)]
+    messages: Box<Vec<String>>,
+    /// Whether credentials were established and have to be deleted again
+    credentials: bool,
+    opened: bool,
+}
+
+impl PamSession {
+    /// Opens a session of `service` for `user` on the terminal `tty`, logged in from `host`.
+    pub fn open(service: &str, user: &str, tty: &str, host: Option<&str>) -> Result<Self> {
+        let service_c = CString::new(service)?;
+        let user_c = CString::new(user)?;
+        let tty_c = CString::new(tty)?;
+        let mut messages = Box::new(Vec::new());
+        let conversation = Box::new(PamConv {
+            conv: conversation,
+            appdata_ptr: &mut *messages as *mut Vec<String> as *mut c_void,
+        });
+        let mut handle = std::ptr::null_mut();
+        let rc = unsafe {
+            pam_start(
+                service_c.as_ptr(),
+                user_c.as_ptr(),
+                &*conversation,
+                &mut handle,
+            )
+        };
+        if rc != PAM_SUCCESS {
+            bail!("failed to start PAM service '{service}' for user '{user}' - error {rc}");
+        }
+        let mut session = Self {
+            handle,
+            _conversation: conversation,
+            messages,
+            credentials: false,
+            opened: false,
+        };
+
+        session.check("set the terminal", unsafe {
+            pam_set_item(handle, PAM_TTY, tty_c.as_ptr() as *const c_void)
+        })?;
+        if let Some(host) = host {
+            let host_c = CString::new(host)?;
+            session.check("set the remote host", unsafe {
+                pam_set_item(handle, PAM_RHOST, host_c.as_ptr() as *const c_void)
+            })?;
+        }
+        session.check("establish credentials", unsafe {
+            pam_setcred(handle, PAM_ESTABLISH_CRED)
+        })?;
+        session.credentials = true;
+        session.check("open session", unsafe { pam_open_session(handle, 0) })?;
+        session.opened = true;
+        Ok(session)
+    }
+
+    /// Takes the messages of the modules collected so far.
+    pub fn take_messages(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.messages)
+    }
+
+    /// The environment the modules set up for the session, e.g. with pam_env.
+    pub fn env(&self) -> Vec<(String, String)> {
+        let list = unsafe { pam_getenvlist(self.handle) };
+        if list.is_null() {
+            return Vec::new();
+        }
+        let mut env = Vec::new();
+        let mut entry = list;
+        unsafe {
+            while !(*entry).is_null() {
+                let pair = CStr::from_ptr(*entry).to_string_lossy();
+                if let Some((name, value)) = pair.split_once('=') {
+                    env.push((name.to_string(), value.to_string()));
+                }
+                libc::free(*entry as *mut c_void);
+                entry = entry.add(1);
+            }
+            libc::free(list as *mut c_void);
+        }
+        env
+    }
+
+    fn check(&self, what: &str, rc: c_int) -> Result<()> {
+        if rc != PAM_SUCCESS {
+            bail!("PAM failed to {what} - {}", self.error(rc));
+        }
+        Ok(())
+    }
+
+    fn error(&self, rc: c_int) -> String {
+        let text = unsafe { pam_strerror(self.handle, rc) };
+        if text.is_null() {
+            return format!("error {rc}");
+        }
+        unsafe { CStr::from_ptr(text) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl Drop for PamSession {
+    fn drop(&mut self) {
+        let mut rc = PAM_SUCCESS;
+        if self.opened {
+            rc = unsafe { pam_close_session(self.handle, 0) };
+            if rc != PAM_SUCCESS {
+                let err = self.error(rc);
+                crate::log::warn(
+                    "pam-failed",
+                    format_args!("failed to close PAM session - {err}"),
+                );
+            }
+        }
+        if self.credentials {
+            unsafe { pam_setcred(self.handle, PAM_DELETE_CRED) };
+        }
+        unsafe { pam_end(self.handle, rc) };
+    }
+}
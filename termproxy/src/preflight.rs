@@ -0,0 +1,123 @@
+//! Checks of the runtime environment
+//!
+//! `preflight` checks whatever a session needs from the system before a client ever connects:
+//! pseudo terminals, a listening socket, the API daemon for authentication, terminfo entries
+//! and the programs that get executed. It prints a `PASS`, `WARN` or `FAIL` line per check and
+//! fails if any check failed, so packaging scripts and support tooling can run it as is.
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, format_err, Result};
+use nix::unistd::{access, AccessFlags};
+
+use crate::cli::PreflightOptions;
+use crate::pty::PTY;
+
+enum Outcome {
+    Pass(String),
+    /// Only matters for some setups
+    Warn(String),
+    Fail(String),
+}
+
+fn check_pty() -> Outcome {
+    match PTY::new() {
+        Ok((_pty, secondary)) => Outcome::Pass(format!("allocated {secondary}")),
+        Err(err) => Outcome::Fail(format!("failed to allocate a pseudo terminal - {err}")),
+    }
+}
+
+fn check_listen() -> Outcome {
+    match std::net::TcpListener::bind(("localhost", 0)) {
+        Ok(_) => Outcome::Pass("can listen on localhost".to_string()),
+        Err(err) => Outcome::Fail(format!("failed to listen on localhost - {err}")),
+    }
+}
+
+#[cfg(feature = "auth-http")]
+fn check_auth(port: u16) -> Outcome {
+    let address = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+    let timeout = std::time::Duration::from_secs(2);
+    match std::net::TcpStream::connect_timeout(&address, timeout) {
+        Ok(_) => Outcome::Pass(format!("API daemon reachable on port {port}")),
+        Err(err) => Outcome::Fail(format!("API daemon not reachable on port {port} - {err}")),
+    }
+}
+
+#[cfg(not(feature = "auth-http"))]
+fn check_auth(_port: u16) -> Outcome {
+    Outcome::Warn("built without HTTP authentication".to_string())
+}
+
+fn check_terminfo() -> Outcome {
+    let candidates: Vec<String> = crate::terminfo::DEFAULT_CANDIDATES
+        .iter()
+        .map(|term| term.to_string())
+        .collect();
+    match crate::terminfo::select(Path::new("/"), &candidates) {
+        Some(term) => Outcome::Pass(format!("using '{term}'")),
+        None => Outcome::Fail(format!("no entry for any of {}", candidates.join(", "))),
+    }
+}
+
+/// Finds `program` like `execvp` does.
+fn find_executable(program: &OsStr) -> Result<PathBuf> {
+    let candidates: Vec<PathBuf> = if program.as_bytes().contains(&b'/') {
+        vec![PathBuf::from(program)]
+    } else {
+        let path = std::env::var_os("PATH").unwrap_or_else(|| "/usr/bin:/bin".into());
+        std::env::split_paths(&path)
+            .map(|dir| dir.join(program))
+            .collect()
+    };
+    candidates
+        .into_iter()
+        .find(|path| path.is_file() && access(path.as_path(), AccessFlags::X_OK).is_ok())
+        .ok_or_else(|| format_err!("{program:?} not found or not executable"))
+}
+
+fn check_command(program: &OsStr) -> Outcome {
+    match find_executable(program) {
+        Ok(path) => Outcome::Pass(format!("found {path:?}")),
+        Err(err) => Outcome::Fail(err.to_string()),
+    }
+}
+
+fn check_systemd_run() -> Outcome {
+    match find_executable(OsStr::new("systemd-run")) {
+        Ok(path) => Outcome::Pass(format!("found {path:?}")),
+        Err(err) => Outcome::Warn(format!("{err}, --systemd-scope is not available")),
+    }
+}
+
+pub fn preflight(options: &PreflightOptions) -> Result<()> {
+    let mut checks = vec![
+        ("pty", check_pty()),
+        ("listen", check_listen()),
+        ("auth", check_auth(options.api_daemon_port)),
+        ("terminfo", check_terminfo()),
+        ("systemd-run", check_systemd_run()),
+    ];
+    if let Some(program) = options.terminal_command.first() {
+        checks.push(("command", check_command(program)));
+    }
+
+    let mut failed = 0;
+    for (name, outcome) in &checks {
+        match outcome {
+            Outcome::Pass(message) => println!("PASS: {name} - {message}"),
+            Outcome::Warn(message) => println!("WARN: {name} - {message}"),
+            Outcome::Fail(message) => {
+                println!("FAIL: {name} - {message}");
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("{failed} of {} checks failed", checks.len());
+    }
+    Ok(())
+}
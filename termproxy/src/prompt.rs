@@ -0,0 +1,115 @@
+//! Answering password prompts with a secret provider
+//!
+//! Consoles behind `su`, `sudo` or a login prompt ask for a password before they are of any
+//! use. With `--secret-provider`, termproxy watches for such prompts, that is the terminal's
+//! echo being off while the last output line looks like a prompt, and answers them with the
+//! output of the provider program, e.g. a wrapper around a secret manager. The secret only
+//! ever goes to the terminal, it is neither logged nor sent to the client.
+
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{bail, format_err, Result};
+
+use crate::timer::Deadline;
+
+/// Longer lines are not prompts.
+const MAX_PROMPT_LEN: usize = 256;
+
+/// How many prompts are answered per session, so a rejected secret doesn't loop forever.
+pub const MAX_ANSWERS: usize = 3;
+
+/// How long the provider may take to print the secret.
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Words that mark the end of a line as a password prompt.
+const PROMPT_WORDS: &[&str] = &["password", "passphrase", "passwort"];
+
+#[derive(Default)]
+pub struct PromptDetector {
+    /// The output since the last line break
+    line: Vec<u8>,
+    line_too_long: bool,
+    /// Prompts answered so far
+    pub answered: usize,
+}
+
+impl PromptDetector {
+    /// Accounts for output of the command, returns the prompt if the output ends in one.
+    pub fn scan(&mut self, data: &[u8]) -> Option<String> {
+        for &byte in data {
+            match byte {
+                b'\n' | b'\r' => {
+                    self.line.clear();
+                    self.line_too_long = false;
+                }
+                _ if self.line.len() < MAX_PROMPT_LEN => self.line.push(byte),
+                _ => self.line_too_long = true,
+            }
+        }
+        if self.line_too_long {
+            return None;
+        }
+        let line = String::from_utf8_lossy(&self.line);
+        is_password_prompt(&line).then(|| line.trim().to_string())
+    }
+
+    /// Forgets the current line, after its prompt was answered.
+    pub fn clear(&mut self) {
+        self.line.clear();
+    }
+}
+
+/// Whether the line looks like a prompt for a password, e.g. `[sudo] password for root: `.
+fn is_password_prompt(line: &str) -> bool {
+    let Some(text) = line.trim_end().strip_suffix(':') else {
+        return false;
+    };
+    let text = text.to_lowercase();
+    PROMPT_WORDS.iter().any(|word| text.contains(word))
+}
+
+/// Runs the provider for `prompt` and returns the secret it printed, without line break.
+pub fn provide_secret(program: &Path, prompt: &str, session_id: &str) -> Result<Vec<u8>> {
+    let mut child = Command::new(program)
+        .env("TERMPROXY_SESSION_ID", session_id)
+        .env("TERMPROXY_PROMPT", prompt)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        // whatever the provider complains about might contain the secret
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| format_err!("failed to run secret provider - {err}"))?;
+
+    let deadline = Deadline::after(PROVIDER_TIMEOUT);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if deadline.is_expired() {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("secret provider timed out");
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    if !status.success() {
+        bail!("secret provider failed - {status}");
+    }
+
+    let mut secret = Vec::new();
+    if let Some(mut stdout) = child.stdout.take() {
+        stdout.read_to_end(&mut secret)?;
+    }
+    let len = secret
+        .iter()
+        .position(|&b| b == b'\n' || b == b'\r')
+        .unwrap_or(secret.len());
+    secret.truncate(len);
+    if secret.is_empty() {
+        bail!("secret provider printed no secret");
+    }
+    Ok(secret)
+}
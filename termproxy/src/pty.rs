@@ -7,11 +7,16 @@ use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
 use nix::fcntl::OFlag;
 use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt, PtyMaster};
 use nix::sys::stat::Mode;
-use nix::unistd::{dup2, setsid};
-use nix::{ioctl_write_int_bad, ioctl_write_ptr_bad, Result};
+use nix::sys::termios::{
+    tcgetattr, tcsetattr, ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg,
+    SpecialCharacterIndices,
+};
+use nix::unistd::{dup2, setsid, tcgetpgrp, Pid};
+use nix::{ioctl_read_bad, ioctl_write_int_bad, ioctl_write_ptr_bad, Result};
 
 ioctl_write_int_bad!(set_controlling_tty, libc::TIOCSCTTY);
 ioctl_write_ptr_bad!(set_size, libc::TIOCSWINSZ, nix::pty::Winsize);
+ioctl_read_bad!(get_size, libc::TIOCGWINSZ, nix::pty::Winsize);
 
 /// Represents a PTY
 ///
@@ -48,6 +53,7 @@ ioctl_write_ptr_bad!(set_size, libc::TIOCSWINSZ, nix::pty::Winsize);
 ///     Ok(())
 ///  }
 /// ```
+#[allow(clippy::upper_case_acronyms)]
 pub struct PTY {
     primary: PtyMaster,
 }
@@ -89,6 +95,11 @@ impl PTY {
         Ok((Self { primary }, secondary))
     }
 
+    /// Returns the path to the secondary terminal.
+    pub fn secondary_name(&self) -> Result<String> {
+        ptsname_r(&self.primary)
+    }
+
     /// Uses the ioctl 'TIOCSWINSZ' on the terminal fd to set the terminals
     /// columns and rows
     pub fn set_size(&mut self, col: u16, row: u16) -> Result<()> {
@@ -103,6 +114,83 @@ impl PTY {
 
         Ok(())
     }
+
+    /// Uses the ioctl 'TIOCGWINSZ' on the terminal fd to get the terminals
+    /// columns and rows
+    pub fn get_size(&self) -> Result<(u16, u16)> {
+        let mut size = nix::pty::Winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+
+        unsafe { get_size(self.primary.as_raw_fd(), &mut size) }?;
+
+        Ok((size.ws_col, size.ws_row))
+    }
+
+    /// Restores sane settings of the terminal's line discipline, like `stty sane` does, e.g.
+    /// after a program crashed and left it in raw mode without echo.
+    pub fn make_sane(&mut self) -> Result<()> {
+        let mut termios = tcgetattr(self.primary.as_raw_fd())?;
+
+        termios.control_flags |= ControlFlags::CREAD;
+        termios.input_flags |= InputFlags::BRKINT | InputFlags::ICRNL | InputFlags::IMAXBEL;
+        termios.input_flags &= !(InputFlags::IGNBRK
+            | InputFlags::INLCR
+            | InputFlags::IGNCR
+            | InputFlags::IXOFF
+            | InputFlags::IXANY);
+        termios.output_flags |= OutputFlags::OPOST | OutputFlags::ONLCR;
+        termios.output_flags &= !(OutputFlags::OCRNL
+            | OutputFlags::ONOCR
+            | OutputFlags::ONLRET
+            | OutputFlags::OFILL
+            | OutputFlags::OFDEL);
+        termios.local_flags |= LocalFlags::ISIG
+            | LocalFlags::ICANON
+            | LocalFlags::IEXTEN
+            | LocalFlags::ECHO
+            | LocalFlags::ECHOE
+            | LocalFlags::ECHOK
+            | LocalFlags::ECHOCTL
+            | LocalFlags::ECHOKE;
+        termios.local_flags &=
+            !(LocalFlags::ECHONL | LocalFlags::NOFLSH | LocalFlags::TOSTOP | LocalFlags::ECHOPRT);
+
+        for (index, value) in [
+            (SpecialCharacterIndices::VINTR, 0x03),
+            (SpecialCharacterIndices::VQUIT, 0x1c),
+            (SpecialCharacterIndices::VERASE, 0x7f),
+            (SpecialCharacterIndices::VKILL, 0x15),
+            (SpecialCharacterIndices::VEOF, 0x04),
+            (SpecialCharacterIndices::VSTART, 0x11),
+            (SpecialCharacterIndices::VSTOP, 0x13),
+            (SpecialCharacterIndices::VSUSP, 0x1a),
+            (SpecialCharacterIndices::VREPRINT, 0x12),
+            (SpecialCharacterIndices::VWERASE, 0x17),
+            (SpecialCharacterIndices::VLNEXT, 0x16),
+        ] {
+            termios.control_chars[index as usize] = value;
+        }
+
+        tcsetattr(self.primary.as_raw_fd(), SetArg::TCSANOW, &termios)
+    }
+
+    /// Whether the terminal reads lines without echoing them, as programs set it up to read a
+    /// password.
+    pub fn reads_hidden_line(&self) -> Result<bool> {
+        let termios = tcgetattr(self.primary.as_raw_fd())?;
+        Ok(termios.local_flags.contains(LocalFlags::ICANON)
+            && !termios.local_flags.contains(LocalFlags::ECHO))
+    }
+
+    /// Returns the foreground process group of the terminal, i.e. the job currently in control
+    /// of the terminal.
+    pub fn foreground_process_group(&self) -> Result<Pid> {
+        tcgetpgrp(self.primary.as_raw_fd())
+    }
 }
 
 impl std::io::Read for PTY {
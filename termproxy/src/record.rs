@@ -0,0 +1,191 @@
+//! Recording of the terminal output
+//!
+//! With `--record`, everything the command writes to the terminal is also written to a file,
+//! either as an asciicast (version 2, as written by asciinema) or in the binary ttyrec format
+//! that ttyplay, ipbt and friends understand. Control messages of termproxy itself are not
+//! part of the recording.
+//!
+//! A ttyrec file is a sequence of frames, each one a header of three 32 bit little endian
+//! values, the seconds and microseconds since the epoch and the length of the data, followed by
+//! the data. ttyrec has no notion of the terminal size, resizes are only recorded in asciicasts.
+//!
+//! Asciicasts of sessions recorded together, e.g. of a teacher and the students, can share a
+//! time base with `--record-group`: the first recording of a group leaves its start time in
+//! `.record-group-ID` next to it, and the recordings of the group time their events from then
+//! on, instead of from their own start. Played back at the same time, they stay in sync. The
+//! header notes the group and its start as `"group": {"id": ID, "start": SECONDS}`.
+
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, format_err, Result};
+
+use crate::cli::RecordFormat;
+
+/// Recordings timed from the same start
+pub struct RecordGroup {
+    id: String,
+    /// The start of the first recording of the group, in seconds since the epoch
+    start: f64,
+}
+
+impl RecordGroup {
+    /// Joins the group `id` of the recordings in `dir`, starting it now if there is none yet.
+    pub fn join(dir: &Path, id: &str) -> Result<Self> {
+        let path = dir.join(format!(".record-group-{id}"));
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // microseconds, as asciinema writes times
+        let start = (start.as_secs_f64() * 1_000_000.0).round() / 1_000_000.0;
+
+        // written completely before it appears under its name, sessions of the group may start
+        // at the same time
+        let tmp_path = dir.join(format!(".record-group-{id}.{}", std::process::id()));
+        let content = serde_json::to_vec(&serde_json::json!({ "id": id, "start": start }))?;
+        std::fs::write(&tmp_path, content)
+            .map_err(|err| format_err!("failed to create recording group {path:?} - {err}"))?;
+        let linked = std::fs::hard_link(&tmp_path, &path);
+        let _ = std::fs::remove_file(&tmp_path);
+        match linked {
+            Ok(()) => {
+                return Ok(Self {
+                    id: id.to_string(),
+                    start,
+                })
+            }
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => (),
+            Err(err) => bail!("failed to create recording group {path:?} - {err}"),
+        }
+
+        let content = std::fs::read(&path)
+            .map_err(|err| format_err!("failed to read recording group {path:?} - {err}"))?;
+        let group: serde_json::Value = serde_json::from_slice(&content)?;
+        match group["start"].as_f64() {
+            Some(start) if start >= 0.0 => Ok(Self {
+                id: id.to_string(),
+                start,
+            }),
+            _ => bail!("invalid recording group {path:?}"),
+        }
+    }
+}
+
+pub struct Recorder {
+    file: File,
+    format: RecordFormat,
+    start: Instant,
+    /// The start of a UTF-8 sequence split between two reads, asciicasts only store text
+    partial: Vec<u8>,
+}
+
+impl Recorder {
+    /// Creates the recording at `path` for a terminal of the given size, timed from the start of
+    /// `group` if it is part of one.
+    pub fn create(
+        path: &Path,
+        format: RecordFormat,
+        cols: u16,
+        rows: u16,
+        group: Option<&RecordGroup>,
+    ) -> Result<Self> {
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|err| format_err!("failed to create recording {path:?} - {err}"))?;
+        let now = SystemTime::now();
+        let start = match group {
+            Some(group) => UNIX_EPOCH + Duration::from_secs_f64(group.start),
+            None => now,
+        };
+        let mut recorder = Self {
+            file,
+            format,
+            // a group started in the future would be a clock going backwards
+            start: Instant::now()
+                .checked_sub(now.duration_since(start).unwrap_or_default())
+                .unwrap_or_else(Instant::now),
+            partial: Vec::new(),
+        };
+        if format == RecordFormat::Asciicast {
+            let mut header = serde_json::json!({
+                "version": 2,
+                "width": cols,
+                "height": rows,
+                "timestamp": crate::status::unix_time(start),
+            });
+            if let Some(group) = group {
+                header["group"] = serde_json::json!({ "id": group.id, "start": group.start });
+            }
+            recorder.write_line(&header)?;
+        }
+        Ok(recorder)
+    }
+
+    /// Records output of the command.
+    pub fn output(&mut self, data: &[u8]) -> Result<()> {
+        match self.format {
+            RecordFormat::Asciicast => {
+                self.partial.extend_from_slice(data);
+                let text = take_text(&mut self.partial);
+                if text.is_empty() {
+                    return Ok(());
+                }
+                self.event("o", text)
+            }
+            RecordFormat::Ttyrec => {
+                let time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let mut frame = Vec::with_capacity(12 + data.len());
+                frame.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+                frame.extend_from_slice(&time.subsec_micros().to_le_bytes());
+                frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                frame.extend_from_slice(data);
+                self.file.write_all(&frame)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Records a new size of the terminal.
+    pub fn resize(&mut self, cols: u16, rows: u16) -> Result<()> {
+        match self.format {
+            RecordFormat::Asciicast => self.event("r", format!("{cols}x{rows}")),
+            RecordFormat::Ttyrec => Ok(()),
+        }
+    }
+
+    fn event(&mut self, kind: &str, data: String) -> Result<()> {
+        self.write_line(&event(self.start.elapsed(), kind, data))
+    }
+
+    fn write_line(&mut self, value: &serde_json::Value) -> Result<()> {
+        let mut line = serde_json::to_vec(value)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// Takes the text from the start of `partial`, the output of the command so far. An incomplete
+/// UTF-8 sequence at the end is kept for the next output, invalid ones are replaced.
+pub fn take_text(partial: &mut Vec<u8>) -> String {
+    let valid = match std::str::from_utf8(partial) {
+        Err(err) if err.error_len().is_none() => err.valid_up_to(),
+        _ => partial.len(),
+    };
+    let text = String::from_utf8_lossy(&partial[..valid]).into_owned();
+    partial.drain(..valid);
+    text
+}
+
+/// An asciicast event of `kind` at `time` since the start of the recording.
+pub fn event(time: Duration, kind: &str, data: String) -> serde_json::Value {
+    // asciinema writes times with microsecond precision
+    let time = (time.as_secs_f64() * 1_000_000.0).round() / 1_000_000.0;
+    serde_json::json!([time, kind, data])
+}
@@ -0,0 +1,1622 @@
+//! The relay loop
+//!
+//! [`Relay`] is the running session: it waits for the terminal of the command, its clients,
+//! the control and admin sockets, signals and timers, and relays between them until the
+//! session ends.
+
+use std::cmp::min;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::process::Child;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, format_err, Result};
+use mio::event::Event;
+use mio::unix::SourceFd;
+use mio::{Events, Poll, Token};
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::signalfd::SignalFd;
+use nix::unistd::Pid;
+use zeroize::Zeroizing;
+
+use crate::admin::AdminSocket;
+use crate::audit::WakeupAudit;
+use crate::backlog::Backlog;
+use crate::binary::{BinaryDetector, Verdict};
+use crate::cli::Options;
+use crate::client::{
+    announce_admin, announce_clients, answer_size, attach_client, buffer_size, check_keepalive,
+    client_memory, fan_out, fits_all_clients, participants, reading_paused, send_context, Client,
+    ClientStream,
+};
+use crate::compat::ByteBuffer;
+use crate::control::{base64_encode, encode_control_message, ControlCommand};
+#[cfg(feature = "daemon")]
+use crate::detach::{ControlSocket, Request};
+use crate::escape::Scan;
+use crate::files::FileAccess;
+#[cfg(feature = "serial")]
+use crate::guest::GuestDetector;
+use crate::history::CommandHistory;
+use crate::join::Joiner;
+use crate::listener::Listener;
+use crate::log::Phase;
+use crate::message::{process_queue, Message};
+use crate::pacing::LoopPacer;
+use crate::prompt::PromptDetector;
+use crate::pty::PTY;
+#[cfg(feature = "recording")]
+use crate::record::Recorder;
+use crate::resize::SizeArbiter;
+#[cfg(feature = "serial")]
+use crate::sac::SacFilter;
+use crate::screen::{Screen, SnapshotFile};
+#[cfg(feature = "recording")]
+use crate::session::check_recording;
+use crate::session::{
+    answer_prompt, check_history, drain_output, finish_binary_flush, flush_binary, freeze,
+    handle_control, input_allowance, lock_session, pause_binary, queue_data, queue_message,
+    read_limited, report_loop, report_sysrq, resume_binary, send_break, session_end_message, thaw,
+    unlock_session, wait_for_exit, wrap_child_stderr, write_status, BinaryOutput, ControlState,
+    Readiness, SessionStats, SessionTimer, SysrqBreak, BINARY_FLUSH_IDLE, DRAIN_TIMEOUT,
+    EXIT_WAIT_TIMEOUT, INPUT_RATE_INTERVAL, MIN_STDERR_SPACE, STATUS_INTERVAL, STDERR_OVERHEAD,
+    SYSRQ_BREAK_TIMEOUT, THROTTLED_OUTPUT,
+};
+#[cfg(feature = "daemon")]
+use crate::share::ShareRequest;
+use crate::status::StatusFile;
+use crate::timer::{Deadline, Timers};
+use crate::timing::{StartupTiming, Step};
+use crate::watchdog::LoopWatchdog;
+
+pub const LISTENER: Token = Token(0);
+pub const PTY: Token = Token(1);
+pub const STDERR: Token = Token(2);
+pub const CONTROL: Token = Token(3);
+pub const SIGNAL: Token = Token(4);
+pub const ADMIN: Token = Token(5);
+pub const JOINER: Token = Token(6);
+/// The token of the first client, later ones count up from it
+pub const FIRST_CLIENT: usize = 7;
+
+/// A running session: the command's terminal, its clients and everything looking at the output
+/// in between.
+///
+/// [`Relay::run`] waits for any of them to be ready and leaves each concern to a handler of its
+/// own: timers, clients joining, input from the clients, output of the command and the output
+/// to the clients.
+pub struct Relay {
+    pub options: Arc<Options>,
+    /// The user the session runs for
+    pub username: Box<[u8]>,
+    pub listen_port: u16,
+    pub poll: Poll,
+    pub listener: Listener,
+    pub listener_ready: bool,
+    pub joiner: Joiner,
+    #[cfg(feature = "daemon")]
+    pub control_socket: Option<ControlSocket>,
+    pub control_ready: bool,
+    pub admin_socket: Option<AdminSocket>,
+    pub admin_ready: bool,
+    pub signals: SignalFd,
+    pub signal_ready: bool,
+    pub pty: PTY,
+    pub pty_ready: Readiness,
+    pub child: Child,
+    pub child_stderr: Option<std::fs::File>,
+    pub stderr_ready: Readiness,
+    pub clients: Vec<Client>,
+    /// The token of the client joining next
+    pub next_token: usize,
+    /// Output for all clients, before it is handed to each of them
+    pub tcp_buf: ByteBuffer,
+    /// Output while no client is attached
+    pub backlog: Backlog,
+    pub finished: bool,
+    // why the session ended, for the final message to the clients
+    pub client_closed: bool,
+    pub ended_by: Option<&'static str>,
+    /// Set once the time to reconnect ran out, the session ends as soon as no client is
+    /// authenticating anymore
+    pub reconnect_expired: bool,
+    pub control_state: ControlState,
+    pub stats: SessionStats,
+    pub timers: Timers<SessionTimer>,
+    pub binary_detector: Option<BinaryDetector>,
+    pub loop_watchdog: Option<LoopWatchdog>,
+    pub prompt_detector: Option<PromptDetector>,
+    #[cfg(feature = "serial")]
+    pub guest_detector: Option<GuestDetector>,
+    #[cfg(feature = "serial")]
+    pub sac_filter: Option<SacFilter>,
+    pub screen: Option<Screen>,
+    pub snapshot: Option<SnapshotFile>,
+    #[cfg(feature = "recording")]
+    pub recorder: Option<Recorder>,
+    pub history: Option<CommandHistory>,
+    pub file_access: Option<FileAccess>,
+    pub status: Option<StatusFile>,
+    /// Decides on the size of the terminal among the sizes the clients ask for
+    pub sizes: SizeArbiter,
+    pub sysrq_break: Option<SysrqBreak>,
+}
+
+impl Relay {
+    /// Relays between the command and the clients until the session ends.
+    pub fn run(&mut self, timing: &mut Option<StartupTiming>) -> Result<()> {
+        let mut events = Events::with_capacity(128);
+        let mut wakeup_audit = self.options.audit_wakeups.then(WakeupAudit::new);
+        let mut pacer = LoopPacer::new(self.options.poll_strategy);
+
+        while !self.finished {
+            let zero_timeout = self.has_work();
+            // events still holds what the previous poll returned
+            let progress =
+                self.stats.from_client + self.stats.to_client + self.control_state.binary_discarded;
+            let (timeout, backing_off) = pacer.next_timeout(
+                zero_timeout,
+                !events.is_empty(),
+                progress,
+                self.timers.next_timeout(),
+            );
+            if backing_off {
+                crate::log::warn(
+                    "busy-loop",
+                    format_args!(
+                        "relay loop spun for {}s without getting anything done, backing off",
+                        crate::pacing::SPIN_LIMIT.as_secs(),
+                    ),
+                );
+            }
+            self.poll.poll(&mut events, timeout)?;
+
+            // whether something happened that the status file may have to reflect
+            let mut activity = !events.is_empty();
+            let mut timer_expired = false;
+            while let Some(timer) = self.timers.pop_expired() {
+                timer_expired = true;
+                activity |= timer != SessionTimer::Status;
+                self.handle_timer(timer)?;
+            }
+
+            if let Some(audit) = wakeup_audit.as_mut() {
+                let spurious = !zero_timeout && events.is_empty() && !timer_expired;
+                if let Some(report) = audit.record(zero_timeout, spurious) {
+                    println!("{report}");
+                }
+            }
+
+            if activity && self.status.is_some() && !self.timers.is_pending(&SessionTimer::Status) {
+                self.timers.set(SessionTimer::Status, STATUS_INTERVAL);
+            }
+
+            for event in &events {
+                self.handle_event(event);
+            }
+
+            self.accept_clients()?;
+            self.handle_signals()?;
+            self.finish_sysrq();
+            self.read_clients()?;
+            self.control_state.queue_pending_notice(&mut self.tcp_buf);
+            self.queue_lines();
+            self.read_pty()?;
+            self.discard_binary()?;
+            self.read_stderr()?;
+            fan_out(&mut self.tcp_buf, &mut self.clients, &mut self.backlog);
+            self.enforce_memory_limit();
+            self.write_clients(timing)?;
+            self.dispatch_input()?;
+            self.remove_closed_clients();
+        }
+        Ok(())
+    }
+
+    /// Whether anything is ready for the relay to go on right away, without waiting for events.
+    fn has_work(&self) -> bool {
+        let clients_busy = self.clients.iter().any(|client| {
+            client.ready.readable && !client.input.is_full() && client.input_allowance != Some(0)
+                || client.ready.writable
+                    && (!client.output.is_empty() || client.stream.has_pending_output())
+        });
+        let reading_paused = reading_paused(&self.clients, &self.backlog);
+        clients_busy
+            || self.listener_ready
+            || self.control_ready
+            || self.admin_ready
+            || self.signal_ready
+            || !self.tcp_buf.is_empty()
+                && participants(&self.clients) > 0
+                && fits_all_clients(&self.tcp_buf, &self.clients)
+            || self.pty_ready.readable
+                && !self.tcp_buf.is_full()
+                && !self.output_held()
+                && !reading_paused
+            || self.tcp_buf.is_empty()
+                && participants(&self.clients) > 0
+                && !self.backlog.is_empty()
+            || self.tcp_buf.is_empty() && self.lines_pending()
+            || self.pty_ready.readable && self.control_state.binary == BinaryOutput::Flushing
+            || self.stderr_ready.readable
+                && self.tcp_buf.free_size() >= MIN_STDERR_SPACE
+                && !self.output_held()
+                && !reading_paused
+    }
+
+    /// Whether a failing connection of a client ends the session, i.e. if it is the only client
+    /// and the session doesn't outlive it.
+    fn client_ends_session(&self) -> bool {
+        // detachable sessions and those with a grace period for reconnecting outlive their
+        // last client
+        participants(&self.clients) == 1 && !self.options.outlives_clients()
+    }
+
+    fn handle_timer(&mut self, timer: SessionTimer) -> Result<()> {
+        let options = &*self.options;
+        match timer {
+            SessionTimer::FirstOutput => {
+                let command = options.command_name();
+                let timeout = options.first_output_timeout.unwrap_or_default();
+                let mut message = format!(
+                    "\r\ncommand appears hung: '{command}' produced no output within {}s",
+                    timeout.as_secs(),
+                );
+                if options.first_output_kill {
+                    message.push_str(", terminating it");
+                    let _ = killpg(Pid::from_raw(self.child.id() as i32), Signal::SIGTERM);
+                }
+                crate::log::warn("first-output-timeout", message.trim_start());
+                queue_message(&mut self.tcp_buf, &format!("{message}\r\n"));
+            }
+            SessionTimer::Status => {
+                if let Some(status) = &self.status {
+                    write_status(
+                        status,
+                        options,
+                        &self.child,
+                        &self.stats,
+                        &self.control_state,
+                        &self.username,
+                        participants(&self.clients),
+                    );
+                }
+                // re-armed by the next activity, an idle session doesn't need to wake up
+            }
+            // clients that connected in time still get to finish their handshake
+            SessionTimer::Reconnect => self.reconnect_expired = true,
+            SessionTimer::Idle => {
+                let timeout = options.idle_timeout.unwrap_or_default().as_secs();
+                let message = format!("no input for {timeout}s, terminating the session");
+                crate::log::warn("idle-timeout", &message);
+                queue_message(&mut self.tcp_buf, &format!("\r\n{message}\r\n"));
+                // like a hangup of the terminal, which shells don't ignore unlike SIGTERM
+                let _ = killpg(Pid::from_raw(self.child.id() as i32), Signal::SIGHUP);
+                self.finished = true;
+                self.ended_by = Some("idle");
+            }
+            SessionTimer::Lock => {
+                if !lock_session(options, &mut self.control_state, &mut self.tcp_buf) {
+                    // try again once the client caught up with the output
+                    self.timers
+                        .set(SessionTimer::Lock, Duration::from_millis(100));
+                }
+            }
+            SessionTimer::LoopCheck => {
+                let idle = self
+                    .loop_watchdog
+                    .as_ref()
+                    .is_none_or(LoopWatchdog::is_idle);
+                if let Some(rate) = self.loop_watchdog.as_mut().and_then(LoopWatchdog::check) {
+                    report_loop(options, rate, &mut self.control_state, &mut self.tcp_buf);
+                }
+                // throttled output waits for the next interval, otherwise output arms the
+                // check again
+                let exhausted = self.control_state.throttle == Some(0);
+                if self.control_state.throttle.is_some() {
+                    self.control_state.throttle = Some(THROTTLED_OUTPUT);
+                }
+                if !idle || exhausted {
+                    self.timers
+                        .set(SessionTimer::LoopCheck, crate::watchdog::CHECK_INTERVAL);
+                }
+            }
+            SessionTimer::BinaryFlush => {
+                if !finish_binary_flush(&mut self.control_state, &mut self.tcp_buf) {
+                    self.timers
+                        .set(SessionTimer::BinaryFlush, Duration::from_millis(100));
+                }
+            }
+            SessionTimer::Keepalive => {
+                let interval = options.keepalive.unwrap_or_default();
+                let ends_session = self.client_ends_session();
+                for client in self.clients.iter_mut() {
+                    check_keepalive(client, interval, ends_session)?;
+                }
+                self.timers.set(SessionTimer::Keepalive, interval);
+            }
+            SessionTimer::Snapshot => {
+                let text = self.screen.as_mut().and_then(Screen::take_snapshot);
+                if let (Some(text), Some(snapshot)) = (text, &self.snapshot) {
+                    if let Err(err) = snapshot.write(&text) {
+                        crate::log::warn(
+                            "snapshot-failed",
+                            format_args!("failed to write snapshot - {err}"),
+                        );
+                    }
+                }
+                // re-armed by the next output, an idle session doesn't need to wake up
+            }
+            SessionTimer::InputRate => {
+                let allowance = options.max_input_rate.map(input_allowance);
+                for client in self.clients.iter_mut() {
+                    client.input_allowance = allowance;
+                }
+            }
+            SessionTimer::SysrqBreak => {
+                if let Some(sysrq) = self.sysrq_break.take() {
+                    crate::log::warn(
+                        "break-failed",
+                        format_args!(
+                            "break command did not finish within {}s, dropping sysrq key '{}'",
+                            SYSRQ_BREAK_TIMEOUT.as_secs(),
+                            char::from(sysrq.key),
+                        ),
+                    );
+                    report_sysrq(
+                        sysrq.key,
+                        "failed",
+                        &mut self.control_state,
+                        &mut self.tcp_buf,
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: &Event) {
+        match event.token() {
+            LISTENER => self.listener_ready = true,
+            // handshakes, control socket requests and breaks that finished are looked for anyway
+            JOINER => (),
+            CONTROL => self.control_ready = true,
+            ADMIN => self.admin_ready = true,
+            SIGNAL => self.signal_ready = true,
+            PTY => {
+                if event.is_read_closed() {
+                    self.finished = true;
+                }
+                self.pty_ready.update(event);
+            }
+            // the command may close its stderr and continue
+            STDERR => self.stderr_ready.update(event),
+            token => {
+                // events of a removed client may still be pending
+                if let Some(client) = self.clients.iter_mut().find(|client| client.token == token) {
+                    // whatever it sent before is still relayed
+                    if event.is_read_closed() {
+                        client.closed = true;
+                    }
+                    client.ready.update(event);
+                }
+            }
+        }
+    }
+
+    /// Accepts clients joining through the listener, attaching through the control socket or
+    /// watching as administrators, and adds those ready to the session.
+    fn accept_clients(&mut self) -> Result<()> {
+        self.accept_joining();
+        #[cfg(feature = "daemon")]
+        self.accept_requests();
+        // clients joining or attaching, added once all of them were accepted
+        let mut joined = Vec::new();
+        self.take_authenticated(&mut joined);
+        #[cfg(feature = "daemon")]
+        self.accept_hand_overs(&mut joined);
+        self.accept_admins(&mut joined);
+        self.add_clients(joined)?;
+
+        if self.reconnect_expired && self.joiner.pending() == 0 {
+            crate::log::warn(
+                "reconnect-timeout",
+                "no client reconnected in time, ending the session",
+            );
+            self.finished = true;
+            self.client_closed = true;
+        }
+        Ok(())
+    }
+
+    /// Starts the handshakes of clients connecting to the listener.
+    fn accept_joining(&mut self) {
+        while self.listener_ready {
+            let stream = match self.listener.accept_pending() {
+                Ok(Some(stream)) => stream,
+                Ok(None) => {
+                    self.listener_ready = false;
+                    break;
+                }
+                Err(err) => {
+                    crate::log::warn(
+                        "accept-failed",
+                        format_args!("failed to accept client - {err}"),
+                    );
+                    self.listener_ready = false;
+                    break;
+                }
+            };
+            if self.reconnect_expired {
+                crate::log::warn(
+                    "reconnect-timeout",
+                    "rejecting client, the time to reconnect ran out",
+                );
+                continue;
+            }
+            let pending = self.joiner.pending();
+            if pending >= crate::join::MAX_PENDING {
+                crate::log::warn(
+                    "session-busy",
+                    format_args!("rejecting client, {pending} clients are still authenticating"),
+                );
+                continue;
+            }
+            let participants = participants(&self.clients);
+            let full = participants + pending >= self.options.max_clients;
+            // a client reconnecting after a network failure may well arrive before its old
+            // connection was noticed to be gone, if it ever is
+            let replacing =
+                full && self.options.max_clients == 1 && self.options.outlives_clients();
+            if full && !replacing {
+                crate::log::warn(
+                    "session-full",
+                    format_args!("rejecting client, session has {participants} clients"),
+                );
+                continue;
+            }
+            let first = participants == 0 && pending == 0;
+            let user = (replacing || first).then_some(&*self.username);
+            let token = Token(self.next_token);
+            if let Err(err) = self.joiner.start(stream, token, user, replacing) {
+                crate::log::warn(
+                    "accept-failed",
+                    format_args!("failed to start authenticating client - {err}"),
+                );
+                continue;
+            }
+            self.next_token += 1;
+        }
+    }
+
+    /// Takes the clients that finished their handshake.
+    fn take_authenticated(&mut self, joined: &mut Vec<Client>) {
+        // the session may have changed while the clients authenticated
+        while let Some(finished) = self.joiner.finished() {
+            let (mut client, user) = match finished.result {
+                Ok(joined) => joined,
+                Err(err) => {
+                    crate::log::warn(
+                        crate::log::error_code(&err),
+                        format_args!("client failed to join - {err}"),
+                    );
+                    continue;
+                }
+            };
+            let user = String::from_utf8_lossy(&user).into_owned();
+            // the login shell of a local user is not shared with other local users
+            if self.options.peer_user && *user.as_bytes() != *self.username {
+                client.stream.close();
+                crate::log::warn(
+                    "join-denied",
+                    format_args!("{user} cannot join the session of another user"),
+                );
+                continue;
+            }
+            let first = participants(&self.clients) + joined.len() == 0;
+            if first && !finished.user_checked && *user.as_bytes() != *self.username {
+                client.stream.close();
+                crate::log::warn(
+                    "reconnect-denied",
+                    format_args!("{user} cannot take over the session of another user"),
+                );
+                continue;
+            }
+            if finished.replacing {
+                println!("client reconnected, dropping its old connection");
+                for old in self
+                    .clients
+                    .iter_mut()
+                    .chain(joined.iter_mut())
+                    .filter(|old| old.admin.is_none())
+                {
+                    old.closed = true;
+                }
+            } else if participants(&self.clients) + joined.len() >= self.options.max_clients {
+                client.stream.close();
+                crate::log::warn(
+                    "session-full",
+                    format_args!(
+                        "rejecting client, session has {} clients",
+                        participants(&self.clients),
+                    ),
+                );
+                continue;
+            }
+            if let (false, false, Some(screen)) = (first, finished.replacing, &self.screen) {
+                if self.options.join_context.is_some() {
+                    send_context(&mut client, screen);
+                }
+            }
+            // the context it got counts as well
+            if !self.fits_memory(joined, &client) {
+                client.stream.close();
+                crate::log::warn(
+                    "memory-limit",
+                    "rejecting client, its buffers would exceed --max-memory",
+                );
+                continue;
+            }
+            println!(
+                "client of {user} joined the session{}",
+                if client.observer { " as observer" } else { "" },
+            );
+            joined.push(client);
+        }
+    }
+
+    /// What the session buffers that can't be dropped: its relay buffers and those of its
+    /// clients.
+    fn fixed_memory(&self) -> usize {
+        let clients = self.clients.iter().filter(|client| !client.closed);
+        buffer_size(&self.tcp_buf) + clients.map(client_memory).sum::<usize>()
+    }
+
+    /// Whether the buffers of `client` fit into --max-memory, along with those of the clients
+    /// `joined` so far. Scrollback and held back output make room for it if need be.
+    fn fits_memory(&self, joined: &[Client], client: &Client) -> bool {
+        self.options.max_memory.is_none_or(|limit| {
+            let joined: usize = joined.iter().map(client_memory).sum();
+            self.fixed_memory() + joined + client_memory(client) <= limit
+        })
+    }
+
+    /// Drops the oldest lines scrolled off the screen, and then the oldest output held back
+    /// for clients to come, while the session buffers more than --max-memory.
+    fn enforce_memory_limit(&mut self) {
+        let Some(limit) = self.options.max_memory else {
+            return;
+        };
+        let scrollback = self.screen.as_ref().map_or(0, Screen::scrollback_size);
+        let used = self.fixed_memory() + scrollback + self.backlog.len();
+        let mut excess = used.saturating_sub(limit);
+        if excess == 0 {
+            return;
+        }
+        let scrollback = match self.screen.as_mut() {
+            Some(screen) => screen.drop_scrollback(excess),
+            None => 0,
+        };
+        excess = excess.saturating_sub(scrollback);
+        let output = self.backlog.drop_oldest(excess);
+        crate::log::warn(
+            "memory-limit",
+            format_args!(
+                "session buffers {used} bytes, more than --max-memory, dropped {scrollback} \
+                 bytes of scrollback and {output} bytes of held back output"
+            ),
+        );
+    }
+
+    /// Registers a share link with the handshakes of joining clients, and tells the process
+    /// registering it whether that worked.
+    #[cfg(feature = "daemon")]
+    fn register_share(&mut self, request: ShareRequest) {
+        let result = if self.options.share_links {
+            self.joiner
+                .handshake()
+                .shares
+                .register(&request, &self.username)
+        } else {
+            Err(format_err!("session does not accept share links"))
+        };
+        match &result {
+            Ok(()) => println!(
+                "share link registered, valid for {}s{}",
+                request.valid_for().as_secs(),
+                if request.observer() {
+                    " to observe"
+                } else {
+                    ""
+                },
+            ),
+            Err(err) => crate::log::warn(
+                "share-denied",
+                format_args!("failed to register share link - {err}"),
+            ),
+        }
+        request.reply(&result);
+    }
+
+    /// Starts reading the requests of processes connecting to the control socket.
+    #[cfg(feature = "daemon")]
+    fn accept_requests(&mut self) {
+        while self.control_ready {
+            let Some(socket) = self.control_socket.as_mut() else {
+                break;
+            };
+            let stream = match socket.accept() {
+                Ok(Some(stream)) => stream,
+                Ok(None) => {
+                    self.control_ready = false;
+                    break;
+                }
+                Err(err) => {
+                    crate::log::warn(
+                        "accept-failed",
+                        format_args!("failed to accept on control socket - {err}"),
+                    );
+                    self.control_ready = false;
+                    break;
+                }
+            };
+            if let Err(err) = socket.receive(stream, self.joiner.waker()) {
+                crate::log::warn(
+                    "attach-failed",
+                    format_args!("failed to read from control socket - {err}"),
+                );
+            }
+        }
+    }
+
+    /// Takes over clients another termproxy authenticated and handed over via the control
+    /// socket.
+    #[cfg(feature = "daemon")]
+    fn accept_hand_overs(&mut self, joined: &mut Vec<Client>) {
+        while let Some(request) = self
+            .control_socket
+            .as_mut()
+            .and_then(ControlSocket::received)
+        {
+            let hand_over = match request {
+                Ok(Request::HandOver(hand_over)) => hand_over,
+                Ok(Request::Share(request)) => {
+                    self.register_share(request);
+                    continue;
+                }
+                Err(err) => {
+                    crate::log::warn(
+                        "attach-failed",
+                        format_args!("failed to take over client - {err}"),
+                    );
+                    continue;
+                }
+            };
+            if participants(&self.clients) + joined.len() >= self.options.max_clients {
+                crate::log::warn(
+                    "session-full",
+                    format_args!(
+                        "rejecting client, session has {} clients",
+                        participants(&self.clients),
+                    ),
+                );
+                continue;
+            }
+            // the other termproxy authenticated the client, for the session's ACL like clients
+            // joining on the listener, and as the session's user to attach to a session without
+            // clients
+            let user = String::from_utf8_lossy(&hand_over.username).into_owned();
+            if !hand_over.authorized_for(&self.options) {
+                crate::log::warn(
+                    "attach-denied",
+                    format_args!(
+                        "{user} was authenticated for {} ({}), not for this session",
+                        hand_over.acl_path, hand_over.acl_permission,
+                    ),
+                );
+                continue;
+            }
+            let first = participants(&self.clients) == 0 && joined.is_empty();
+            if (first || self.options.peer_user) && *hand_over.username != *self.username {
+                crate::log::warn(
+                    "attach-denied",
+                    format_args!("{user} cannot attach to the session of another user"),
+                );
+                continue;
+            }
+            let mut input = ByteBuffer::new();
+            queue_data(&mut input, &hand_over.input);
+            match attach_client(
+                ClientStream::Plain(hand_over.connection),
+                input,
+                &self.options,
+                None,
+                Token(self.next_token),
+                self.options.observers && !first,
+                None,
+            ) {
+                Ok(mut client) if !self.fits_memory(joined, &client) => {
+                    client.stream.close();
+                    crate::log::warn(
+                        "memory-limit",
+                        "rejecting client, its buffers would exceed --max-memory",
+                    );
+                }
+                Ok(client) => {
+                    println!("client of {user} attached to the session");
+                    self.next_token += 1;
+                    joined.push(client);
+                }
+                Err(err) => crate::log::warn(
+                    crate::log::error_code(&err),
+                    format_args!("client failed to attach - {err}"),
+                ),
+            }
+        }
+    }
+
+    /// Lets administrators connecting to the admin socket watch the session.
+    fn accept_admins(&mut self, joined: &mut Vec<Client>) {
+        while self.admin_ready {
+            let Some(socket) = &self.admin_socket else {
+                break;
+            };
+            let peer = match socket.accept() {
+                Ok(Some(peer)) => peer,
+                Ok(None) => {
+                    self.admin_ready = false;
+                    break;
+                }
+                Err(err) => {
+                    crate::log::warn(
+                        "accept-failed",
+                        format_args!("failed to accept administrator - {err}"),
+                    );
+                    self.admin_ready = false;
+                    break;
+                }
+            };
+            let user = format!("uid {}", peer.uid);
+            if let Err(err) = peer.authorize() {
+                let err = crate::log::with_code("admin-denied", err);
+                crate::seclog::record(
+                    "admin",
+                    "unix",
+                    Some(user.as_bytes()),
+                    &self.options,
+                    Some(&err),
+                );
+                crate::log::warn(
+                    "admin-denied",
+                    format_args!("rejecting administrator - {err}"),
+                );
+                continue;
+            }
+            crate::seclog::record("admin", "unix", Some(user.as_bytes()), &self.options, None);
+            match attach_client(
+                ClientStream::Plain(peer.connection),
+                ByteBuffer::new(),
+                &self.options,
+                None,
+                Token(self.next_token),
+                true,
+                Some(peer.uid),
+            ) {
+                Ok(client) => {
+                    println!("administrator ({user}) is watching the session");
+                    self.next_token += 1;
+                    joined.push(client);
+                }
+                Err(err) => crate::log::warn(
+                    crate::log::error_code(&err),
+                    format_args!("administrator failed to attach - {err}"),
+                ),
+            }
+        }
+    }
+
+    /// Adds clients that joined or attached to the session, resuming it for the first one.
+    fn add_clients(&mut self, joined: Vec<Client>) -> Result<()> {
+        for mut client in joined {
+            client.register(self.poll.registry())?;
+            if let Some(uid) = client.admin {
+                announce_admin(uid, true, &mut self.control_state, &mut self.tcp_buf);
+                self.clients.push(client);
+                continue;
+            }
+            if self
+                .clients
+                .iter()
+                .filter(|client| client.admin.is_none())
+                .all(|client| client.closed)
+            {
+                self.timers.cancel(&SessionTimer::Reconnect);
+                self.reconnect_expired = false;
+                thaw(&mut self.control_state);
+                let held = self.backlog.len() + self.tcp_buf.len();
+                let mut fields = vec![("held", held.to_string())];
+                if self.backlog.dropped() > 0 {
+                    fields.push(("dropped", self.backlog.dropped().to_string()));
+                }
+                let message = encode_control_message("resumed", &fields);
+                queue_message(&mut client.output, &message);
+            }
+            self.clients.push(client);
+            if self.options.max_clients > 1 {
+                announce_clients(&self.clients, &mut self.control_state, &mut self.tcp_buf);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_signals(&mut self) -> Result<()> {
+        while self.signal_ready {
+            match self.signals.read_signal() {
+                Ok(Some(info)) if info.ssi_signo == Signal::SIGUSR1 as u32 => {
+                    println!("detaching all clients");
+                    for client in self.clients.iter_mut() {
+                        client.closed = true;
+                    }
+                }
+                Ok(Some(info)) => {
+                    let signal = Signal::try_from(info.ssi_signo as i32)
+                        .map(Signal::as_str)
+                        .unwrap_or("signal");
+                    println!("received {signal}, ending the session");
+                    let _ = killpg(Pid::from_raw(self.child.id() as i32), Signal::SIGHUP);
+                    self.finished = true;
+                    self.ended_by = Some("terminated");
+                }
+                Ok(None) => self.signal_ready = false,
+                Err(err) => return Err(format_err!("error reading signals: {err}")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads what the clients sent into their input buffers.
+    fn read_clients(&mut self) -> Result<()> {
+        let ends_session = self.client_ends_session();
+        for client in self.clients.iter_mut() {
+            // clients that used up their allowance wait for the next interval
+            while client.ready.readable
+                && !client.input.is_full()
+                && client.input_allowance != Some(0)
+            {
+                let result = match client.input_allowance {
+                    Some(limit) => read_limited(&mut client.stream, &mut client.input, limit),
+                    None => client.input.read_from(&mut client.stream),
+                };
+                let bytes = match result {
+                    Ok(bytes) => bytes,
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                        client.ready.readable = false;
+                        break;
+                    }
+                    Err(err) => {
+                        if !self.finished {
+                            client
+                                .fail(format_err!("error reading from tcp: {err}"), ends_session)?;
+                        }
+                        break;
+                    }
+                };
+                if bytes == 0 {
+                    client.closed = true;
+                    break;
+                }
+                self.stats.from_client += bytes as u64;
+                self.stats.last_activity = SystemTime::now();
+                client.last_heard = Instant::now();
+                client.pinged = None;
+                if let Some(allowance) = client.input_allowance.as_mut() {
+                    *allowance -= bytes;
+                    if *allowance == 0 && !self.timers.is_pending(&SessionTimer::InputRate) {
+                        self.timers
+                            .set(SessionTimer::InputRate, INPUT_RATE_INTERVAL);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads output of the command into the buffer, through the filter of --sac, and no more
+    /// than allowed while throttled.
+    fn read_output(&mut self) -> std::io::Result<usize> {
+        let limit = self.control_state.throttle;
+        #[cfg(feature = "serial")]
+        if let Some(filter) = &mut self.sac_filter {
+            return filter.read_from(
+                &mut self.pty,
+                &mut self.tcp_buf,
+                limit.unwrap_or(usize::MAX),
+            );
+        }
+        match limit {
+            Some(limit) => read_limited(&mut self.pty, &mut self.tcp_buf, limit),
+            None => self.tcp_buf.read_from(&mut self.pty),
+        }
+    }
+
+    /// Reads the output of the command for the clients.
+    fn read_pty(&mut self) -> Result<()> {
+        // output is held back while locked, paused or throttled, the command blocks once the
+        // terminal is full
+        let paused = reading_paused(&self.clients, &self.backlog);
+        while self.pty_ready.readable && !self.tcp_buf.is_full() && !self.output_held() && !paused {
+            let bytes = match self.read_output() {
+                Ok(bytes) => bytes,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    self.pty_ready.readable = false;
+                    break;
+                }
+                Err(err) => {
+                    if !self.finished {
+                        return Err(format_err!("error reading from pty: {err}"));
+                    }
+                    break;
+                }
+            };
+            if bytes == 0 {
+                self.finished = true;
+                break;
+            }
+            self.timers.cancel(&SessionTimer::FirstOutput);
+            if let Some(limit) = self.control_state.throttle.as_mut() {
+                // translated SAC output can be longer than what was read
+                *limit = limit.saturating_sub(bytes);
+            }
+            let output = self.tcp_buf.len() - bytes..self.tcp_buf.len();
+            self.watch_output(output);
+        }
+        Ok(())
+    }
+
+    /// Whether output of the command is held back, also while finished lines of
+    /// --semantic-lines wait for room in the buffer, so they don't fall behind.
+    pub fn output_held(&self) -> bool {
+        self.control_state.output_held() || self.lines_pending()
+    }
+
+    fn lines_pending(&self) -> bool {
+        self.screen
+            .as_ref()
+            .is_some_and(|screen| screen.finished_line().is_some())
+    }
+
+    /// Queues the lines the command finished with --semantic-lines, as many as fit.
+    fn queue_lines(&mut self) {
+        let Some(screen) = self.screen.as_mut() else {
+            return;
+        };
+        while let Some(line) = screen.finished_line() {
+            let message =
+                encode_control_message("line", &[("data", base64_encode(line.as_bytes()))]);
+            if !queue_message(&mut self.tcp_buf, &message) {
+                break;
+            }
+            screen.take_finished_line();
+        }
+    }
+
+    /// Hands `output` just read into the buffer to everything looking at the command's output.
+    fn watch_output(&mut self, output: std::ops::Range<usize>) {
+        // control messages queued by the detectors end up behind the output
+        let options = &*self.options;
+        #[cfg(feature = "recording")]
+        if let Some(rec) = self.recorder.as_mut() {
+            let result = rec.output(&self.tcp_buf[output.clone()]);
+            check_recording(&mut self.recorder, result);
+        }
+        if let Some(hist) = self.history.as_mut() {
+            let result = hist.output(&self.tcp_buf[output.clone()]);
+            check_history(&mut self.history, result);
+        }
+        if let Some(screen) = self.screen.as_mut() {
+            screen.feed(&self.tcp_buf[output.clone()]);
+            // the screen may only be followed for --join-context
+            if let Some(interval) = options.snapshot_interval {
+                if !self.timers.is_pending(&SessionTimer::Snapshot) {
+                    self.timers.set(SessionTimer::Snapshot, interval);
+                }
+            }
+        }
+        if let Some(watchdog) = self.loop_watchdog.as_mut() {
+            watchdog.scan(&self.tcp_buf[output.clone()]);
+            if !self.timers.is_pending(&SessionTimer::LoopCheck) {
+                self.timers
+                    .set(SessionTimer::LoopCheck, crate::watchdog::CHECK_INTERVAL);
+            }
+        }
+        if let Some(detector) = self.prompt_detector.as_mut() {
+            let prompt = detector.scan(&self.tcp_buf[output.clone()]);
+            if let Some(prompt) = prompt.filter(|_| detector.answered < crate::prompt::MAX_ANSWERS)
+            {
+                if self.pty.reads_hidden_line().unwrap_or(false) {
+                    detector.clear();
+                    detector.answered += 1;
+                    answer_prompt(
+                        options,
+                        &prompt,
+                        &mut self.pty,
+                        &mut self.control_state,
+                        &mut self.tcp_buf,
+                    );
+                }
+            }
+        }
+        #[cfg(feature = "serial")]
+        if let Some(detector) = self.guest_detector.as_mut() {
+            if let Some(guest) = detector.scan(&self.tcp_buf[output.clone()]) {
+                let message = encode_control_message(
+                    "guest",
+                    &[
+                        ("os", guest.id().to_string()),
+                        ("label", guest.label().to_string()),
+                    ],
+                );
+                self.control_state.notify(&mut self.tcp_buf, &message);
+            }
+        }
+        if let Some(detector) = self.binary_detector.as_mut() {
+            match detector.scan(&self.tcp_buf[output]) {
+                Verdict::Binary if !self.control_state.binary_accepted => {
+                    pause_binary(&mut self.control_state, &mut self.tcp_buf);
+                }
+                Verdict::Text => self.control_state.binary_accepted = false,
+                _ => (),
+            }
+        }
+    }
+
+    /// Discards binary output a client asked to flush, until the command writes text again.
+    fn discard_binary(&mut self) -> Result<()> {
+        while self.pty_ready.readable && self.control_state.binary == BinaryOutput::Flushing {
+            let mut data = [0u8; 4096];
+            let bytes = match self.pty.read(&mut data) {
+                Ok(bytes) => bytes,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    self.pty_ready.readable = false;
+                    break;
+                }
+                Err(err) => {
+                    if !self.finished {
+                        return Err(format_err!("error reading from pty: {err}"));
+                    }
+                    break;
+                }
+            };
+            if bytes == 0 {
+                self.finished = true;
+                break;
+            }
+            self.control_state.binary_discarded += bytes as u64;
+            self.timers
+                .set(SessionTimer::BinaryFlush, BINARY_FLUSH_IDLE);
+            let text = self
+                .binary_detector
+                .as_mut()
+                .is_some_and(|detector| detector.scan(&data[..bytes]) == Verdict::Text);
+            if text && finish_binary_flush(&mut self.control_state, &mut self.tcp_buf) {
+                self.timers.cancel(&SessionTimer::BinaryFlush);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads what the command wrote to its stderr, with --child-stderr.
+    fn read_stderr(&mut self) -> Result<()> {
+        let paused = reading_paused(&self.clients, &self.backlog);
+        while self.stderr_ready.readable
+            && self.tcp_buf.free_size() >= MIN_STDERR_SPACE
+            && !self.output_held()
+            && !paused
+        {
+            let (Some(stderr), Some(mode)) =
+                (self.child_stderr.as_mut(), self.options.child_stderr)
+            else {
+                break;
+            };
+            // wrapped output at most doubles in size, and has to fit into the buffer as a whole
+            let mut data = [0u8; 1024];
+            let max = ((self.tcp_buf.free_size() - STDERR_OVERHEAD) / 2).min(data.len());
+            match stderr.read(&mut data[..max]) {
+                Ok(0) => {
+                    self.poll
+                        .registry()
+                        .deregister(&mut SourceFd(&stderr.as_raw_fd()))?;
+                    self.child_stderr = None;
+                    self.stderr_ready.readable = false;
+                }
+                Ok(bytes) => {
+                    queue_data(&mut self.tcp_buf, &wrap_child_stderr(mode, &data[..bytes]));
+                    self.timers.cancel(&SessionTimer::FirstOutput);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    self.stderr_ready.readable = false
+                }
+                Err(err) => return Err(format_err!("error reading from stderr pipe: {err}")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends each client what was queued for it.
+    fn write_clients(&mut self, timing: &mut Option<StartupTiming>) -> Result<()> {
+        let ends_session = self.client_ends_session();
+        for client in self.clients.iter_mut() {
+            client.queue_pending_reply();
+            while !client.output.is_empty() && client.ready.writable {
+                let len = min(client.output.len(), client.max_write);
+                let bytes = match client.stream.write(&client.output[..len]) {
+                    Ok(bytes) => bytes,
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                        client.ready.writable = false;
+                        break;
+                    }
+                    Err(err) => {
+                        if !self.finished {
+                            client
+                                .fail(format_err!("error writing to tcp : {err}"), ends_session)?;
+                        }
+                        break;
+                    }
+                };
+                self.stats.to_client += bytes as u64;
+                if let Some(timing) = timing {
+                    timing.mark(Step::FirstByte);
+                }
+                self.stats.last_activity = SystemTime::now();
+                client.output.consume(bytes);
+                client.queue_pending_reply();
+            }
+
+            if client.ready.writable && client.stream.has_pending_output() {
+                match client.stream.flush() {
+                    Ok(()) => (),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                        client.ready.writable = false
+                    }
+                    Err(err) => {
+                        if !self.finished {
+                            client
+                                .fail(format_err!("error writing to tcp : {err}"), ends_session)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the messages of the clients and writes their input to the terminal.
+    fn dispatch_input(&mut self) -> Result<()> {
+        let only_client = participants(&self.clients) == 1;
+        // taken out of the session while their input is handled, which may affect all of it
+        let mut clients = std::mem::take(&mut self.clients);
+        // input of several clients is merged message by message
+        let result = clients
+            .iter_mut()
+            .try_for_each(|client| self.relay_input(client, only_client));
+        self.clients = clients;
+        result
+    }
+
+    fn relay_input(&mut self, client: &mut Client, only_client: bool) -> Result<()> {
+        while !client.input.is_empty() && self.pty_ready.writable && client.pending_reply.is_none()
+        {
+            if client.discard > 0 {
+                let len = min(client.discard, client.input.len());
+                client.discard -= len;
+                client.input.consume(len);
+                continue;
+            }
+            if client.remaining == 0 {
+                client.remaining = match process_queue(&mut client.input) {
+                    Some(Message::Data(len)) => {
+                        if !client.observer {
+                            self.rearm_input_timers();
+                        }
+                        len
+                    }
+                    Some(Message::Discard(len)) => {
+                        client.discard = len;
+                        continue;
+                    }
+                    Some(message) => {
+                        self.handle_message(client, message);
+                        continue;
+                    }
+                    None => break,
+                };
+            }
+            let mut len = min(client.remaining, client.input.len());
+            if self.control_state.locked || client.observer {
+                // input typed at the lock screen or by observers doesn't reach the terminal
+                client.remaining -= len;
+                client.input.consume(len);
+                continue;
+            }
+            if let Some(escape) = client.escape.as_mut() {
+                match escape.scan(&client.input[..len]) {
+                    Scan::Pass(pass) => len = pass,
+                    Scan::Escape => {
+                        escape.hold();
+                        client.remaining -= 1;
+                        client.input.consume(1);
+                        continue;
+                    }
+                    Scan::Command(command) => {
+                        escape.release();
+                        client.remaining -= 1;
+                        client.input.consume(1);
+                        if self.handle_escape(client, command, only_client) {
+                            break;
+                        }
+                        continue;
+                    }
+                    Scan::Release => match self.pty.write(&[escape.escape_char()]) {
+                        Ok(1) => {
+                            escape.written(&[escape.escape_char()]);
+                            continue;
+                        }
+                        Ok(_) => continue,
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                            self.pty_ready.writable = false;
+                            break;
+                        }
+                        Err(err) => return Err(format_err!("error writing to pty : {err}")),
+                    },
+                }
+            }
+            let bytes = match self.pty.write(&client.input[..len]) {
+                Ok(bytes) => bytes,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    self.pty_ready.writable = false;
+                    break;
+                }
+                Err(err) => {
+                    if !self.finished {
+                        return Err(format_err!("error writing to pty : {err}"));
+                    }
+                    break;
+                }
+            };
+            if let Some(escape) = client.escape.as_mut() {
+                escape.written(&client.input[..bytes]);
+            }
+            if let Some(hist) = self.history.as_mut() {
+                let hidden = self.pty.reads_hidden_line().unwrap_or(false);
+                hist.input(&client.input[..bytes], hidden);
+            }
+            client.remaining -= bytes;
+            client.input.consume(bytes);
+        }
+        Ok(())
+    }
+
+    /// Input of a client restarts the timers of --lock-after and --idle-timeout.
+    fn rearm_input_timers(&mut self) {
+        if let (Some(lock_after), false) = (self.options.lock_after, self.control_state.locked) {
+            self.timers.set(SessionTimer::Lock, lock_after);
+        }
+        if let Some(timeout) = self.options.idle_timeout {
+            self.timers.set(SessionTimer::Idle, timeout);
+        }
+    }
+
+    /// Resizes the terminal to the size the clients' requests come down to, if that changed,
+    /// and tells all clients about it with --resize-policy.
+    fn arbitrate_size(&mut self) {
+        let Some((cols, rows)) = self.sizes.size() else {
+            return;
+        };
+        if self.pty.get_size().is_ok_and(|size| size == (cols, rows)) {
+            return;
+        }
+        let _ = self.pty.set_size(cols, rows);
+        if let Some(screen) = self.screen.as_mut() {
+            screen.resize(cols, rows);
+        }
+        if let Some(hist) = self.history.as_mut() {
+            hist.resize(cols, rows);
+        }
+        #[cfg(feature = "recording")]
+        if let Some(rec) = self.recorder.as_mut() {
+            let result = rec.resize(cols, rows);
+            check_recording(&mut self.recorder, result);
+        }
+        if let Some(policy) = self.options.resize_policy {
+            let message = encode_control_message(
+                "size",
+                &[
+                    ("cols", cols.to_string()),
+                    ("rows", rows.to_string()),
+                    ("policy", policy.as_str().to_string()),
+                ],
+            );
+            self.control_state.notify(&mut self.tcp_buf, &message);
+        }
+    }
+
+    /// Handles a message of `client` other than data.
+    fn handle_message(&mut self, client: &mut Client, message: Message) {
+        match message {
+            Message::Data(_) | Message::Discard(_) => (),
+            Message::Resize { .. } if client.observer => (),
+            Message::Resize { cols, rows } => {
+                self.sizes.request(client.token, cols, rows);
+                self.arbitrate_size();
+            }
+            Message::Ping => {
+                if self.options.keepalive.is_some() {
+                    queue_message(&mut client.output, &encode_control_message("pong", &[]));
+                }
+                if let Some(quality) = client.heartbeat.record_ping() {
+                    if self.options.quality_hints {
+                        let jitter = client.heartbeat.jitter().as_millis();
+                        let message = encode_control_message(
+                            "quality",
+                            &[
+                                ("level", quality.to_string()),
+                                ("jitter-ms", jitter.to_string()),
+                            ],
+                        );
+                        queue_message(&mut client.output, &message);
+                    }
+                }
+            }
+            // watching clients must not stall the session for everyone else
+            Message::FlowControl { .. } if client.observer => (),
+            Message::FlowControl { paused } => client.output_paused = paused,
+            // observers may watch the size of the terminal as well
+            Message::Control(ControlCommand::Size) => answer_size(&self.pty, &mut client.output),
+            Message::Control(_) if client.observer => (),
+            Message::Control(command) => {
+                if let Err(err) = self.handle_client_control(client, command) {
+                    crate::log::warn(
+                        "control-failed",
+                        format_args!("failed to handle control message - {err}"),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Handles a control message of a client taking part in the session.
+    fn handle_client_control(
+        &mut self,
+        client: &mut Client,
+        command: ControlCommand,
+    ) -> Result<()> {
+        let options = &*self.options;
+        match command {
+            ControlCommand::Unlock {
+                username: user,
+                ticket,
+            } => {
+                let ticket = Zeroizing::new(ticket);
+                let source = client.stream.connection().peer();
+                let result = unlock_session(
+                    options,
+                    &self.username,
+                    self.listen_port,
+                    &user,
+                    &ticket,
+                    &source,
+                    &mut self.control_state,
+                    &mut self.tcp_buf,
+                );
+                if let (Some(lock_after), false) = (options.lock_after, self.control_state.locked) {
+                    self.timers.set(SessionTimer::Lock, lock_after);
+                }
+                result
+            }
+            ControlCommand::Detach if options.detachable => {
+                println!("client detached");
+                client.closed = true;
+                Ok(())
+            }
+            ControlCommand::Detach => Err(format_err!("session is not detachable")),
+            _ if self.control_state.locked => Err(format_err!("session is locked")),
+            ControlCommand::BinaryFlush => flush_binary(&mut self.control_state, &mut self.timers),
+            ControlCommand::SysrqConfirm(key) => self.confirm_sysrq(key),
+            ControlCommand::Files { id, request } => {
+                client.reply(FileAccess::handle(self.file_access.as_ref(), &id, &request));
+                Ok(())
+            }
+            command => handle_control(
+                command,
+                options,
+                &mut self.pty,
+                &self.child,
+                &mut self.control_state,
+                &mut self.tcp_buf,
+            ),
+        }
+    }
+
+    /// Handles a command `client` typed after the escape character. Returns whether the rest
+    /// of its input is left alone, as it disconnected.
+    fn handle_escape(&mut self, client: &mut Client, command: u8, only_client: bool) -> bool {
+        // replies only go to the client that typed the command
+        let output = &mut client.output;
+        match command {
+            // other clients of a shared session stay connected, and a detachable session
+            // keeps running without its client
+            b'.' if !only_client || self.options.detachable => {
+                client.closed = true;
+                return true;
+            }
+            b'.' => {
+                self.finished = true;
+                self.ended_by = Some("disconnected");
+            }
+            b's' => {
+                let summary = self.stats.summary(&self.options.session_id);
+                queue_message(output, &format!("\r\n{summary}\r\n"));
+            }
+            b'B' => {
+                if let Err(err) = send_break(&self.options) {
+                    queue_message(output, &format!("\r\n{err}\r\n"));
+                }
+            }
+            b'r' | b'f' => {
+                let result = if command == b'r' {
+                    resume_binary(&mut self.control_state, &mut self.tcp_buf)
+                } else {
+                    flush_binary(&mut self.control_state, &mut self.timers)
+                };
+                if let Err(err) = result {
+                    queue_message(output, &format!("\r\n{err}\r\n"));
+                }
+            }
+            _ => {
+                if let Some(escape) = &client.escape {
+                    queue_message(output, &escape.help());
+                }
+            }
+        }
+        false
+    }
+
+    /// Starts the BREAK of a SysRq key the client confirmed, the key follows once it finished,
+    /// see [`Relay::finish_sysrq`].
+    fn confirm_sysrq(&mut self, key: u8) -> Result<()> {
+        match self.control_state.sysrq.take() {
+            Some((pending, deadline)) if pending == key && !deadline.is_expired() => (),
+            _ => bail!("no pending sysrq request for '{}'", char::from(key)),
+        }
+        if self.sysrq_break.is_some() {
+            bail!("the previous sysrq key is still being sent");
+        }
+        self.sysrq_break = Some(SysrqBreak::start(&self.options, key, self.joiner.waker())?);
+        self.timers
+            .set(SessionTimer::SysrqBreak, SYSRQ_BREAK_TIMEOUT);
+        Ok(())
+    }
+
+    /// Writes a confirmed SysRq key to the terminal once the break command finished.
+    fn finish_sysrq(&mut self) {
+        let Some(result) = self.sysrq_break.as_ref().and_then(SysrqBreak::finished) else {
+            return;
+        };
+        let Some(SysrqBreak { key, .. }) = self.sysrq_break.take() else {
+            return;
+        };
+        self.timers.cancel(&SessionTimer::SysrqBreak);
+        let result = result.and_then(|()| {
+            self.pty
+                .write_all(&[key])
+                .map_err(|err| format_err!("failed to write sysrq key - {err}"))
+        });
+        let state = match result {
+            Ok(()) => "sent",
+            Err(err) => {
+                crate::log::warn("break-failed", format_args!("{err}"));
+                "failed"
+            }
+        };
+        report_sysrq(key, state, &mut self.control_state, &mut self.tcp_buf);
+    }
+
+    /// Removes the clients that are gone, and decides how the session goes on without them.
+    fn remove_closed_clients(&mut self) {
+        if !self.clients.iter().any(|client| client.closed) {
+            return;
+        }
+        let participants_before = participants(&self.clients);
+        let (control_state, tcp_buf) = (&mut self.control_state, &mut self.tcp_buf);
+        let sizes = &mut self.sizes;
+        self.clients.retain(|client| {
+            if client.closed {
+                sizes.remove(client.token);
+                client.report_pings();
+                if let Some(uid) = client.admin {
+                    println!("administrator (uid {uid}) stopped watching the session");
+                    announce_admin(uid, false, control_state, tcp_buf);
+                }
+            }
+            !client.closed
+        });
+        // administrators leaving don't change anything else
+        let remaining = participants(&self.clients);
+        if remaining >= participants_before {
+            return;
+        }
+        let options = &*self.options;
+        if remaining == 0 && options.freeze_detached {
+            freeze(&self.pty, &self.child, &mut self.control_state);
+        }
+        if remaining == 0 && options.detachable {
+            println!("session detached, waiting for a client to attach");
+        } else if let (0, Some(grace)) = (remaining, options.reconnect_grace) {
+            println!(
+                "client disconnected, waiting {}s for it to reconnect",
+                grace.as_secs()
+            );
+            self.timers.set(SessionTimer::Reconnect, grace);
+        } else if remaining == 0 {
+            self.finished = true;
+            self.client_closed = true;
+        } else if options.max_clients > 1 {
+            announce_clients(&self.clients, &mut self.control_state, &mut self.tcp_buf);
+            // the size may have been the one of a client that left
+            self.arbitrate_size();
+        }
+    }
+
+    /// Ends the session once the relay loop is done, sending the clients what is left of the
+    /// output and why the session ended.
+    pub fn shut_down(&mut self) -> Result<()> {
+        crate::log::set_phase(Phase::Shutdown);
+
+        thaw(&mut self.control_state);
+        if let Some(pgrp) = self.control_state.suspended {
+            let _ = killpg(pgrp, Signal::SIGCONT);
+        }
+
+        if !self.client_closed {
+            let exit_status = if self.ended_by.is_some() {
+                None
+            } else {
+                wait_for_exit(&mut self.child, EXIT_WAIT_TIMEOUT)
+            };
+            let end_message = session_end_message(&self.stats, self.ended_by, exit_status);
+            self.poll
+                .registry()
+                .deregister(&mut SourceFd(&self.pty.as_raw_fd()))?;
+            let deadline = Deadline::after(DRAIN_TIMEOUT);
+            // every client gets what is left of the backlog, not just the first one
+            let backlog = self.backlog.take(self.backlog.len());
+            for client in self.clients.iter_mut() {
+                let mut output = client.output[..].to_vec();
+                output.extend_from_slice(&backlog);
+                output.extend_from_slice(&self.tcp_buf);
+                output.extend_from_slice(end_message.as_bytes());
+                if let Err(err) =
+                    drain_output(&mut self.poll, &mut client.stream, &output, deadline)
+                {
+                    crate::log::warn(
+                        "drain-failed",
+                        format_args!("failed to send remaining output - {err}"),
+                    );
+                }
+            }
+        }
+
+        // the command got a hangup, which it may well ignore
+        if self.ended_by == Some("terminated")
+            && wait_for_exit(&mut self.child, EXIT_WAIT_TIMEOUT).is_none()
+        {
+            let _ = killpg(Pid::from_raw(self.child.id() as i32), Signal::SIGTERM);
+        }
+
+        for client in &self.clients {
+            client.report_pings();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+//! Playing back recordings
+//!
+//! `replay` writes the output of a recording made with `--record` to its terminal, with the
+//! timing of the recorded session or sped up. Run as the command of a proxy session, the
+//! recording can be reviewed with the usual web frontend, behind the usual authentication.
+//! The format is detected from the content, asciicasts start with their JSON header.
+//! `serve-recordings` hands them out as asciicasts, converting ttyrec recordings.
+
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, format_err, Result};
+use nix::sys::termios::{tcgetattr, tcsetattr, OutputFlags, SetArg};
+
+use crate::cli::ReplayOptions;
+use crate::record::{event, take_text};
+
+/// Output of the recorded session, with its time since the start of the recording
+struct Frame {
+    time: Duration,
+    data: Vec<u8>,
+}
+
+/// Parses the events of an asciicast, only the output is of interest.
+fn parse_asciicast(content: &[u8]) -> Result<Vec<Frame>> {
+    let mut lines = content.split(|&b| b == b'\n');
+    let header: serde_json::Value = serde_json::from_slice(lines.next().unwrap_or_default())?;
+    if header["version"] != 2 {
+        bail!("unsupported asciicast version {}", header["version"]);
+    }
+
+    let mut frames = Vec::new();
+    for (num, line) in lines.enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let event: (f64, String, String) = match serde_json::from_slice(line) {
+            Ok(event) => event,
+            Err(err) => {
+                crate::log::warn(
+                    "recording-invalid",
+                    format_args!("stopping at invalid event on line {} - {err}", num + 2),
+                );
+                break;
+            }
+        };
+        if event.1 == "o" {
+            frames.push(Frame {
+                time: Duration::try_from_secs_f64(event.0).unwrap_or_default(),
+                data: event.2.into_bytes(),
+            });
+        }
+    }
+    Ok(frames)
+}
+
+/// Parses the frames of a ttyrec recording, whose times are relative to the epoch.
+fn parse_ttyrec(mut content: &[u8]) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    let mut start = None;
+    while content.len() >= 12 {
+        let value = |pos: usize| u32::from_le_bytes(content[pos..pos + 4].try_into().unwrap());
+        let time = Duration::new(value(0).into(), value(4).saturating_mul(1000));
+        let len = value(8) as usize;
+        let Some(data) = content.get(12..12 + len) else {
+            break;
+        };
+        let start = *start.get_or_insert(time);
+        frames.push(Frame {
+            time: time.saturating_sub(start),
+            data: data.to_vec(),
+        });
+        content = &content[12 + len..];
+    }
+    if !content.is_empty() {
+        // e.g. the recording session got killed
+        crate::log::warn("recording-invalid", "ignoring truncated frame at the end");
+    }
+    frames
+}
+
+fn read_recording(path: &Path) -> Result<Vec<Frame>> {
+    let content =
+        std::fs::read(path).map_err(|err| format_err!("failed to read {path:?} - {err}"))?;
+    if content.first() == Some(&b'{') {
+        if let Ok(frames) = parse_asciicast(&content) {
+            return Ok(frames);
+        }
+    }
+    Ok(parse_ttyrec(&content))
+}
+
+/// Converts a recording to an asciicast, asciicasts are returned as they are.
+///
+/// ttyrec recordings don't know the size of the terminal, they get the usual 80x24.
+pub fn to_asciicast(content: &[u8]) -> Result<Vec<u8>> {
+    if content.first() == Some(&b'{') && parse_asciicast(content).is_ok() {
+        return Ok(content.to_vec());
+    }
+
+    let mut header = serde_json::json!({ "version": 2, "width": 80, "height": 24 });
+    if let Some(start) = content.get(..4) {
+        header["timestamp"] = u32::from_le_bytes(start.try_into().unwrap()).into();
+    }
+    let mut cast = serde_json::to_vec(&header)?;
+    cast.push(b'\n');
+    let mut partial = Vec::new();
+    for frame in parse_ttyrec(content) {
+        partial.extend_from_slice(&frame.data);
+        let text = take_text(&mut partial);
+        if !text.is_empty() {
+            serde_json::to_writer(&mut cast, &event(frame.time, "o", text))?;
+            cast.push(b'\n');
+        }
+    }
+    Ok(cast)
+}
+
+pub fn replay(options: &ReplayOptions) -> Result<()> {
+    let frames = read_recording(&options.file)?;
+
+    // the recorded output was already processed by a terminal, e.g. line feeds come with their
+    // carriage returns
+    let stdout = std::io::stdout();
+    let termios = tcgetattr(stdout.as_raw_fd()).ok();
+    if let Some(termios) = &termios {
+        let mut raw = termios.clone();
+        raw.output_flags.remove(OutputFlags::OPOST);
+        tcsetattr(stdout.as_raw_fd(), SetArg::TCSADRAIN, &raw)?;
+    }
+
+    let start = Instant::now();
+    let mut out = stdout.lock();
+    let result = frames.iter().try_for_each(|frame| {
+        let due = start + frame.time.div_f64(options.speed);
+        std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        out.write_all(&frame.data)?;
+        out.flush()
+    });
+
+    if let Some(termios) = &termios {
+        let _ = tcsetattr(stdout.as_raw_fd(), SetArg::TCSADRAIN, termios);
+    }
+    Ok(result?)
+}
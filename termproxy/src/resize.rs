@@ -0,0 +1,87 @@
+//! The size of the terminal of a shared session
+//!
+//! Every client of a shared session has a window of its own and asks for the terminal to match
+//! it. Instead of whichever resize arrives last winning, the arbiter keeps the size each client
+//! asked for and decides on one with the session's [`ResizePolicy`], which also gives the
+//! terminal a sensible size again once a client left.
+
+use mio::Token;
+
+use crate::cli::ResizePolicy;
+
+pub struct SizeArbiter {
+    policy: ResizePolicy,
+    /// The size each client asked for last, the client that resized last at the end
+    requests: Vec<(Token, u16, u16)>,
+}
+
+impl SizeArbiter {
+    pub fn new(policy: ResizePolicy) -> Self {
+        Self {
+            policy,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Records that the client `token` asked for `cols` x `rows`.
+    pub fn request(&mut self, token: Token, cols: u16, rows: u16) {
+        self.remove(token);
+        self.requests.push((token, cols, rows));
+    }
+
+    /// Forgets the size the client `token` asked for, once it left.
+    pub fn remove(&mut self, token: Token) {
+        self.requests.retain(|(client, _, _)| *client != token);
+    }
+
+    /// The size the terminal should have, if any client asked for one.
+    pub fn size(&self) -> Option<(u16, u16)> {
+        let size = |(_, cols, rows): &(Token, u16, u16)| (*cols, *rows);
+        match self.policy {
+            ResizePolicy::Last => self.requests.last().map(size),
+            ResizePolicy::Largest => {
+                self.requests
+                    .iter()
+                    .map(size)
+                    .reduce(|(max_cols, max_rows), (cols, rows)| {
+                        (max_cols.max(cols), max_rows.max(rows))
+                    })
+            }
+            // tokens are handed out in order, the lowest one is the client attached longest
+            ResizePolicy::Primary => self
+                .requests
+                .iter()
+                .min_by_key(|(token, _, _)| *token)
+                .map(size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arbitrates_sizes() {
+        let mut last = SizeArbiter::new(ResizePolicy::Last);
+        let mut largest = SizeArbiter::new(ResizePolicy::Largest);
+        let mut primary = SizeArbiter::new(ResizePolicy::Primary);
+        for arbiter in [&mut last, &mut largest, &mut primary] {
+            assert_eq!(arbiter.size(), None);
+            arbiter.request(Token(3), 80, 40);
+            arbiter.request(Token(2), 120, 24);
+            arbiter.request(Token(4), 100, 30);
+        }
+        assert_eq!(last.size(), Some((100, 30)));
+        assert_eq!(largest.size(), Some((120, 40)));
+        assert_eq!(primary.size(), Some((120, 24)));
+
+        for arbiter in [&mut last, &mut largest, &mut primary] {
+            arbiter.remove(Token(2));
+            arbiter.request(Token(3), 90, 35);
+        }
+        assert_eq!(last.size(), Some((90, 35)));
+        assert_eq!(largest.size(), Some((100, 35)));
+        assert_eq!(primary.size(), Some((90, 35)));
+    }
+}
@@ -0,0 +1,85 @@
+//! Conveniences for Windows' Special Administration Console
+//!
+//! The Emergency Management Services of Windows offer the Special Administration Console (SAC)
+//! and command prompt channels on a serial port. Their output doesn't always suit a terminal:
+//! line feeds come without carriage returns and some programs write UTF-16LE text. With `--sac`
+//! the output is translated as far as it can be told apart, and the client can switch channels
+//! with control messages instead of typing ESC TAB sequences.
+//!
+//! Terminals ignore NUL bytes, so a byte followed by NUL is taken as a UTF-16LE code unit.
+//! That covers ASCII and Latin-1 text, anything beyond stays garbled.
+
+use std::io::Read;
+
+use crate::compat::ByteBuffer;
+
+/// Switches to the next channel.
+pub const NEXT_CHANNEL: &[u8] = b"\x1b\t";
+
+/// Switches back to the SAC channel.
+pub const SAC_CHANNEL: &[u8] = b"\x1b\t0";
+
+#[derive(Default)]
+pub struct SacFilter {
+    /// The last byte that was translated, besides NUL
+    previous: u8,
+    /// A non-ASCII byte at the end of the last read, which may turn out to be UTF-16
+    held: Option<u8>,
+    /// Translated output that didn't fit into the buffer anymore
+    pending: Vec<u8>,
+}
+
+impl SacFilter {
+    fn translate(&mut self, data: &[u8]) {
+        let mut bytes = self.held.take().into_iter().chain(data.iter().copied());
+        let mut next = bytes.next();
+        while let Some(byte) = next {
+            next = bytes.next();
+            match (byte, next) {
+                (0, _) => continue,
+                // the byte order mark of UTF-16LE
+                (0xff, Some(0xfe)) => next = bytes.next(),
+                (0x80.., Some(0)) => {
+                    let mut utf8 = [0; 2];
+                    let len = char::from(byte).encode_utf8(&mut utf8).len();
+                    self.pending.extend_from_slice(&utf8[..len]);
+                    next = bytes.next();
+                }
+                (0x80.., None) => {
+                    self.held = Some(byte);
+                    break;
+                }
+                (b'\n', _) if self.previous != b'\r' => self.pending.extend_from_slice(b"\r\n"),
+                _ => self.pending.push(byte),
+            }
+            self.previous = byte;
+        }
+    }
+
+    /// Reads output of the console into `buf` translated for the client, reading at most
+    /// `limit` bytes.
+    pub fn read_from(
+        &mut self,
+        reader: &mut impl Read,
+        buf: &mut ByteBuffer,
+        limit: usize,
+    ) -> std::io::Result<usize> {
+        loop {
+            if !self.pending.is_empty() {
+                let len = self.pending.len().min(buf.free_size());
+                buf.read_from(&mut &self.pending[..len])?;
+                self.pending.drain(..len);
+                return Ok(len);
+            }
+
+            let mut data = [0u8; 4096];
+            let max = limit.min(buf.free_size()).min(data.len());
+            let bytes = reader.read(&mut data[..max])?;
+            if bytes == 0 {
+                return Ok(0);
+            }
+            // a read of nothing but NUL bytes leaves nothing to queue, go on reading
+            self.translate(&data[..bytes]);
+        }
+    }
+}
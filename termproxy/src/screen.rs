@@ -0,0 +1,589 @@
+//! Tracking the terminal's screen for snapshots
+//!
+//! With `--snapshot-interval`, termproxy follows what the command draws on the screen and
+//! periodically writes it as plain text to `<session-id>.snapshot` in the status directory, so
+//! dashboards can show a preview of each console without attaching a client. The command
+//! history of `--command-history` reads the commands off the screen as well. With
+//! `--join-context`, the screen and the lines scrolled off it are what clients joining a shared
+//! session get to catch up. With `--semantic-lines`, the lines the command finishes on the normal
+//! screen are passed on to the clients as plain text, for screen readers and the like.
+//!
+//! The screen model is a small subset of what xterm.js implements: text, line breaks, cursor
+//! movement, erasing, inserting and deleting, scroll regions and the alternate screen. Colors and
+//! other attributes are ignored, and every character takes a single cell, which is good enough
+//! for a thumbnail but not for an exact copy.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{format_err, Result};
+
+/// The maximal length of the parameters of a control sequence, longer ones are garbage.
+const MAX_PARAMS: usize = 64;
+
+/// The maximal length of a finished line in bytes, longer ones are split, so that each fits
+/// into a control message.
+const MAX_LINE: usize = 2048;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Ground,
+    Escape,
+    /// A control sequence, `ESC [` followed by parameters and a final byte
+    Csi,
+    /// An operating system command like a window title, up to BEL or ST
+    Osc,
+    OscEscape,
+    /// The character set designation following `ESC (` and friends
+    Charset,
+}
+
+pub struct Screen {
+    cols: usize,
+    rows: usize,
+    lines: Vec<Vec<char>>,
+    row: usize,
+    col: usize,
+    /// The last column was written, the next character goes to the next line
+    wrap_pending: bool,
+    saved_cursor: (usize, usize),
+    /// The scroll region, first and last line
+    top: usize,
+    bottom: usize,
+    /// The lines of the normal screen while the alternate screen is shown
+    normal_lines: Option<Vec<Vec<char>>>,
+    state: State,
+    params: Vec<u8>,
+    /// The beginning of a UTF-8 sequence split between reads
+    utf8: Vec<u8>,
+    /// Whether the screen changed since the last snapshot
+    changed: bool,
+    /// The lines scrolled off the top of the normal screen, the oldest first
+    scrollback: VecDeque<String>,
+    /// How many lines scrolled off the screen are kept
+    max_scrollback: usize,
+    /// The lines finished on the normal screen, if they are followed
+    line_log: Option<LineLog>,
+}
+
+/// The lines of text the command finished, with rows wrapped at the end joined again
+#[derive(Default)]
+struct LineLog {
+    /// The rows of a line still going on
+    current: String,
+    finished: VecDeque<String>,
+}
+
+impl Screen {
+    pub fn new(cols: u16, rows: u16) -> Self {
+        let (cols, rows) = (usize::from(cols.max(1)), usize::from(rows.max(1)));
+        Self {
+            cols,
+            rows,
+            lines: vec![vec![' '; cols]; rows],
+            row: 0,
+            col: 0,
+            wrap_pending: false,
+            saved_cursor: (0, 0),
+            top: 0,
+            bottom: rows - 1,
+            normal_lines: None,
+            state: State::Ground,
+            params: Vec::new(),
+            utf8: Vec::new(),
+            changed: true,
+            scrollback: VecDeque::new(),
+            max_scrollback: 0,
+            line_log: None,
+        }
+    }
+
+    /// Keeps up to `lines` lines scrolled off the screen.
+    pub fn with_scrollback(mut self, lines: usize) -> Self {
+        self.max_scrollback = lines;
+        self
+    }
+
+    /// Follows the lines the command finishes, see [`Screen::finished_line`].
+    pub fn with_line_log(mut self) -> Self {
+        self.line_log = Some(LineLog::default());
+        self
+    }
+
+    /// Changes the size of the screen, keeping the lines around the cursor.
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        let (cols, rows) = (usize::from(cols.max(1)), usize::from(rows.max(1)));
+        if self.row >= rows {
+            let lines: Vec<_> = self.lines.drain(..self.row + 1 - rows).collect();
+            if self.normal_lines.is_none() {
+                self.keep_scrolled_off(lines);
+            }
+            self.row = rows - 1;
+        }
+        self.lines.resize(rows, Vec::new());
+        for line in self.lines.iter_mut() {
+            line.resize(cols, ' ');
+        }
+        if let Some(lines) = self.normal_lines.as_mut() {
+            lines.resize(rows, Vec::new());
+            for line in lines.iter_mut() {
+                line.resize(cols, ' ');
+            }
+        }
+        self.cols = cols;
+        self.rows = rows;
+        self.col = self.col.min(cols - 1);
+        self.wrap_pending = false;
+        self.top = 0;
+        self.bottom = rows - 1;
+        self.changed = true;
+    }
+
+    /// Applies output of the command to the screen.
+    pub fn feed(&mut self, data: &[u8]) {
+        for &byte in data {
+            match self.state {
+                State::Ground => self.ground(byte),
+                State::Escape => self.escape(byte),
+                State::Csi => match byte {
+                    0x20..=0x3f if self.params.len() < MAX_PARAMS => self.params.push(byte),
+                    0x20..=0x3f => (),
+                    0x40..=0x7e => {
+                        self.state = State::Ground;
+                        self.csi(byte);
+                    }
+                    0x1b => self.state = State::Escape,
+                    _ => (),
+                },
+                State::Osc => match byte {
+                    0x07 => self.state = State::Ground,
+                    0x1b => self.state = State::OscEscape,
+                    _ => (),
+                },
+                // ST or the beginning of another sequence ends the command alike
+                State::OscEscape | State::Charset => self.state = State::Ground,
+            }
+        }
+        self.changed = true;
+    }
+
+    /// Returns the screen as text if it changed since the last time.
+    pub fn take_snapshot(&mut self) -> Option<String> {
+        if !std::mem::take(&mut self.changed) {
+            return None;
+        }
+        Some(self.text())
+    }
+
+    /// The screen as text, a line per row.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for line in &self.lines {
+            let line: String = line.iter().collect();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+
+    /// The lines scrolled off the screen as text, up to the number given to
+    /// [`Screen::with_scrollback`].
+    pub fn scrollback(&self) -> String {
+        let mut text = String::new();
+        for line in &self.scrollback {
+            text.push_str(line);
+            text.push('\n');
+        }
+        text
+    }
+
+    /// The size of the text of the lines scrolled off the screen, in bytes.
+    pub fn scrollback_size(&self) -> usize {
+        self.scrollback.iter().map(|line| line.len() + 1).sum()
+    }
+
+    /// Drops the oldest lines scrolled off the screen until at least `size` bytes are freed,
+    /// or none are left, returns how much was freed.
+    pub fn drop_scrollback(&mut self, size: usize) -> usize {
+        let mut freed = 0;
+        while freed < size {
+            let Some(line) = self.scrollback.pop_front() else {
+                break;
+            };
+            freed += line.len() + 1;
+        }
+        freed
+    }
+
+    /// The oldest line finished on the normal screen not taken yet, with trailing blanks
+    /// removed.
+    pub fn finished_line(&self) -> Option<&str> {
+        let log = self.line_log.as_ref()?;
+        log.finished.front().map(String::as_str)
+    }
+
+    /// Takes the line returned by [`Screen::finished_line`].
+    pub fn take_finished_line(&mut self) {
+        if let Some(log) = self.line_log.as_mut() {
+            log.finished.pop_front();
+        }
+    }
+
+    /// The row and column of the cursor.
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+
+    /// The text of the cursor's row left of the cursor, `None` on the alternate screen.
+    pub fn before_cursor(&self) -> Option<String> {
+        if self.normal_lines.is_some() {
+            return None;
+        }
+        let end = if self.wrap_pending {
+            self.cols
+        } else {
+            self.col
+        };
+        Some(self.lines[self.row][..end].iter().collect())
+    }
+
+    /// The rows from the top down to the cursor's row, `None` on the alternate screen.
+    pub fn rows_to_cursor(&self) -> Option<Vec<String>> {
+        if self.normal_lines.is_some() {
+            return None;
+        }
+        Some(
+            self.lines[..=self.row]
+                .iter()
+                .map(|line| line.iter().collect())
+                .collect(),
+        )
+    }
+
+    fn ground(&mut self, byte: u8) {
+        match byte {
+            0x1b => self.state = State::Escape,
+            b'\r' => {
+                self.col = 0;
+                self.wrap_pending = false;
+            }
+            b'\n' | 0x0b | 0x0c => self.line_feed(),
+            0x08 => {
+                self.col = self.col.saturating_sub(1);
+                self.wrap_pending = false;
+            }
+            b'\t' => self.col = ((self.col / 8 + 1) * 8).min(self.cols - 1),
+            0x00..=0x1f | 0x7f => (),
+            0x20..=0x7e => self.print(byte as char),
+            _ => {
+                self.utf8.push(byte);
+                match std::str::from_utf8(&self.utf8) {
+                    Ok(text) => {
+                        let c = text.chars().next().unwrap_or(' ');
+                        self.utf8.clear();
+                        self.print(c);
+                    }
+                    // anything else shows up as a placeholder
+                    Err(err) if err.error_len().is_some() || self.utf8.len() >= 4 => {
+                        self.utf8.clear();
+                        self.print(char::REPLACEMENT_CHARACTER);
+                    }
+                    Err(_) => (),
+                }
+            }
+        }
+    }
+
+    fn escape(&mut self, byte: u8) {
+        self.state = State::Ground;
+        match byte {
+            b'[' => {
+                self.params.clear();
+                self.state = State::Csi;
+            }
+            b']' => self.state = State::Osc,
+            b'(' | b')' | b'*' | b'+' => self.state = State::Charset,
+            b'7' => self.saved_cursor = (self.row, self.col),
+            b'8' => self.restore_cursor(),
+            b'D' => self.line_feed(),
+            b'E' => {
+                self.col = 0;
+                self.line_feed();
+            }
+            b'M' => self.reverse_index(),
+            // a reset clears the screen, not what scrolled off it
+            b'c' => {
+                let scrollback = std::mem::take(&mut self.scrollback);
+                let line_log = self.line_log.take();
+                *self = Self::new(self.cols as u16, self.rows as u16)
+                    .with_scrollback(self.max_scrollback);
+                self.scrollback = scrollback;
+                self.line_log = line_log;
+            }
+            _ => (),
+        }
+    }
+
+    fn csi(&mut self, command: u8) {
+        let private = self.params.first() == Some(&b'?');
+        let params: Vec<usize> = String::from_utf8_lossy(&self.params)
+            .trim_start_matches('?')
+            .split(';')
+            .map(|param| param.parse().unwrap_or(0))
+            .collect();
+        let param = |index: usize| params.get(index).copied().unwrap_or(0);
+        // counts and positions of 0 mean 1
+        let count = param(0).max(1);
+        self.wrap_pending = false;
+        match command {
+            b'A' => self.row = self.row.saturating_sub(count),
+            b'B' => self.row = self.row.saturating_add(count).min(self.rows - 1),
+            b'C' => self.col = self.col.saturating_add(count).min(self.cols - 1),
+            b'D' => self.col = self.col.saturating_sub(count),
+            b'E' => {
+                self.row = self.row.saturating_add(count).min(self.rows - 1);
+                self.col = 0;
+            }
+            b'F' => {
+                self.row = self.row.saturating_sub(count);
+                self.col = 0;
+            }
+            b'G' | b'`' => self.col = (count - 1).min(self.cols - 1),
+            b'd' => self.row = (count - 1).min(self.rows - 1),
+            b'H' | b'f' => {
+                self.row = (count - 1).min(self.rows - 1);
+                self.col = (param(1).max(1) - 1).min(self.cols - 1);
+            }
+            b'J' => match param(0) {
+                0 => {
+                    self.erase_line(self.row, self.col, self.cols);
+                    for row in self.row + 1..self.rows {
+                        self.erase_line(row, 0, self.cols);
+                    }
+                }
+                1 => {
+                    for row in 0..self.row {
+                        self.erase_line(row, 0, self.cols);
+                    }
+                    self.erase_line(self.row, 0, self.col + 1);
+                }
+                _ => {
+                    for row in 0..self.rows {
+                        self.erase_line(row, 0, self.cols);
+                    }
+                }
+            },
+            b'K' => match param(0) {
+                0 => self.erase_line(self.row, self.col, self.cols),
+                1 => self.erase_line(self.row, 0, self.col + 1),
+                _ => self.erase_line(self.row, 0, self.cols),
+            },
+            b'X' => self.erase_line(self.row, self.col, self.col.saturating_add(count)),
+            b'@' => {
+                let line = &mut self.lines[self.row];
+                for _ in 0..count.min(self.cols - self.col) {
+                    line.insert(self.col, ' ');
+                    line.pop();
+                }
+            }
+            b'P' => {
+                let line = &mut self.lines[self.row];
+                for _ in 0..count.min(self.cols - self.col) {
+                    line.remove(self.col);
+                    line.push(' ');
+                }
+            }
+            b'L' if (self.top..=self.bottom).contains(&self.row) => {
+                self.scroll_down(self.row, count)
+            }
+            b'M' if (self.top..=self.bottom).contains(&self.row) => self.scroll_up(self.row, count),
+            b'S' => self.scroll_up(self.top, count),
+            b'T' if !private => self.scroll_down(self.top, count),
+            b'r' if !private => {
+                let top = count - 1;
+                let bottom = match param(1) {
+                    0 => self.rows - 1,
+                    bottom => bottom.min(self.rows) - 1,
+                };
+                if top < bottom {
+                    (self.top, self.bottom) = (top, bottom);
+                    (self.row, self.col) = (0, 0);
+                }
+            }
+            b's' if !private => self.saved_cursor = (self.row, self.col),
+            b'u' if !private => self.restore_cursor(),
+            b'h' | b'l' if private => {
+                let alternate = params.iter().any(|&mode| matches!(mode, 47 | 1047 | 1049));
+                if alternate {
+                    self.switch_screen(command == b'h');
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn print(&mut self, c: char) {
+        if self.wrap_pending {
+            self.col = 0;
+            self.log_row(true);
+            self.wrap_pending = false;
+            self.move_down();
+        }
+        self.lines[self.row][self.col] = c;
+        if self.col + 1 == self.cols {
+            self.wrap_pending = true;
+        } else {
+            self.col += 1;
+        }
+    }
+
+    /// Moves the cursor back to where it was saved, as far as it still fits on the screen.
+    fn restore_cursor(&mut self) {
+        let (row, col) = self.saved_cursor;
+        self.row = row.min(self.rows - 1);
+        self.col = col.min(self.cols - 1);
+        self.wrap_pending = false;
+    }
+
+    fn line_feed(&mut self) {
+        self.log_row(false);
+        self.wrap_pending = false;
+        self.move_down();
+    }
+
+    /// Moves the cursor to the next row, scrolling at the end of the scroll region.
+    fn move_down(&mut self) {
+        if self.row == self.bottom {
+            self.scroll_up(self.top, 1);
+        } else if self.row + 1 < self.rows {
+            self.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        if self.row == self.top {
+            self.scroll_down(self.top, 1);
+        } else {
+            self.row = self.row.saturating_sub(1);
+        }
+    }
+
+    /// Moves the lines from `from` to the end of the scroll region up by `count` lines.
+    fn scroll_up(&mut self, from: usize, count: usize) {
+        let count = count.min(self.bottom + 1 - from);
+        let lines: Vec<_> = self.lines.drain(from..from + count).collect();
+        // only lines leaving the top of the whole screen scroll back, like in xterm.js
+        if from == 0 && self.normal_lines.is_none() {
+            self.keep_scrolled_off(lines);
+        }
+        let blank = vec![' '; self.cols];
+        for _ in 0..count {
+            self.lines.insert(self.bottom + 1 - count, blank.clone());
+        }
+    }
+
+    /// Moves the lines from `from` to the end of the scroll region down by `count` lines.
+    fn scroll_down(&mut self, from: usize, count: usize) {
+        let count = count.min(self.bottom + 1 - from);
+        self.lines.drain(self.bottom + 1 - count..=self.bottom);
+        let blank = vec![' '; self.cols];
+        for _ in 0..count {
+            self.lines.insert(from, blank.clone());
+        }
+    }
+
+    /// Adds the cursor's row to the line log, finishing the line unless it `continues` on the
+    /// next row. Full screen programs on the alternate screen don't write lines.
+    fn log_row(&mut self, continues: bool) {
+        if self.normal_lines.is_some() {
+            return;
+        }
+        let Some(log) = self.line_log.as_mut() else {
+            return;
+        };
+        for &c in &self.lines[self.row] {
+            if log.current.len() + c.len_utf8() > MAX_LINE {
+                let line = std::mem::take(&mut log.current);
+                log.finished.push_back(line);
+            }
+            log.current.push(c);
+        }
+        if !continues {
+            let line = std::mem::take(&mut log.current);
+            log.finished.push_back(line.trim_end().to_string());
+        }
+    }
+
+    /// Adds lines scrolled off the top to the scrollback, dropping the oldest beyond its size.
+    fn keep_scrolled_off(&mut self, lines: Vec<Vec<char>>) {
+        if self.max_scrollback == 0 {
+            return;
+        }
+        for line in lines {
+            let line: String = line.iter().collect();
+            self.scrollback.push_back(line.trim_end().to_string());
+        }
+        let excess = self.scrollback.len().saturating_sub(self.max_scrollback);
+        self.scrollback.drain(..excess);
+    }
+
+    fn erase_line(&mut self, row: usize, from: usize, to: usize) {
+        let to = to.min(self.cols);
+        if from < to {
+            self.lines[row][from..to].fill(' ');
+        }
+    }
+
+    fn switch_screen(&mut self, alternate: bool) {
+        let blank = vec![vec![' '; self.cols]; self.rows];
+        match (alternate, self.normal_lines.take()) {
+            (true, None) => {
+                self.normal_lines = Some(std::mem::replace(&mut self.lines, blank));
+                self.saved_cursor = (self.row, self.col);
+            }
+            (false, Some(lines)) => {
+                self.lines = lines;
+                self.restore_cursor();
+            }
+            (_, lines) => self.normal_lines = lines,
+        }
+    }
+}
+
+/// The snapshot file of a session, `<session-id>.snapshot` in the status directory
+pub struct SnapshotFile {
+    path: PathBuf,
+}
+
+impl SnapshotFile {
+    pub fn new(dir: &Path, session_id: &str) -> Self {
+        Self {
+            path: dir.join(format!("{session_id}.snapshot")),
+        }
+    }
+
+    /// Replaces the snapshot file with `text`.
+    ///
+    /// The screen may show anything the user typed or was shown, so the file is only readable
+    /// by its owner.
+    pub fn write(&self, text: &str) -> Result<()> {
+        let tmp_path = self.path.with_extension("snapshot.tmp");
+        let mut file = std::fs::File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(text.as_bytes())?;
+        std::fs::rename(&tmp_path, &self.path)
+            .map_err(|err| format_err!("failed to update {:?} - {err}", self.path))
+    }
+}
+
+impl Drop for SnapshotFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
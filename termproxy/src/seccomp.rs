@@ -0,0 +1,490 @@
+//! Restricting the proxy's system calls
+//!
+//! With `--seccomp`, once the listener is bound, the first client is authenticated and the
+//! command runs, termproxy installs a seccomp filter on itself that only allows the system calls
+//! the relay needs from then on: reading and writing, polling, accepting further clients,
+//! signalling the command and starting threads. Everything else, most notably running programs,
+//! fails with EPERM, so a compromised proxy cannot do much beyond what it relays already.
+//!
+//! What else is allowed depends on the features of the session, see [`Needs`]: opening files
+//! only with a status directory, the file browser, crash reports or utmp, otherwise only reading
+//! for resolving host names and looking up users, and connecting only to authenticate further
+//! clients with the API daemon, over IP or Unix sockets. Signals only go to the process group of
+//! the command.
+//!
+//! Features that run programs while the session runs, like `--break-command`, or that leave
+//! it to PAM modules what they do, can't be combined with it.
+
+use crate::cli::{Options, PortOrFd};
+
+/// What the relay does while the session runs, besides relaying.
+pub struct Needs {
+    /// The process group of the command, the only one signals can be sent to
+    pub command_group: i32,
+    /// Whether files are opened, written and renamed, for the status directory, the file
+    /// browser, crash reports and utmp
+    pub files: bool,
+    /// Whether files are removed, like the sockets of the session once it ends
+    pub removing: bool,
+    /// Whether configuration files are read, like /etc/hosts to resolve host names or
+    /// /etc/passwd to look up users
+    pub reading: bool,
+    /// Whether further clients are authenticated with the API daemon over HTTP
+    pub network: bool,
+}
+
+impl Needs {
+    /// What the session started with `options` needs, with the command in `command_group`.
+    pub fn new(options: &Options, command_group: i32) -> Self {
+        let files = options.status_dir.is_some()
+            || options.files_root.is_some()
+            || options.crash_dir.is_some()
+            || options.utmp;
+        let network =
+            cfg!(feature = "auth-http") && options.preauthenticated.is_none() && !options.peer_user;
+        Self {
+            command_group,
+            files,
+            removing: files
+                || options.detachable
+                || options.share_links
+                || options.cgroup_parent.is_some()
+                || matches!(options.listen_port, PortOrFd::Unix(_)),
+            reading: network || options.peer_user,
+            network,
+        }
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub use filter::install;
+
+/// The list of system calls is only checked for the architectures above.
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+pub fn install(_needs: &Needs) -> anyhow::Result<()> {
+    anyhow::bail!("seccomp filters are not supported on this architecture");
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod filter {
+    use anyhow::{bail, Result};
+
+    use super::Needs;
+
+    // constants of linux/filter.h and linux/seccomp.h, not all of them bound by older libc versions
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JMP_JEQ_K: u16 = 0x15;
+    const BPF_JMP_JGE_K: u16 = 0x35;
+    const BPF_JMP_JSET_K: u16 = 0x45;
+    const BPF_RET_K: u16 = 0x06;
+
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+    const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+
+    // offsets into struct seccomp_data, the arguments are 64 bits wide and the architectures
+    // above little endian
+    const DATA_NR: u32 = 0;
+    const DATA_ARCH: u32 = 4;
+    const fn data_arg_low(arg: u32) -> u32 {
+        16 + 8 * arg
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+    #[cfg(target_arch = "riscv64")]
+    const AUDIT_ARCH: u32 = 0xc000_00f3;
+
+    /// System calls of the x32 ABI have this bit set on x86_64.
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    const DENY: u32 = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub(super) struct SockFilter {
+        code: u16,
+        jt: u8,
+        jf: u8,
+        k: u32,
+    }
+
+    #[repr(C)]
+    struct SockFprog {
+        len: libc::c_ushort,
+        filter: *const SockFilter,
+    }
+
+    const fn stmt(code: u16, k: u32) -> SockFilter {
+        SockFilter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    const fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+        SockFilter { code, jt, jf, k }
+    }
+
+    /// What the relay loop and the shutdown after it need in any case.
+    const ALLOWED: &[libc::c_long] = &[
+        // reading and writing
+        libc::SYS_read,
+        libc::SYS_readv,
+        libc::SYS_pread64,
+        libc::SYS_write,
+        libc::SYS_writev,
+        libc::SYS_pwrite64,
+        libc::SYS_lseek,
+        libc::SYS_close,
+        libc::SYS_fcntl,
+        libc::SYS_ioctl,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_fsync,
+        libc::SYS_fdatasync,
+        libc::SYS_ftruncate,
+        libc::SYS_newfstatat,
+        libc::SYS_fstat,
+        libc::SYS_statx,
+        // polling
+        libc::SYS_epoll_create1,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_ppoll,
+        // clients
+        libc::SYS_accept4,
+        libc::SYS_getsockopt,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_shutdown,
+        // memory
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        // threads, clone itself is only allowed for threads
+        libc::SYS_futex,
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_gettid,
+        libc::SYS_getpid,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        libc::SYS_tgkill,
+        // signals and the command, kill only for its process group
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_wait4,
+        libc::SYS_waitid,
+        libc::SYS_getpgid,
+        // time and randomness
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_nanosleep,
+        libc::SYS_gettimeofday,
+        libc::SYS_setitimer,
+        libc::SYS_getrandom,
+        // user lookups
+        libc::SYS_getuid,
+        libc::SYS_geteuid,
+        libc::SYS_getgid,
+        libc::SYS_getegid,
+        libc::SYS_uname,
+    ];
+
+    /// Status files, snapshots, the file browser, crash reports and utmp.
+    const ALLOWED_FILES: &[libc::c_long] = &[
+        libc::SYS_openat,
+        libc::SYS_getdents64,
+        libc::SYS_readlinkat,
+        libc::SYS_renameat,
+        libc::SYS_renameat2,
+        libc::SYS_fchmod,
+        libc::SYS_getcwd,
+        libc::SYS_setfsuid,
+        libc::SYS_setfsgid,
+    ];
+
+    /// Authenticating further clients, socket is only allowed for IP and Unix sockets.
+    const ALLOWED_NETWORK: &[libc::c_long] = &[libc::SYS_connect];
+
+    /// The variants of the above only x86_64 has.
+    #[cfg(target_arch = "x86_64")]
+    const ALLOWED_LEGACY: &[libc::c_long] = &[
+        libc::SYS_dup2,
+        libc::SYS_epoll_wait,
+        libc::SYS_poll,
+        libc::SYS_stat,
+        libc::SYS_lstat,
+        libc::SYS_accept,
+        libc::SYS_alarm,
+    ];
+    #[cfg(not(target_arch = "x86_64"))]
+    const ALLOWED_LEGACY: &[libc::c_long] = &[];
+    #[cfg(target_arch = "x86_64")]
+    const ALLOWED_FILES_LEGACY: &[libc::c_long] =
+        &[libc::SYS_open, libc::SYS_readlink, libc::SYS_rename];
+    #[cfg(not(target_arch = "x86_64"))]
+    const ALLOWED_FILES_LEGACY: &[libc::c_long] = &[];
+    #[cfg(target_arch = "x86_64")]
+    const ALLOWED_REMOVING_LEGACY: &[libc::c_long] = &[libc::SYS_unlink, libc::SYS_rmdir];
+    #[cfg(not(target_arch = "x86_64"))]
+    const ALLOWED_REMOVING_LEGACY: &[libc::c_long] = &[];
+
+    /// Allows the system call `nr`.
+    fn allow(program: &mut Vec<SockFilter>, nr: libc::c_long) {
+        program.push(jump(BPF_JMP_JEQ_K, nr as u32, 0, 1));
+        program.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    }
+
+    /// Allows the system call `nr` if its argument `arg` is one of `values`, only the lower 32
+    /// bits are compared.
+    fn allow_with_arg(program: &mut Vec<SockFilter>, nr: libc::c_long, arg: u32, values: &[u32]) {
+        let count = values.len() as u8;
+        program.push(jump(BPF_JMP_JEQ_K, nr as u32, 0, count + 3));
+        program.push(stmt(BPF_LD_W_ABS, data_arg_low(arg)));
+        for (i, &value) in values.iter().enumerate() {
+            let left = count - 1 - i as u8;
+            // the last value falls through to the denial
+            program.push(jump(BPF_JMP_JEQ_K, value, left, u8::from(left == 0)));
+        }
+        program.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+        program.push(stmt(BPF_RET_K, DENY));
+    }
+
+    /// Builds the filter program for a session that needs `needs`.
+    pub(super) fn filter(needs: &Needs) -> Vec<SockFilter> {
+        let mut program = vec![
+            // system calls of another architecture have other numbers
+            stmt(BPF_LD_W_ABS, DATA_ARCH),
+            jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+            stmt(BPF_LD_W_ABS, DATA_NR),
+            jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1),
+            stmt(BPF_RET_K, DENY),
+        ];
+        for &nr in ALLOWED.iter().chain(ALLOWED_LEGACY) {
+            allow(&mut program, nr);
+        }
+        if needs.files {
+            for &nr in ALLOWED_FILES.iter().chain(ALLOWED_FILES_LEGACY) {
+                allow(&mut program, nr);
+            }
+        } else if needs.reading {
+            let writing = (libc::O_ACCMODE | libc::O_CREAT | libc::O_TRUNC) as u32;
+            program.push(jump(BPF_JMP_JEQ_K, libc::SYS_openat as u32, 0, 4));
+            program.push(stmt(BPF_LD_W_ABS, data_arg_low(2)));
+            program.push(jump(BPF_JMP_JSET_K, writing, 1, 0));
+            program.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+            program.push(stmt(BPF_RET_K, DENY));
+        }
+        if needs.removing {
+            allow(&mut program, libc::SYS_unlinkat);
+            for &nr in ALLOWED_REMOVING_LEGACY {
+                allow(&mut program, nr);
+            }
+        }
+        if needs.network {
+            for &nr in ALLOWED_NETWORK {
+                allow(&mut program, nr);
+            }
+            let families = [libc::AF_UNIX, libc::AF_INET, libc::AF_INET6];
+            allow_with_arg(
+                &mut program,
+                libc::SYS_socket,
+                0,
+                &families.map(|family| family as u32),
+            );
+        }
+        // killpg passes the negated process group
+        allow_with_arg(
+            &mut program,
+            libc::SYS_kill,
+            0,
+            &[needs.command_group.wrapping_neg() as u32],
+        );
+        // glibc falls back to clone if clone3 isn't available, whose flags can be checked
+        program.push(jump(BPF_JMP_JEQ_K, libc::SYS_clone3 as u32, 0, 1));
+        program.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+        program.push(jump(BPF_JMP_JEQ_K, libc::SYS_clone as u32, 0, 3));
+        program.push(stmt(BPF_LD_W_ABS, data_arg_low(0)));
+        program.push(jump(BPF_JMP_JSET_K, libc::CLONE_THREAD as u32, 0, 1));
+        program.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+        program.push(stmt(BPF_RET_K, DENY));
+        program
+    }
+
+    /// Installs the filter for all threads of the process.
+    pub fn install(needs: &Needs) -> Result<()> {
+        load(&filter(needs))
+    }
+
+    /// Installs the filter `program`, which allocates only when it fails.
+    pub(super) fn load(program: &[SockFilter]) -> Result<()> {
+        let prog = SockFprog {
+            len: program.len() as libc::c_ushort,
+            filter: program.as_ptr(),
+        };
+        // required to install a filter without CAP_SYS_ADMIN, and no privileges are gained anyway
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            bail!(
+                "failed to set no_new_privs - {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                SECCOMP_SET_MODE_FILTER,
+                SECCOMP_FILTER_FLAG_TSYNC,
+                &prog as *const SockFprog,
+            )
+        };
+        if res != 0 {
+            bail!(
+                "failed to install seccomp filter - {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(
+    test,
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+))]
+mod tests {
+    use super::*;
+
+    /// Runs `checks` in a child process with the filter for `needs` installed, the test threads
+    /// keep running unfiltered. Returns the exit code of the child, the number of the first
+    /// check that failed or 0.
+    fn run_filtered(needs: &Needs, checks: &[&dyn Fn() -> bool]) -> i32 {
+        let program = filter::filter(needs);
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork failed - {}", std::io::Error::last_os_error()),
+            0 => {
+                // only system calls from here on, the parent may have held a lock while forking
+                if filter::load(&program).is_err() {
+                    unsafe { libc::_exit(100) };
+                }
+                let failed = checks.iter().position(|check| !check());
+                unsafe { libc::_exit(failed.map_or(0, |index| index as i32 + 1)) };
+            }
+            pid => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status), "child died with status {status}");
+                libc::WEXITSTATUS(status)
+            }
+        }
+    }
+
+    fn denied(result: libc::c_long) -> bool {
+        result == -1 && std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    fn socket(family: libc::c_int) -> libc::c_long {
+        let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd >= 0 {
+            unsafe { libc::close(fd) };
+        }
+        fd.into()
+    }
+
+    fn open_root() -> libc::c_long {
+        let fd = unsafe { libc::openat(libc::AT_FDCWD, c"/".as_ptr(), libc::O_RDONLY) };
+        if fd >= 0 {
+            unsafe { libc::close(fd) };
+        }
+        fd.into()
+    }
+
+    #[test]
+    fn rejects_forbidden_system_calls() {
+        let group = unsafe { libc::getpgrp() };
+        let needs = Needs {
+            command_group: group,
+            files: false,
+            removing: false,
+            reading: false,
+            network: false,
+        };
+        let code = run_filtered(
+            &needs,
+            &[
+                // signal 0 only checks whether the process group exists
+                &|| unsafe { libc::kill(-group, 0) } == 0,
+                // doesn't exist, so this is ESRCH without the filter
+                &|| denied(unsafe { libc::kill(-0x7fff_fff0, 0) }.into()),
+                &|| denied(unsafe { libc::kill(std::process::id() as i32, 0) }.into()),
+                &|| denied(open_root()),
+                &|| denied(socket(libc::AF_UNIX)),
+                &|| denied(unsafe { libc::fork() }.into()),
+            ],
+        );
+        assert_eq!(code, 0, "check {code} failed");
+    }
+
+    #[test]
+    fn allows_what_features_need() {
+        let needs = Needs {
+            command_group: unsafe { libc::getpgrp() },
+            files: false,
+            removing: false,
+            reading: true,
+            network: true,
+        };
+        let code = run_filtered(
+            &needs,
+            &[
+                &|| socket(libc::AF_INET) >= 0,
+                &|| socket(libc::AF_UNIX) >= 0,
+                &|| denied(socket(libc::AF_NETLINK)),
+                // reading the resolver configuration, but nothing else
+                &|| open_root() >= 0,
+                &|| {
+                    let path = c"/tmp/termproxy-seccomp-test".as_ptr();
+                    let flags = libc::O_WRONLY | libc::O_CREAT;
+                    denied(unsafe { libc::openat(libc::AT_FDCWD, path, flags, 0o600) }.into())
+                },
+            ],
+        );
+        assert_eq!(code, 0, "check {code} failed");
+    }
+}
@@ -0,0 +1,83 @@
+//! Security log of authentication attempts
+//!
+//! With `--security-log`, every attempt of a client to authenticate, be it with its ticket line,
+//! to unlock a locked session or as administrator on the admin socket, is appended to a file of
+//! its own as a JSON line, apart from the diagnostics on stderr that callers may rotate or throw
+//! away. Each line holds the time, the session, the user (if the client got as far as naming
+//! one), where the client connected from, the ACL path and privileges it had to have, and
+//! whether it was accepted. Clients the caller already authenticated with `--preauthenticated`,
+//! local users with `--peer-user` and clients presenting a share link are recorded as well, so
+//! the log shows every client that got into a session.
+//!
+//! The file is only ever opened for appending and has to be accessible by its owner only,
+//! sessions of the same host may share it as every line is written at once.
+
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use anyhow::{bail, format_err, Result};
+
+use crate::cli::Options;
+
+static LOG: OnceLock<File> = OnceLock::new();
+
+/// Opens the security log at `path`, creating it if necessary.
+pub fn open(path: &Path) -> Result<()> {
+    let file = File::options()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|err| format_err!("failed to open security log {path:?} - {err}"))?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        bail!("security log {path:?} is not a regular file");
+    }
+    if metadata.mode() & 0o077 != 0 {
+        bail!(
+            "security log {path:?} must only be accessible by its owner, has mode {:o}",
+            metadata.mode() & 0o777,
+        );
+    }
+    let _ = LOG.set(file);
+    Ok(())
+}
+
+/// Records an authentication attempt of `kind` (`ticket`, `observe`, `unlock`, `admin`,
+/// `preauthenticated` for clients the caller authenticated, `peer` for local users with
+/// `--peer-user` or `share` for share links) from `source`, rejected with `error` if set.
+pub fn record(
+    kind: &str,
+    source: &str,
+    user: Option<&[u8]>,
+    options: &Options,
+    error: Option<&anyhow::Error>,
+) {
+    let Some(mut file) = LOG.get() else {
+        return;
+    };
+    let entry = serde_json::json!({
+        "time": crate::status::unix_time(SystemTime::now()),
+        "session": options.session_id,
+        "kind": kind,
+        "user": user.map(String::from_utf8_lossy),
+        "source": source,
+        "path": options.acl_path,
+        "privs": options.acl_permission,
+        "result": if error.is_some() { "rejected" } else { "accepted" },
+        "code": error.map(crate::log::error_code),
+        "error": error.map(ToString::to_string),
+    });
+    let mut line = entry.to_string();
+    line.push('\n');
+    if let Err(err) = file.write_all(line.as_bytes()) {
+        crate::log::warn(
+            "security-log-failed",
+            format_args!("failed to write to security log - {err}"),
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Serving recordings over HTTP
+//!
+//! `serve-recordings` lets a small team review the recordings of a directory without setting up
+//! a web server: `GET /` lists them, `GET /NAME` returns one as asciicast, which asciinema plays
+//! from the URL. Every request has to carry the access token printed on startup, either as
+//! `token` query parameter, which the links of the listing do, or as bearer token in the
+//! `Authorization` header. Like sessions, it only listens on localhost, unless the listener is
+//! passed as file descriptor.
+//!
+//! Only regular files directly in the directory are served, and only those whose names need no
+//! escaping in URLs and HTML, which the names of recordings usually don't.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::FromRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+
+use crate::cli::{PortOrFd, ServeOptions};
+
+/// The longest request header accepted
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// How many requests are handled at the same time, further connections are turned away
+const MAX_CONNECTIONS: usize = 16;
+
+/// How long a client may take to send its request, or to receive the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether `name` is served, see the module documentation.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+/// A response, its status line, content type and body
+type Response = (&'static str, &'static str, Vec<u8>);
+
+fn text(status: &'static str, message: &str) -> Response {
+    (status, "text/plain", format!("{message}\n").into_bytes())
+}
+
+/// The value of the header `name` of the request.
+fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    })
+}
+
+/// Whether the request for `query` presents the access token.
+fn authorized(request: &str, query: &str, token: &str) -> bool {
+    let bearer = header(request, "Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    let presented = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .or(bearer);
+    presented
+        .is_some_and(|given| crate::handshake::secret_matches(given.as_bytes(), token.as_bytes()))
+}
+
+fn listing(dir: &Path, token: &str) -> Result<Vec<u8>> {
+    let mut recordings = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        match entry.file_name().into_string() {
+            Ok(name) if metadata.is_file() && valid_name(&name) => {
+                recordings.push((name, metadata.len()))
+            }
+            _ => (),
+        }
+    }
+    recordings.sort();
+
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Recordings</title></head>\n\
+         <body><h1>Recordings</h1><ul>\n",
+    );
+    for (name, size) in recordings {
+        html.push_str(&format!(
+            "<li><a href=\"/{name}?token={token}\">{name}</a> ({size} bytes)</li>\n"
+        ));
+    }
+    html.push_str("</ul></body></html>\n");
+    Ok(html.into_bytes())
+}
+
+fn recording(dir: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+    if !valid_name(name) {
+        return Ok(None);
+    }
+    let path = dir.join(name);
+    match std::fs::metadata(&path) {
+        Ok(metadata) if metadata.is_file() => (),
+        _ => return Ok(None),
+    }
+    let content = std::fs::read(&path)?;
+    Ok(Some(crate::replay::to_asciicast(&content)?))
+}
+
+fn respond_to(request: &str, dir: &Path, token: &str) -> Response {
+    let mut parts = request.split(' ');
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return text("400 Bad Request", "invalid request");
+    };
+    if method != "GET" {
+        return text("405 Method Not Allowed", "only GET requests are supported");
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if !authorized(request, query, token) {
+        return text("401 Unauthorized", "missing or invalid access token");
+    }
+
+    let result = match path.strip_prefix('/') {
+        Some("") => listing(dir, token).map(|html| Some(("text/html; charset=utf-8", html))),
+        Some(name) => {
+            recording(dir, name).map(|cast| cast.map(|cast| ("application/x-asciicast", cast)))
+        }
+        None => Ok(None),
+    };
+    match result {
+        Ok(Some((content_type, body))) => ("200 OK", content_type, body),
+        Ok(None) => text("404 Not Found", "no such recording"),
+        Err(err) => {
+            crate::log::warn(
+                "serve-failed",
+                format_args!("failed to serve {path} - {err}"),
+            );
+            text("500 Internal Server Error", "failed to read the recording")
+        }
+    }
+}
+
+fn respond(stream: &mut TcpStream, (status, content_type, body): Response) -> Result<()> {
+    // the links carry the access token, which must not leak to other sites
+    let head = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: no-store\r\n\
+         Referrer-Policy: no-referrer\r\n\
+         Connection: close\r\n\r\n",
+        body.len(),
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&body)?;
+    Ok(())
+}
+
+/// Reads the request header.
+fn read_request(stream: &mut TcpStream) -> Result<String> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_LEN {
+            bail!("request too long");
+        }
+        match stream.read(&mut buf)? {
+            0 => bail!("connection closed before the end of the request"),
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+fn handle(mut stream: TcpStream, dir: &Path, token: &str) -> Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let request = read_request(&mut stream)?;
+    respond(&mut stream, respond_to(&request, dir, token))
+}
+
+pub fn serve_recordings(options: &ServeOptions) -> Result<()> {
+    let listener = match &options.listen_port {
+        PortOrFd::Fd(fd) => unsafe { TcpListener::from_raw_fd(*fd) },
+        PortOrFd::Port(port) => TcpListener::bind(("localhost", *port))?,
+        PortOrFd::Unix(_) | PortOrFd::Abstract(_) => {
+            bail!("recordings are only served on a TCP port")
+        }
+    };
+    // a listener passed as FD may be non-blocking
+    listener.set_nonblocking(false)?;
+    if !options.dir.is_dir() {
+        bail!("{:?} is not a directory", options.dir);
+    }
+
+    let token: Arc<str> = crate::cli::random_hex(16)?.into();
+    println!("{token}");
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                crate::log::warn("accept-failed", format_args!("{err}"));
+                // e.g. out of file descriptors, which takes a while to change
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            let _ = stream.set_write_timeout(Some(REQUEST_TIMEOUT));
+            let _ = respond(
+                &mut stream,
+                text("503 Service Unavailable", "too many requests"),
+            );
+            continue;
+        }
+
+        let dir = options.dir.clone();
+        let token = Arc::clone(&token);
+        let connections = Arc::clone(&connections);
+        std::thread::spawn(move || {
+            if let Err(err) = handle(stream, &dir, &token) {
+                crate::log::warn("request-failed", format_args!("{err}"));
+            }
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
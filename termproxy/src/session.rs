@@ -0,0 +1,881 @@
+//! State of the session besides its clients
+//!
+//! What control messages change, like a suspended command, a locked session or a pending SysRq
+//! key, and the helpers the relay loop handles the command, its output and the end of the
+//! session with.
+
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, format_err, Result};
+use mio::event::Event;
+use mio::{Events, Poll, Waker};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+
+use crate::auth::authenticate;
+use crate::cli::{ChildStderr, Options};
+use crate::client::{Client, ClientStream};
+use crate::compat::ByteBuffer;
+use crate::control::{base64_encode, encode_control_message, ControlCommand};
+use crate::history::CommandHistory;
+use crate::login::LoginShell;
+use crate::pty::PTY;
+#[cfg(feature = "recording")]
+use crate::record::Recorder;
+use crate::status::{unix_time, StatusFile};
+use crate::timer::{Deadline, Timers};
+use crate::utmp::UtmpEntry;
+
+/// How long a requested SysRq key waits for the client's confirmation.
+const SYSRQ_CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// State of the session changed by control messages
+#[derive(Default)]
+pub struct ControlState {
+    /// The process group stopped by a suspend command
+    pub suspended: Option<Pid>,
+    /// The process groups stopped with --freeze-detached while no client is attached
+    frozen: Vec<Pid>,
+    /// A SysRq key waiting for confirmation
+    pub sysrq: Option<(u8, Deadline)>,
+    /// Whether the session is locked because of missing input
+    pub locked: bool,
+    /// What happens to output after binary data was detected
+    pub binary: BinaryOutput,
+    /// Whether the client chose to see the binary output currently being detected
+    pub binary_accepted: bool,
+    /// The amount of output thrown away while flushing binary output
+    pub binary_discarded: u64,
+    /// The output left to relay in the current check interval, if throttled
+    pub throttle: Option<usize>,
+    /// A message that did not fit into the output buffer yet but must not be dropped
+    pending_notice: Option<String>,
+}
+
+impl ControlState {
+    /// Whether output of the command is currently held back instead of relayed.
+    pub fn output_held(&self) -> bool {
+        self.locked
+            || self.binary != BinaryOutput::Relay
+            || self.throttle == Some(0)
+            || self.pending_notice.is_some()
+    }
+
+    /// Queues a message for the client that must not be dropped, output is held back until it
+    /// fits into the buffer.
+    pub fn notify(&mut self, buf: &mut ByteBuffer, message: &str) {
+        if self.pending_notice.is_none() && queue_message(buf, message) {
+            return;
+        }
+        self.pending_notice
+            .get_or_insert_with(String::new)
+            .push_str(message);
+    }
+
+    /// Queues the pending notice if it fits by now.
+    pub fn queue_pending_notice(&mut self, buf: &mut ByteBuffer) {
+        if let Some(notice) = &self.pending_notice {
+            if queue_message(buf, notice) {
+                self.pending_notice = None;
+            }
+        }
+    }
+}
+
+/// How output is handled with regard to binary data
+#[derive(Default, PartialEq)]
+pub enum BinaryOutput {
+    #[default]
+    Relay,
+    /// Binary output was detected, output is held back until the client decides what to do
+    Paused,
+    /// Output is discarded until it looks like text again or the command stops writing
+    Flushing,
+}
+
+/// Resets the client's terminal emulator to its initial state (RIS).
+const RESET_TERMINAL: &str = "\x1bc";
+
+/// The output relayed per check interval of the loop watchdog while throttled.
+pub const THROTTLED_OUTPUT: usize = 2048;
+
+/// Flushing binary output ends once the command wrote nothing for this long.
+pub const BINARY_FLUSH_IDLE: Duration = Duration::from_millis(200);
+
+pub fn handle_control(
+    command: ControlCommand,
+    options: &Options,
+    pty: &mut PTY,
+    child: &Child,
+    state: &mut ControlState,
+    tcp_buf: &mut ByteBuffer,
+) -> Result<()> {
+    match command {
+        ControlCommand::Suspend => {
+            if state.suspended.is_none() {
+                // stop whatever job is in the foreground instead of just the (job-control
+                // aware) shell we started
+                let pgrp = pty
+                    .foreground_process_group()
+                    .unwrap_or_else(|_| Pid::from_raw(child.id() as i32));
+                killpg(pgrp, Signal::SIGSTOP)?;
+                state.suspended = Some(pgrp);
+            }
+        }
+        ControlCommand::Resume => {
+            if let Some(pgrp) = state.suspended.take() {
+                killpg(pgrp, Signal::SIGCONT)?;
+            }
+        }
+        ControlCommand::Signal(signal) => {
+            if !options.allowed_signals.contains(&signal) {
+                bail!("client is not allowed to send {signal}");
+            }
+            // the command is a session leader, so its PID is also its process group ID
+            killpg(Pid::from_raw(child.id() as i32), signal)?;
+        }
+        ControlCommand::Break => send_break(options)?,
+        ControlCommand::Sysrq(key) => {
+            if !options.allow_sysrq {
+                bail!("client is not allowed to send sysrq keys");
+            }
+            state.sysrq = Some((key, Deadline::after(SYSRQ_CONFIRM_TIMEOUT)));
+            let message = encode_control_message(
+                "sysrq",
+                &[
+                    ("state", "confirm".to_string()),
+                    ("key", char::from(key).to_string()),
+                    ("timeout", SYSRQ_CONFIRM_TIMEOUT.as_secs().to_string()),
+                ],
+            );
+            queue_message(tcp_buf, &message);
+        }
+        // waits for the break command in the relay loop, see Relay::confirm_sysrq
+        ControlCommand::SysrqConfirm(_) => bail!("unexpected sysrq-confirm command"),
+        ControlCommand::Reset { sane } => {
+            if sane {
+                pty.make_sane()?;
+            }
+            if !queue_message(tcp_buf, RESET_TERMINAL) {
+                bail!("output buffer full, cannot reset terminal");
+            }
+        }
+        ControlCommand::LoopThrottle(throttle) => {
+            if options.loop_watchdog.is_none() {
+                bail!("output loop watchdog is not enabled");
+            }
+            state.throttle = throttle.then_some(THROTTLED_OUTPUT);
+            let message = encode_control_message(
+                "loop",
+                &[(
+                    "state",
+                    if throttle { "throttled" } else { "relay" }.to_string(),
+                )],
+            );
+            state.notify(tcp_buf, &message);
+        }
+        ControlCommand::LoopKill => {
+            if options.loop_watchdog.is_none() {
+                bail!("output loop watchdog is not enabled");
+            }
+            let pgrp = pty
+                .foreground_process_group()
+                .unwrap_or_else(|_| Pid::from_raw(child.id() as i32));
+            killpg(pgrp, Signal::SIGKILL)?;
+            let message = encode_control_message("loop", &[("state", "killed".to_string())]);
+            state.notify(tcp_buf, &message);
+        }
+        ControlCommand::BinaryResume => resume_binary(state, tcp_buf)?,
+        ControlCommand::Hex(bytes) => {
+            if !options.allowed_hex_input.contains(&bytes) {
+                bail!("client is not allowed to send {bytes:02x?}");
+            }
+            pty.write_all(&bytes)?;
+        }
+        // needs the client, see the relay loop
+        ControlCommand::Detach => bail!("unexpected detach command"),
+        #[cfg(feature = "serial")]
+        ControlCommand::SacChannel { home } => {
+            if !options.sac {
+                bail!("SAC mode is not enabled");
+            }
+            pty.write_all(if home {
+                crate::sac::SAC_CHANNEL
+            } else {
+                crate::sac::NEXT_CHANNEL
+            })?;
+        }
+        #[cfg(not(feature = "serial"))]
+        ControlCommand::SacChannel { .. } => bail!("SAC mode is not enabled"),
+        // needs the session's timers, see flush_binary
+        ControlCommand::BinaryFlush => bail!("unexpected binary-flush command"),
+        // needs the session's user, see unlock_session
+        ControlCommand::Unlock { .. } => bail!("unexpected unlock command"),
+        // answered to the asking client only, see answer_size
+        ControlCommand::Size => bail!("unexpected size command"),
+        // answered to the asking client only, see FileAccess::handle
+        ControlCommand::Files { .. } => bail!("unexpected files command"),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "serial")]
+fn break_command(options: &Options) -> Result<Command> {
+    let Some(program) = &options.break_command else {
+        bail!("no break command configured");
+    };
+    let mut command = Command::new(program);
+    command
+        .env("TERMPROXY_SESSION_ID", &options.session_id)
+        .stdin(std::process::Stdio::null());
+    Ok(command)
+}
+
+#[cfg(not(feature = "serial"))]
+fn break_command(_options: &Options) -> Result<Command> {
+    bail!("termproxy was built without support for serial consoles");
+}
+
+/// Stops the command while no client is attached.
+///
+/// The process group of the command itself is stopped before the foreground job, so that a
+/// shell does not notice its job stopping and take over the terminal.
+pub fn freeze(pty: &PTY, child: &Child, state: &mut ControlState) {
+    let mut pgrps = vec![Pid::from_raw(child.id() as i32)];
+    if let Ok(pgrp) = pty.foreground_process_group() {
+        if !pgrps.contains(&pgrp) {
+            pgrps.push(pgrp);
+        }
+    }
+    for pgrp in pgrps {
+        match killpg(pgrp, Signal::SIGSTOP) {
+            Ok(()) => state.frozen.push(pgrp),
+            Err(err) => crate::log::warn(
+                "freeze-failed",
+                format_args!("failed to stop process group {pgrp} - {err}"),
+            ),
+        }
+    }
+}
+
+/// Continues the command stopped by [`freeze`], in reverse order, except for a job suspended
+/// by the client.
+pub fn thaw(state: &mut ControlState) {
+    while let Some(pgrp) = state.frozen.pop() {
+        if state.suspended == Some(pgrp) {
+            continue;
+        }
+        if let Err(err) = killpg(pgrp, Signal::SIGCONT) {
+            crate::log::warn(
+                "freeze-failed",
+                format_args!("failed to continue process group {pgrp} - {err}"),
+            );
+        }
+    }
+}
+
+/// Sends a serial BREAK to the backend of the command.
+///
+/// Pseudo terminals have no line that could carry a BREAK, so whoever starts termproxy for a
+/// serial backend has to provide a program that sends it, e.g. to a physical serial line with
+/// `tcsendbreak` or to a virtual one via the hypervisor. It runs in its own thread so that a
+/// slow backend cannot stall the session.
+pub fn send_break(options: &Options) -> Result<()> {
+    let mut command = break_command(options)?;
+    std::thread::spawn(move || match command.status() {
+        Ok(status) if status.success() => (),
+        Ok(status) => crate::log::warn(
+            "break-failed",
+            format_args!("break command failed - {status}"),
+        ),
+        Err(err) => crate::log::warn(
+            "break-failed",
+            format_args!("failed to run break command - {err}"),
+        ),
+    });
+    Ok(())
+}
+
+/// How long the BREAK of a confirmed SysRq key may take, the key is dropped after that.
+pub const SYSRQ_BREAK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The BREAK of a magic SysRq key on its way to the serial backend, the key follows it.
+///
+/// The key has to follow the BREAK within a few seconds, so it waits for the break command to
+/// finish, which runs in its own thread like for [`send_break`], and then goes through the
+/// terminal like any other input.
+pub struct SysrqBreak {
+    pub key: u8,
+    /// Gets the outcome of the break command, the thread wakes up the relay loop with it
+    result: Receiver<Result<()>>,
+}
+
+impl SysrqBreak {
+    pub fn start(options: &Options, key: u8, waker: Arc<Waker>) -> Result<Self> {
+        let mut command = break_command(options)?;
+        let (sender, result) = channel();
+        std::thread::Builder::new().spawn(move || {
+            let result = match command.status() {
+                Ok(status) if status.success() => Ok(()),
+                Ok(status) => Err(format_err!("break command failed - {status}")),
+                Err(err) => Err(format_err!("failed to run break command - {err}")),
+            };
+            // the key may have been given up on in the meantime
+            if sender.send(result).is_ok() {
+                let _ = waker.wake();
+            }
+        })?;
+        Ok(Self { key, result })
+    }
+
+    /// The outcome of the break command once it finished.
+    pub fn finished(&self) -> Option<Result<()>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(format_err!("break command got lost"))),
+        }
+    }
+}
+
+/// Tells the clients how a confirmed SysRq key went.
+pub fn report_sysrq(key: u8, state: &str, control_state: &mut ControlState, buf: &mut ByteBuffer) {
+    let message = encode_control_message(
+        "sysrq",
+        &[
+            ("state", state.to_string()),
+            ("key", char::from(key).to_string()),
+        ],
+    );
+    control_state.notify(buf, &message);
+}
+
+/// Queues a message from termproxy itself for the client, returns whether it was queued.
+///
+/// Messages are dropped if they don't fit into the buffer as a whole, as a truncated control
+/// message would swallow the terminal output following it.
+pub fn queue_message(buf: &mut ByteBuffer, message: &str) -> bool {
+    queue_data(buf, message.as_bytes())
+}
+
+/// Like [`queue_message`], for data that isn't necessarily UTF-8.
+pub fn queue_data(buf: &mut ByteBuffer, mut data: &[u8]) -> bool {
+    if buf.free_size() >= data.len() {
+        let _ = buf.read_from(&mut data);
+        return true;
+    }
+    false
+}
+
+/// Space needed in the output buffer for wrapped stderr output besides the output itself.
+pub const STDERR_OVERHEAD: usize = 64;
+
+/// Stderr output is only read if at least this much space is left in the output buffer.
+pub const MIN_STDERR_SPACE: usize = STDERR_OVERHEAD + 64;
+
+/// Wraps output from the command's stderr for the client.
+pub fn wrap_child_stderr(mode: ChildStderr, data: &[u8]) -> Vec<u8> {
+    match mode {
+        ChildStderr::Color => {
+            // the pipe doesn't translate line breaks like the terminal does
+            let mut wrapped = b"\x1b[31m".to_vec();
+            for byte in data {
+                if *byte == b'\n' {
+                    wrapped.push(b'\r');
+                }
+                wrapped.push(*byte);
+            }
+            wrapped.extend_from_slice(b"\x1b[39m");
+            wrapped
+        }
+        ChildStderr::Frame => {
+            encode_control_message("stderr", &[("data", base64_encode(data))]).into_bytes()
+        }
+    }
+}
+
+/// Locks the session, hiding the terminal behind a lock screen on the alternate screen buffer.
+///
+/// Returns false if the lock screen could not be queued yet.
+pub fn lock_session(
+    options: &Options,
+    control_state: &mut ControlState,
+    buf: &mut ByteBuffer,
+) -> bool {
+    let message = format!(
+        "{}\x1b[?1049h\x1b[H\x1b[2Jsession locked after {}s without input, \
+         re-authenticate to continue\r\n",
+        encode_control_message("lock", &[("state", "locked".to_string())]),
+        options.lock_after.unwrap_or_default().as_secs(),
+    );
+    if !queue_message(buf, &message) {
+        return false;
+    }
+    control_state.locked = true;
+    true
+}
+
+/// Unlocks a locked session if `ticket` is valid for the user the session was started for.
+#[allow(clippy::too_many_arguments)]
+pub fn unlock_session(
+    options: &Options,
+    session_user: &[u8],
+    listen_port: u16,
+    username: &str,
+    ticket: &str,
+    source: &str,
+    control_state: &mut ControlState,
+    buf: &mut ByteBuffer,
+) -> Result<()> {
+    if !control_state.locked {
+        return Ok(());
+    }
+
+    let result = if username.as_bytes() != session_user {
+        Err(format_err!("unlock attempt by different user '{username}'"))
+    } else {
+        authenticate(username.as_bytes(), ticket.as_bytes(), options, listen_port)
+    };
+    let user = Some(username.as_bytes());
+    crate::seclog::record("unlock", source, user, options, result.as_ref().err());
+    if let Err(err) = result {
+        let message = encode_control_message(
+            "lock",
+            &[
+                ("state", "locked".to_string()),
+                ("error", "authentication failed".to_string()),
+            ],
+        );
+        queue_message(buf, &message);
+        return Err(err);
+    }
+
+    control_state.locked = false;
+    let message = format!(
+        "\x1b[?1049l{}",
+        encode_control_message("lock", &[("state", "unlocked".to_string())])
+    );
+    queue_message(buf, &message);
+    Ok(())
+}
+
+/// Warns the client about output looping at `rate` bytes per second.
+pub fn report_loop(
+    options: &Options,
+    rate: u64,
+    control_state: &mut ControlState,
+    buf: &mut ByteBuffer,
+) {
+    let looping = options.loop_watchdog.unwrap_or_default().as_secs();
+    crate::log::warn(
+        "output-loop",
+        format_args!("output has been looping at {rate} bytes/s for {looping}s"),
+    );
+    let message = format!(
+        "\r\n{}output has been looping at {rate} bytes/s for {looping}s - throttle or kill \
+         it\r\n",
+        encode_control_message(
+            "loop",
+            &[
+                ("state", "detected".to_string()),
+                ("rate", rate.to_string()),
+            ],
+        ),
+    );
+    control_state.notify(buf, &message);
+}
+
+/// Answers the password prompt of the command with the secret from the secret provider.
+pub fn answer_prompt(
+    options: &Options,
+    prompt: &str,
+    pty: &mut PTY,
+    control_state: &mut ControlState,
+    buf: &mut ByteBuffer,
+) {
+    let Some(provider) = &options.secret_provider else {
+        return;
+    };
+    let result = crate::prompt::provide_secret(provider, prompt, &options.session_id).and_then(
+        |mut secret| {
+            secret.push(b'\r');
+            let written = pty.write_all(&secret);
+            secret.fill(0);
+            written.map_err(Into::into)
+        },
+    );
+    let message = match result {
+        Ok(()) => encode_control_message("secret", &[("state", "provided".to_string())]),
+        Err(err) => {
+            crate::log::warn(
+                "secret-provider-failed",
+                format_args!("failed to answer prompt {prompt:?} - {err}"),
+            );
+            encode_control_message(
+                "secret",
+                &[("state", "failed".to_string()), ("error", err.to_string())],
+            )
+        }
+    };
+    control_state.notify(buf, &message);
+}
+
+/// Reads from `input`, the command's output or a client's input, into `buf`, at most `limit`
+/// bytes.
+pub fn read_limited<R: Read>(
+    input: &mut R,
+    buf: &mut ByteBuffer,
+    limit: usize,
+) -> std::io::Result<usize> {
+    let mut data = [0u8; 4096];
+    let max = limit.min(buf.free_size()).min(data.len());
+    let bytes = input.read(&mut data[..max])?;
+    queue_data(buf, &data[..bytes]);
+    Ok(bytes)
+}
+
+/// Stops the recording if writing to it failed, the session goes on without it.
+#[cfg(feature = "recording")]
+pub fn check_recording(recorder: &mut Option<Recorder>, result: Result<()>) {
+    if let Err(err) = result {
+        crate::log::warn("record-failed", format_args!("stopped recording - {err}"));
+        *recorder = None;
+    }
+}
+
+/// The address a client connected from without the port, `None` on the unix socket.
+pub fn client_host(client: &mut Client) -> Option<String> {
+    let peer = client.stream.connection().peer();
+    let (host, _port) = peer.rsplit_once(':')?;
+    Some(
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+    )
+}
+
+/// Switches termproxy to `user` for good, nothing to do if it runs as the user already.
+///
+/// Everything the relay needs is open by now, the listener, the terminal and the files of
+/// recordings, so only what it opens later, like files of the file browser, is subject to the
+/// permissions of the user. Signals to the command only arrive if it runs as the same user.
+pub fn drop_privileges(user: &str) -> Result<()> {
+    if let Some(credentials) = LoginShell::lookup(user)?.credentials() {
+        credentials
+            .switch()
+            .map_err(|err| format_err!("failed to switch to user '{user}' - {err}"))?;
+    }
+    Ok(())
+}
+
+/// Registers the session's terminal in utmp, as the login shell's user or the user the session
+/// was started for, logged in from `host`, the address of its first client.
+pub fn register_utmp(
+    options: &Options,
+    pty: &PTY,
+    child: &Child,
+    username: &[u8],
+    host: Option<&str>,
+) -> Option<UtmpEntry> {
+    let user = match &options.login_shell {
+        Some(user) => user.clone(),
+        None => String::from_utf8_lossy(username).into_owned(),
+    };
+    let result = pty
+        .secondary_name()
+        .map_err(anyhow::Error::from)
+        .and_then(|tty| UtmpEntry::login(&tty, child.id(), &user, host.unwrap_or_default()));
+    match result {
+        Ok(entry) => Some(entry),
+        Err(err) => {
+            crate::log::warn(
+                "utmp-failed",
+                format_args!("not registering the session in utmp - {err}"),
+            );
+            None
+        }
+    }
+}
+
+/// Stops the command history if writing to it failed, the session goes on without it.
+pub fn check_history(history: &mut Option<CommandHistory>, result: Result<()>) {
+    if let Err(err) = result {
+        crate::log::warn(
+            "history-failed",
+            format_args!("stopped command history - {err}"),
+        );
+        *history = None;
+    }
+}
+
+/// Holds back output after binary data was detected and asks the client what to do with it.
+pub fn pause_binary(control_state: &mut ControlState, buf: &mut ByteBuffer) {
+    control_state.binary = BinaryOutput::Paused;
+    // the binary data may have changed character sets and attributes, make the notice readable
+    let message = format!(
+        "\x1b[!p\x1b(B\x0f\x1b[0m\r\n{}binary output detected, output paused - \
+         resume or flush it\r\n",
+        encode_control_message("binary", &[("state", "paused".to_string())]),
+    );
+    control_state.notify(buf, &message);
+}
+
+/// Relays the held back binary output after all.
+pub fn resume_binary(control_state: &mut ControlState, buf: &mut ByteBuffer) -> Result<()> {
+    if control_state.binary != BinaryOutput::Paused {
+        bail!("no binary output paused");
+    }
+    control_state.binary = BinaryOutput::Relay;
+    control_state.binary_accepted = true;
+    queue_message(
+        buf,
+        &encode_control_message("binary", &[("state", "relay".to_string())]),
+    );
+    Ok(())
+}
+
+/// Discards the held back binary output and whatever binary output follows it.
+pub fn flush_binary(
+    control_state: &mut ControlState,
+    timers: &mut Timers<SessionTimer>,
+) -> Result<()> {
+    if control_state.binary != BinaryOutput::Paused {
+        bail!("no binary output paused");
+    }
+    control_state.binary = BinaryOutput::Flushing;
+    control_state.binary_discarded = 0;
+    timers.set(SessionTimer::BinaryFlush, BINARY_FLUSH_IDLE);
+    Ok(())
+}
+
+/// Ends flushing binary output, resetting the terminal's state it may have changed.
+///
+/// Returns false if the reset could not be queued yet.
+pub fn finish_binary_flush(control_state: &mut ControlState, buf: &mut ByteBuffer) -> bool {
+    let message = format!(
+        "\x1b[!p\x1b(B\x0f\x1b[0m{}\r\ndiscarded {} bytes of binary output\r\n",
+        encode_control_message("binary", &[("state", "relay".to_string())]),
+        control_state.binary_discarded,
+    );
+    if !queue_message(buf, &message) {
+        return false;
+    }
+    control_state.binary = BinaryOutput::Relay;
+    true
+}
+
+/// How long to wait for the command to exit after it closed the terminal.
+pub const EXIT_WAIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long sending the remaining output to the client may take once the session ended.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits for the command to exit, which it usually does right before the terminal gets closed.
+pub fn wait_for_exit(child: &mut Child, timeout: Duration) -> Option<ExitStatus> {
+    let deadline = Deadline::after(timeout);
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Some(status),
+            Ok(None) if !deadline.is_expired() => std::thread::sleep(Duration::from_millis(10)),
+            _ => return None,
+        }
+    }
+}
+
+/// The final message to the client, summarizing the session.
+///
+/// `ended_by` is the reason if termproxy ended the session itself, instead of the command.
+pub fn session_end_message(
+    stats: &SessionStats,
+    ended_by: Option<&str>,
+    exit_status: Option<ExitStatus>,
+) -> String {
+    let mut fields = Vec::new();
+    match (ended_by, exit_status) {
+        (Some(reason), _) => fields.push(("reason", reason.to_string())),
+        (None, Some(status)) => {
+            fields.push(("reason", "exited".to_string()));
+            match (status.code(), status.signal()) {
+                (Some(code), _) => fields.push(("status", code.to_string())),
+                (None, Some(signal)) => {
+                    let name = Signal::try_from(signal)
+                        .map(|signal| signal.as_str().trim_start_matches("SIG").to_string())
+                        .unwrap_or_else(|_| signal.to_string());
+                    fields.push(("signal", name));
+                }
+                (None, None) => (),
+            }
+        }
+        (None, None) => fields.push(("reason", "closed".to_string())),
+    }
+    fields.push(("duration", stats.started.elapsed().as_secs().to_string()));
+    fields.push(("from-client", stats.from_client.to_string()));
+    fields.push(("to-client", stats.to_client.to_string()));
+    encode_control_message("session-end", &fields)
+}
+
+/// Sends the remaining output to the client after the session ended.
+pub fn drain_output(
+    poll: &mut Poll,
+    stream: &mut ClientStream,
+    mut data: &[u8],
+    deadline: Deadline,
+) -> Result<()> {
+    let mut events = Events::with_capacity(4);
+    loop {
+        while !data.is_empty() {
+            match stream.write(data) {
+                Ok(0) => bail!("connection closed"),
+                Ok(bytes) => data = &data[bytes..],
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if data.is_empty() {
+            stream.close();
+            match stream.flush() {
+                Ok(()) => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if deadline.is_expired() {
+            bail!("timed out");
+        }
+        poll.poll(&mut events, Some(deadline.remaining()))?;
+    }
+}
+
+/// Readiness of an event source, cached between its events
+///
+/// mio only reports when a source becomes ready (edge-triggered), so a source counts as ready
+/// from its event until an operation on it would block. Polling without timeout while a ready
+/// source has work left, and only then, keeps idle sessions from waking up.
+#[derive(Clone, Copy)]
+pub struct Readiness {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl Readiness {
+    pub fn ready() -> Self {
+        Self {
+            readable: true,
+            writable: true,
+        }
+    }
+
+    pub fn update(&mut self, event: &Event) {
+        self.readable |= event.is_readable();
+        self.writable |= event.is_writable();
+    }
+}
+
+/// Traffic counters of the session
+pub struct SessionStats {
+    started: Instant,
+    started_at: SystemTime,
+    pub last_activity: SystemTime,
+    pub from_client: u64,
+    pub to_client: u64,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            last_activity: SystemTime::now(),
+            from_client: 0,
+            to_client: 0,
+        }
+    }
+
+    pub fn summary(&self, session_id: &str) -> String {
+        format!(
+            "session {session_id}: up {}s, {} bytes from client, {} bytes to client",
+            self.started.elapsed().as_secs(),
+            self.from_client,
+            self.to_client,
+        )
+    }
+}
+
+#[derive(PartialEq)]
+pub enum SessionTimer {
+    FirstOutput,
+    Status,
+    Lock,
+    LoopCheck,
+    BinaryFlush,
+    Reconnect,
+    Idle,
+    Keepalive,
+    Snapshot,
+    InputRate,
+    SysrqBreak,
+}
+
+/// How often the status file gets updated.
+pub const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The interval the input allowance of clients with `--max-input-rate` is renewed in.
+pub const INPUT_RATE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The input a client may send per [`INPUT_RATE_INTERVAL`], at `rate` bytes per second.
+pub fn input_allowance(rate: u64) -> usize {
+    (rate / 10).clamp(1, usize::MAX as u64) as usize
+}
+
+pub fn write_status(
+    status: &StatusFile,
+    options: &Options,
+    child: &Child,
+    stats: &SessionStats,
+    control_state: &ControlState,
+    user: &[u8],
+    clients: usize,
+) {
+    let state = if control_state.locked {
+        "locked"
+    } else if control_state.binary != BinaryOutput::Relay {
+        "binary-paused"
+    } else if control_state.suspended.is_some() {
+        "suspended"
+    } else if !control_state.frozen.is_empty() {
+        "frozen"
+    } else {
+        "running"
+    };
+    let mut fields = vec![
+        ("session", options.session_id.clone()),
+        ("user", String::from_utf8_lossy(user).into_owned()),
+        ("state", state.to_string()),
+        ("command", options.command_name()),
+        ("pid", child.id().to_string()),
+        ("started", unix_time(stats.started_at).to_string()),
+        ("last-activity", unix_time(stats.last_activity).to_string()),
+        ("bytes-from-client", stats.from_client.to_string()),
+        ("bytes-to-client", stats.to_client.to_string()),
+        ("clients", clients.to_string()),
+    ];
+    let tag_keys: Vec<String> = options
+        .tags
+        .iter()
+        .map(|(key, _)| format!("tag.{key}"))
+        .collect();
+    for (key, (_, value)) in tag_keys.iter().zip(&options.tags) {
+        fields.push((key, value.clone()));
+    }
+    if let Err(err) = status.write(&fields) {
+        crate::log::warn(
+            "status-failed",
+            format_args!("failed to write status file - {err}"),
+        );
+    }
+}
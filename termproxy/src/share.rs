@@ -0,0 +1,301 @@
+//! Share links
+//!
+//! With `--share-links`, the user of a session shared with `--max-clients` can let someone
+//! without an account of their own join it: `termproxy share <session-id>` registers a random
+//! token with the session through its control socket and prints it, to be passed on as part of
+//! a link. A client presenting `share:TOKEN` as its ticket line joins the session as the
+//! session's user, a single time and only until the token expires, as an observer if the token
+//! was registered with `--observe`.
+//!
+//! Registering a token is a message on the control socket without a connection attached,
+//! `share\n`, the seconds the token is valid for, `1` or `0` for whether its client only
+//! watches, each followed by a line break, and the token itself. The session answers with a
+//! line, `OK` or the error. Registering is logged along with the session's other messages,
+//! redeeming a token in the security log as well.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, format_err, Result};
+use zeroize::Zeroizing;
+
+use crate::cli::ShareOptions;
+
+/// What a ticket line starts with to present a token, `share:TOKEN`.
+pub const TICKET_PREFIX: &[u8] = b"share";
+
+/// What a message on the control socket registering a token starts with.
+pub const REQUEST_PREFIX: &[u8] = b"share\n";
+
+/// How many random bytes a token consists of.
+const TOKEN_LEN: usize = 16;
+
+/// How many tokens a session keeps at most, expired ones included until they are cleaned up.
+const MAX_TOKENS: usize = 16;
+
+/// How long `share` waits for the session to answer.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A token to register, received on the control socket
+pub struct ShareRequest {
+    token: Zeroizing<String>,
+    valid_for: Duration,
+    observer: bool,
+    /// The connection to the control socket it came with, to answer on
+    stream: UnixStream,
+}
+
+impl ShareRequest {
+    /// Parses a registration, `message` being what follows [`REQUEST_PREFIX`].
+    pub fn parse(message: &[u8], stream: UnixStream) -> Result<Self> {
+        let message = std::str::from_utf8(message)
+            .map_err(|_| format_err!("share request is not valid UTF-8"))?;
+        let mut lines = message.splitn(3, '\n');
+        let (Some(valid_for), Some(observer), Some(token)) =
+            (lines.next(), lines.next(), lines.next())
+        else {
+            bail!("share request without validity and token");
+        };
+        let valid_for = valid_for
+            .parse()
+            .map_err(|_| format_err!("invalid validity '{valid_for}' of share request"))?;
+        if token.len() != 2 * TOKEN_LEN || !token.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("invalid token in share request");
+        }
+        Ok(Self {
+            token: Zeroizing::new(token.to_string()),
+            valid_for: Duration::from_secs(valid_for),
+            observer: observer == "1",
+            stream,
+        })
+    }
+
+    pub fn valid_for(&self) -> Duration {
+        self.valid_for
+    }
+
+    pub fn observer(&self) -> bool {
+        self.observer
+    }
+
+    /// Tells the registering process whether the token was registered.
+    pub fn reply(mut self, result: &Result<()>) {
+        let line = match result {
+            Ok(()) => "OK\n".to_string(),
+            Err(err) => format!("{err}\n"),
+        };
+        let _ = self.stream.write_all(line.as_bytes());
+    }
+}
+
+struct Share {
+    token: Zeroizing<String>,
+    expires: Instant,
+    observer: bool,
+    username: Box<[u8]>,
+}
+
+/// The tokens registered with a session, shared with the handshakes of joining clients
+#[derive(Default)]
+pub struct ShareTokens(Mutex<Vec<Share>>);
+
+/// A client that presented a valid token
+pub struct Redeemed {
+    pub username: Box<[u8]>,
+    pub observer: bool,
+}
+
+impl ShareTokens {
+    /// Registers the token of `request`, for a client joining as `username`.
+    pub fn register(&self, request: &ShareRequest, username: &[u8]) -> Result<()> {
+        let mut shares = self.0.lock().unwrap();
+        let now = Instant::now();
+        shares.retain(|share| share.expires > now);
+        if shares.len() >= MAX_TOKENS {
+            bail!("too many share links are still valid");
+        }
+        shares.push(Share {
+            token: request.token.clone(),
+            expires: now + request.valid_for,
+            observer: request.observer,
+            username: username.into(),
+        });
+        Ok(())
+    }
+
+    /// Takes the share `token` if it is still valid, it can't be used again.
+    pub fn redeem(&self, token: &[u8]) -> Result<Redeemed> {
+        let mut shares = self.0.lock().unwrap();
+        let now = Instant::now();
+        shares.retain(|share| share.expires > now);
+        // every token is compared, which one matched doesn't show in the time it took
+        let found = shares
+            .iter()
+            .map(|share| crate::handshake::secret_matches(token, share.token.as_bytes()))
+            .enumerate()
+            .fold(None, |found, (index, matches)| {
+                found.or(matches.then_some(index))
+            })
+            .ok_or_else(|| format_err!("share token is invalid or expired"))?;
+        let share = shares.swap_remove(found);
+        Ok(Redeemed {
+            username: share.username,
+            observer: share.observer,
+        })
+    }
+}
+
+/// Registers a new token with the session `options.session_id` and prints it.
+pub fn share(options: &ShareOptions) -> Result<()> {
+    let mut stream = crate::detach::connect(&options.status_dir, &options.session_id)?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+
+    let token = Zeroizing::new(crate::cli::random_hex(TOKEN_LEN)?);
+    let message = Zeroizing::new(
+        [
+            REQUEST_PREFIX,
+            options.valid_for.as_secs().to_string().as_bytes(),
+            b"\n",
+            if options.observe { b"1" } else { b"0" },
+            b"\n",
+            token.as_bytes(),
+        ]
+        .concat(),
+    );
+    stream.write_all(&message)?;
+    // the session reads the request up to its end
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    let mut reply = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut reply)
+        .map_err(|err| format_err!("no answer from session '{}' - {err}", options.session_id))?;
+    match reply.trim_end() {
+        "OK" => {
+            println!("{}", *token);
+            Ok(())
+        }
+        "" => bail!("session '{}' closed the connection", options.session_id),
+        err => bail!(
+            "session '{}' refused the share link - {err}",
+            options.session_id
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdef0123456789ABCDEF";
+
+    fn parse(message: &str) -> Result<ShareRequest> {
+        let (stream, _) = UnixStream::pair().unwrap();
+        ShareRequest::parse(message.as_bytes(), stream)
+    }
+
+    /// [`TOKEN`] ending in `last` instead.
+    fn token(last: char) -> String {
+        format!("{}{last}", &TOKEN[..TOKEN.len() - 1])
+    }
+
+    /// A request for the token ending in `last`.
+    fn request(last: char, valid_for: u64, observer: bool) -> ShareRequest {
+        let observer = u8::from(observer);
+        parse(&format!("{valid_for}\n{observer}\n{}", token(last))).unwrap()
+    }
+
+    #[test]
+    fn parses_requests() {
+        let request = parse(&format!("300\n1\n{TOKEN}")).unwrap();
+        assert_eq!(*request.token, TOKEN);
+        assert_eq!(request.valid_for(), Duration::from_secs(300));
+        assert!(request.observer());
+        assert!(!parse(&format!("60\n0\n{TOKEN}")).unwrap().observer());
+
+        for invalid in [
+            String::new(),
+            format!("300\n1{TOKEN}"),
+            format!("-1\n0\n{TOKEN}"),
+            format!("5m\n0\n{TOKEN}"),
+            // tokens of the wrong length, with characters other than hex digits or a line break
+            format!("300\n0\n{}", &TOKEN[1..]),
+            format!("300\n0\n{TOKEN}0"),
+            format!("300\n0\n{}g", &TOKEN[1..]),
+            format!("300\n0\n{TOKEN}\n"),
+        ] {
+            assert!(parse(&invalid).is_err(), "accepted {invalid:?}");
+        }
+        let (stream, _) = UnixStream::pair().unwrap();
+        assert!(ShareRequest::parse(b"300\n0\n\xff", stream).is_err());
+    }
+
+    #[test]
+    fn replies() {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        let request = ShareRequest::parse(format!("60\n0\n{TOKEN}").as_bytes(), stream).unwrap();
+        request.reply(&Err(format_err!("session does not accept share links")));
+        let mut reply = String::new();
+        peer.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "session does not accept share links\n");
+    }
+
+    #[test]
+    fn redeems_tokens_once() {
+        let tokens = ShareTokens::default();
+        tokens
+            .register(&request('0', 60, false), b"alice@pve")
+            .unwrap();
+        tokens
+            .register(&request('1', 60, true), b"alice@pve")
+            .unwrap();
+
+        let redeemed = tokens.redeem(token('1').as_bytes()).unwrap();
+        assert_eq!(&*redeemed.username, b"alice@pve");
+        assert!(redeemed.observer);
+        assert!(tokens.redeem(token('1').as_bytes()).is_err());
+
+        // other tokens, or parts of one, are not accepted, and don't use up the token
+        for invalid in [TOKEN, &TOKEN[..TOKEN.len() - 1], ""] {
+            assert!(tokens.redeem(invalid.as_bytes()).is_err());
+        }
+        let redeemed = tokens.redeem(token('0').as_bytes()).unwrap();
+        assert!(!redeemed.observer);
+    }
+
+    #[test]
+    fn expires_tokens() {
+        let tokens = ShareTokens::default();
+        tokens
+            .register(&request('0', 0, false), b"alice@pve")
+            .unwrap();
+        assert!(tokens.redeem(token('0').as_bytes()).is_err());
+    }
+
+    #[test]
+    fn limits_tokens() {
+        let tokens = ShareTokens::default();
+        let last = |index: usize| char::from_digit(index as u32, 16).unwrap();
+        for index in 0..MAX_TOKENS {
+            tokens
+                .register(&request(last(index), 60, false), b"alice@pve")
+                .unwrap();
+        }
+        assert!(tokens
+            .register(&request('0', 60, false), b"alice@pve")
+            .is_err());
+
+        // redeemed and expired tokens make room for new ones
+        tokens.redeem(token('0').as_bytes()).unwrap();
+        tokens
+            .register(&request('0', 0, false), b"alice@pve")
+            .unwrap();
+        tokens
+            .register(&request('1', 60, false), b"alice@pve")
+            .unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+//! Status files for monitoring sessions
+//!
+//! A running session periodically writes `<session>.status` into the status directory, with one
+//! `key=value` pair per line, so it can be inspected with `cat`, `grep` and friends. The file is
+//! replaced atomically on every update and removed once the session ends. `list` reads them
+//! back to show the sessions of the host.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{format_err, Result};
+
+pub struct StatusFile {
+    path: PathBuf,
+}
+
+impl StatusFile {
+    pub fn new(dir: &Path, session_id: &str) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .map_err(|err| format_err!("failed to create status directory {dir:?} - {err}"))?;
+        Ok(Self {
+            path: dir.join(format!("{session_id}.status")),
+        })
+    }
+
+    /// Replaces the status file with the given fields.
+    pub fn write(&self, fields: &[(&str, String)]) -> Result<()> {
+        let mut content = String::new();
+        for (key, value) in fields {
+            // a line break in a value would add a line that looks like another field
+            let value = value.replace(['\n', '\r'], " ");
+            content.push_str(&format!("{key}={value}\n"));
+        }
+
+        let tmp_path = self.path.with_extension("status.tmp");
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        std::fs::rename(&tmp_path, &self.path)
+            .map_err(|err| format_err!("failed to update {:?} - {err}", self.path))
+    }
+}
+
+impl Drop for StatusFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The status directory `list` looks at by default.
+pub const DEFAULT_STATUS_DIR: &str = "/run/termproxy";
+
+/// Reads the fields of a status file, `None` if it doesn't exist (anymore).
+pub fn read_status(path: &Path) -> Result<Option<Vec<(String, String)>>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format_err!("failed to read {path:?} - {err}")),
+    };
+    let fields = content
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    Ok(Some(fields))
+}
+
+/// Seconds since the epoch, the format of times in status files.
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
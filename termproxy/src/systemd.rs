@@ -0,0 +1,33 @@
+//! Running the terminal command in a transient systemd scope
+//!
+//! `systemd-run --scope` registers a scope unit for its own process with the service manager
+//! and then executes the command in place, so the command keeps the PID, session and controlling
+//! terminal termproxy set up for it, while resource control and accounting follow the policy of
+//! the slice it lands in.
+
+use std::ffi::OsString;
+use std::process::Command;
+
+/// Settings for the transient scope unit
+#[derive(Debug, Default)]
+pub struct ScopeOptions {
+    /// The slice to place the scope in
+    pub slice: Option<String>,
+    /// Unit properties like `MemoryMax=1G`, passed to systemd as they are
+    pub properties: Vec<String>,
+}
+
+/// Builds a command running `command` in the scope `termproxy-<session_id>.scope`.
+pub fn scope_command(scope: &ScopeOptions, session_id: &str, command: &[OsString]) -> Command {
+    let mut run = Command::new("systemd-run");
+    run.args(["--scope", "--quiet", "--collect"])
+        .arg(format!("--unit=termproxy-{session_id}"));
+    if let Some(slice) = &scope.slice {
+        run.arg(format!("--slice={slice}"));
+    }
+    for property in &scope.properties {
+        run.arg(format!("--property={property}"));
+    }
+    run.arg("--").args(command);
+    run
+}
@@ -0,0 +1,52 @@
+//! Choosing a TERM value the command's system knows
+//!
+//! Programs look up the capabilities of the terminal named by `TERM` in the terminfo database.
+//! Minimal systems, e.g. containers without ncurses-term, may lack an entry for the preferred
+//! type, which leaves users with broken keys and garbled screens, so the first of a list of
+//! candidates that has an entry is used.
+
+use std::path::{Path, PathBuf};
+
+/// The candidates tried if none are configured, from most to least capable.
+pub const DEFAULT_CANDIDATES: &[&str] = &["xterm-256color", "xterm", "vt100"];
+
+/// Where ncurses looks for terminfo entries by default.
+const TERMINFO_DIRS: &[&str] = &[
+    "etc/terminfo",
+    "lib/terminfo",
+    "usr/share/terminfo",
+    "usr/lib/terminfo",
+];
+
+/// The possible paths of the entry for `term` below the terminfo directory `dir`.
+fn entry_paths(dir: &Path, term: &str) -> Vec<PathBuf> {
+    let Some(first) = term.bytes().next() else {
+        return Vec::new();
+    };
+    vec![
+        dir.join((first as char).to_string()).join(term),
+        // some systems use the hex code of the first character as directory
+        dir.join(format!("{first:02x}")).join(term),
+    ]
+}
+
+/// Whether the terminfo database of the system at `root` has an entry for `term`.
+pub fn has_entry(root: &Path, term: &str) -> bool {
+    // a name with a slash would escape the database
+    if term.contains('/') {
+        return false;
+    }
+    TERMINFO_DIRS.iter().any(|dir| {
+        entry_paths(&root.join(dir), term)
+            .iter()
+            .any(|path| path.is_file())
+    })
+}
+
+/// Picks the first of `candidates` known to the system at `root`.
+pub fn select<'a>(root: &Path, candidates: &'a [String]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .find(|term| has_entry(root, term))
+        .map(String::as_str)
+}
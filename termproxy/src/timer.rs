@@ -0,0 +1,80 @@
+//! Helpers for tracking timeouts
+//!
+//! Instead of remembering a start [`Instant`] and subtracting the elapsed time on every loop
+//! iteration, code waiting for some event creates a [`Deadline`] once and asks it for the
+//! remaining time whenever it needs to (re-)enter a poll. Event loops waiting on more than one
+//! timeout at once keep them in a [`Timers`] set.
+
+use std::time::{Duration, Instant};
+
+/// A point in time after which a pending operation is considered to have timed out.
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    /// Creates a deadline that expires `timeout` from now.
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now() + timeout)
+    }
+
+    /// Returns the time left until the deadline expires, zero if it already did.
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+
+    /// Returns true if the deadline has passed.
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.0
+    }
+}
+
+/// Timers expiring this close to each other are handled in the same wake-up.
+const GRANULARITY: Duration = Duration::from_millis(10);
+
+/// A set of pending timers for an event loop, identified by `T`.
+///
+/// There are only ever a handful of timers per session, so this is just an unordered list.
+pub struct Timers<T> {
+    timers: Vec<(Instant, T)>,
+}
+
+impl<T: PartialEq> Timers<T> {
+    pub fn new() -> Self {
+        Self { timers: Vec::new() }
+    }
+
+    /// Arms `timer` to expire after `timeout`, replacing a pending one of the same kind.
+    pub fn set(&mut self, timer: T, timeout: Duration) {
+        self.cancel(&timer);
+        self.timers.push((Instant::now() + timeout, timer));
+    }
+
+    /// Disarms `timer` if it is pending.
+    pub fn cancel(&mut self, timer: &T) {
+        self.timers.retain(|(_, pending)| pending != timer);
+    }
+
+    /// Returns true if `timer` is armed.
+    pub fn is_pending(&self, timer: &T) -> bool {
+        self.timers.iter().any(|(_, pending)| pending == timer)
+    }
+
+    /// Returns how long the event loop may sleep before the next timer expires, `None` if no
+    /// timer is pending.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        self.timers
+            .iter()
+            .map(|(expires, _)| expires.saturating_duration_since(Instant::now()))
+            .min()
+    }
+
+    /// Removes and returns an expired timer.
+    pub fn pop_expired(&mut self) -> Option<T> {
+        let now = Instant::now() + GRANULARITY;
+        let index = self
+            .timers
+            .iter()
+            .position(|(expires, _)| *expires <= now)?;
+        Some(self.timers.swap_remove(index).1)
+    }
+}
@@ -0,0 +1,87 @@
+//! Profiling the start of a session
+//!
+//! With `--timing`, termproxy notes when it got through each step of starting a session and,
+//! once it exits, prints how long every step took since the previous one, e.g.
+//!
+//! ```text
+//! startup timing: listen 0.4ms, accept 212.9ms, auth 1804.2ms, spawn 3.1ms, first-byte 41.7ms, total 2062.3ms
+//! ```
+//!
+//! `accept` includes waiting for the client to connect at all, `first-byte` lasts until the
+//! first output of the command was sent to a client. Steps not reached are left out, e.g. if
+//! the authentication failed.
+
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Step {
+    /// The listener is ready for clients
+    Listen,
+    /// The first client connected
+    Accept,
+    /// The first client is authenticated
+    Auth,
+    /// The command runs
+    Spawn,
+    /// The first output of the command was sent to a client
+    FirstByte,
+}
+
+impl Step {
+    fn as_str(self) -> &'static str {
+        match self {
+            Step::Listen => "listen",
+            Step::Accept => "accept",
+            Step::Auth => "auth",
+            Step::Spawn => "spawn",
+            Step::FirstByte => "first-byte",
+        }
+    }
+}
+
+/// The steps reached so far, printed once dropped
+pub struct StartupTiming {
+    started: Instant,
+    steps: Vec<(Step, Instant)>,
+}
+
+impl StartupTiming {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Notes that `step` was reached now, unless it was before already.
+    pub fn mark(&mut self, step: Step) {
+        if !self.steps.iter().any(|(reached, _)| *reached == step) {
+            self.steps.push((step, Instant::now()));
+        }
+    }
+
+    fn report(&self) -> String {
+        let mut report = String::from("startup timing:");
+        let mut previous = self.started;
+        for (step, reached) in &self.steps {
+            report.push_str(&format!(
+                " {} {},",
+                step.as_str(),
+                millis(*reached - previous)
+            ));
+            previous = *reached;
+        }
+        report.push_str(&format!(" total {}", millis(previous - self.started)));
+        report
+    }
+}
+
+impl Drop for StartupTiming {
+    fn drop(&mut self) {
+        println!("{}", self.report());
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
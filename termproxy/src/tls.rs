@@ -0,0 +1,187 @@
+//! TLS for the client connection
+//!
+//! Usually the client connects through the API daemon on localhost, where the ticket and the
+//! terminal data don't need protection. If the listener is reachable from elsewhere, the
+//! connection can be wrapped in TLS with `--tls-cert` and `--tls-key`, before anything else is
+//! read from it.
+//!
+//! Clients can ask for the version of the message protocol with ALPN, before any of it is sent:
+//! `termproxy/1` is the protocol described in the README. A client offering versions of which
+//! termproxy knows none is rejected during the handshake, instead of misunderstanding what is
+//! sent later. Clients not using ALPN, or offering other protocols only like browsers do for
+//! the WebSocket, get the one termproxy speaks.
+
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, format_err, Result};
+use mio::event::Source;
+use mio::{Events, Interest, Poll, Registry, Token};
+use openssl::ssl::{
+    select_next_proto, AlpnError, HandshakeError, SslAcceptor, SslFiletype, SslMethod, SslStream,
+};
+
+use crate::connection::Connection;
+use crate::timer::Deadline;
+
+/// The size of a TLS record beyond the data in it: the header, the explicit nonce of TLS 1.2
+/// AES-GCM ciphers and the authentication tag.
+pub const RECORD_OVERHEAD: usize = 5 + 8 + 16;
+
+/// The versions of the message protocol termproxy speaks, in the ALPN wire format, the
+/// preferred one first.
+const PROTOCOLS: &[u8] = b"\x0btermproxy/1";
+
+/// What the ALPN protocols of the message protocol start with.
+const PROTOCOL_PREFIX: &[u8] = b"termproxy/";
+
+/// Selects the version of the message protocol for a client offering the `client` protocols, in
+/// the ALPN wire format.
+fn select_protocol(client: &[u8]) -> Result<&[u8], AlpnError> {
+    if let Some(protocol) = select_next_proto(PROTOCOLS, client) {
+        return Ok(protocol);
+    }
+    let mut rest = client;
+    while let Some((&len, tail)) = rest.split_first() {
+        let len = usize::from(len).min(tail.len());
+        if tail[..len].starts_with(PROTOCOL_PREFIX) {
+            // the client only speaks versions termproxy doesn't
+            return Err(AlpnError::ALERT_FATAL);
+        }
+        rest = &tail[len..];
+    }
+    Err(AlpnError::NOACK)
+}
+
+/// Builds the acceptor for all connections from the PEM encoded certificate chain and key.
+pub fn acceptor(cert: &Path, key: &Path) -> Result<SslAcceptor> {
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    acceptor.set_alpn_select_callback(|_, client| select_protocol(client));
+    acceptor
+        .set_certificate_chain_file(cert)
+        .map_err(|err| format_err!("failed to load TLS certificate {cert:?} - {err}"))?;
+    acceptor
+        .set_private_key_file(key, SslFiletype::PEM)
+        .map_err(|err| format_err!("failed to load TLS key {key:?} - {err}"))?;
+    acceptor
+        .check_private_key()
+        .map_err(|err| format_err!("TLS key does not match the certificate - {err}"))?;
+    Ok(acceptor.build())
+}
+
+/// Performs the server side of the TLS handshake on `stream`.
+pub fn accept(
+    acceptor: &SslAcceptor,
+    mut stream: Connection,
+    deadline: Deadline,
+) -> Result<TlsStream> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(1);
+    poll.registry().register(
+        &mut stream,
+        Token(0),
+        Interest::READABLE | Interest::WRITABLE,
+    )?;
+
+    let mut handshake = acceptor.accept(stream);
+    let mut stream = loop {
+        match handshake {
+            Ok(stream) => break stream,
+            Err(HandshakeError::WouldBlock(mid_handshake)) => {
+                if deadline.is_expired() {
+                    bail!("TLS handshake timed out");
+                }
+                poll.poll(&mut events, Some(deadline.remaining()))?;
+                handshake = mid_handshake.handshake();
+            }
+            Err(HandshakeError::Failure(mid_handshake)) => {
+                bail!("TLS handshake failed - {}", mid_handshake.error());
+            }
+            Err(HandshakeError::SetupFailure(err)) => bail!("TLS setup failed - {err}"),
+        }
+    };
+    // the stream gets registered with other polls afterwards
+    poll.registry().deregister(stream.get_mut())?;
+
+    Ok(TlsStream { stream })
+}
+
+/// A client connection wrapped in TLS
+pub struct TlsStream {
+    stream: SslStream<Connection>,
+}
+
+impl TlsStream {
+    pub fn stream_mut(&mut self) -> &mut Connection {
+        self.stream.get_mut()
+    }
+
+    /// Sends the close notification, to be called once all data is written.
+    pub fn close(&mut self) {
+        let _ = self.stream.shutdown();
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Source for TlsStream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.stream_mut().register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.stream_mut().reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> std::io::Result<()> {
+        self.stream_mut().deregister(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_protocol_version() {
+        assert_eq!(select_protocol(b"\x0btermproxy/1"), Ok(&b"termproxy/1"[..]));
+        assert_eq!(
+            select_protocol(b"\x0btermproxy/2\x0btermproxy/1"),
+            Ok(&b"termproxy/1"[..]),
+        );
+        assert_eq!(
+            select_protocol(b"\x0btermproxy/2"),
+            Err(AlpnError::ALERT_FATAL)
+        );
+        // browsers connecting for the WebSocket don't know about it
+        assert_eq!(
+            select_protocol(b"\x02h2\x08http/1.1"),
+            Err(AlpnError::NOACK)
+        );
+        assert_eq!(select_protocol(b""), Err(AlpnError::NOACK));
+    }
+}
@@ -0,0 +1,92 @@
+//! Registering sessions in utmp and wtmp
+//!
+//! With `--utmp`, the terminal of a session is registered in utmp like the ones of other logins,
+//! so `who` and `w` list it, and the login and the logout are appended to wtmp for `last`. The
+//! entries name the user the session was started for and the address of its client.
+//!
+//! Writing the files needs root, or at least the utmp group. If that fails, the session goes on
+//! without its entries.
+
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+
+const WTMP_PATH: &str = "/var/log/wtmp";
+
+extern "C" {
+    // not bound by the libc crate
+    fn updwtmpx(wtmpx_file: *const c_char, utmpx: *const libc::utmpx);
+}
+
+/// The utmp entry of a session, marked as dead again once dropped
+pub struct UtmpEntry {
+    entry: libc::utmpx,
+}
+
+impl UtmpEntry {
+    /// Registers the terminal `tty` (e.g. /dev/pts/3) of the session led by `pid`.
+    pub fn login(tty: &str, pid: u32, user: &str, host: &str) -> Result<Self> {
+        let line = tty.strip_prefix("/dev/").unwrap_or(tty);
+        // zeroed, as the structure has reserved fields and its time field differs by architecture
+        let mut entry: libc::utmpx = unsafe { std::mem::zeroed() };
+        entry.ut_type = libc::USER_PROCESS;
+        entry.ut_pid = pid as libc::pid_t;
+        copy_field(&mut entry.ut_line, line);
+        // the end of the line, like login does
+        copy_field(&mut entry.ut_id, &line[line.len().saturating_sub(4)..]);
+        copy_field(&mut entry.ut_user, user);
+        copy_field(&mut entry.ut_host, host);
+        set_time(&mut entry);
+
+        let mut utmp = Self { entry };
+        utmp.write()?;
+        Ok(utmp)
+    }
+
+    fn write(&mut self) -> Result<()> {
+        let written = unsafe {
+            libc::setutxent();
+            let written = !libc::pututxline(&self.entry).is_null();
+            libc::endutxent();
+            written
+        };
+        if !written {
+            bail!(
+                "failed to write utmp entry - {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let path = CString::new(WTMP_PATH)?;
+        unsafe { updwtmpx(path.as_ptr(), &self.entry) };
+        Ok(())
+    }
+}
+
+impl Drop for UtmpEntry {
+    fn drop(&mut self) {
+        self.entry.ut_type = libc::DEAD_PROCESS;
+        self.entry.ut_user = [0; libc::__UT_NAMESIZE];
+        self.entry.ut_host = [0; libc::__UT_HOSTSIZE];
+        set_time(&mut self.entry);
+        if let Err(err) = self.write() {
+            crate::log::warn("utmp-failed", format_args!("{err}"));
+        }
+    }
+}
+
+/// Copies `value` into a field, cut off if it is too long, the fields needn't be NUL terminated.
+fn copy_field(field: &mut [c_char], value: &str) {
+    for (dest, &byte) in field.iter_mut().zip(value.as_bytes()) {
+        *dest = byte as c_char;
+    }
+}
+
+fn set_time(entry: &mut libc::utmpx) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    entry.ut_tv.tv_sec = now.as_secs() as _;
+    entry.ut_tv.tv_usec = now.subsec_micros() as _;
+}
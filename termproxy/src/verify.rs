@@ -0,0 +1,573 @@
+//! Protocol conformance check for clients
+//!
+//! Instead of running a command, `verify-client` accepts a client like the proxy would, walks
+//! the user through a few exercises (typing, resize storm, big paste, non-ASCII input, a flood of
+//! output to pause, ping) and checks that every message the client sends strictly follows the
+//! protocol. The proxy itself is
+//! rather lenient and silently skips garbage, so this is the place to catch client bugs like
+//! miscounted data lengths early.
+
+use std::io::{ErrorKind, Write};
+use std::time::Duration;
+
+use anyhow::{bail, format_err, Result};
+use mio::{Events, Interest, Poll, Token};
+
+use crate::cli::{ListenerOptions, PortOrFd, DEFAULT_MAX_AUTH_LINE};
+use crate::compat::ByteBuffer;
+use crate::connection::Connection;
+use crate::control::{base64_decode, ControlCommand, MAX_CONTROL_LEN};
+use crate::message::MAX_BASE64_LEN;
+use crate::timer::Deadline;
+
+const STEP_TIMEOUT: Duration = Duration::from_secs(60);
+// clients are expected to ping at least every 30 seconds
+const PING_TIMEOUT: Duration = Duration::from_secs(35);
+// the longest number we accept in a message, same as the proxy
+const MAX_NUMBER_LEN: usize = 20;
+// the output of the flow control step, more than a client renders right away
+const FLOOD_SIZE: usize = 4 * 1024 * 1024;
+const FLOOD_LINE: &[u8] =
+    b"termproxy flow control check, clients may pause this output while they fall behind\r\n";
+
+#[derive(Debug, PartialEq)]
+enum Message {
+    /// Header of a data message with the payload length
+    Data(usize),
+    Resize(u64, u64),
+    Ping,
+    /// A control message with its payload, which always fits into the buffer
+    Control(Vec<u8>),
+    /// A base64 data message with its payload, still encoded
+    Base64(Vec<u8>),
+    Pause,
+    Resume,
+}
+
+/// Parses the `NUMBER:` at the start of `buf`, returns the number and the bytes consumed.
+fn parse_number(buf: &[u8]) -> Result<Option<(u64, usize)>> {
+    let colon = match buf.iter().position(|&b| b == b':') {
+        Some(colon) => colon,
+        None if buf.len() > MAX_NUMBER_LEN => bail!("number without terminating ':'"),
+        None if buf.iter().all(u8::is_ascii_digit) => return Ok(None),
+        None => bail!("invalid number {:?}", String::from_utf8_lossy(buf)),
+    };
+
+    let number = std::str::from_utf8(&buf[..colon])
+        .ok()
+        .filter(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|number| number.parse().ok())
+        .ok_or_else(|| {
+            format_err!(
+                "invalid number {:?}",
+                String::from_utf8_lossy(&buf[..colon])
+            )
+        })?;
+
+    Ok(Some((number, colon + 1)))
+}
+
+/// Strictly parses the message at the start of `buf`, returns it and the bytes it occupies or
+/// `None` if the message is not complete yet. For data messages only the header is parsed.
+fn parse_message(buf: &[u8]) -> Result<Option<(Message, usize)>> {
+    let msgtype = match buf.first() {
+        Some(msgtype) => *msgtype,
+        None => return Ok(None),
+    };
+
+    match msgtype {
+        b'2' => return Ok(Some((Message::Ping, 1))),
+        b'5' => return Ok(Some((Message::Pause, 1))),
+        b'6' => return Ok(Some((Message::Resume, 1))),
+        b'0' | b'1' | b'3' | b'4' => (),
+        _ => bail!("invalid message type {:?}", msgtype as char),
+    }
+
+    match buf.get(1) {
+        None => return Ok(None),
+        Some(b':') => (),
+        Some(_) => bail!("missing ':' after message type"),
+    }
+    let mut pos = 2;
+
+    let first = match parse_number(&buf[pos..])? {
+        Some((number, len)) => {
+            pos += len;
+            number
+        }
+        None => return Ok(None),
+    };
+
+    if msgtype == b'0' {
+        return Ok(Some((Message::Data(first as usize), pos)));
+    }
+    if msgtype == b'3' {
+        // the proxy skips longer ones as garbage
+        if first > MAX_CONTROL_LEN as u64 {
+            bail!("control message of {first} bytes, longer than {MAX_CONTROL_LEN}");
+        }
+        let end = pos + first as usize;
+        return Ok(buf
+            .get(pos..end)
+            .map(|payload| (Message::Control(payload.to_vec()), end)));
+    }
+    if msgtype == b'4' {
+        // the proxy drops longer ones, they would never fit into its buffer
+        if first > MAX_BASE64_LEN as u64 {
+            bail!("base64 data message of {first} bytes, longer than {MAX_BASE64_LEN}");
+        }
+        let end = pos + first as usize;
+        return Ok(buf
+            .get(pos..end)
+            .map(|payload| (Message::Base64(payload.to_vec()), end)));
+    }
+
+    match parse_number(&buf[pos..])? {
+        Some((rows, len)) => Ok(Some((Message::Resize(first, rows), pos + len))),
+        None => Ok(None),
+    }
+}
+
+#[derive(Default)]
+struct StepStats {
+    data_messages: usize,
+    data_bytes: usize,
+    max_data_len: usize,
+    non_ascii_bytes: usize,
+    saw_enter: bool,
+    resize_messages: usize,
+    pings: usize,
+    control_messages: usize,
+    pauses: usize,
+    /// Whether output is paused by the client
+    paused: bool,
+    /// Whether all output of a flood was written
+    flooded: bool,
+    split_messages: usize,
+    issues: Vec<String>,
+}
+
+impl StepStats {
+    fn record_data(&mut self, data: &[u8]) {
+        self.count_data(data);
+        if std::str::from_utf8(data).is_err() {
+            self.issues.push(format!(
+                "data message of {} bytes is not valid UTF-8, is the length counted in bytes?",
+                data.len()
+            ));
+        }
+    }
+
+    /// Counts the data of a data message, base64 ones are binary and need not be UTF-8.
+    fn count_data(&mut self, data: &[u8]) {
+        self.data_messages += 1;
+        self.data_bytes += data.len();
+        self.max_data_len = self.max_data_len.max(data.len());
+        self.non_ascii_bytes += data.iter().filter(|b| !b.is_ascii()).count();
+        self.saw_enter |= data.contains(&b'\r');
+    }
+}
+
+/// The payload of a data message, which may be bigger than the receive buffer
+#[derive(Default)]
+struct PendingData {
+    remaining: usize,
+    payload: Vec<u8>,
+}
+
+struct Step {
+    name: &'static str,
+    instruction: &'static str,
+    timeout: Duration,
+    /// Whether to flood the client with output during the step
+    flood: bool,
+    is_done: fn(&StepStats) -> bool,
+}
+
+const STEPS: &[Step] = &[
+    Step {
+        name: "typing",
+        instruction: "Type a few characters and press Enter.",
+        timeout: STEP_TIMEOUT,
+        flood: false,
+        is_done: |stats| stats.saw_enter,
+    },
+    Step {
+        name: "resize storm",
+        instruction: "Resize the terminal (e.g. the browser window) quickly, several times.",
+        timeout: STEP_TIMEOUT,
+        flood: false,
+        is_done: |stats| stats.resize_messages >= 10,
+    },
+    Step {
+        name: "big paste",
+        instruction: "Paste a large block of text, at least 8 KiB.",
+        timeout: STEP_TIMEOUT,
+        flood: false,
+        is_done: |stats| stats.data_bytes >= 8192,
+    },
+    Step {
+        name: "non-ASCII input",
+        instruction: "Type or paste some non-ASCII characters (e.g. \u{e4}\u{20ac}\u{1f600}) and press Enter.",
+        timeout: STEP_TIMEOUT,
+        flood: false,
+        is_done: |stats| stats.saw_enter && stats.non_ascii_bytes > 0,
+    },
+    Step {
+        name: "flow control",
+        instruction: "Wait for a flood of output to end, a client with flow control pauses it while \
+                      it falls behind and resumes it later.",
+        timeout: STEP_TIMEOUT,
+        flood: true,
+        // pausing is optional, but a paused flood has to be resumed
+        is_done: |stats| stats.flooded && !stats.paused,
+    },
+    Step {
+        name: "ping",
+        instruction: "Wait for the client to send a keep-alive ping, this takes up to 30 seconds.",
+        timeout: PING_TIMEOUT,
+        flood: false,
+        is_done: |stats| stats.pings > 0,
+    },
+];
+
+fn send_line(stream: &mut Connection, line: &str) -> Result<()> {
+    // the connection is non-blocking, but these are only a few bytes and the client is idle
+    let mut data = format!("{line}\r\n").into_bytes();
+    while !data.is_empty() {
+        match stream.write(&data) {
+            Ok(n) => drop(data.drain(..n)),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(10))
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Writes the output of a flood until the connection blocks, `left` being what is left of it,
+/// and returns what is left then.
+fn write_flood(stream: &mut Connection, mut left: usize) -> Result<usize> {
+    let chunk = FLOOD_LINE.repeat(64);
+    while left > 0 {
+        // continue where the last write stopped, lines stay intact
+        let offset = (FLOOD_SIZE - left) % chunk.len();
+        let len = (chunk.len() - offset).min(left);
+        match stream.write(&chunk[offset..offset + len]) {
+            Ok(0) => bail!("client closed the connection"),
+            Ok(n) => left -= n,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(left)
+}
+
+/// Consumes all complete messages from `buf`, records them in `stats`.
+fn process_messages(
+    buf: &mut ByteBuffer,
+    pending: &mut PendingData,
+    stats: &mut StepStats,
+) -> Result<()> {
+    loop {
+        if pending.remaining > 0 {
+            let len = pending.remaining.min(buf.len());
+            if len == 0 {
+                return Ok(());
+            }
+            pending.payload.extend_from_slice(&buf[..len]);
+            buf.consume(len);
+            pending.remaining -= len;
+            if pending.remaining == 0 {
+                stats.record_data(&pending.payload);
+                pending.payload.clear();
+            }
+            continue;
+        }
+
+        let (message, len) = match parse_message(buf)? {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        match message {
+            Message::Data(0) => stats.record_data(&[]),
+            Message::Data(data_len) => pending.remaining = data_len,
+            Message::Resize(cols, rows) => {
+                stats.resize_messages += 1;
+                if cols == 0 || rows == 0 || cols > u16::MAX as u64 || rows > u16::MAX as u64 {
+                    stats
+                        .issues
+                        .push(format!("invalid terminal size {cols}x{rows}"));
+                }
+            }
+            Message::Ping => stats.pings += 1,
+            Message::Control(payload) => {
+                stats.control_messages += 1;
+                if let Err(err) = ControlCommand::parse(&payload) {
+                    stats.issues.push(format!(
+                        "invalid control message {:?} - {err}",
+                        String::from_utf8_lossy(&payload)
+                    ));
+                }
+            }
+            // the proxy drops the whole message
+            Message::Base64(payload) => match base64_decode(&payload) {
+                Ok(data) => stats.count_data(&data),
+                Err(err) => stats
+                    .issues
+                    .push(format!("invalid base64 data message - {err}")),
+            },
+            Message::Pause => {
+                stats.pauses += 1;
+                stats.paused = true;
+            }
+            Message::Resume => stats.paused = false,
+        }
+        buf.consume(len);
+    }
+}
+
+fn run_step(
+    stream: &mut Connection,
+    poll: &mut Poll,
+    buf: &mut ByteBuffer,
+    pending: &mut PendingData,
+    step: &Step,
+    paused: bool,
+) -> Result<StepStats> {
+    let mut events = Events::with_capacity(1);
+    // a client may pause output any time, even before the flood started
+    let mut stats = StepStats {
+        paused,
+        ..Default::default()
+    };
+    let deadline = Deadline::after(step.timeout);
+
+    let mut flood = step.flood.then_some(FLOOD_SIZE);
+    if flood.is_some() {
+        poll.registry()
+            .reregister(stream, Token(0), Interest::READABLE | Interest::WRITABLE)?;
+    }
+
+    while !(step.is_done)(&stats) {
+        if deadline.is_expired() {
+            stats.issues.push("timed out".to_string());
+            break;
+        }
+        poll.poll(&mut events, Some(deadline.remaining()))?;
+
+        loop {
+            if buf.is_full() {
+                bail!("message does not fit into {} bytes", buf.len());
+            }
+            match buf.read_from(stream) {
+                Ok(0) => bail!("client closed the connection"),
+                Ok(_) => (),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err.into()),
+            }
+            process_messages(buf, pending, &mut stats)
+                .map_err(|err| format_err!("protocol violation - {err}"))?;
+        }
+
+        // whatever is left over is the start of a message split across reads, which clients
+        // may do, but the proxy must be able to cope with, so count it
+        if !buf.is_empty() || pending.remaining > 0 {
+            stats.split_messages += 1;
+        }
+
+        // like the proxy, nothing is written while the client paused output
+        if let Some(left) = flood.filter(|_| !stats.paused) {
+            let left = write_flood(stream, left)?;
+            flood = (left > 0).then_some(left);
+            stats.flooded = flood.is_none();
+        }
+    }
+
+    if step.flood {
+        poll.registry()
+            .reregister(stream, Token(0), Interest::READABLE)?;
+    }
+    Ok(stats)
+}
+
+fn report_step(step: &Step, stats: &StepStats) -> String {
+    let result = if stats.issues.is_empty() {
+        "PASS"
+    } else {
+        "FAIL"
+    };
+    let mut report = format!(
+        "{result}: {} - {} data messages ({} bytes, max. {}), {} resizes, {} pings, {} control, \
+         {} pauses, {} split",
+        step.name,
+        stats.data_messages,
+        stats.data_bytes,
+        stats.max_data_len,
+        stats.resize_messages,
+        stats.pings,
+        stats.control_messages,
+        stats.pauses,
+        stats.split_messages,
+    );
+    for issue in &stats.issues {
+        report.push_str(&format!("\r\n    {issue}"));
+    }
+    report
+}
+
+pub fn verify_client(listen_port: &PortOrFd) -> Result<()> {
+    let (mut stream, _port) = crate::listener::listen_and_accept(
+        "localhost",
+        listen_port,
+        &ListenerOptions::default(),
+        Deadline::after(STEP_TIMEOUT),
+    )
+    .map_err(|err| format_err!("failed waiting for client: {err}"))?;
+
+    let mut buf = ByteBuffer::new();
+    let deadline = Deadline::after(STEP_TIMEOUT);
+    let username =
+        crate::handshake::read_ticket_line(&mut stream, &mut buf, deadline, DEFAULT_MAX_AUTH_LINE)
+            .map_err(|err| format_err!("failed reading ticket line: {err}"))?
+            .username;
+    stream.write_all(b"OK")?;
+
+    let mut report = vec![format!(
+        "PASS: handshake - ticket line for user '{}'",
+        String::from_utf8_lossy(&username)
+    )];
+
+    let mut poll = Poll::new()?;
+    poll.registry()
+        .register(&mut stream, Token(0), Interest::READABLE)?;
+
+    let mut pending = PendingData::default();
+    let mut paused = false;
+
+    send_line(&mut stream, "termproxy client conformance check\r\n")?;
+    for (num, step) in STEPS.iter().enumerate() {
+        send_line(
+            &mut stream,
+            &format!("[{}/{}] {}", num + 1, STEPS.len(), step.instruction),
+        )?;
+
+        match run_step(&mut stream, &mut poll, &mut buf, &mut pending, step, paused) {
+            Ok(stats) => {
+                paused = stats.paused;
+                report.push(report_step(step, &stats));
+            }
+            Err(err) => {
+                report.push(format!("FAIL: {} - {err}", step.name));
+                break;
+            }
+        }
+        send_line(&mut stream, report.last().unwrap())?;
+    }
+
+    let _ = send_line(&mut stream, "\r\nSummary:");
+    for line in &report {
+        println!("{}", line.replace("\r\n", "\n"));
+        let _ = send_line(&mut stream, line);
+    }
+
+    if report.iter().any(|line| line.starts_with("FAIL")) {
+        bail!("client does not conform to the protocol");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(data: &[u8]) -> ByteBuffer {
+        let mut buf = ByteBuffer::new();
+        buf.read_from(&mut &data[..]).unwrap();
+        buf
+    }
+
+    fn process(data: &[u8]) -> StepStats {
+        let mut stats = StepStats::default();
+        process_messages(&mut buffer(data), &mut PendingData::default(), &mut stats).unwrap();
+        stats
+    }
+
+    #[test]
+    fn parses_messages() {
+        let parse = |buf: &[u8]| parse_message(buf).unwrap();
+        assert_eq!(parse(b"0:5:hello"), Some((Message::Data(5), 4)));
+        assert_eq!(parse(b"1:80:24:"), Some((Message::Resize(80, 24), 8)));
+        assert_eq!(parse(b"2"), Some((Message::Ping, 1)));
+        for incomplete in [&b""[..], b"0", b"0:", b"0:12", b"1:80:", b"1:80:2"] {
+            assert_eq!(parse(incomplete), None, "{incomplete:?}");
+        }
+        for invalid in [&b"0:-1:"[..], b"0::", b"0;5:", b"1:80::", b"x", b"9"] {
+            assert!(parse_message(invalid).is_err(), "{invalid:?}");
+        }
+        assert!(parse_message(&[b'0'; 23]).is_err());
+    }
+
+    #[test]
+    fn parses_control_messages() {
+        let parse = |buf: &[u8]| parse_message(buf).unwrap();
+        assert_eq!(
+            parse(b"3:7:suspend2"),
+            Some((Message::Control(b"suspend".to_vec()), 11))
+        );
+        assert_eq!(parse(b"3:0:"), Some((Message::Control(Vec::new()), 4)));
+        // the payload has to arrive as a whole
+        assert_eq!(parse(b"3:7:susp"), None);
+        assert_eq!(parse(b"3:7:"), None);
+
+        let max = format!("3:{MAX_CONTROL_LEN}:");
+        assert_eq!(parse(max.as_bytes()), None);
+        let longer = format!("3:{}:", MAX_CONTROL_LEN + 1);
+        assert!(parse_message(longer.as_bytes()).is_err());
+        assert!(parse_message(b"3:x:").is_err());
+
+        // well formed, but unknown commands are reported
+        let stats = process(b"3:7:suspend3:10:signal:int3:5:bogus");
+        assert_eq!(stats.control_messages, 3);
+        assert_eq!(stats.issues.len(), 1);
+        assert!(stats.issues[0].contains("bogus"));
+    }
+
+    #[test]
+    fn parses_base64_messages() {
+        let parse = |buf: &[u8]| parse_message(buf).unwrap();
+        assert_eq!(
+            parse(b"4:4:/w==0"),
+            Some((Message::Base64(b"/w==".to_vec()), 8))
+        );
+        assert_eq!(parse(b"4:4:/w="), None);
+
+        let max = format!("4:{MAX_BASE64_LEN}:");
+        assert_eq!(parse(max.as_bytes()), None);
+        let longer = format!("4:{}:", MAX_BASE64_LEN + 4);
+        assert!(parse_message(longer.as_bytes()).is_err());
+
+        // binary data is fine, invalid base64 is not
+        let stats = process(b"4:4:/w==4:8:aGkNCg==4:3:aGk");
+        assert_eq!(stats.data_messages, 2);
+        assert_eq!(stats.data_bytes, 5);
+        assert!(stats.saw_enter);
+        assert_eq!(stats.issues.len(), 1);
+        assert!(stats.issues[0].contains("base64"));
+    }
+
+    #[test]
+    fn parses_flow_control_messages() {
+        let parse = |buf: &[u8]| parse_message(buf).unwrap();
+        assert_eq!(parse(b"5"), Some((Message::Pause, 1)));
+        assert_eq!(parse(b"60:1:a"), Some((Message::Resume, 1)));
+
+        let stats = process(b"50:1:a6");
+        assert_eq!(stats.pauses, 1);
+        assert_eq!(stats.data_messages, 1);
+        assert!(!stats.paused);
+        assert!(process(b"65").paused);
+        assert!(stats.issues.is_empty());
+    }
+}
@@ -0,0 +1,103 @@
+//! Detection of output loops
+//!
+//! Crashed guests and misbehaving programs tend to print the same line over and over, at a rate
+//! that drowns everything else and keeps the client busy rendering. Output is considered to
+//! loop if it comes in at a high rate and almost all of its lines repeat the line before them.
+//! The watchdog is checked once per [`CHECK_INTERVAL`] while there is output and reports a loop
+//! once it lasted for the configured time.
+
+use std::time::Duration;
+
+/// How often the output is judged.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Output below this rate (bytes per check interval) is never considered a loop.
+const MIN_RATE: u64 = 4096;
+
+/// The share of output in repeated lines, in percent, from which output is considered a loop.
+const MIN_REPEATED_PERCENT: u64 = 90;
+
+/// Longer lines are not compared, loops are about short sequences.
+const MAX_LINE: usize = 256;
+
+pub struct LoopWatchdog {
+    /// How long output has to loop before it is reported
+    report_after: Duration,
+    line: Vec<u8>,
+    line_too_long: bool,
+    previous_line: Vec<u8>,
+    /// Output since the last check
+    total: u64,
+    /// Output in lines repeating their previous line since the last check
+    repeated: u64,
+    /// How long the output has been looping
+    looping: Duration,
+    reported: bool,
+}
+
+impl LoopWatchdog {
+    pub fn new(report_after: Duration) -> Self {
+        Self {
+            report_after,
+            line: Vec::new(),
+            line_too_long: false,
+            previous_line: Vec::new(),
+            total: 0,
+            repeated: 0,
+            looping: Duration::ZERO,
+            reported: false,
+        }
+    }
+
+    /// Accounts for output of the command.
+    pub fn scan(&mut self, data: &[u8]) {
+        self.total += data.len() as u64;
+        for &byte in data {
+            if byte != b'\n' {
+                if self.line.len() < MAX_LINE {
+                    self.line.push(byte);
+                } else {
+                    self.line_too_long = true;
+                }
+                continue;
+            }
+            if !self.line_too_long && self.line == self.previous_line {
+                self.repeated += self.line.len() as u64 + 1;
+            }
+            std::mem::swap(&mut self.line, &mut self.previous_line);
+            self.line.clear();
+            if self.line_too_long {
+                self.previous_line.clear();
+                self.line_too_long = false;
+            }
+        }
+    }
+
+    /// Whether there was no output since the last check, which doesn't need to be judged then.
+    pub fn is_idle(&self) -> bool {
+        self.total == 0
+    }
+
+    /// Judges the output since the last check, to be called every [`CHECK_INTERVAL`].
+    ///
+    /// Returns the rate of the output in bytes per second once it looped for long enough, once
+    /// per loop.
+    pub fn check(&mut self) -> Option<u64> {
+        let rate = self.total;
+        let looping = rate >= MIN_RATE && self.repeated * 100 >= self.total * MIN_REPEATED_PERCENT;
+        self.total = 0;
+        self.repeated = 0;
+
+        if !looping {
+            self.looping = Duration::ZERO;
+            self.reported = false;
+            return None;
+        }
+        self.looping += CHECK_INTERVAL;
+        if self.looping >= self.report_after && !self.reported {
+            self.reported = true;
+            return Some(rate / CHECK_INTERVAL.as_secs());
+        }
+        None
+    }
+}
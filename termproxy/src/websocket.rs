@@ -0,0 +1,624 @@
+//! WebSocket transport
+//!
+//! Usually the API daemon terminates the client's WebSocket and relays its payload to termproxy
+//! as plain TCP stream. With `--websocket`, termproxy accepts the WebSocket itself, so it can be
+//! exposed directly by lightweight frontends. The payload of all data messages forms the same
+//! byte stream the relay would pass on, starting with the ticket line; the output is sent in
+//! binary messages.
+
+use std::io::{ErrorKind, Read, Write};
+
+use anyhow::{bail, format_err, Result};
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+use sha1::{Digest, Sha1};
+
+use crate::compat::ByteBuffer;
+use crate::control::base64_encode;
+use crate::timer::Deadline;
+
+/// The GUID every handshake response is derived with (RFC 6455, section 1.3).
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The maximal size of the client's handshake request.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// The maximal payload of a frame from the client.
+const MAX_FRAME_PAYLOAD: u64 = 1024 * 1024;
+
+/// The size of the largest header of a frame sent by termproxy.
+pub const MAX_HEADER_LEN: usize = 10;
+
+/// The maximal amount of data sent in a single frame.
+const MAX_FRAME_DATA: usize = 64 * 1024;
+
+/// Framed data queued for sending beyond which writes block.
+const MAX_PENDING_OUTPUT: usize = 64 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// The value of the header `name` in the request, if present.
+fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    })
+}
+
+/// Whether the comma separated header value contains `token`.
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|value| {
+        value
+            .split(',')
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+/// The key for the `Sec-WebSocket-Accept` header of the response.
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(HANDSHAKE_GUID.as_bytes());
+    base64_encode(&hasher.finalize())
+}
+
+/// Builds the response to the client's handshake request.
+fn handshake_response(request: &str) -> Result<String> {
+    if !request.starts_with("GET ") {
+        bail!("handshake is not a GET request");
+    }
+    if !has_token(header(request, "Upgrade"), "websocket")
+        || !has_token(header(request, "Connection"), "upgrade")
+    {
+        bail!("handshake does not request a websocket upgrade");
+    }
+    if header(request, "Sec-WebSocket-Version") != Some("13") {
+        bail!("unsupported websocket version");
+    }
+    let Some(key) = header(request, "Sec-WebSocket-Key") else {
+        bail!("handshake without key");
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n",
+        accept_key(key),
+    );
+    // noVNC style clients insist on the protocol they asked for
+    if has_token(header(request, "Sec-WebSocket-Protocol"), "binary") {
+        response.push_str("Sec-WebSocket-Protocol: binary\r\n");
+    }
+    response.push_str("\r\n");
+    Ok(response)
+}
+
+/// Performs the server side of the opening handshake on `stream`.
+pub fn accept<S: Read + Write + Source>(
+    mut stream: S,
+    deadline: Deadline,
+) -> Result<WebSocketStream<S>> {
+    let mut buf = ByteBuffer::with_capacity(MAX_REQUEST_LEN);
+    crate::handshake::read_until(&mut stream, &mut buf, deadline, |data| {
+        data.windows(4).any(|window| window == b"\r\n\r\n")
+    })
+    .map_err(|err| format_err!("failed reading websocket handshake: {err}"))?;
+
+    let end = buf[..]
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .unwrap_or_default()
+        + 4;
+    let request = buf.remove_data(end);
+    let request = String::from_utf8_lossy(&request);
+
+    match handshake_response(&request) {
+        Ok(response) => {
+            stream.write_all(response.as_bytes())?;
+            Ok(WebSocketStream::new(stream, &buf[..]))
+        }
+        Err(err) => {
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n");
+            Err(err)
+        }
+    }
+}
+
+/// A client connection speaking the WebSocket protocol
+pub struct WebSocketStream<S> {
+    stream: S,
+    /// Received data not forming a complete frame yet
+    input: Vec<u8>,
+    /// Payload not read yet
+    payload: Vec<u8>,
+    /// Frames not sent yet
+    output: Vec<u8>,
+    /// The payload of the latest ping not answered yet, earlier ones only get answered if there
+    /// is room for their pongs (RFC 6455, section 5.5.3)
+    pending_pong: Option<Vec<u8>>,
+    /// Whether the client closed the WebSocket
+    closed: bool,
+}
+
+impl<S: Read + Write> WebSocketStream<S> {
+    /// Wraps `stream`, `initial` is data already received after the handshake.
+    pub fn new(stream: S, initial: &[u8]) -> Self {
+        Self {
+            stream,
+            input: initial.to_vec(),
+            payload: Vec::new(),
+            output: Vec::new(),
+            pending_pong: None,
+            closed: false,
+        }
+    }
+
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn stream_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Whether there are frames waiting to be sent.
+    pub fn has_pending_output(&self) -> bool {
+        !self.output.is_empty() || self.pending_pong.is_some()
+    }
+
+    /// Queues a close frame, to be sent before closing the connection.
+    pub fn close(&mut self) {
+        if !self.closed {
+            self.queue_close(CLOSE_NORMAL);
+        }
+    }
+
+    fn queue_frame(&mut self, opcode: u8, data: &[u8]) {
+        self.output.push(0x80 | opcode);
+        match data.len() {
+            len @ 0..=125 => self.output.push(len as u8),
+            len @ 126..=0xffff => {
+                self.output.push(126);
+                self.output.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                self.output.push(127);
+                self.output.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        self.output.extend_from_slice(data);
+    }
+
+    fn queue_close(&mut self, code: u16) {
+        // nothing may follow the close frame
+        self.pending_pong = None;
+        self.queue_frame(OPCODE_CLOSE, &code.to_be_bytes());
+        self.closed = true;
+    }
+
+    /// Fails the connection with a close frame carrying `code`.
+    fn fail(&mut self, code: u16, message: &str) -> std::io::Error {
+        self.queue_close(code);
+        let _ = self.write_output();
+        std::io::Error::new(ErrorKind::InvalidData, message.to_string())
+    }
+
+    /// Decodes all complete frames in the input.
+    fn decode_input(&mut self) -> std::io::Result<()> {
+        while !self.closed && self.input.len() >= 2 {
+            let fin = self.input[0] & 0x80 != 0;
+            let opcode = self.input[0] & 0x0f;
+            if self.input[1] & 0x80 == 0 {
+                return Err(self.fail(CLOSE_PROTOCOL_ERROR, "unmasked websocket frame"));
+            }
+
+            let (len, mut pos) = match self.input[1] & 0x7f {
+                126 if self.input.len() >= 4 => {
+                    (u16::from_be_bytes([self.input[2], self.input[3]]) as u64, 4)
+                }
+                127 if self.input.len() >= 10 => {
+                    let mut len = [0u8; 8];
+                    len.copy_from_slice(&self.input[2..10]);
+                    (u64::from_be_bytes(len), 10)
+                }
+                126 | 127 => break,
+                len => (len as u64, 2),
+            };
+            if len > MAX_FRAME_PAYLOAD {
+                return Err(self.fail(CLOSE_TOO_BIG, "websocket frame too big"));
+            }
+            let len = len as usize;
+            if self.input.len() < pos + 4 + len {
+                break;
+            }
+
+            let mut mask = [0u8; 4];
+            mask.copy_from_slice(&self.input[pos..pos + 4]);
+            pos += 4;
+            let data: Vec<u8> = self.input[pos..pos + len]
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4])
+                .collect();
+            self.input.drain(..pos + len);
+
+            match opcode {
+                OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                    self.payload.extend_from_slice(&data)
+                }
+                _ if !fin || len > 125 => {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, "invalid websocket control frame"));
+                }
+                OPCODE_CLOSE => {
+                    let code = match data[..] {
+                        [high, low, ..] => u16::from_be_bytes([high, low]),
+                        _ => CLOSE_NORMAL,
+                    };
+                    self.queue_close(code);
+                }
+                // a client flooding pings without reading must not grow the output unbounded
+                OPCODE_PING => {
+                    self.pending_pong = Some(data);
+                    self.queue_pong();
+                }
+                OPCODE_PONG => (),
+                _ => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "unknown websocket opcode")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues the pending pong if the output has room for it.
+    fn queue_pong(&mut self) {
+        if self.output.len() < MAX_PENDING_OUTPUT {
+            if let Some(data) = self.pending_pong.take() {
+                self.queue_frame(OPCODE_PONG, &data);
+            }
+        }
+    }
+
+    fn write_output(&mut self) -> std::io::Result<()> {
+        self.queue_pong();
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.output.drain(..n);
+                    // a pong held back goes out as soon as there is room for it
+                    self.queue_pong();
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Read for WebSocketStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.decode_input()?;
+        while self.payload.is_empty() {
+            if self.closed {
+                // answer the close frame, the connection ends either way
+                let _ = self.write_output();
+                return Ok(0);
+            }
+            let mut chunk = [0u8; 4096];
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.input.extend_from_slice(&chunk[..n]);
+            self.decode_input()?;
+        }
+        // pongs are sent along with the next output or flush
+        let n = buf.len().min(self.payload.len());
+        buf[..n].copy_from_slice(&self.payload[..n]);
+        self.payload.drain(..n);
+        Ok(n)
+    }
+}
+
+impl<S: Read + Write> Write for WebSocketStream<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.output.len() >= MAX_PENDING_OUTPUT {
+            self.write_output()?;
+        }
+
+        let data = &buf[..buf.len().min(MAX_FRAME_DATA)];
+        self.queue_frame(OPCODE_BINARY, data);
+
+        // the data is accepted either way, what could not be sent is flushed later
+        match self.write_output() {
+            Err(err) if err.kind() != ErrorKind::WouldBlock => Err(err),
+            _ => Ok(data.len()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_output()
+    }
+}
+
+impl<S: Source> Source for WebSocketStream<S> {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.stream.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.stream.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> std::io::Result<()> {
+        self.stream.deregister(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The client's side of the connection: what it sent, and what it received while not
+    /// `blocked`
+    #[derive(Default)]
+    struct Peer {
+        input: Vec<u8>,
+        output: Vec<u8>,
+        blocked: bool,
+    }
+
+    impl Read for Peer {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.input.is_empty() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.input.len());
+            buf[..n].copy_from_slice(&self.input[..n]);
+            self.input.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for Peer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.blocked {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A frame as the client sends it, masked.
+    fn frame(opcode: u8, fin: bool, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match data.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        frame.extend_from_slice(&mask);
+        frame.extend(data.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    /// The opcodes and payloads of the frames termproxy sent.
+    fn sent_frames(mut output: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while !output.is_empty() {
+            assert_eq!(output[0] & 0x80, 0x80, "fragmented frame");
+            let (len, pos) = match output[1] {
+                126 => (u16::from_be_bytes([output[2], output[3]]) as usize, 4),
+                127 => (
+                    u64::from_be_bytes(output[2..10].try_into().unwrap()) as usize,
+                    10,
+                ),
+                len => (len as usize, 2),
+            };
+            frames.push((output[0] & 0x0f, output[pos..pos + len].to_vec()));
+            output = &output[pos + len..];
+        }
+        frames
+    }
+
+    /// A stream of a client that sent `input`.
+    fn client(input: &[u8]) -> WebSocketStream<Peer> {
+        WebSocketStream::new(Peer::default(), input)
+    }
+
+    /// Reads the payload of all complete frames.
+    fn read_payload(stream: &mut WebSocketStream<Peer>) -> std::io::Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => return Ok(payload),
+                Ok(n) => payload.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(payload),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Reads from a stream whose client violates the protocol, and returns the close code it
+    /// got.
+    fn failed_close_code(input: &[u8]) -> u16 {
+        let mut stream = client(input);
+        let err = read_payload(&mut stream).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        match &sent_frames(&stream.stream().output)[..] {
+            [(OPCODE_CLOSE, code)] => u16::from_be_bytes([code[0], code[1]]),
+            frames => panic!("expected a close frame, sent {frames:?}"),
+        }
+    }
+
+    #[test]
+    fn decodes_frames() {
+        let medium = vec![b'm'; 300];
+        let large = vec![b'l'; 70_000];
+        let input = [
+            frame(OPCODE_BINARY, true, b"short"),
+            frame(OPCODE_TEXT, true, b""),
+            frame(OPCODE_BINARY, true, &medium),
+            frame(OPCODE_BINARY, true, &large),
+        ]
+        .concat();
+        let mut stream = client(&input);
+        assert_eq!(
+            read_payload(&mut stream).unwrap(),
+            [&b"short"[..], &medium, &large].concat()
+        );
+
+        // frames arriving in pieces wait for the rest, the extended length as well
+        let input = frame(OPCODE_BINARY, true, &large);
+        for split in [1, 5, 14, input.len() - 1] {
+            let mut stream = client(&input[..split]);
+            assert!(read_payload(&mut stream).unwrap().is_empty());
+            stream.stream_mut().input.extend_from_slice(&input[split..]);
+            assert_eq!(read_payload(&mut stream).unwrap(), large);
+        }
+    }
+
+    #[test]
+    fn joins_fragments() {
+        // control frames may come in between the fragments of a message
+        let input = [
+            frame(OPCODE_TEXT, false, b"frag"),
+            frame(OPCODE_PING, true, b"ping"),
+            frame(OPCODE_CONTINUATION, false, b"men"),
+            frame(OPCODE_CONTINUATION, true, b"ted"),
+        ]
+        .concat();
+        let mut stream = client(&input);
+        assert_eq!(read_payload(&mut stream).unwrap(), b"fragmented");
+        stream.flush().unwrap();
+        assert_eq!(
+            sent_frames(&stream.stream().output),
+            [(OPCODE_PONG, b"ping".to_vec())]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        // unmasked frames
+        assert_eq!(failed_close_code(&[0x82, 1, b'x']), CLOSE_PROTOCOL_ERROR);
+
+        // frames longer than allowed, rejected before their payload arrived
+        let mut oversize = vec![0x82, 0x80 | 127];
+        oversize.extend_from_slice(&(MAX_FRAME_PAYLOAD + 1).to_be_bytes());
+        assert_eq!(failed_close_code(&oversize), CLOSE_TOO_BIG);
+
+        // fragmented control frames, control frames with more than 125 bytes and unknown
+        // opcodes
+        for invalid in [
+            frame(OPCODE_PING, false, b"ping"),
+            frame(OPCODE_PING, true, &[0; 126]),
+            frame(OPCODE_CLOSE, true, &[0; 126]),
+            frame(0x3, true, b""),
+            frame(0xb, true, b""),
+        ] {
+            assert_eq!(failed_close_code(&invalid), CLOSE_PROTOCOL_ERROR);
+        }
+    }
+
+    #[test]
+    fn echoes_close() {
+        // whatever follows the close frame is ignored
+        let input = [
+            frame(OPCODE_BINARY, true, b"last"),
+            frame(OPCODE_CLOSE, true, &1001u16.to_be_bytes()),
+            frame(OPCODE_BINARY, true, b"ignored"),
+        ]
+        .concat();
+        let mut stream = client(&input);
+        assert_eq!(read_payload(&mut stream).unwrap(), b"last");
+        let mut buf = [0u8; 16];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        // closing the connection afterwards doesn't send another close frame
+        stream.close();
+        stream.flush().unwrap();
+        assert_eq!(
+            sent_frames(&stream.stream().output),
+            [(OPCODE_CLOSE, 1001u16.to_be_bytes().to_vec())]
+        );
+
+        // a close frame without a code is answered with a normal closure
+        let mut stream = client(&frame(OPCODE_CLOSE, true, b""));
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            sent_frames(&stream.stream().output),
+            [(OPCODE_CLOSE, CLOSE_NORMAL.to_be_bytes().to_vec())]
+        );
+    }
+
+    #[test]
+    fn coalesces_pongs() {
+        let mut stream = client(&[]);
+        stream.stream_mut().blocked = true;
+        let data = vec![b'o'; MAX_FRAME_DATA];
+        assert_eq!(stream.write(&data).unwrap(), MAX_FRAME_DATA);
+
+        // with the output full, only the latest ping gets answered once there is room
+        for ping in [&b"1"[..], b"2", b"3"] {
+            stream
+                .stream_mut()
+                .input
+                .extend_from_slice(&frame(OPCODE_PING, true, ping));
+            assert!(read_payload(&mut stream).unwrap().is_empty());
+        }
+        assert!(stream.has_pending_output());
+        stream.stream_mut().blocked = false;
+        stream.flush().unwrap();
+        assert_eq!(
+            sent_frames(&stream.stream().output),
+            [(OPCODE_BINARY, data), (OPCODE_PONG, b"3".to_vec())]
+        );
+        assert!(!stream.has_pending_output());
+
+        // pings are answered right away while there is room
+        stream.stream_mut().output.clear();
+        stream
+            .stream_mut()
+            .input
+            .extend_from_slice(&frame(OPCODE_PING, true, b"4"));
+        assert!(read_payload(&mut stream).unwrap().is_empty());
+        stream.flush().unwrap();
+        assert_eq!(
+            sent_frames(&stream.stream().output),
+            [(OPCODE_PONG, b"4".to_vec())]
+        );
+    }
+}
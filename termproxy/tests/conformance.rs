@@ -0,0 +1,2016 @@
+//! Conformance corpus for the data path
+//!
+//! Every test runs the proxy binary against a shell that switches its terminal to raw mode and
+//! echoes its input with `cat`, so whatever the client sends in data messages has to come back
+//! byte for byte, no matter how it is split into messages and TCP segments or what other
+//! messages are interleaved. Authentication is skipped with `--preauthenticated` on a listener
+//! handed over as file descriptor.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+#[cfg(feature = "auth-http")]
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+const USER: &str = "root@pam";
+
+/// Switches the terminal to raw mode and echoes the input.
+const ECHO_SCRIPT: &str = "stty raw -echo && printf READY && exec cat";
+
+/// Printed by [`ECHO_SCRIPT`] once the terminal is in raw mode.
+const READY: &[u8] = b"READY";
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Session {
+    /// The proxy, `None` for further clients of a shared session
+    proxy: Option<Child>,
+    port: u16,
+    stream: TcpStream,
+    /// Output received but not checked yet
+    output: Vec<u8>,
+}
+
+impl Session {
+    /// Starts a session echoing its input, with `args` as additional options of the proxy.
+    fn start(args: &[&str]) -> Self {
+        let mut session = Self::start_command(args, ECHO_SCRIPT);
+        session.expect(READY);
+        session
+    }
+
+    /// Starts a session running `script` with `sh -c`.
+    fn start_command(args: &[&str], script: &str) -> Self {
+        let proxy = Path::new(env!("CARGO_BIN_EXE_proxmox-termproxy"));
+        Self::start_as(proxy, None, args, script)
+    }
+
+    /// Like [`Session::start_command`], running the `proxy` binary as user and group `id` if set.
+    fn start_as(proxy: &Path, id: Option<u32>, args: &[&str], script: &str) -> Self {
+        let args = [args, &["--", "/bin/sh", "-c", script]].concat();
+        Self::launch(proxy, id, &args)
+    }
+
+    /// Starts the `proxy` binary with `args`, without a command to run unless `args` has one.
+    fn launch(proxy: &Path, id: Option<u32>, args: &[&str]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind listener");
+        let port = listener.local_addr().unwrap().port();
+        let fd = listener.as_raw_fd();
+
+        let mut command = Command::new(proxy);
+        command
+            .arg(fd.to_string())
+            .args(["--port-as-fd", "--path", "/", "--preauthenticated", USER])
+            .args(args)
+            .env("TERMPROXY_PREAUTHENTICATED", USER)
+            .stdout(Stdio::null());
+        // the listener has to survive the exec
+        unsafe {
+            command.pre_exec(move || {
+                if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if let Some(id) = id {
+                    if libc::setgroups(0, std::ptr::null()) < 0
+                        || libc::setgid(id) < 0
+                        || libc::setuid(id) < 0
+                    {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        let proxy = command.spawn().expect("failed to start proxy");
+        drop(listener);
+
+        Self::connect(Some(proxy), port)
+    }
+
+    fn connect(proxy: Option<Child>, port: u16) -> Self {
+        let stream = TcpStream::connect(("127.0.0.1", port)).expect("failed to connect");
+        stream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        Self {
+            proxy,
+            port,
+            stream,
+            output: Vec::new(),
+        }
+    }
+
+    /// Connects another client to the session, which needs to be shared with `--max-clients`.
+    fn join(&self) -> Self {
+        Self::connect(None, self.port)
+    }
+
+    /// Drops the connection and connects again, as a client does after a network failure.
+    fn reconnect(mut self) -> Self {
+        let proxy = self.proxy.take();
+        let port = self.port;
+        drop(self);
+        Self::connect(proxy, port)
+    }
+
+    fn proxy(&self) -> &Child {
+        self.proxy.as_ref().expect("not the first client")
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        self.stream.write_all(data).expect("failed to send");
+    }
+
+    /// Sends `data` in a data message.
+    fn send_data(&mut self, data: &[u8]) {
+        let mut message = format!("0:{}:", data.len()).into_bytes();
+        message.extend_from_slice(data);
+        self.send(&message);
+    }
+
+    /// Reads until `done` is true for the output, and returns the output up to the position it
+    /// returned.
+    fn read_until(&mut self, done: impl Fn(&[u8]) -> Option<usize>) -> Vec<u8> {
+        let start = Instant::now();
+        loop {
+            if let Some(end) = done(&self.output) {
+                return self.output.drain(..end).collect();
+            }
+            assert!(
+                start.elapsed() < TIMEOUT,
+                "timed out, received {:?}",
+                String::from_utf8_lossy(&self.output),
+            );
+            self.receive();
+        }
+    }
+
+    /// Receives more output, if there is any.
+    fn receive(&mut self) {
+        let mut buf = [0u8; 64 * 1024];
+        match self.stream.read(&mut buf) {
+            Ok(0) => panic!(
+                "connection closed, received {:?}",
+                String::from_utf8_lossy(&self.output),
+            ),
+            Ok(n) => self.output.extend_from_slice(&buf[..n]),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) => panic!("failed to read - {err}"),
+        }
+    }
+
+    /// Asserts that the next output is exactly `expected`.
+    fn expect(&mut self, expected: &[u8]) {
+        let received =
+            self.read_until(|output| (output.len() >= expected.len()).then_some(expected.len()));
+        assert_eq!(
+            received,
+            expected,
+            "expected {:?}, received {:?}",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&received),
+        );
+    }
+
+    /// Skips output up to and including `pattern`.
+    fn skip_until(&mut self, pattern: &[u8]) {
+        let start = Instant::now();
+        loop {
+            let found = self
+                .output
+                .windows(pattern.len())
+                .position(|window| window == pattern);
+            if let Some(pos) = found {
+                self.output.drain(..pos + pattern.len());
+                return;
+            }
+            // keep only what could be the beginning of the pattern, the output may be huge
+            let keep = self.output.len().min(pattern.len() - 1);
+            self.output.drain(..self.output.len() - keep);
+            assert!(
+                start.elapsed() < TIMEOUT,
+                "timed out waiting for {pattern:?}"
+            );
+            self.receive();
+        }
+    }
+
+    /// Reads everything until the proxy closes the connection.
+    fn read_to_end(&mut self) -> Vec<u8> {
+        self.stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut output = std::mem::take(&mut self.output);
+        self.stream
+            .read_to_end(&mut output)
+            .expect("failed to read until the end");
+        output
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+        if let Some(proxy) = self.proxy.as_mut() {
+            let _ = proxy.wait();
+        }
+    }
+}
+
+/// Sequences from vttest's screens, which have to reach the terminal emulator unchanged.
+const VTTEST_SEQUENCES: &[&[u8]] = &[
+    // cursor movement and erasing
+    b"\x1b[H\x1b[2J\x1b[10;20H*\x1b[5A\x1b[3B\x1b[2C\x1b[1D\x1b[K\x1b[1J",
+    // screen alignment pattern
+    b"\x1b#8",
+    // double width and height lines
+    b"\x1b#6wide\r\n\x1b#3top\r\n\x1b#4bottom",
+    // scrolling region, origin mode and autowrap
+    b"\x1b[5;15r\x1b[?6h\x1b[?7l\x1b[?6l\x1b[?7h\x1b[r",
+    // graphic renditions
+    b"\x1b[0;1;4;5;7mattrs\x1b[0m\x1b[38;5;208m\x1b[48;2;1;2;3m\x1b[m",
+    // character sets and shift in/out
+    b"\x1b(0lqqk\x1b(B\x1b)0\x0elqk\x0f",
+    // tab stops
+    b"\x1b[3g\x1bH\t\x1b[0g",
+    // device status and attribute requests
+    b"\x1b[5n\x1b[6n\x1b[c\x1b[>c",
+    // save/restore cursor, DECSTR, RIS
+    b"\x1b7\x1b8\x1b[!p\x1bc",
+    // OSC window title, terminated by BEL and by ST
+    b"\x1b]0;title\x07\x1b]2;other\x1b\\",
+    // DCS request status string
+    b"\x1bP$qm\x1b\\",
+];
+
+#[test]
+fn vttest_sequences() {
+    let mut session = Session::start(&[]);
+    for sequence in VTTEST_SEQUENCES {
+        session.send_data(sequence);
+        session.expect(sequence);
+    }
+}
+
+#[test]
+fn byte_order_mark() {
+    let mut session = Session::start(&[]);
+    session.send_data(b"\xef\xbb\xbfafter a BOM");
+    session.expect(b"\xef\xbb\xbfafter a BOM");
+    // a BOM in the middle is a zero width no-break space and stays as well
+    session.send_data(b"zero\xef\xbb\xbfwidth");
+    session.expect(b"zero\xef\xbb\xbfwidth");
+}
+
+#[test]
+fn split_utf8() {
+    let mut session = Session::start(&[]);
+    let text = "aä€𝄞z".as_bytes();
+    // every split point, including those in the middle of a character
+    for split in 1..text.len() {
+        session.send_data(&text[..split]);
+        session.send_data(&text[split..]);
+        session.expect(text);
+    }
+    // one message per byte
+    for byte in text {
+        session.send_data(&[*byte]);
+    }
+    session.expect(text);
+}
+
+#[test]
+fn split_messages() {
+    let mut session = Session::start(&[]);
+    let message = b"0:11:hello world";
+    // every split point of the message, including inside its header
+    for split in 1..message.len() {
+        session.send(&message[..split]);
+        std::thread::sleep(Duration::from_millis(5));
+        session.send(&message[split..]);
+        session.expect(b"hello world");
+    }
+}
+
+#[test]
+fn all_byte_values() {
+    let mut session = Session::start(&[]);
+    let data: Vec<u8> = (0..=255).collect();
+    session.send_data(&data);
+    session.expect(&data);
+}
+
+#[test]
+fn large_messages() {
+    let mut session = Session::start(&[]);
+    let data: Vec<u8> = (0..256 * 1024).map(|i| b'a' + (i % 26) as u8).collect();
+    session.send_data(&data);
+    session.expect(&data);
+}
+
+#[test]
+fn poll_strategies() {
+    let data: Vec<u8> = (0..256 * 1024).map(|i| b'a' + (i % 26) as u8).collect();
+    for strategy in ["small-sleep", "adaptive"] {
+        let mut session = Session::start(&["--poll-strategy", strategy]);
+        session.send_data(&data);
+        session.expect(&data);
+    }
+}
+
+#[test]
+fn interleaved_messages() {
+    let mut session = Session::start(&[]);
+    session.send(b"0:3:one2");
+    session.send(b"1:100:40:");
+    session.send(b"0:0:");
+    session.send(b"22220:3:two");
+    session.send(b"1:80:24:0:5:three");
+    session.expect(b"onetwothree");
+}
+
+#[test]
+fn invalid_messages() {
+    let mut session = Session::start(&[]);
+    // unknown message types are skipped byte by byte
+    session.send(b"9x\x00\xff0:5:first");
+    session.expect(b"first");
+    // unknown and malformed control messages are ignored
+    session.send(b"3:7:unknown0:6:second");
+    session.expect(b"second");
+    session.send(b"3:0:0:5:third");
+    session.expect(b"third");
+}
+
+#[test]
+fn base64_data() {
+    let mut session = Session::start(&[]);
+    session.send(b"4:8:AP+AG0EN");
+    session.expect(&[0x00, 0xff, 0x80, 0x1b, b'A', b'\r']);
+    // split across segments like any other message
+    session.send(b"4:8:aGVs");
+    std::thread::sleep(Duration::from_millis(50));
+    session.send(b"bG8=0:1:!");
+    session.expect(b"hello!");
+    // invalid base64 is dropped as a whole
+    session.send(b"4:4:a*==0:2:ok");
+    session.expect(b"ok");
+}
+
+#[test]
+fn base64_data_limit() {
+    let mut session = Session::start(&[]);
+    // the longest message fits into the input buffer along with its header
+    let message = format!("4:4064:{}", "QUJD".repeat(1016));
+    session.send(message.as_bytes());
+    session.expect("ABC".repeat(1016).as_bytes());
+    // a longer one is dropped as a whole, without stalling the input
+    let message = format!("4:4100:{}0:2:ok", "MDoy".repeat(1025));
+    session.send(message.as_bytes());
+    session.expect(b"ok");
+}
+
+#[test]
+fn flow_control() {
+    let mut session = Session::start(&[]);
+    session.send(b"5");
+    session.send_data(b"held");
+    for _ in 0..3 {
+        session.receive();
+    }
+    assert!(
+        session.output.is_empty(),
+        "received output while paused: {:?}",
+        String::from_utf8_lossy(&session.output),
+    );
+    session.send(b"6");
+    session.expect(b"held");
+}
+
+#[test]
+fn size_query() {
+    let mut session = Session::start(&[]);
+    session.send(b"3:4:size");
+    session.expect(b"\x1b]2016;size;cols=80;rows=20\x07");
+    session.send(b"1:100:30:3:4:size");
+    session.expect(b"\x1b]2016;size;cols=100;rows=30\x07");
+}
+
+#[test]
+fn file_access() {
+    let root = Path::new(env!("CARGO_TARGET_TMPDIR")).join("files-root");
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("a.txt"), "hello").unwrap();
+
+    let mut session = Session::start(&["--files-root", root.to_str().unwrap()]);
+    // "world" to a new file with a colon in its name
+    session.send(b"3:30:files-put:1:0:d29ybGQ=:b:c.txt");
+    session.expect(b"\x1b]2016;files;op=put;id=1;size=5\x07");
+    session.send(b"3:21:files-get:2:0:b:c.txt");
+    session.expect(b"\x1b]2016;files;op=get;id=2;offset=0;eof=1;data=d29ybGQ=\x07");
+    session.send(b"3:18:files-get:3:0:../x");
+    session.expect(b"\x1b]2016;files;op=get;id=3;error=invalid path '../x'\x07");
+    session.send(b"3:14:files-list:4:.");
+    session.skip_until(b"\x1b]2016;files;op=list;id=4;data=");
+    session.skip_until(b"\x07");
+    assert_eq!(std::fs::read(root.join("b:c.txt")).unwrap(), b"world");
+
+    // neither a FIFO without a writer nor a link to one blocks the session
+    nix::unistd::mkfifo(&root.join("fifo"), nix::sys::stat::Mode::S_IRWXU).unwrap();
+    std::os::unix::fs::symlink("a.txt", root.join("link")).unwrap();
+    session.send(b"3:18:files-get:5:0:fifo");
+    session.expect(b"\x1b]2016;files;op=get;id=5;error=not a regular file\x07");
+    session.send(b"3:23:files-put:6:0:eA==:fifo");
+    session.expect(b"\x1b]2016;files;op=put;id=6;error=ENXIO: No such device or address\x07");
+    session.send(b"3:18:files-get:7:0:link");
+    session.expect(b"\x1b]2016;files;op=get;id=7;error=path 'link' is a symbolic link\x07");
+    session.send_data(b"still there");
+    session.skip_until(b"still there");
+}
+
+#[test]
+fn environment() {
+    let mut session = Session::start_command(
+        &[
+            "--env",
+            "GREETING=a=b",
+            "--env-keep",
+            "TERMPROXY_PREAUTHENTICATED",
+        ],
+        // kept running, its output may get lost if it exits before it was read
+        "echo \"$GREETING $TERMPROXY_PREAUTHENTICATED\"; exec cat",
+    );
+    session.expect(format!("a=b {USER}\r\n").as_bytes());
+}
+
+#[test]
+fn invalid_locale() {
+    let mut session =
+        Session::start_command(&["--env", "LANG=xx_XX.UTF-8"], "echo \"$LANG\"; exec cat");
+    session.expect(b"termproxy: locale 'xx_XX.UTF-8' of LANG is not installed, using C.UTF-8\r\n");
+    session.expect(b"C.UTF-8\r\n");
+}
+
+#[test]
+fn working_directory() {
+    let mut session = Session::start_command(&["--cwd", "/usr"], "pwd; exec cat");
+    session.expect(b"/usr\r\n");
+}
+
+#[test]
+fn isolate() {
+    let host_ns = std::fs::read_link("/proc/self/ns/mnt").unwrap();
+    let mut session = Session::start_command(
+        &["--isolate", "--read-only-root"],
+        "readlink /proc/self/ns/mnt; touch /termproxy-isolate 2>/dev/null || echo read-only",
+    );
+    let line = session.read_until(|output| output.iter().position(|&b| b == b'\n'));
+    assert_ne!(
+        String::from_utf8_lossy(&line).trim_end(),
+        host_ns.to_string_lossy(),
+        "same mount namespace"
+    );
+    session.expect(b"\nread-only\r\n");
+}
+
+#[test]
+fn seccomp() {
+    let mut session = Session::start(&["--seccomp"]);
+    session.send_data(b"hello");
+    session.expect(b"hello");
+    let status = std::fs::read_to_string(format!("/proc/{}/status", session.proxy().id())).unwrap();
+    assert!(status.contains("\nSeccomp:\t2\n"), "no seccomp filter");
+    session.send(b"1:100:40:");
+    session.send_data(b"world");
+    session.expect(b"world");
+}
+
+#[test]
+#[cfg(feature = "auth-http")]
+fn seccomp_join() {
+    let (proxy, port) = start_authenticating(&["--seccomp", "--max-clients", "2"], &[]);
+    let mut first = Session::connect(Some(proxy), port);
+    first.send(format!("{USER}:ticket\n").as_bytes());
+    first.expect(b"OK");
+    first.expect(READY);
+
+    // authenticating a further client resolves and connects to the API daemon
+    let mut second = first.join();
+    second.send(format!("{USER}:ticket\n").as_bytes());
+    second.expect(b"OK");
+    first.skip_until(b"\x1b]2016;clients;count=2");
+    second.send_data(b"two");
+    second.skip_until(b"two");
+}
+
+#[test]
+fn drop_privileges() {
+    // switching users requires root
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let mut session = Session::start(&["--drop-privileges", "nobody"]);
+    session.send_data(b"hello");
+    session.expect(b"hello");
+    let status = std::fs::read_to_string(format!("/proc/{}/status", session.proxy().id())).unwrap();
+    assert!(
+        status.contains("\nUid:\t65534\t65534\t65534\t65534\n"),
+        "still running as root"
+    );
+    session.send_data(b"world");
+    session.expect(b"world");
+}
+
+#[test]
+fn peer_user() {
+    let path = std::env::temp_dir().join(format!("termproxy-peer-{}.sock", std::process::id()));
+    let mut proxy = Command::new(env!("CARGO_BIN_EXE_proxmox-termproxy"))
+        .args(["--path", "/", "--peer-user", "--listen-unix"])
+        .arg(&path)
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start proxy");
+    let start = Instant::now();
+    let mut stream = loop {
+        match UnixStream::connect(&path) {
+            Ok(stream) => break stream,
+            Err(_) if start.elapsed() < TIMEOUT => std::thread::sleep(Duration::from_millis(10)),
+            Err(err) => panic!("failed to connect - {err}"),
+        }
+    };
+
+    // no ticket line, the login shell of the user that connected runs right away
+    let command = b"echo peer-$(id -u)\n";
+    let mut message = format!("0:{}:", command.len()).into_bytes();
+    message.extend_from_slice(command);
+    stream.write_all(&message).unwrap();
+    let expected = format!("peer-{}", unsafe { libc::geteuid() });
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut output = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !String::from_utf8_lossy(&output).contains(&expected) {
+        match stream.read(&mut buf) {
+            Ok(n) if n > 0 => output.extend_from_slice(&buf[..n]),
+            _ => panic!("no output, received {:?}", String::from_utf8_lossy(&output)),
+        }
+    }
+    assert!(output.starts_with(b"OK"), "not accepted");
+    let _ = proxy.kill();
+    let _ = proxy.wait();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[cfg(feature = "daemon")]
+fn unix_abstract() {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let status_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("abstract-status");
+    let _ = std::fs::remove_dir_all(&status_dir);
+    // abstract sockets of another run of the test may still be around
+    let name = format!("termproxy-test-{}", std::process::id());
+    let session_id = format!("abstract-test-{}", std::process::id());
+    let mut proxy = Command::new(env!("CARGO_BIN_EXE_proxmox-termproxy"))
+        .args(["--path", "/", "--peer-user", "--unix-abstract", &name])
+        .args(["--detachable", "--session-id", &session_id, "--status-dir"])
+        .arg(&status_dir)
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start proxy");
+    let address = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+    let start = Instant::now();
+    let mut stream = loop {
+        match UnixStream::connect_addr(&address) {
+            Ok(stream) => break stream,
+            Err(_) if start.elapsed() < TIMEOUT => std::thread::sleep(Duration::from_millis(10)),
+            Err(err) => panic!("failed to connect - {err}"),
+        }
+    };
+    let command = b"echo abstract-$((1 + 1))\n";
+    let mut message = format!("0:{}:", command.len()).into_bytes();
+    message.extend_from_slice(command);
+    stream.write_all(&message).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut output = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !String::from_utf8_lossy(&output).contains("abstract-2") {
+        match stream.read(&mut buf) {
+            Ok(n) if n > 0 => output.extend_from_slice(&buf[..n]),
+            _ => panic!("no output, received {:?}", String::from_utf8_lossy(&output)),
+        }
+    }
+    assert!(output.starts_with(b"OK"), "not accepted");
+
+    // the control socket is in the abstract namespace as well, and found without a file
+    assert!(!status_dir.join(format!("{session_id}.sock")).exists());
+    let share = Command::new(env!("CARGO_BIN_EXE_proxmox-termproxy"))
+        .arg("share")
+        .arg("--status-dir")
+        .arg(&status_dir)
+        .arg(&session_id)
+        .output()
+        .expect("failed to run share");
+    let stderr = String::from_utf8_lossy(&share.stderr);
+    assert!(
+        stderr.contains("session does not accept share links"),
+        "share did not reach the session: {stderr}"
+    );
+    let _ = proxy.kill();
+    let _ = proxy.wait();
+}
+
+#[test]
+fn max_input_rate() {
+    let mut session = Session::start(&["--max-input-rate", "10000"]);
+    let data = b"x".repeat(5000);
+    let start = Instant::now();
+    session.send_data(&data);
+    session.expect(&data);
+    // a tenth of the rate per interval, the first one right away
+    assert!(
+        start.elapsed() >= Duration::from_millis(400),
+        "input not limited"
+    );
+}
+
+#[test]
+fn initial_size() {
+    let mut session =
+        Session::start_command(&["--cols", "132", "--rows", "43"], "stty size; exec cat");
+    session.expect(b"43 132\r\n");
+}
+
+#[test]
+fn reset_command() {
+    let mut session = Session::start(&[]);
+    session.send(b"3:5:reset");
+    session.expect(b"\x1bc");
+}
+
+#[test]
+fn binary_output_is_paused() {
+    let mut session = Session::start(&["--detect-binary"]);
+    let text: Vec<u8> = b"plain text\r\n".repeat(1024);
+    session.send_data(&text);
+    session.expect(&text);
+
+    let binary: Vec<u8> = (0..16 * 1024).map(|i| (i * 7 % 32) as u8).collect();
+    session.send_data(&binary);
+    session.skip_until(b"\x1b]2016;binary;state=paused\x07");
+    session.send(b"3:12:binary-flush");
+    session.skip_until(b"\x1b]2016;binary;state=relay\x07");
+    session.skip_until(b"bytes of binary output\r\n");
+
+    session.send_data(b"back to text");
+    session.expect(b"back to text");
+}
+
+#[test]
+fn session_end() {
+    let mut session = Session::start_command(&[], "exit 3");
+    let output = session.read_to_end();
+    let output = String::from_utf8_lossy(&output);
+    assert!(
+        output.ends_with("\x07")
+            && output.contains("\x1b]2016;session-end;reason=exited;status=3;"),
+        "unexpected output {output:?}",
+    );
+}
+
+#[test]
+fn startup_timing() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let report = dir.join("timing.out");
+    let wrapper = dir.join("timing-wrapper");
+    let proxy = env!("CARGO_BIN_EXE_proxmox-termproxy");
+    std::fs::write(
+        &wrapper,
+        format!("#!/bin/sh\nexec {proxy} \"$@\" > {}\n", report.display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut session = Session::start_as(&wrapper, None, &["--timing"], "echo hello");
+    session.read_to_end();
+    drop(session);
+
+    let report = std::fs::read_to_string(&report).unwrap();
+    let steps: Vec<&str> = report
+        .lines()
+        .find_map(|line| line.strip_prefix("startup timing: "))
+        .expect("no timing report")
+        .split(", ")
+        .map(|step| step.split_once(' ').unwrap().0)
+        .collect();
+    assert_eq!(
+        steps,
+        ["listen", "accept", "auth", "spawn", "first-byte", "total"]
+    );
+}
+
+#[test]
+fn terminated() {
+    let mut session = Session::start(&[]);
+    unsafe { libc::kill(session.proxy().id() as i32, libc::SIGTERM) };
+    session.skip_until(b"\x1b]2016;session-end;reason=terminated;");
+    let status = session.proxy.take().unwrap().wait().unwrap();
+    assert!(status.success(), "proxy failed - {status}");
+}
+
+/// Answers the authentication requests of the proxy with success.
+#[cfg(feature = "auth-http")]
+fn fake_api_daemon() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind API daemon");
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            let complete = |request: &[u8]| {
+                let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") else {
+                    return false;
+                };
+                let header = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                let length: usize = header
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .map_or(0, |value| value.trim().parse().unwrap());
+                request.len() >= end + 4 + length
+            };
+            // the proxy waits for the answer, so the whole request has to be read first
+            while !complete(&request) {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body = br#"{"data":{}}"#;
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).unwrap();
+            stream.write_all(body).unwrap();
+        }
+    });
+    port
+}
+
+/// Starts the proxy echoing its input without `--preauthenticated`, authenticating the first
+/// client against [`fake_api_daemon`], with `fds` passed on. Returns it and its port.
+#[cfg(feature = "auth-http")]
+fn start_authenticating(args: &[&str], fds: &[RawFd]) -> (Child, u16) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind listener");
+    let port = listener.local_addr().unwrap().port();
+    let fds = [&[listener.as_raw_fd()], fds].concat();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_proxmox-termproxy"));
+    command
+        .arg(fds[0].to_string())
+        .args(["--port-as-fd", "--path", "/"])
+        .args(["--authport", &fake_api_daemon().to_string()])
+        .args(args)
+        .args(["--", "/bin/sh", "-c", ECHO_SCRIPT])
+        .stdout(Stdio::null());
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &fds {
+                if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let proxy = command.spawn().expect("failed to start proxy");
+    (proxy, port)
+}
+
+#[test]
+#[cfg(feature = "auth-http")]
+fn json_handshake() {
+    let (proxy, port) = start_authenticating(&["--lock-after", "60"], &[]);
+    let mut session = Session::connect(Some(proxy), port);
+    session.send(
+        format!(r#"{{"user":"{USER}","ticket":"ticket","features":["sysrq","lock","x"]}}"#)
+            .as_bytes(),
+    );
+    session.send(b"\n");
+    session.expect(b"OK\x1b]2016;features;list=lock\x07");
+    session.expect(READY);
+}
+
+#[test]
+#[cfg(feature = "auth-http")]
+fn auth_challenge() {
+    let key = [0x11u8; 32];
+    let key_path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("challenge.key");
+    let key_hex: String = key.iter().map(|b| format!("{b:02x}")).collect();
+    std::fs::write(&key_path, &key_hex).unwrap();
+    let key_file = std::fs::File::open(&key_path).unwrap();
+
+    let key_fd = key_file.as_raw_fd().to_string();
+    let (proxy, port) = start_authenticating(
+        &["--accept-attempts", "2", "--auth-challenge-key-fd", &key_fd],
+        &[key_file.as_raw_fd()],
+    );
+
+    let ticket_line = format!("{USER}:ticket");
+    let read_nonce = |session: &mut Session| {
+        let line = session.read_until(|output| output.iter().position(|&b| b == b'\n'));
+        let line = String::from_utf8(line).unwrap();
+        let (challenge_port, nonce) = line
+            .strip_prefix("challenge:")
+            .and_then(|rest| rest.split_once(':'))
+            .expect("no challenge");
+        assert_eq!(challenge_port, port.to_string());
+        nonce.to_string()
+    };
+
+    // an answer captured on another connection is rejected
+    let mut session = Session::connect(None, port);
+    let nonce = read_nonce(&mut session);
+    let stale = "0".repeat(nonce.len());
+    let mac = hmac(&key, &format!("{port}:{stale}:{ticket_line}"));
+    session.send(format!("{port}:{stale}:{mac}\n{ticket_line}\n").as_bytes());
+    assert_eq!(session.read_to_end(), b"\n");
+
+    let mut session = Session::connect(Some(proxy), port);
+    let nonce = read_nonce(&mut session);
+    let mac = hmac(&key, &format!("{port}:{nonce}:{ticket_line}"));
+    session.send(format!("{port}:{nonce}:{mac}\n{ticket_line}\n").as_bytes());
+    session.expect(b"\nOK");
+    session.expect(READY);
+}
+
+#[test]
+#[cfg(feature = "auth-http")]
+fn max_auth_line() {
+    let (proxy, port) = start_authenticating(
+        &["--accept-attempts", "2", "--max-auth-line", "100000"],
+        &[],
+    );
+
+    let mut session = Session::connect(None, port);
+    session.send(&[b'x'; 100_001]);
+    assert_eq!(session.read_to_end(), b"");
+
+    // longer than the buffer of a client's input
+    let ticket = "t".repeat(70_000);
+    let mut session = Session::connect(Some(proxy), port);
+    session.send(format!("{USER}:{ticket}\n").as_bytes());
+    session.expect(b"OK");
+    session.expect(READY);
+}
+
+#[cfg(feature = "auth-http")]
+fn hmac(key: &[u8], data: &str) -> String {
+    let key = openssl::pkey::PKey::hmac(key).unwrap();
+    let mut signer =
+        openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &key).unwrap();
+    signer.update(data.as_bytes()).unwrap();
+    let mac = signer.sign_to_vec().unwrap();
+    mac.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The client side of the encrypted record protocol of --encryption-key-fd.
+#[cfg(feature = "auth-http")]
+struct Records {
+    send: chacha20poly1305::XChaCha20Poly1305,
+    receive: chacha20poly1305::XChaCha20Poly1305,
+    prefix: [u8; 16],
+    server_prefix: [u8; 16],
+    sent: u64,
+    received: u64,
+}
+
+#[cfg(feature = "auth-http")]
+impl Records {
+    fn new(key: &[u8; 32], prefix: [u8; 16], server_prefix: [u8; 16]) -> Self {
+        use chacha20poly1305::KeyInit;
+        let derive = |label: &[u8]| {
+            let mut ctx = openssl::pkey_ctx::PkeyCtx::new_id(openssl::pkey::Id::HKDF).unwrap();
+            ctx.derive_init().unwrap();
+            ctx.set_hkdf_md(openssl::md::Md::sha256()).unwrap();
+            ctx.set_hkdf_key(key).unwrap();
+            ctx.add_hkdf_info(&[label, &server_prefix, &prefix].concat())
+                .unwrap();
+            let mut derived = [0u8; 32];
+            ctx.derive(Some(&mut derived)).unwrap();
+            chacha20poly1305::XChaCha20Poly1305::new(&derived.into())
+        };
+        Self {
+            send: derive(b"termproxy c2s"),
+            receive: derive(b"termproxy s2c"),
+            prefix,
+            server_prefix,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    fn nonce(prefix: &[u8; 16], counter: u64) -> chacha20poly1305::XNonce {
+        let nonce: [u8; 24] = [&prefix[..], &counter.to_be_bytes()]
+            .concat()
+            .try_into()
+            .unwrap();
+        nonce.into()
+    }
+
+    fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        use chacha20poly1305::aead::{Aead, Payload};
+        let header = (data.len() as u16).to_be_bytes();
+        let aad = [&self.server_prefix[..], &header].concat();
+        let nonce = Self::nonce(&self.prefix, self.sent);
+        self.sent += 1;
+        let payload = Payload {
+            msg: data,
+            aad: &aad,
+        };
+        [&header[..], &self.send.encrypt(&nonce, payload).unwrap()].concat()
+    }
+
+    /// Takes the next complete record from `session`'s output and decrypts it.
+    fn open(&mut self, session: &mut Session) -> Vec<u8> {
+        use chacha20poly1305::aead::{Aead, Payload};
+        let record = session.read_until(|output| {
+            let len = u16::from_be_bytes([*output.first()?, *output.get(1)?]) as usize;
+            (output.len() >= 2 + len + 16).then_some(2 + len + 16)
+        });
+        let aad = [&self.prefix[..], &record[..2]].concat();
+        let nonce = Self::nonce(&self.server_prefix, self.received);
+        self.received += 1;
+        let payload = Payload {
+            msg: &record[2..],
+            aad: &aad,
+        };
+        self.receive
+            .decrypt(&nonce, payload)
+            .expect("invalid record")
+    }
+}
+
+#[test]
+#[cfg(feature = "auth-http")]
+fn encrypted_relay() {
+    let key = [0x22u8; 32];
+    let key_path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("encryption.key");
+    let key_hex: String = key.iter().map(|b| format!("{b:02x}")).collect();
+    std::fs::write(&key_path, &key_hex).unwrap();
+    let prefix = [0x33u8; 16];
+
+    // connects and reads the prefix of termproxy, with `prefix` sent after the ticket line or
+    // termproxy's prefix reflected if it is `None`
+    let connect = |prefix: Option<[u8; 16]>| {
+        let key_file = std::fs::File::open(&key_path).unwrap();
+        let key_fd = key_file.as_raw_fd().to_string();
+        let (proxy, port) =
+            start_authenticating(&["--encryption-key-fd", &key_fd], &[key_file.as_raw_fd()]);
+        let mut session = Session::connect(Some(proxy), port);
+        session.send(format!("{USER}:ticket\n").as_bytes());
+        if let Some(prefix) = prefix {
+            session.send(&prefix);
+        }
+        session.expect(b"OK");
+        let server_prefix: [u8; 16] = session
+            .read_until(|output| (output.len() >= 16).then_some(16))
+            .try_into()
+            .unwrap();
+        (session, server_prefix)
+    };
+
+    let (mut session, server_prefix) = connect(Some(prefix));
+    let mut records = Records::new(&key, prefix, server_prefix);
+    assert_eq!(records.open(&mut session), READY);
+    let message = records.seal(b"0:5:hello");
+    session.send(&message);
+    assert_eq!(records.open(&mut session), b"hello");
+
+    // a record with a tampered tag ends the session before its data gets through
+    let (mut session, server_prefix) = connect(Some(prefix));
+    let mut records = Records::new(&key, prefix, server_prefix);
+    assert_eq!(records.open(&mut session), READY);
+    let mut message = records.seal(b"0:5:hello");
+    *message.last_mut().unwrap() ^= 1;
+    session.send(&message);
+    assert!(session.read_to_end().is_empty(), "tampered record accepted");
+
+    // records of termproxy reflected back don't decrypt with the key of the client's direction
+    let (mut session, _) = connect(Some(prefix));
+    let record = session.read_until(|output| {
+        (output.len() >= 2 + READY.len() + 16).then_some(2 + READY.len() + 16)
+    });
+    session.send(&record);
+    assert!(
+        session.read_to_end().is_empty(),
+        "reflected record accepted"
+    );
+
+    // neither does a client get to use the prefix of termproxy, which would give both
+    // directions the same nonces
+    let (mut session, server_prefix) = connect(None);
+    session.send(&server_prefix);
+    assert!(
+        session.read_to_end().is_empty(),
+        "reflected prefix accepted"
+    );
+}
+
+#[test]
+fn security_log() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("security.log");
+    let _ = std::fs::remove_file(&path);
+    let mut session = Session::start(&[
+        "--lock-after",
+        "1",
+        "--security-log",
+        path.to_str().unwrap(),
+    ]);
+    session.skip_until(b"\x1b]2016;lock;state=locked\x07");
+    let command = b"unlock:other@pam:ticket";
+    session.send(format!("3:{}:", command.len()).as_bytes());
+    session.send(command);
+    session.skip_until(b"\x1b]2016;lock;state=locked;error=authentication failed\x07");
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    let entries: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(entries[0]["kind"], "preauthenticated");
+    assert_eq!(entries[0]["user"], USER);
+    let entry = &entries[1];
+    assert_eq!(entry["kind"], "unlock");
+    assert_eq!(entry["user"], "other@pam");
+    assert_eq!(entry["result"], "rejected");
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn idle_timeout() {
+    let mut session = Session::start(&["--idle-timeout", "2"]);
+    session.send_data(b"one");
+    session.expect(b"one");
+    session.skip_until(b"no input for 2s, terminating the session");
+    session.skip_until(b"\x1b]2016;session-end;reason=idle;");
+}
+
+#[test]
+fn output_loop() {
+    let mut session = Session::start_command(
+        &["--loop-watchdog", "1"],
+        "while true; do echo 'kernel panic - not syncing'; done",
+    );
+    session.skip_until(b"\x1b]2016;loop;state=detected;rate=");
+    session.send(b"3:13:loop-throttle");
+    session.skip_until(b"\x1b]2016;loop;state=throttled\x07");
+    session.send(b"3:9:loop-kill");
+    session.skip_until(b"\x1b]2016;loop;state=killed\x07");
+    let output = String::from_utf8_lossy(&session.read_to_end()).into_owned();
+    assert!(
+        output.contains("session-end;reason=exited;signal=KILL;"),
+        "unexpected output {output:?}",
+    );
+}
+
+/// Builds a masked WebSocket frame like clients send them.
+#[cfg(feature = "websocket")]
+fn websocket_frame(opcode: u8, fin: bool, data: &[u8]) -> Vec<u8> {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![if fin { 0x80 } else { 0 } | opcode, 0x80 | data.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(data.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    frame
+}
+
+#[test]
+#[cfg(feature = "websocket")]
+fn websocket() {
+    let mut session = Session::start_command(&["--websocket"], ECHO_SCRIPT);
+    session.send(
+        b"GET / HTTP/1.1\r\n\
+          Host: localhost\r\n\
+          Upgrade: websocket\r\n\
+          Connection: Upgrade\r\n\
+          Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+          Sec-WebSocket-Version: 13\r\n\r\n",
+    );
+    // the example from RFC 6455
+    session.skip_until(b"Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n");
+    session.expect(b"\x82\x05READY");
+
+    session.send(&websocket_frame(0x9, true, b"ping"));
+    session.expect(b"\x8a\x04ping");
+
+    // a message split into fragments
+    let mut message = websocket_frame(0x2, false, b"0:5:he");
+    message.extend(websocket_frame(0x0, true, b"llo"));
+    session.send(&message);
+    session.expect(b"\x82\x05hello");
+
+    session.send(&websocket_frame(0x8, true, &1000u16.to_be_bytes()));
+    session.expect(b"\x88\x02\x03\xe8");
+}
+
+#[test]
+fn secret_provider() {
+    let provider = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("secret-provider");
+    std::fs::write(&provider, "#!/bin/sh\necho s3cret\n").unwrap();
+    std::fs::set_permissions(&provider, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut session = Session::start_command(
+        &["--secret-provider", provider.to_str().unwrap()],
+        "stty -echo && printf 'Password: ' && read pw && stty echo && echo \"got $pw\" && exec cat",
+    );
+    session.skip_until(b"\x1b]2016;secret;state=provided\x07");
+    session.skip_until(b"got s3cret");
+}
+
+#[test]
+fn cmd_from() {
+    let helper = Path::new(env!("CARGO_TARGET_TMPDIR")).join("cmd-from");
+    std::fs::write(
+        &helper,
+        "#!/bin/sh\nprintf '[\"/bin/echo\", \"%s\", \"%s\"]' \"$TERMPROXY_USER\" \"$TERMPROXY_PATH\"\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let proxy = Path::new(env!("CARGO_BIN_EXE_proxmox-termproxy"));
+    let mut session = Session::launch(proxy, None, &["--cmd-from", helper.to_str().unwrap()]);
+    session.expect(b"root@pam /\r\n");
+}
+
+#[test]
+fn unprivileged() {
+    // other users run all tests unprivileged anyway
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    const NOBODY: u32 = 65534;
+    // the build directory might not be accessible to other users
+    let proxy = std::env::temp_dir().join(format!("proxmox-termproxy-{}", std::process::id()));
+    std::fs::copy(env!("CARGO_BIN_EXE_proxmox-termproxy"), &proxy).unwrap();
+
+    let mut session = Session::start_as(
+        &proxy,
+        Some(NOBODY),
+        &[],
+        &format!("id -u && {ECHO_SCRIPT}"),
+    );
+    session.expect(format!("{NOBODY}\r\n").as_bytes());
+    session.expect(READY);
+    session.send_data(b"hello");
+    session.expect(b"hello");
+
+    drop(session);
+    let _ = std::fs::remove_file(proxy);
+}
+
+#[test]
+fn shared_session() {
+    let mut first = Session::start(&["--max-clients", "2"]);
+    let mut second = first.join();
+    first.expect(b"\x1b]2016;clients;count=2;observers=0\x07");
+    second.expect(b"\x1b]2016;clients;count=2;observers=0\x07");
+
+    // output of the command reaches both, whoever sent the input
+    first.send_data(b"one");
+    first.expect(b"one");
+    second.expect(b"one");
+    second.send_data(b"two");
+    first.expect(b"two");
+    second.expect(b"two");
+
+    // the session goes on without the second client
+    drop(second);
+    first.expect(b"\x1b]2016;clients;count=1;observers=0\x07");
+    first.send_data(b"three");
+    first.expect(b"three");
+}
+
+#[test]
+fn resize_policy() {
+    let mut first = Session::start(&["--max-clients", "2", "--resize-policy", "largest"]);
+    let mut second = first.join();
+    first.expect(b"\x1b]2016;clients;count=2;observers=0\x07");
+    second.expect(b"\x1b]2016;clients;count=2;observers=0\x07");
+
+    // the terminal gets the most columns and rows either client asked for
+    second.send(b"1:100:30:");
+    first.expect(b"\x1b]2016;size;cols=100;rows=30;policy=largest\x07");
+    second.expect(b"\x1b]2016;size;cols=100;rows=30;policy=largest\x07");
+    first.send(b"1:80:40:");
+    first.expect(b"\x1b]2016;size;cols=100;rows=40;policy=largest\x07");
+    second.expect(b"\x1b]2016;size;cols=100;rows=40;policy=largest\x07");
+
+    // and shrinks to the window of the one left
+    drop(second);
+    first.expect(b"\x1b]2016;clients;count=1;observers=0\x07");
+    first.expect(b"\x1b]2016;size;cols=80;rows=40;policy=largest\x07");
+}
+
+#[test]
+fn join_context() {
+    let mut first = Session::start(&["--max-clients", "2", "--join-context", "5", "--rows", "3"]);
+    first.send_data(b"a\r\nb\r\nc\r\nd\r\n");
+    first.expect(b"a\r\nb\r\nc\r\nd\r\n");
+
+    // the joining client catches up before it gets any output
+    let mut second = first.join();
+    second.expect(b"\x1b]2016;context;part=scrollback;data=UkVBRFlhCmIK\x07");
+    second.expect(b"\x1b]2016;context;part=screen;data=YwpkCgo=\x07");
+    second.expect(b"\x1b]2016;context;part=end;row=2;col=0\x07");
+    second.expect(b"\x1b]2016;clients;count=2;observers=0\x07");
+    first.expect(b"\x1b]2016;clients;count=2;observers=0\x07");
+    first.send_data(b"e");
+    second.expect(b"e");
+}
+
+#[test]
+fn max_memory() {
+    // the session's buffer and those of three clients take 28 KiB
+    let mut first = Session::start(&["--max-clients", "4", "--max-memory", "32K"]);
+    let mut second = first.join();
+    second.expect(b"\x1b]2016;clients;count=2;observers=0\x07");
+    let mut third = first.join();
+    third.expect(b"\x1b]2016;clients;count=3;observers=0\x07");
+
+    // a fourth one doesn't fit anymore
+    let mut fourth = first.join();
+    assert_eq!(fourth.read_to_end(), b"");
+    first.send_data(b"one");
+    first.skip_until(b"one");
+}
+
+#[test]
+fn observer() {
+    let mut first = Session::start(&["--max-clients", "2", "--observers"]);
+    let mut observer = first.join();
+    first.expect(b"\x1b]2016;clients;count=2;observers=1\x07");
+    observer.expect(b"\x1b]2016;clients;count=2;observers=1\x07");
+
+    // neither input nor control messages of the observer reach the command
+    observer.send_data(b"ignored");
+    observer.send(b"1:20:5:3:7:suspend");
+    first.send_data(b"one");
+    first.expect(b"one");
+    observer.expect(b"one");
+}
+
+#[test]
+#[cfg(feature = "auth-http")]
+fn stalled_join() {
+    let (proxy, port) = start_authenticating(&["--max-clients", "2"], &[]);
+    let mut first = Session::connect(Some(proxy), port);
+    first.send(format!("{USER}:ticket\n").as_bytes());
+    first.expect(b"OK");
+    first.expect(READY);
+
+    // a client not finishing its handshake doesn't hold up the session until it times out
+    let mut stalled = first.join();
+    stalled.send(USER.as_bytes());
+    let start = Instant::now();
+    first.send_data(b"one");
+    first.expect(b"one");
+    assert!(
+        start.elapsed() < Duration::from_secs(5),
+        "relay waited for the handshake"
+    );
+}
+
+#[test]
+#[cfg(all(feature = "auth-http", feature = "daemon"))]
+fn share_link() {
+    let status_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("share-status");
+    let _ = std::fs::remove_dir_all(&status_dir);
+    let status_arg = status_dir.to_str().unwrap();
+    let args = [
+        "--max-clients",
+        "3",
+        "--share-links",
+        "--status-dir",
+        status_arg,
+        "--session-id",
+        "share-test",
+    ];
+    let (proxy, port) = start_authenticating(&args, &[]);
+    let mut first = Session::connect(Some(proxy), port);
+    first.send(format!("{USER}:ticket\n").as_bytes());
+    first.expect(b"OK");
+    first.expect(READY);
+
+    let share = Command::new(env!("CARGO_BIN_EXE_proxmox-termproxy"))
+        .args([
+            "share",
+            "--status-dir",
+            status_arg,
+            "--observe",
+            "share-test",
+        ])
+        .output()
+        .expect("failed to run share");
+    assert!(share.status.success(), "share failed: {share:?}");
+    let token = String::from_utf8(share.stdout).unwrap();
+
+    // the token lets a client in without a ticket, as an observer
+    let mut shared = first.join();
+    shared.send(format!("share:{token}").as_bytes());
+    shared.expect(b"OK");
+    first.expect(b"\x1b]2016;clients;count=2;observers=1\x07");
+    shared.expect(b"\x1b]2016;clients;count=2;observers=1\x07");
+
+    // but only once
+    let mut again = first.join();
+    again.send(format!("share:{token}").as_bytes());
+    assert_eq!(again.read_to_end(), b"");
+}
+
+#[test]
+fn reconnect() {
+    let mut session = Session::start_command(
+        &["--reconnect-grace", "10"],
+        "stty raw -echo && printf READY && head -c 1 && sleep 0.5 && printf away && exec head -c 4",
+    );
+    session.expect(READY);
+    session.send_data(b"1");
+    session.expect(b"1");
+
+    // output of the command while the client was gone is held for it
+    let mut session = session.reconnect();
+    session.skip_until(b"\x1b]2016;resumed;held=");
+    session.skip_until(b"away");
+    session.send_data(b"back");
+    session.expect(b"back");
+    session.skip_until(b"\x1b]2016;session-end;reason=exited;status=0;");
+}
+
+#[test]
+#[cfg(feature = "auth-http")]
+fn reconnect_handshake() {
+    let (proxy, port) = start_authenticating(&["--reconnect-grace", "1"], &[]);
+    let mut session = Session::connect(Some(proxy), port);
+    session.send(format!("{USER}:ticket\n").as_bytes());
+    session.expect(b"OK");
+    session.expect(READY);
+
+    // the time to reconnect runs out while the client is still authenticating
+    let mut session = session.reconnect();
+    session.send(USER.as_bytes());
+    std::thread::sleep(Duration::from_millis(1500));
+    session.send(b":ticket\n");
+    session.expect(b"OK\x1b]2016;resumed;held=0\x07");
+    session.send_data(b"back");
+    session.expect(b"back");
+}
+
+/// The state of the process `pid` as shown in `/proc/<pid>/stat`, e.g. 'T' if stopped.
+fn process_state(pid: u32) -> char {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).unwrap();
+    let (_, rest) = stat.rsplit_once(") ").unwrap();
+    rest.chars().next().unwrap()
+}
+
+#[test]
+fn freeze_detached() {
+    let mut session = Session::start(&["--reconnect-grace", "10", "--freeze-detached"]);
+    let proxy = session.proxy.take().unwrap();
+    let children = format!("/proc/{0}/task/{0}/children", proxy.id());
+    let command: u32 = std::fs::read_to_string(children)
+        .unwrap()
+        .split_whitespace()
+        .next()
+        .expect("no command running")
+        .parse()
+        .unwrap();
+    let port = session.port;
+    drop(session);
+
+    let start = Instant::now();
+    while process_state(command) != 'T' {
+        assert!(start.elapsed() < TIMEOUT, "command not stopped");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut session = Session::connect(Some(proxy), port);
+    session.skip_until(b"\x1b]2016;resumed;held=0\x07");
+    session.send_data(b"back");
+    session.expect(b"back");
+
+    // instead of waiting for the grace period
+    let mut proxy = session.proxy.take().unwrap();
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+}
+
+#[test]
+#[cfg(feature = "daemon")]
+fn detach() {
+    let status_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("detach-status");
+    let _ = std::fs::remove_dir_all(&status_dir);
+    let status_arg = status_dir.to_str().unwrap();
+    let mut session = Session::start(&[
+        "--detachable",
+        "--status-dir",
+        status_arg,
+        "--session-id",
+        "detach-test",
+    ]);
+    let mut proxy = session.proxy.take().unwrap();
+
+    // processes connecting to the control socket without sending anything don't hold up the
+    // session until they time out
+    let socket = status_dir.join("detach-test.sock");
+    let _stalled: Vec<UnixStream> = (0..4)
+        .map(|_| UnixStream::connect(&socket).unwrap())
+        .collect();
+    let start = Instant::now();
+    session.send_data(b"one");
+    session.expect(b"one");
+    assert!(
+        start.elapsed() < Duration::from_secs(2),
+        "relay waited for the control socket"
+    );
+    session.send(b"3:6:detach");
+    session.read_to_end();
+
+    // clients authenticated for another privilege are not taken over
+    let proxy_path = Path::new(env!("CARGO_BIN_EXE_proxmox-termproxy"));
+    let args = [
+        "--status-dir",
+        status_arg,
+        "--attach",
+        "detach-test",
+        "--perm",
+        "Sys.Console",
+    ];
+    let mut rejected = Session::launch(proxy_path, None, &args);
+    assert!(rejected.read_to_end().is_empty(), "client taken over");
+
+    // another termproxy hands the next client over to the session
+    let args = ["--status-dir", status_arg, "--attach", "detach-test"];
+    let mut attached = Session::launch(proxy_path, None, &args);
+    attached.expect(b"\x1b]2016;resumed;held=0\x07");
+    attached.send_data(b"two");
+    attached.expect(b"two");
+
+    // SIGUSR1 detaches all clients
+    unsafe { libc::kill(proxy.id() as i32, libc::SIGUSR1) };
+    attached.read_to_end();
+    assert!(proxy.try_wait().unwrap().is_none(), "session ended");
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+}
+
+#[test]
+#[cfg(feature = "daemon")]
+fn escape_detach() {
+    let status_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("escape-detach-status");
+    let _ = std::fs::remove_dir_all(&status_dir);
+    let status_arg = status_dir.to_str().unwrap();
+    let mut session = Session::start(&[
+        "--detachable",
+        "--escape-char",
+        "~",
+        "--status-dir",
+        status_arg,
+    ]);
+    // disconnecting with the escape sequence detaches from a detachable session
+    session.send_data(b"~.");
+    session.read_to_end();
+    let proxy = session.proxy.as_mut().unwrap();
+    assert!(proxy.try_wait().unwrap().is_none(), "session ended");
+
+    let mut session = session.reconnect();
+    session.expect(b"\x1b]2016;resumed;held=0\x07");
+    session.send_data(b"back");
+    session.expect(b"back");
+    let mut proxy = session.proxy.take().unwrap();
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+}
+
+#[test]
+#[cfg(feature = "daemon")]
+fn detached_output() {
+    let status_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("detached-output-status");
+    let _ = std::fs::remove_dir_all(&status_dir);
+    let status_arg = status_dir.to_str().unwrap();
+    let done = status_dir.join("done");
+    // far more output than the terminal and the relay buffer hold, once the client detached
+    let script = format!(
+        "stty raw -echo && printf READY && read line && \
+         head -c 1000000 /dev/zero | tr '\\0' x && printf END && touch {done:?} && exec cat"
+    );
+    let args = ["--detachable", "--status-dir", status_arg];
+    let mut session = Session::start_command(&args, &script);
+    // the session outlives its client, it must not be waited for if the test fails
+    let mut proxy = session.proxy.take().unwrap();
+    session.expect(READY);
+    session.send_data(b"go\n");
+    session.send(b"3:6:detach");
+    session.read_to_end();
+
+    // the command doesn't block while no client reads its output
+    let start = Instant::now();
+    while !done.exists() {
+        assert!(start.elapsed() < TIMEOUT, "command blocked");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let mut session = Session::connect(None, session.port);
+    session.skip_until(b"\x1b]2016;resumed;held=65536;dropped=");
+    session.skip_until(b"END");
+    session.send_data(b"back");
+    session.expect(b"back");
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+}
+
+#[test]
+#[cfg(all(feature = "auth-http", feature = "daemon"))]
+fn background() {
+    let status_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("background-status");
+    let _ = std::fs::remove_dir_all(&status_dir);
+    let status_arg = status_dir.to_str().unwrap();
+    let (mut proxy, _port) = start_authenticating(
+        &[
+            "--detachable",
+            "--background",
+            USER,
+            "--status-dir",
+            status_arg,
+            "--session-id",
+            "background-test",
+        ],
+        &[],
+    );
+
+    // the session runs without any client, until one attaches
+    let start = Instant::now();
+    while !status_dir.join("background-test.sock").exists() {
+        assert!(start.elapsed() < TIMEOUT, "no control socket");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let status = std::fs::read_to_string(status_dir.join("background-test.status")).unwrap();
+    assert!(status.contains("\nclients=0\n"), "{status}");
+    let proxy_path = Path::new(env!("CARGO_BIN_EXE_proxmox-termproxy"));
+    let args = ["--status-dir", status_arg, "--attach", "background-test"];
+    let mut attached = Session::launch(proxy_path, None, &args);
+    attached.skip_until(READY);
+    attached.send_data(b"hello");
+    attached.expect(b"hello");
+
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+}
+
+#[test]
+fn admin_observe() {
+    // only root may observe
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let status_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("admin-status");
+    let _ = std::fs::remove_dir_all(&status_dir);
+    let status_arg = status_dir.to_str().unwrap();
+    let mut session = Session::start(&["--status-dir", status_arg, "--session-id", "admin-test"]);
+
+    let mut observer = Command::new(env!("CARGO_BIN_EXE_proxmox-termproxy"))
+        .args(["observe", "--status-dir", status_arg, "admin-test"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run observe");
+    session.expect(b"\r\n\x1b]2016;admin;state=observing;uid=0\x07");
+    session.skip_until(b"this session]\r\n");
+
+    session.send_data(b"watched");
+    session.expect(b"watched");
+    let mut stdout = observer.stdout.take().unwrap();
+    let mut watched = Vec::new();
+    while !watched.ends_with(b"watched") {
+        let mut buf = [0u8; 1024];
+        let n = stdout.read(&mut buf).unwrap();
+        assert!(n > 0, "observe ended, printed {watched:?}");
+        watched.extend_from_slice(&buf[..n]);
+    }
+    // the notice goes to the administrator as well
+    assert!(watched.starts_with(b"\r\n\x1b]2016;admin;state=observing;uid=0\x07"));
+
+    observer.kill().unwrap();
+    observer.wait().unwrap();
+    session.expect(b"\r\n\x1b]2016;admin;state=left;uid=0\x07");
+}
+
+#[test]
+fn keepalive() {
+    let mut session = Session::start(&["--keepalive", "2"]);
+    session.send(b"2");
+    session.skip_until(b"\x1b]2016;pong\x07");
+    // silent for two seconds
+    session.skip_until(b"\x1b]2016;ping\x07");
+    session.send(b"2");
+    session.skip_until(b"\x1b]2016;pong\x07");
+}
+
+#[test]
+fn hex_input() {
+    let mut session = Session::start_command(
+        &["--allow-hex-input", "00,1b5b41"],
+        "stty raw -echo && printf READY && head -c 3 | od -An -tx1",
+    );
+    session.expect(READY);
+    // sequences that are not allowed are dropped
+    session.send(b"3:8:hex:0303");
+    session.send(b"3:10:hex:1b5b41");
+    session.skip_until(b" 1b 5b 41");
+}
+
+#[test]
+#[cfg(feature = "serial")]
+fn sysrq() {
+    let break_command = Path::new(env!("CARGO_TARGET_TMPDIR")).join("slow-break");
+    std::fs::write(&break_command, "#!/bin/sh\nsleep 1\n").unwrap();
+    std::fs::set_permissions(&break_command, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let mut session = Session::start(&[
+        "--break-command",
+        break_command.to_str().unwrap(),
+        "--allow-sysrq",
+    ]);
+    session.send(b"3:7:sysrq:h");
+    session.expect(b"\x1b]2016;sysrq;state=confirm;key=h;timeout=10\x07");
+    // input is still relayed while the BREAK is being sent, the key follows it
+    session.send(b"3:15:sysrq-confirm:h");
+    session.send_data(b"x");
+    session.expect(b"x");
+    session.expect(b"\x1b]2016;sysrq;state=sent;key=h\x07h");
+}
+
+#[test]
+#[cfg(feature = "serial")]
+fn guest_detection() {
+    let mut session = Session::start_command(
+        &["--detect-guest"],
+        // only the latest change of a read is reported, so the boot takes a moment
+        "printf '\\033[1;1H   GNU GRUB  version 2.06\\r\\n' && sleep 0.2 && \
+         printf 'Debian GNU/Linux 12 pve ttyS0\\r\\n\\r\\npve login: ' && exec cat",
+    );
+    session.skip_until(b"\x1b]2016;guest;os=grub;label=GRUB\x07");
+    session.skip_until(b"\x1b]2016;guest;os=linux-login;label=Linux login\x07");
+}
+
+#[test]
+fn semantic_lines() {
+    let mut session = Session::start_command(
+        &["--semantic-lines", "--cols", "10"],
+        // the second line wraps, the one on the alternate screen is left out
+        "printf 'hello\\r\\n0123456789abc\\r\\n\\033[?1049hfull\\r\\n\\033[?1049lend\\r\\n' && \
+         exec cat",
+    );
+    session.skip_until(b"\x1b]2016;line;data=aGVsbG8=\x07");
+    session.skip_until(b"\x1b]2016;line;data=MDEyMzQ1Njc4OWFiYw==\x07");
+    session.skip_until(b"\x1b]2016;line;data=ZW5k\x07");
+}
+
+#[test]
+#[cfg(feature = "serial")]
+fn sac() {
+    let mut session = Session::start_command(
+        &["--sac"],
+        "stty -opost -icanon -echo && printf 'a\\nb\\0c\\0\\r\\0\\n\\0\\377\\376\\351\\0\\r\\n' && \
+         head -c 3 | od -An -c",
+    );
+    session.expect("a\r\nbc\r\né\r\n".as_bytes());
+    session.send(b"3:8:sac-home");
+    session.skip_until(b"033  \\t   0");
+}
+
+#[test]
+#[cfg(feature = "recording")]
+fn ttyrec_recording() {
+    let recording = Path::new(env!("CARGO_TARGET_TMPDIR")).join("recording.ttyrec");
+    let _ = std::fs::remove_file(&recording);
+    let mut session = Session::start_command(
+        &[
+            "--record",
+            recording.to_str().unwrap(),
+            "--record-format",
+            "ttyrec",
+        ],
+        "printf recorded",
+    );
+    session.read_to_end();
+
+    // frames of seconds, microseconds, length and the data, all of the output in this case
+    let recording = std::fs::read(&recording).unwrap();
+    let mut data = Vec::new();
+    let mut frames = recording.as_slice();
+    while !frames.is_empty() {
+        let len = u32::from_le_bytes(frames[8..12].try_into().unwrap()) as usize;
+        data.extend_from_slice(&frames[12..12 + len]);
+        frames = &frames[12 + len..];
+    }
+    assert_eq!(data, b"recorded");
+}
+
+#[test]
+#[cfg(feature = "recording")]
+fn record_group() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("record-group");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let record = |name: &str| {
+        let recording = dir.join(name);
+        let mut session = Session::start_command(
+            &[
+                "--record",
+                recording.to_str().unwrap(),
+                "--record-group",
+                "lesson",
+            ],
+            "printf recorded",
+        );
+        session.read_to_end();
+        let recording = std::fs::read_to_string(&recording).unwrap();
+        let events: Vec<serde_json::Value> = recording
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        events
+    };
+
+    // the second session starts later, its events are timed from the start of the first one
+    let teacher = record("teacher.cast");
+    std::thread::sleep(Duration::from_millis(500));
+    let student = record("student.cast");
+    assert_eq!(teacher[0]["group"]["id"], "lesson");
+    assert_eq!(teacher[0]["group"], student[0]["group"]);
+    assert_eq!(teacher[0]["timestamp"], student[0]["timestamp"]);
+    let first_output = |events: &[serde_json::Value]| events[1][0].as_f64().unwrap();
+    assert!(
+        first_output(&student) >= first_output(&teacher) + 0.5,
+        "unexpected times {teacher:?} and {student:?}",
+    );
+}
+
+#[test]
+fn command_history() {
+    let history = Path::new(env!("CARGO_TARGET_TMPDIR")).join("history.jsonl");
+    let _ = std::fs::remove_file(&history);
+    let mut session = Session::start_command(
+        &["--command-history", history.to_str().unwrap()],
+        "printf 'test$ '; read line; printf 'ran %s\\n' \"$line\"; stty -echo; printf 'secret> '; read secret",
+    );
+    session.expect(b"test$ ");
+    session.send_data(b"ls -l\r");
+    session.expect(b"ls -l\r\nran ls -l\r\n");
+    // read without echo, like a password
+    session.expect(b"secret> ");
+    session.send_data(b"hunter2\r");
+    session.read_to_end();
+
+    let history = std::fs::read_to_string(&history).unwrap();
+    let lines: Vec<serde_json::Value> = history
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 1, "unexpected history {history:?}");
+    assert_eq!(lines[0]["command"], "ls -l");
+    assert_eq!(lines[0]["user"], USER);
+}
+
+#[test]
+#[cfg(feature = "recording")]
+fn replay() {
+    // a second of output recorded in between, played back a hundred times faster
+    let recording = Path::new(env!("CARGO_TARGET_TMPDIR")).join("replay.cast");
+    std::fs::write(
+        &recording,
+        "{\"version\": 2, \"width\": 80, \"height\": 20}\n\
+         [0.5, \"o\", \"first\\r\\n\"]\n\
+         [1.0, \"r\", \"100x30\"]\n\
+         [1.5, \"o\", \"second\\r\\n\"]\n",
+    )
+    .unwrap();
+    let script = format!(
+        "exec {} replay --speed 100 {}",
+        env!("CARGO_BIN_EXE_proxmox-termproxy"),
+        recording.to_str().unwrap(),
+    );
+    let mut session = Session::start_command(&[], &script);
+    let output = session.read_to_end();
+    let output = String::from_utf8_lossy(&output);
+    assert!(
+        output.starts_with("first\r\nsecond\r\n\x1b]2016;session-end;reason=exited;status=0;"),
+        "unexpected output {output:?}",
+    );
+}
+
+/// Sends a GET request for `target` with the extra `headers` to `port`, returns the status line
+/// and the body of the response.
+#[cfg(feature = "recording")]
+fn http_get(port: u16, target: &str, headers: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("failed to connect");
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    write!(
+        stream,
+        "GET {target} HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n"
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .expect("incomplete response");
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+#[test]
+#[cfg(feature = "recording")]
+fn serve_recordings() {
+    use std::io::{BufRead, BufReader};
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("served-recordings");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let cast = "{\"version\": 2, \"width\": 100, \"height\": 30}\n[0.5, \"o\", \"cast\"]\n";
+    std::fs::write(dir.join("session.cast"), cast).unwrap();
+    // frames at 1000.25 and 1001.5 seconds since the epoch, with a character split between them
+    let mut ttyrec = Vec::new();
+    for (secs, micros, data) in [
+        (1000u32, 250_000u32, &b"tty\xc3"[..]),
+        (1001, 500_000, b"\xa9"),
+    ] {
+        ttyrec.extend_from_slice(&secs.to_le_bytes());
+        ttyrec.extend_from_slice(&micros.to_le_bytes());
+        ttyrec.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        ttyrec.extend_from_slice(data);
+    }
+    std::fs::write(dir.join("session.ttyrec"), ttyrec).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind listener");
+    let port = listener.local_addr().unwrap().port();
+    let fd = listener.as_raw_fd();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_proxmox-termproxy"));
+    command
+        .args([
+            "serve-recordings",
+            "--dir",
+            dir.to_str().unwrap(),
+            "--port-as-fd",
+        ])
+        .arg(fd.to_string())
+        .stdout(Stdio::piped());
+    // the listener has to survive the exec
+    unsafe {
+        command.pre_exec(move || {
+            if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut server = command.spawn().expect("failed to start the server");
+    drop(listener);
+    let mut token = String::new();
+    BufReader::new(server.stdout.take().unwrap())
+        .read_line(&mut token)
+        .unwrap();
+    let token = token.trim();
+
+    let (status, _) = http_get(port, "/", "");
+    assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+    let (status, _) = http_get(port, "/?token=0123", "");
+    assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+
+    let (status, listing) = http_get(port, &format!("/?token={token}"), "");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    for name in ["session.cast", "session.ttyrec"] {
+        let link = format!("<a href=\"/{name}?token={token}\">{name}</a>");
+        assert!(listing.contains(&link), "no link to {name} in {listing:?}");
+    }
+
+    // asciicasts are served as they are, ttyrec recordings converted
+    let bearer = format!("Authorization: Bearer {token}\r\n");
+    let (status, served) = http_get(port, "/session.cast", &bearer);
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(served, cast);
+    let (status, served) = http_get(port, "/session.ttyrec", &bearer);
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let lines: Vec<serde_json::Value> = served
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines[0]["version"], 2);
+    assert_eq!(lines[0]["timestamp"], 1000);
+    assert_eq!(lines[1], serde_json::json!([0.0, "o", "tty"]));
+    assert_eq!(lines[2], serde_json::json!([1.25, "o", "\u{e9}"]));
+
+    let (status, _) = http_get(port, "/../session.cast", &bearer);
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn snapshot() {
+    let status_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("snapshot-status");
+    let _ = std::fs::remove_dir_all(&status_dir);
+    let mut session = Session::start(&[
+        "--status-dir",
+        status_dir.to_str().unwrap(),
+        "--session-id",
+        "snapshot-test",
+        "--snapshot-interval",
+        "1",
+    ]);
+    // clear the screen and draw at the third line
+    let drawing = b"\x1b[2J\x1b[3;5Hsnap\r\nshot";
+    session.send_data(drawing);
+    session.expect(drawing);
+
+    let mut expected = String::from("\n\n    snap\nshot\n");
+    expected.push_str(&"\n".repeat(16));
+    let path = status_dir.join("snapshot-test.snapshot");
+    let start = Instant::now();
+    while std::fs::read_to_string(&path).ok().as_ref() != Some(&expected) {
+        assert!(start.elapsed() < TIMEOUT, "no snapshot of the drawing");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    drop(session);
+    assert!(!path.exists(), "snapshot not removed");
+}
+
+#[test]
+fn list() {
+    let status_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("list-status");
+    let _ = std::fs::remove_dir_all(&status_dir);
+    let status_arg = status_dir.to_str().unwrap();
+    let _session = Session::start(&[
+        "--status-dir",
+        status_arg,
+        "--session-id",
+        "list-test",
+        "--tag",
+        "vmid=100",
+    ]);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_proxmox-termproxy"))
+        .args([
+            "list",
+            "--status-dir",
+            status_arg,
+            "--output-format",
+            "json",
+        ])
+        .output()
+        .expect("failed to run list");
+    assert!(output.status.success(), "list failed: {output:?}");
+    let sessions: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let session = &sessions[0];
+    assert_eq!(session["id"], "list-test");
+    assert_eq!(session["user"], USER);
+    assert_eq!(session["command"], "/bin/sh");
+    assert_eq!(session["vmid"], 100);
+    assert_eq!(session["state"], "running");
+    assert_eq!(session["clients"], 1);
+}
+
+/// How often `proxy` blocked, e.g. waiting for events, so far.
+fn wakeups(proxy: &Child) -> u64 {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", proxy.id())).unwrap();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("voluntary_ctxt_switches:"))
+        .expect("no context switch count")
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+/// Asserts that `proxy` barely wakes up within a second.
+fn assert_sleeping(proxy: &Child) {
+    let before = wakeups(proxy);
+    std::thread::sleep(Duration::from_secs(1));
+    let count = wakeups(proxy) - before;
+    assert!(count < 5, "woke up {count} times within a second");
+}
+
+#[test]
+fn idle_wakeups() {
+    let session = Session::start(&[]);
+    assert_sleeping(session.proxy());
+
+    // neither may output piling up while the client doesn't read
+    let session = Session::start_command(&[], "exec yes");
+    std::thread::sleep(Duration::from_millis(500));
+    assert_sleeping(session.proxy());
+}